@@ -2,7 +2,7 @@ use std::io::{Write, Read};
 use std::process::Command;
 use std::env;
 use std::fs::File;
-use std::io::{Error, ErrorKind};
+use std::io::Error;
 
 const VER_FILE_NAME: &str = ".VERSION";
 
@@ -53,7 +53,7 @@ fn version_to_file(filename: &str, rev: &str, branch: &str) -> Result<(), String
 fn main() -> Result<(), std::io::Error> {
     let base_dir = env::vars()
         .find(|(name, _)| name == "CARGO_MANIFEST_DIR")
-        .ok_or(Error::new(ErrorKind::Other, "CARGO_MANIFEST_DIR env not found"))?
+        .ok_or(Error::other("CARGO_MANIFEST_DIR env not found"))?
         .1;
     println!("base dir = {}", &base_dir);
 
@@ -62,7 +62,7 @@ fn main() -> Result<(), std::io::Error> {
     let (rev, branch) = if let Ok((rev, branch)) = version_from_git() {
         println!("got version from git, saving to file");
         version_to_file(version_file, &rev, &branch)
-            .map_err(|e| Error::new(ErrorKind::Other, e))?;
+            .map_err(Error::other)?;
         println!("saved to file");
         (rev, branch)
     }
@@ -1,4 +1,5 @@
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand};
+use crate::stats::Label;
 
 #[derive(Parser)]
 #[command(name = "bigarchiver")]
@@ -14,9 +15,46 @@ pub struct ArgOpts {
 pub enum Commands {
     /// Backup mode: read data from stdin and write into output files(s)
     Backup {
-        /// Template for output chunks; '%' symbols will transform into a sequence number
-        #[arg(long, value_name = "path_with_%")]
-        out_template: String,
+        /// Template for output chunks; '%' symbols will transform into a sequence number; {n/1000}/{n%1000} spreads chunks over directories by their number instead; {date}, {date:%Y-%m-%d_%H%M}, {host}, {label} and {label:key} are replaced once at start of backup; s3://bucket/prefix%%%% stores chunks and metadata in S3-compatible storage, sftp://user@host/path%%%% on remote host, dav://host/path%%%% or davs://... on WebDAV server; repeat to write identical copies to several destinations, chunk commands apply to the first one only
+        #[arg(long, value_name = "path_with_%", required_unless_present = "repo")]
+        out_template: Vec<String>,
+
+        /// Repository to store deduplicated data in instead of --out-template: input is split into chunks by content, only chunks the repository does not have yet are stored, and the backup is a manifest listing its chunks; created if missing, with --split-size as the average size of chunks; new chunks are compressed by --compress-threads threads, one chunk each
+        #[arg(long, value_name = "path", conflicts_with_all = ["out_template", "min_copies", "s3_endpoint", "ssh_key", "chunk_cmd", "check_chunk_cmd", "post_chunk_cmd",
+            "auth", "auth_every", "parity_every", "parity_chunks", "max_write_rate", "max_read_rate", "rate_burst", "rate_schedule",
+            "io_retries", "io_retry_delay", "min_free_space", "wait_for_space", "input_size"])]
+        repo: Option<String>,
+
+        /// Label stored in metadata of the archive, key=value or a plain tag, e.g. db=main or nightly; repeat to set several; authenticated with the password for an encrypted archive; the value of a tag or of key=value is put in place of {label} or {label:key} in --out-template
+        #[arg(long, value_name = "label", value_parser = Label::parse)]
+        label: Vec<Label>,
+
+        /// How many copies of the archive must be written for backup to succeed when --out-template is repeated; a destination that fails is not written anymore; defaults to all of them
+        #[arg(long, value_name = "how_many")]
+        min_copies: Option<usize>,
+
+        #[command(flatten)]
+        remote: RemoteArgs,
+
+        /// Pipe each chunk into stdin of the indicated shell command instead of writing it to a file; {name} and {path} are replaced with shell-quoted file name and full path of the chunk, which are also in BIGARCHIVER_CHUNK_NAME and BIGARCHIVER_CHUNK_PATH
+        #[arg(long, value_name = "command")]
+        chunk_cmd: Option<String>,
+
+        /// Shell command printing a chunk to stdout, used to verify the archive after backup with --chunk-cmd or --post-chunk-cmd; {name} and {path} are replaced as well; exit code 4 without output means the chunk does not exist
+        #[arg(long, value_name = "command")]
+        check_chunk_cmd: Option<String>,
+
+        /// Shell command to run for each chunk once it is written, e.g. to upload and delete it; BIGARCHIVER_CHUNK_PATH, BIGARCHIVER_CHUNK_NAME, BIGARCHIVER_CHUNK_INDEX and BIGARCHIVER_CHUNK_KIND (data or parity) are set in its environment; requires --check-chunk-cmd or --no-check
+        #[arg(long, value_name = "command")]
+        post_chunk_cmd: Option<String>,
+
+        /// Max number of chunks not yet processed by --post-chunk-cmd, including the one being written; backup waits when it is reached, so local disk usage is capped at this number of chunks
+        #[arg(long, value_name = "nr_chunks", default_value_t = 2)]
+        max_pending_chunks: usize,
+
+        /// How many times to retry a failed --post-chunk-cmd, waiting 1, 2, 4, ... (at most 60) seconds in between
+        #[arg(long, value_name = "how_many", default_value_t = 5)]
+        post_chunk_retries: usize,
 
         /// Encryption & authentication algorithm
         #[arg(long, value_name = "algorithm")]
@@ -34,10 +72,18 @@ pub enum Commands {
         #[arg(long, value_name = "size_mb")]
         auth_every: Option<usize>,
 
-        /// Size of output chunks, in MB
+        /// Size of output chunks, in MB; with --repo, average size of chunks of a new repository
         #[arg(long, value_name = "size_mb")]
         split_size: usize,
 
+        /// Generate parity chunks for each group of indicated number of output chunks (requires --parity-chunks)
+        #[arg(long, value_name = "nr_chunks")]
+        parity_every: Option<usize>,
+
+        /// How many parity chunks to generate for each group, i.e. how many lost chunks per group can be recovered
+        #[arg(long, value_name = "nr_chunks")]
+        parity_chunks: Option<usize>,
+
         /// LZMA compression level, 0 - 9
         #[arg(long, value_name = "level")]
         compress_level: u8,
@@ -50,15 +96,58 @@ pub enum Commands {
         #[arg(long, value_name ="size_mb")]
         buf_size: usize,
 
+        /// Max rate of writing chunks to each destination, in bytes per second, e.g. 500K or 10M; unlimited by default
+        #[arg(long, value_name = "rate", value_parser = crate::throttle::parse_size)]
+        max_write_rate: Option<u64>,
+
+        #[command(flatten)]
+        rate: RateArgs,
+
+        #[command(flatten)]
+        io_retry: IoRetryArgs,
+
+        /// Check before each chunk that at least this much space is left free on the filesystem of the first --out-template besides the chunk, e.g. 10G; if it is not, backup fails and removes chunks written so far
+        #[arg(long, value_name = "size", value_parser = crate::throttle::parse_size)]
+        min_free_space: Option<u64>,
+
+        /// When there is not enough free space for the next chunk, check again every indicated number of seconds until there is, e.g. while --post-chunk-cmd uploads and removes chunks
+        #[arg(long, value_name = "seconds", value_parser = clap::value_parser!(u64).range(1..))]
+        wait_for_space: Option<u64>,
+
+        /// Expected size of input data, to estimate whether the whole archive fits before backup starts; defaults to the size of stdin if it is a regular file
+        #[arg(long, value_name = "size", value_parser = crate::throttle::parse_size)]
+        input_size: Option<u64>,
+
         /// Do not check the integrity of the whole archive after backup is done (the default is to always check)
         #[arg(long, action)]
-        no_check: bool
+        no_check: bool,
+
+        /// Append a record of the backup, of the check after it, and its result to the indicated catalog file, e.g. /var/lib/bigarchiver/catalog.jsonl, created if missing; see the catalog mode
+        #[arg(long, value_name = "path")]
+        catalog: Option<String>,
+
+        #[command(flatten)]
+        reporting: ProgressArgs
     },
     /// Restore mode: restore data from file(s) and write into stdout
     Restore {
-        /// Full path to config file of the archive to restore
-        #[arg(long, value_name = "full_path")]
-        config: String,
+        /// Full path to config file of the archive to restore, s3://bucket/key of its metadata object, sftp://user@host/path, or dav://host/path (davs:// for https); repeat to read missing or damaged chunks from other copies of the archive, chunk commands apply to the first one only; or full path to a manifest in a repository, e.g. /bk/repo/manifests/<archive_id>.manifest
+        #[arg(long, value_name = "full_path", required = true)]
+        config: Vec<String>,
+
+        /// Refuse to restore the archive unless it has the indicated label: key=value, or a tag or key alone; repeat to require several; labels of an encrypted archive are verified with the password
+        #[arg(long, value_name = "label", value_parser = Label::parse)]
+        label: Vec<Label>,
+
+        #[command(flatten)]
+        remote: RemoteArgs,
+
+        /// Read each chunk from stdout of the indicated shell command instead of a file; {name} and {path} are replaced with shell-quoted file name and full path of the chunk, which are also in BIGARCHIVER_CHUNK_NAME and BIGARCHIVER_CHUNK_PATH; exit code 4 without output means the chunk does not exist
+        #[arg(long, value_name = "command")]
+        chunk_cmd: Option<String>,
+
+        #[command(flatten)]
+        fetch: FetchArgs,
 
         /// Password to decrypt data with (only if the archive was created with encryption)
         #[arg(long, value_name = "password")]
@@ -72,19 +161,54 @@ pub enum Commands {
         #[arg(long, value_name ="size_mb")]
         buf_size: usize,
 
+        #[command(flatten)]
+        rate: RateArgs,
+
+        #[command(flatten)]
+        io_retry: IoRetryArgs,
+
         /// Check free space available on the indicated filesystem before restore
         #[arg(long, value_name = "mountpoint_or_path")]
         check_free_space: Option<String>,
 
-        /// Do not check the integrity of the whole archive before actual restore (the default is to always check)
+        /// Do not check the integrity of the whole archive before actual restore (the default is to always check, except for a manifest in a repository)
         #[arg(long, action)]
-        no_check: bool
+        no_check: bool,
+
+        /// Check the whole backup of a manifest in a repository before restoring it; otherwise its chunks are verified as they are restored, and the data is verified at the end
+        #[arg(long, action, conflicts_with = "no_check")]
+        check_first: bool,
+
+        /// Best-effort restore of a damaged archive, without checking it beforehand: write zeros in place of unrecoverable data or skip it, and report lost ranges
+        #[arg(long, value_name = "lost_data")]
+        salvage: Option<LostData>,
+
+        /// Append a record of the restore, of the check before it, and its result to the indicated catalog file, e.g. /var/lib/bigarchiver/catalog.jsonl, created if missing; see the catalog mode
+        #[arg(long, value_name = "path")]
+        catalog: Option<String>,
+
+        #[command(flatten)]
+        reporting: ProgressArgs
     },
     /// Check mode: check integrity of data from file(s)
     Check {
-        /// Full path to config file of the archive to restore
-        #[arg(long, value_name = "full_path")]
-        config: String,
+        /// Full path to config file of the archive to restore, s3://bucket/key of its metadata object, sftp://user@host/path, or dav://host/path (davs:// for https); repeat to read missing or damaged chunks from other copies of the archive, chunk commands apply to the first one only; or full path to a manifest in a repository, e.g. /bk/repo/manifests/<archive_id>.manifest
+        #[arg(long, value_name = "full_path", required = true)]
+        config: Vec<String>,
+
+        /// Refuse to check the archive unless it has the indicated label: key=value, or a tag or key alone; repeat to require several; labels of an encrypted archive are verified with the password
+        #[arg(long, value_name = "label", value_parser = Label::parse)]
+        label: Vec<Label>,
+
+        #[command(flatten)]
+        remote: RemoteArgs,
+
+        /// Read each chunk from stdout of the indicated shell command instead of a file; {name} and {path} are replaced with shell-quoted file name and full path of the chunk, which are also in BIGARCHIVER_CHUNK_NAME and BIGARCHIVER_CHUNK_PATH; exit code 4 without output means the chunk does not exist
+        #[arg(long, value_name = "command")]
+        chunk_cmd: Option<String>,
+
+        #[command(flatten)]
+        fetch: FetchArgs,
 
         /// Password to decrypt data with (only if the archive was created with encryption)
         #[arg(long, value_name = "password")]
@@ -97,6 +221,129 @@ pub enum Commands {
         /// Buffer size for reading disk files, in MB
         #[arg(long, value_name ="size_mb")]
         buf_size: usize,
+
+        #[command(flatten)]
+        rate: RateArgs,
+
+        #[command(flatten)]
+        io_retry: IoRetryArgs,
+
+        /// Append a record of the check and its result to the indicated catalog file, e.g. /var/lib/bigarchiver/catalog.jsonl, created if missing; see the catalog mode
+        #[arg(long, value_name = "path")]
+        catalog: Option<String>,
+
+        #[command(flatten)]
+        reporting: ProgressArgs
+    },
+    /// Repair mode: rebuild missing or damaged chunks from parity chunks and write them back in place
+    Repair {
+        /// Full path to config file of the archive to repair, s3://bucket/key of its metadata object, sftp://user@host/path, or dav://host/path (davs:// for https)
+        #[arg(long, value_name = "full_path")]
+        config: String,
+
+        /// Buffer size for reading disk files, in MB
+        #[arg(long, value_name ="size_mb")]
+        buf_size: usize,
+
+        #[command(flatten)]
+        remote: RemoteArgs,
+    },
+    /// Info mode: print metadata of the archive to stdout as JSON, without reading its chunks
+    Info {
+        /// Full path to config file of the archive, s3://bucket/key of its metadata object, sftp://user@host/path, or dav://host/path (davs:// for https); several ones, e.g. /bk/*/0.cfg, are printed one per line
+        #[arg(long, value_name = "full_path", required = true, num_args = 1..)]
+        config: Vec<String>,
+
+        /// Print only archives which have the indicated label: key=value, or a tag or key alone; repeat to require several
+        #[arg(long, value_name = "label", value_parser = Label::parse)]
+        label: Vec<Label>,
+
+        /// Password of encrypted archives, to verify their labels; without it, labels are printed and matched unverified
+        #[arg(long, value_name = "password")]
+        pass: Option<String>,
+
+        #[command(flatten)]
+        remote: RemoteArgs,
+    },
+    /// Recover-cfg mode: rebuild lost metadata file of an archive from headers of its chunks
+    RecoverCfg {
+        /// Template the chunks were written with, e.g. /path/to/archive%%%%, s3://bucket/prefix%%%%, sftp://user@host/path%%%% or dav://host/path%%%%; the metadata is written next to the chunks in remote storage
+        #[arg(long, value_name = "path_with_%")]
+        out_template: String,
+
+        /// Read each chunk from stdout of the indicated shell command instead of a file; {name} and {path} are replaced with shell-quoted file name and full path of the chunk, which are also in BIGARCHIVER_CHUNK_NAME and BIGARCHIVER_CHUNK_PATH; exit code 4 without output means the chunk does not exist
+        #[arg(long, value_name = "command")]
+        chunk_cmd: Option<String>,
+
+        /// Password the archive was encrypted with; not needed for unencrypted archives
+        #[arg(long, value_name = "password")]
+        pass: Option<String>,
+
+        /// Buffer size for reading disk files, in MB
+        #[arg(long, value_name ="size_mb")]
+        buf_size: usize,
+
+        #[command(flatten)]
+        remote: RemoteArgs,
+    },
+    /// Catalog mode: list and search archives recorded in a catalog file by backup, check and restore with --catalog
+    Catalog {
+        #[command(subcommand)]
+        command: CatalogCommands
+    },
+    /// Prune mode: delete archives recorded in a catalog file by backup with --catalog which are not kept by the retention policy
+    Prune {
+        /// Path to the catalog file
+        #[arg(long, value_name = "path")]
+        catalog: String,
+
+        /// Keep the indicated number of the most recent archives
+        #[arg(long, value_name = "how_many", default_value_t = 0)]
+        keep_last: usize,
+
+        /// Keep the most recent archive of each of the indicated number of the last days with archives
+        #[arg(long, value_name = "how_many", default_value_t = 0)]
+        keep_daily: usize,
+
+        /// Keep the most recent archive of each of the indicated number of the last weeks with archives
+        #[arg(long, value_name = "how_many", default_value_t = 0)]
+        keep_weekly: usize,
+
+        /// Keep the most recent archive of each of the indicated number of the last months with archives
+        #[arg(long, value_name = "how_many", default_value_t = 0)]
+        keep_monthly: usize,
+
+        /// Apply the policy only to archives which have the indicated label: key=value, or a tag or key alone; repeat to require several
+        #[arg(long, value_name = "label", value_parser = Label::parse)]
+        label: Vec<Label>,
+
+        /// Only tell which archives would be deleted
+        #[arg(long, action)]
+        dry_run: bool,
+    },
+    /// Gc mode: remove chunks of a repository which no manifest refers to, e.g. after manifests of old backups are deleted; fails if a backup into the repository is running
+    Gc {
+        /// Directory of the repository
+        #[arg(long, value_name = "path")]
+        repo: String,
+
+        /// Password the repository is encrypted with; not needed for unencrypted repositories
+        #[arg(long, value_name = "password")]
+        pass: Option<String>,
+
+        /// Only tell how many chunks would be removed
+        #[arg(long, action)]
+        dry_run: bool,
+    },
+    /// Repo-check mode: verify every chunk stored in a repository and that every manifest can be restored
+    RepoCheck {
+        /// Directory of the repository
+        #[arg(long, value_name = "path")]
+        repo: String,
+
+        /// Password the repository is encrypted with; not needed for unencrypted repositories
+        #[arg(long, value_name = "password")]
+        pass: Option<String>,
     },
     /// Benchmark mode: read data from stdin and try different combinations of input params to see how fast the archiving is
     Bench {
@@ -126,6 +373,116 @@ pub enum Commands {
     }
 }
 
+// access to archives at s3:// and sftp:// paths
+#[derive(Args)]
+pub struct RemoteArgs {
+    /// Endpoint of S3-compatible storage, e.g. http://localhost:9000; defaults to AWS_ENDPOINT_URL or AWS S3 in AWS_REGION
+    #[arg(long, value_name = "url")]
+    pub s3_endpoint: Option<String>,
+
+    /// Private key for sftp:// paths, used if ssh-agent has no suitable key; defaults to ~/.ssh/id_ed25519, id_ecdsa or id_rsa
+    #[arg(long, value_name = "path")]
+    pub ssh_key: Option<String>
+}
+
+// fetching of chunks into local scratch space, for restore and check modes
+#[derive(Args)]
+pub struct FetchArgs {
+    /// Shell command to fetch each chunk into its local path before reading it; BIGARCHIVER_CHUNK_PATH, BIGARCHIVER_CHUNK_NAME, BIGARCHIVER_CHUNK_INDEX and BIGARCHIVER_CHUNK_KIND (data or parity) are set in its environment; restore then skips the check before it, so that every chunk is fetched once, and verifies chunks as it reads them
+    #[arg(long, value_name = "command")]
+    pub fetch_cmd: Option<String>,
+
+    /// Shell command to remove a fetched chunk once it is read, with the same environment as --fetch-cmd
+    #[arg(long, value_name = "command")]
+    pub cleanup_cmd: Option<String>,
+
+    /// How many next chunks to fetch in background with --fetch-cmd while the current one is read
+    #[arg(long, value_name = "nr_chunks", default_value_t = 2)]
+    pub prefetch_chunks: usize,
+
+    /// How many times to retry a failed --fetch-cmd, waiting 1, 2, 4, ... (at most 60) seconds in between
+    #[arg(long, value_name = "how_many", default_value_t = 5)]
+    pub fetch_retries: usize
+}
+
+// limits of reading chunks, for backup, restore and check modes; writing is limited with --max-write-rate of backup
+#[derive(Args)]
+pub struct RateArgs {
+    /// Max rate of reading chunks, in bytes per second, e.g. 500K or 10M; unlimited by default
+    #[arg(long, value_name = "rate", value_parser = crate::throttle::parse_size)]
+    pub max_read_rate: Option<u64>,
+
+    /// How many bytes may be transferred at once after a pause when rate is limited; defaults to one second worth of data
+    #[arg(long, value_name = "size", value_parser = crate::throttle::parse_size)]
+    pub rate_burst: Option<u64>,
+
+    /// Time windows when rate limits apply, e.g. 08:00-20:00 or 22:00-06:00,12:00-13:00 (local time); always by default
+    #[arg(long, value_name = "windows")]
+    pub rate_schedule: Option<String>
+}
+
+// retries of failed operations on local chunk files
+#[derive(Args)]
+pub struct IoRetryArgs {
+    /// How many times to retry an operation on a local chunk file which failed with a transient error such as EIO or ETIMEDOUT on network filesystem; a chunk being written is then synced every 16 MB; not retried by default
+    #[arg(long, value_name = "how_many")]
+    pub io_retries: Option<usize>,
+
+    /// Delay before the first retry of a failed operation on a local chunk file, in milliseconds, 1000 by default; doubled after every attempt, up to 60 seconds
+    #[arg(long, value_name = "ms")]
+    pub io_retry_delay: Option<u64>
+}
+
+// progress events of backup, restore and check modes
+#[derive(Args)]
+pub struct ProgressArgs {
+    /// Report progress as events in the indicated format, one per line, to stderr or --progress-fd: the phase (backup, verify or restore), bytes in and out, current chunk, compression ratio, rate and ETA, once a second; the last event tells the result
+    #[arg(long, value_name = "format")]
+    pub progress: Option<ProgressFormat>,
+
+    /// File descriptor to write progress events to instead of stderr, e.g. 3 when run with 3>progress.log
+    #[arg(long, value_name = "fd")]
+    pub progress_fd: Option<i32>
+}
+
+#[derive(Subcommand)]
+pub enum CatalogCommands {
+    /// List every copy of every archive in the catalog, one per line, with the result of its last check or restore
+    List {
+        /// Path to the catalog file
+        #[arg(long, value_name = "path")]
+        catalog: String,
+    },
+    /// Print all records of an archive as JSON, one per line
+    Show {
+        /// Path to the catalog file
+        #[arg(long, value_name = "path")]
+        catalog: String,
+
+        /// Archive id or full path to config file of the archive
+        #[arg(value_name = "archive")]
+        archive: String,
+    },
+    /// List archives which match all of the indicated conditions
+    Search {
+        /// Path to the catalog file
+        #[arg(long, value_name = "path")]
+        catalog: String,
+
+        /// Label the archive must have: key=value, or a tag or key alone; repeat to require several
+        #[arg(long, value_name = "label", value_parser = Label::parse)]
+        label: Vec<Label>,
+
+        /// Text the path to config file or the output template of the archive must contain
+        #[arg(long, value_name = "text")]
+        path: Option<String>,
+
+        /// Only archives which were never verified or restored, or failed the last time
+        #[arg(long, action)]
+        unverified: bool,
+    }
+}
+
 #[derive(clap::ValueEnum, Default, Clone, PartialEq, Debug)]
 pub enum Alg {
     None,
@@ -134,6 +491,17 @@ pub enum Alg {
     Chacha20Poly1305
 }
 
+#[derive(clap::ValueEnum, Clone, PartialEq, Debug)]
+pub enum LostData {
+    Zeros,
+    Skip
+}
+
+#[derive(clap::ValueEnum, Clone, PartialEq, Debug)]
+pub enum ProgressFormat {
+    Json
+}
+
 pub fn nr_threads_from_arg(opt_nr: &Option<usize>) -> Result<usize, String> {
     Ok(opt_nr.unwrap_or(std::thread::available_parallelism().map_err(|_| "could not get number of processor cores")?.get()))
 }
@@ -1,12 +1,26 @@
-use bigarchiver::arg_opts::{ArgOpts, Alg, Commands, nr_threads_from_arg};
-use bigarchiver::{backup, check, timestamp, EncParams};
-use bigarchiver::file_set::cfg_from_pattern;
+use bigarchiver::arg_opts::{ArgOpts, Alg, Commands, CatalogCommands, FetchArgs, IoRetryArgs, LostData, ProgressFormat, RateArgs, RemoteArgs, nr_threads_from_arg};
+use bigarchiver::{backup, check, check_auth_len, check_chunk_names, has_labels, info, repair, recover_cfg, salvage, set_local_offset, timestamp, BackupOptions, CheckOptions, EncParams, ParityParams, Storage, Mirrors};
+use bigarchiver::file_set::{cfg_from_pattern, resolve_template, static_dir, FileSet};
+use bigarchiver::chunk_hooks::{PostChunkHook, FetchHook};
+use bigarchiver::s3::{S3Config, is_s3_path};
+use bigarchiver::sftp::{SftpConfig, is_sftp_path};
+use bigarchiver::webdav::{WebDavConfig, is_dav_path};
+use bigarchiver::throttle::{ThrottleConfig, parse_schedule};
+use bigarchiver::io_retry::RetryPolicy;
+use bigarchiver::free_space::SpaceCheck;
 use bigarchiver::finalizable::DataSink;
+use bigarchiver::progress::{Progress, Phase};
+use bigarchiver::catalog::{Catalog, Archive};
+use bigarchiver::prune::{prune, RetentionPolicy};
+use bigarchiver::repository::{Repository, is_manifest_path, repository_of};
+use bigarchiver::stats::{Label, labels_as_string};
 use clap::Parser;
 use std::io::{stdout, Write};
 use std::process::ExitCode;
 use std::{thread, fs};
 use std::sync::{Arc, atomic::AtomicBool};
+use std::time::{Duration, Instant};
+use time::UtcOffset;
 
 struct StdoutWriter;
 
@@ -21,12 +35,237 @@ impl DataSink for StdoutWriter {
     }
 }
 
-fn process_args(args: &ArgOpts) -> Result<(), String> {
+fn storage_from_arg(chunk_cmd: &Option<String>) -> Storage {
+    match chunk_cmd {
+        Some(cmd) => Storage::Command(cmd.clone()),
+        None => Storage::Files
+    }
+}
+
+fn remote_storage(path: &str, remote: &RemoteArgs) -> Result<Option<Storage>, String> {
+    if is_s3_path(path) {
+        Ok(Some(Storage::S3(S3Config::from_env(&remote.s3_endpoint)?)))
+    } else if is_sftp_path(path) {
+        Ok(Some(Storage::Sftp(SftpConfig::new(&remote.ssh_key))))
+    } else if is_dav_path(path) {
+        Ok(Some(Storage::WebDav(WebDavConfig::from_env())))
+    } else {
+        Ok(None)
+    }
+}
+
+fn check_remote_args(paths: &[String], remote: &RemoteArgs) -> Result<(), String> {
+    if remote.s3_endpoint.is_some() && !paths.iter().any(|p| is_s3_path(p)) {
+        return Err("--s3-endpoint is only used with s3:// paths".to_owned());
+    }
+    if remote.ssh_key.is_some() && !paths.iter().any(|p| is_sftp_path(p)) {
+        return Err("--ssh-key is only used with sftp:// paths".to_owned());
+    }
+    Ok(())
+}
+
+fn main_storage_from_args(config: &str, remote: &RemoteArgs, chunk_cmd: &Option<String>, fetch: &FetchArgs) -> Result<Storage, String> {
+    if let Some(storage) = remote_storage(config, remote)? {
+        if chunk_cmd.is_some() || fetch.fetch_cmd.is_some() || fetch.cleanup_cmd.is_some() {
+            return Err("chunk commands cannot be used with remote storage".to_owned());
+        }
+        return Ok(storage);
+    }
+    match (chunk_cmd, &fetch.fetch_cmd) {
+        (Some(_), Some(_)) => Err("--chunk-cmd and --fetch-cmd cannot be used together".to_owned()),
+        (_, None) if fetch.cleanup_cmd.is_some() => Err("--cleanup-cmd is only used together with --fetch-cmd".to_owned()),
+        (_, Some(cmd)) => Ok(Storage::Fetched(FetchHook {
+            fetch_cmd: cmd.clone(),
+            cleanup_cmd: fetch.cleanup_cmd.clone(),
+            prefetch: fetch.prefetch_chunks,
+            retries: fetch.fetch_retries,
+            first_backoff: Duration::from_secs(1)
+        })),
+        (_, None) => Ok(storage_from_arg(chunk_cmd))
+    }
+}
+
+// metadata path of the main copy and storage to read the archive from; with several copies
+// chunk commands apply to the first one, and missing or damaged chunks are read from the others
+fn read_storage_from_args(configs: &[String], remote: &RemoteArgs, chunk_cmd: &Option<String>, fetch: &FetchArgs) -> Result<(String, Storage), String> {
+    check_remote_args(configs, remote)?;
+    let (main_config, other_configs) = configs.split_first().ok_or("no --config".to_owned())?;
+    let storage = main_storage_from_args(main_config, remote, chunk_cmd, fetch)?;
+    if other_configs.is_empty() {
+        return Ok((main_config.clone(), storage));
+    }
+    let mut copies = vec![(main_config.clone(), storage)];
+    for config in other_configs {
+        copies.push((config.clone(), remote_storage(config, remote)?.unwrap_or(Storage::Files)));
+    }
+    Ok((main_config.clone(), Storage::Failover(copies)))
+}
+
+fn throttle_from_args(write_rate: &Option<u64>, rate: &RateArgs) -> Result<ThrottleConfig, String> {
+    if write_rate.is_none() && rate.max_read_rate.is_none() && (rate.rate_burst.is_some() || rate.rate_schedule.is_some()) {
+        return Err("--rate-burst and --rate-schedule are only used with --max-write-rate or --max-read-rate".to_owned());
+    }
+    Ok(ThrottleConfig {
+        write_rate: *write_rate,
+        read_rate: rate.max_read_rate,
+        burst: rate.rate_burst,
+        schedule: rate.rate_schedule.as_deref().map(parse_schedule).transpose()?.unwrap_or_default()
+    })
+}
+
+// local chunk files are retried only if asked to, as every chunk being written is then synced now and then
+fn retry_policy_from_args(io_retry: &IoRetryArgs) -> Result<Option<RetryPolicy>, String> {
+    match (io_retry.io_retries, io_retry.io_retry_delay) {
+        (None, Some(_)) => Err("--io-retry-delay is only used with --io-retries".to_owned()),
+        (None, None) => Ok(None),
+        (Some(retries), delay) => Ok(Some(RetryPolicy { retries, first_backoff: Duration::from_millis(delay.unwrap_or(1000)) }))
+    }
+}
+
+fn with_io_retries(storage: Storage, policy: &Option<RetryPolicy>) -> Storage {
+    match policy {
+        Some(policy) => storage.with_retries(policy),
+        None => storage
+    }
+}
+
+// size of input data if stdin is redirected from a file
+fn stdin_len() -> Option<u64> {
+    fs::metadata("/dev/stdin").ok().filter(|m| m.is_file()).map(|m| m.len())
+}
+
+fn require_labels(config: &str, storage: &Storage, pass: &Option<String>, labels: &[Label]) -> Result<(), String> {
+    if labels.is_empty() || has_labels(config, storage, pass, labels)? {
+        Ok(())
+    } else {
+        Err(format!("archive {} does not have labels {}", config, labels_as_string(labels)))
+    }
+}
+
+// passes the result of a check or restore through, recording it in the catalog if one is given
+fn record_check(catalog: &Option<String>, phase: Phase, config: &str, storage: &Storage, res: Result<(), String>) -> Result<(), String> {
+    if let Some(path) = catalog {
+        Catalog::new(path).record_check(phase, config, storage, &res);
+    }
+    res
+}
+
+// the same for a manifest in a repository, which is not read through a storage
+fn record_repo_check(catalog: &Option<String>, phase: Phase, manifest_path: &str, archive_id: Option<[u8; 16]>, res: Result<(), String>) -> Result<(), String> {
+    if let Some(path) = catalog {
+        Catalog::new(path).record_result(phase, archive_id, manifest_path, &res);
+    }
+    res
+}
+
+fn report_lost(lost: &[(usize, usize)], lost_data: &LostData) -> Result<(), String> {
+    if lost.is_empty() {
+        eprintln!("no data is lost");
+        return Ok(());
+    }
+    let total: usize = lost.iter().map(|(_, len)| len).sum();
+    eprintln!("lost ranges of data ({}):", if lost_data == &LostData::Zeros { "filled with zeros" } else { "skipped" });
+    for (offset, len) in lost {
+        eprintln!("offset {}, length {}", offset, len);
+    }
+    Err(format!("{} bytes in {} range(s) could not be salvaged", total, lost.len()))
+}
+
+fn print_archives(archives: Vec<Archive>) {
+    for archive in archives {
+        println!("{}", archive.summary());
+    }
+}
+
+fn backup_to_repository(path: &str, alg: &Alg, pass: &Option<String>, avg_chunk_len: usize, opts: &BackupOptions, no_check: bool, catalog: &Option<String>) -> Result<(), String> {
+    let repo = Repository::open_or_create(path, alg, pass, avg_chunk_len)?;
+    eprintln!("backing up into repository {} (using {} threads)...", path, opts.nr_threads);
+    let started = Instant::now();
+    let res = repo.backup(&mut std::io::stdin(), opts)?;
+    eprintln!("{} bytes in {} chunks, {} of them new ({} bytes stored)", res.in_len, res.nr_chunks, res.nr_new_chunks, res.stored_len);
+    eprintln!("manifest is written to {}", res.manifest_path);
+    if let Some(catalog) = catalog {
+        Catalog::new(catalog).record_repo_backup(path, &res, &opts.labels, started.elapsed().as_millis() as u64);
+    }
+    if no_check {
+        return Ok(());
+    }
+    eprintln!("verifying...");
+    let check_res = repo.restore(None::<StdoutWriter>, &res.manifest_path, &None, &opts.progress);
+    record_repo_check(catalog, Phase::Verify, &res.manifest_path, Some(res.archive_id), check_res)
+}
+
+// restore or check of a manifest, read from a local repository; chunks are verified as they are read,
+// so the whole backup is checked before restore only with `check_first`
+fn restore_from_repository(configs: &[String], storage_options: bool, labels: &[Label], opts: &CheckOptions, check_first: bool, write: bool,
+    catalog: &Option<String>) -> Result<(), String>
+{
+    if configs.len() > 1 || storage_options {
+        return Err("a manifest is read from a local repository, without other copies, chunk commands, remote storage, rate limits or --salvage".to_owned());
+    }
+    let manifest_path = &configs[0];
+    let repo = Repository::open(&repository_of(manifest_path)?, &opts.pass)?;
+    let manifest = match repo.read_manifest(manifest_path) {
+        Ok(manifest) => manifest,
+        Err(e) => {
+            let phase = if write { Phase::Restore } else { Phase::Verify };
+            return record_repo_check(catalog, phase, manifest_path, None, Err(e));
+        }
+    };
+    let archive_id = Some(manifest.archive_id);
+    if !labels.iter().all(|l| l.matches(&manifest.labels)) {
+        return Err(format!("archive {} does not have labels {}", manifest_path, labels_as_string(labels)));
+    }
+    if !write {
+        eprintln!("verifying...");
+        let res = repo.restore(None::<StdoutWriter>, manifest_path, &None, &opts.progress);
+        return record_repo_check(catalog, Phase::Verify, manifest_path, archive_id, res);
+    }
+    if check_first {
+        eprintln!("verifying before restore...");
+        let res = repo.restore(None::<StdoutWriter>, manifest_path, &None, &opts.progress);
+        record_repo_check(catalog, Phase::Verify, manifest_path, archive_id, res)
+            .map_err(|e| format!("will not restore data, integrity check error: {}", e))?;
+    }
+    eprintln!("restoring...");
+    let res = repo.restore(Some(StdoutWriter{}), manifest_path, &opts.check_free_space.as_deref(), &opts.progress);
+    record_repo_check(catalog, Phase::Restore, manifest_path, archive_id, res)
+        .map_err(|e| format!("error restoring data: {}", e))
+}
+
+fn progress_from_args(command: &Commands) -> Result<Option<Progress>, String> {
+    let (format, fd, input_len) = match command {
+        Commands::Backup { reporting, input_size, .. } => (&reporting.progress, &reporting.progress_fd, input_size.or(stdin_len())),
+        Commands::Restore { reporting, .. } | Commands::Check { reporting, .. } => (&reporting.progress, &reporting.progress_fd, None),
+        _ => return Ok(None)
+    };
+    let interval = Duration::from_secs(1);
+    match (format, fd) {
+        (None, Some(_)) => Err("--progress-fd is only used with --progress".to_owned()),
+        (None, None) => Ok(None),
+        (Some(ProgressFormat::Json), None) => Ok(Some(Progress::to_stderr(interval, input_len))),
+        (Some(ProgressFormat::Json), Some(fd)) => Ok(Some(Progress::to_fd(*fd, interval, input_len)?))
+    }
+}
+
+fn process_args(args: &ArgOpts, progress: &Option<Progress>) -> Result<(), String> {
     match &args.command {
         Commands::Backup { 
-            out_template, alg, pass, auth, auth_every, 
-            split_size, compress_level, compress_threads, buf_size, no_check
+            out_template, label, min_copies, remote, chunk_cmd, check_chunk_cmd, post_chunk_cmd, max_pending_chunks, post_chunk_retries, alg, pass, auth, auth_every, 
+            split_size, parity_every, parity_chunks, compress_level, compress_threads, buf_size, max_write_rate, rate, io_retry,
+            min_free_space, wait_for_space, input_size, no_check, catalog, repo, ..
         } => {
+            if let Some(repo) = repo {
+                let opts = BackupOptions {
+                    compress_level: *compress_level,
+                    nr_threads: nr_threads_from_arg(compress_threads)?,
+                    buf_size_bytes: *buf_size * 1_048_576,
+                    labels: label.clone(),
+                    progress: progress.clone(),
+                    ..Default::default()
+                };
+                return backup_to_repository(repo, alg, pass, *split_size * 1_048_576, &opts, *no_check, catalog);
+            }
             let nr_threads = nr_threads_from_arg(compress_threads)?;
             eprintln!("backing up (using {} threads)...", nr_threads);
 
@@ -37,6 +276,7 @@ fn process_args(args: &ArgOpts) -> Result<(), String> {
                 if pass.is_none() || auth.is_none() || auth_every.is_none() {
                     return Err("not all encryption params are set for encryption mode".to_owned());
                 }
+                check_auth_len(auth.as_ref().unwrap())?;
                 Some(EncParams{ 
                     alg: alg.clone(), 
                     auth_msg: auth.as_ref().unwrap().clone(), 
@@ -50,39 +290,329 @@ fn process_args(args: &ArgOpts) -> Result<(), String> {
                 None
             };
 
-            backup(&mut std::io::stdin(),
-                &opt_enc, split_size, &out_template, 
-                *compress_level, nr_threads, buf_size, None)?;
-            if !no_check {
-                let cfg_path = cfg_from_pattern(&out_template);
-                eprintln!("verifying...");
-                check(None::<StdoutWriter>, &cfg_path, pass, nr_threads, buf_size, &None::<&str>, true)
-            } else {
-                Ok(())
+            let opt_parity = match (parity_every, parity_chunks) {
+                (Some(data_chunks), Some(parity_chunks)) => Some(ParityParams{ 
+                    data_chunks: *data_chunks, 
+                    parity_chunks: *parity_chunks 
+                }),
+                (None, None) => None,
+                _ => { return Err("both --parity-every and --parity-chunks must be set for parity mode".to_owned()); }
+            };
+
+            let throttle = throttle_from_args(max_write_rate, rate)?;
+            let retries = retry_policy_from_args(io_retry)?;
+            let out_template = out_template.iter().map(|tpl| resolve_template(tpl, label)).collect::<Result<Vec<_>, String>>()?;
+            let out_template = &out_template;
+            check_remote_args(out_template, remote)?;
+            let (main_template, mirror_templates) = out_template.split_first().ok_or("no --out-template".to_owned())?;
+            let storage = match remote_storage(main_template, remote)? {
+                Some(_) if chunk_cmd.is_some() || check_chunk_cmd.is_some() || post_chunk_cmd.is_some() => {
+                    return Err("chunk commands cannot be used with remote storage".to_owned());
+                },
+                Some(storage) => storage,
+                None => storage_from_arg(chunk_cmd)
+            };
+
+            let opt_space = match (min_free_space, wait_for_space) {
+                (None, None) if input_size.is_some() => {
+                    return Err("--input-size is only used with --min-free-space or --wait-for-space".to_owned());
+                },
+                (None, None) => None,
+                _ if !matches!(storage, Storage::Files) => {
+                    return Err("free space can be checked only when chunks are written to local files".to_owned());
+                },
+                _ => Some(SpaceCheck {
+                    path: match static_dir(main_template) {
+                        "" => ".".to_owned(),
+                        dir => dir.to_owned()
+                    },
+                    margin: min_free_space.unwrap_or(0) as usize,
+                    poll: wait_for_space.map(Duration::from_secs),
+                    input_len: input_size.or(stdin_len()).map(|len| len as usize)
+                })
+            };
+
+            let check_storage = with_io_retries(match (chunk_cmd, check_chunk_cmd) {
+                (Some(_), None) if !no_check => {
+                    return Err("verification after backup with --chunk-cmd requires --check-chunk-cmd, or use --no-check".to_owned());
+                },
+                (None, None) if post_chunk_cmd.is_some() && !no_check => {
+                    // the command may have moved chunks away, so they are not verified where they were written
+                    return Err("verification after backup with --post-chunk-cmd requires --check-chunk-cmd, or use --no-check".to_owned());
+                },
+                (None, Some(_)) if post_chunk_cmd.is_none() => {
+                    return Err("--check-chunk-cmd is only used together with --chunk-cmd or --post-chunk-cmd".to_owned());
+                },
+                _ if matches!(storage, Storage::S3(_) | Storage::Sftp(_) | Storage::WebDav(_)) => storage.clone(),
+                _ => storage_from_arg(check_chunk_cmd)
+            }, &retries).throttled(&throttle);
+            // fails before backup rather than at verification after it
+            check_chunk_names(&check_storage, FileSet::from_pattern(main_template)?.chunk_pattern().is_some())?;
+            let storage = with_io_retries(storage, &retries).throttled(&throttle);
+
+            let opt_mirrors = match (mirror_templates.len(), min_copies) {
+                (0, Some(_)) => { return Err("--min-copies is only used with several --out-template".to_owned()); },
+                (0, None) => None,
+                (_, _) => Some(Mirrors {
+                    destinations: mirror_templates.iter()
+                        .map(|tpl| Ok((tpl.clone(), with_io_retries(remote_storage(tpl, remote)?.unwrap_or(Storage::Files), &retries).throttled(&throttle))))
+                        .collect::<Result<Vec<_>, String>>()?,
+                    min_copies: min_copies.unwrap_or(out_template.len())
+                })
+            };
+
+            let opt_hook = post_chunk_cmd.as_ref().map(|cmd| PostChunkHook {
+                cmd: cmd.clone(),
+                max_pending: *max_pending_chunks,
+                retries: *post_chunk_retries,
+                first_backoff: Duration::from_secs(1)
+            });
+
+            let opts = BackupOptions {
+                enc: opt_enc,
+                parity: opt_parity,
+                post_chunk_hook: opt_hook,
+                mirrors: opt_mirrors,
+                space: opt_space,
+                compress_level: *compress_level,
+                nr_threads,
+                buf_size_bytes: buf_size,
+                exit_flag: None,
+                labels: label.clone(),
+                progress: progress.clone()
+            };
+            backup(&mut std::io::stdin(), split_size, main_template, &storage, &opts)?;
+            if let Some(path) = catalog {
+                let copies = [(main_template.clone(), check_storage.clone())].into_iter()
+                    .chain(opts.mirrors.iter().flat_map(|mirrors| mirrors.destinations.iter().cloned()));
+                for (tpl, storage) in copies {
+                    Catalog::new(path).record_backup(&cfg_from_pattern(&tpl), &tpl, &storage);
+                }
             }
+            if *no_check {
+                return Ok(());
+            }
+            let check_opts = CheckOptions { pass: pass.clone(), nr_threads, buf_size_bytes: buf_size, show_info: true, progress: progress.clone(), ..Default::default() };
+            let mirrors = match &opts.mirrors {
+                Some(mirrors) => mirrors,
+                None => {
+                    eprintln!("verifying...");
+                    let cfg_path = cfg_from_pattern(main_template);
+                    let res = check(None::<StdoutWriter>, &cfg_path, &check_storage, &check_opts);
+                    return record_check(catalog, Phase::Verify, &cfg_path, &check_storage, res);
+                }
+            };
+            let mut nr_verified = 0;
+            for (tpl, storage) in [(main_template.clone(), check_storage)].into_iter().chain(mirrors.destinations.iter().cloned()) {
+                eprintln!("verifying copy in {}...", tpl);
+                let cfg_path = cfg_from_pattern(&tpl);
+                let res = check(None::<StdoutWriter>, &cfg_path, &storage, &check_opts);
+                match record_check(catalog, Phase::Verify, &cfg_path, &storage, res) {
+                    Ok(()) => { nr_verified += 1; },
+                    Err(e) => eprintln!("copy in {} is not valid: {}", tpl, e)
+                }
+            }
+            if nr_verified < mirrors.min_copies {
+                return Err(format!("only {} of {} copies are valid while {} required", nr_verified, out_template.len(), mirrors.min_copies));
+            }
+            Ok(())
         },
 
-        Commands::Restore { config, pass, decompress_threads, buf_size, check_free_space, no_check } => {
-            let buf_size = *buf_size * 1_048_576;
+        Commands::Restore {
+            config, remote, chunk_cmd, fetch, pass, rate, check_free_space, check_first, salvage, label, catalog, ..
+        } if is_manifest_path(&config[0]) => {
+            let storage_options = remote.s3_endpoint.is_some() || remote.ssh_key.is_some() || chunk_cmd.is_some() || fetch.fetch_cmd.is_some() || fetch.cleanup_cmd.is_some()
+                || rate.max_read_rate.is_some() || rate.rate_burst.is_some() || rate.rate_schedule.is_some() || salvage.is_some();
+            let opts = CheckOptions { pass: pass.clone(), check_free_space: check_free_space.clone(), progress: progress.clone(), ..Default::default() };
+            restore_from_repository(config, storage_options, label, &opts, *check_first, true, catalog)
+        },
+
+        Commands::Restore { check_first: true, .. } => {
+            Err("--check-first is only used with a manifest in a repository, other archives are checked before restore unless --no-check".to_owned())
+        },
+
+        Commands::Check {
+            config, remote, chunk_cmd, fetch, pass, rate, label, catalog, ..
+        } if is_manifest_path(&config[0]) => {
+            let storage_options = remote.s3_endpoint.is_some() || remote.ssh_key.is_some() || chunk_cmd.is_some() || fetch.fetch_cmd.is_some() || fetch.cleanup_cmd.is_some()
+                || rate.max_read_rate.is_some() || rate.rate_burst.is_some() || rate.rate_schedule.is_some();
+            let opts = CheckOptions { pass: pass.clone(), progress: progress.clone(), ..Default::default() };
+            restore_from_repository(config, storage_options, label, &opts, false, false, catalog)
+        },
+
+        Commands::Restore {
+            config, remote, chunk_cmd, fetch, pass, buf_size, rate, io_retry, check_free_space, salvage: Some(lost_data), label, catalog, ..
+        } => {
+            let throttle = throttle_from_args(&None, rate)?;
+            let (config, storage) = read_storage_from_args(config, remote, chunk_cmd, fetch)?;
+            let storage = with_io_retries(storage, &retry_policy_from_args(io_retry)?).throttled(&throttle);
+            require_labels(&config, &storage, pass, label)?;
+            eprintln!("salvaging...");
+            let opts = CheckOptions {
+                pass: pass.clone(),
+                buf_size_bytes: *buf_size * 1_048_576,
+                check_free_space: check_free_space.clone(),
+                progress: progress.clone(),
+                ..Default::default()
+            };
+            let res = salvage(StdoutWriter{}, &config, &storage, &opts, lost_data == &LostData::Zeros)
+                .map_err(|e| format!("error salvaging data: {}", e))
+                .and_then(|lost| report_lost(&lost, lost_data));
+            record_check(catalog, Phase::Restore, &config, &storage, res)
+        },
+
+        Commands::Restore {
+            config, remote, chunk_cmd, fetch, pass, decompress_threads, buf_size,
+            rate, io_retry, check_free_space, no_check, salvage: None, label, catalog, ..
+        } => {
             let nr_threads = nr_threads_from_arg(decompress_threads)?;
-            if !no_check {
+            let throttle = throttle_from_args(&None, rate)?;
+            let (config, storage) = read_storage_from_args(config, remote, chunk_cmd, fetch)?;
+            let storage = with_io_retries(storage, &retry_policy_from_args(io_retry)?).throttled(&throttle);
+            require_labels(&config, &storage, pass, label)?;
+            let opts = CheckOptions {
+                pass: pass.clone(),
+                nr_threads,
+                buf_size_bytes: *buf_size * 1_048_576,
+                check_free_space: None,
+                show_info: true,
+                progress: progress.clone()
+            };
+            if fetch.fetch_cmd.is_some() && !no_check {
+                // fetching every chunk twice is too costly; chunks are verified while they are restored
+                eprintln!("chunks are fetched once, so the archive is not checked before restore");
+            } else if !no_check {
                 eprintln!("verifying before restore (using {} threads)...", nr_threads);
-                check(None::<StdoutWriter>, &config, pass, nr_threads, buf_size, &None, true)
+                let res = check(None::<StdoutWriter>, &config, &storage, &opts);
+                record_check(catalog, Phase::Verify, &config, &storage, res)
                     .map_err(|e| format!("will not restore data, integrity check error: {}", e))?;
             }
             eprintln!("restoring (using {} threads)...", nr_threads);
-            let may_be_check = check_free_space.as_ref().map(|s| s.as_str());
-            check(Some(StdoutWriter{}), &config, pass, nr_threads,
-                buf_size, &may_be_check, true)
-                    .map_err(|e| format!("error restoring data: {}", e))
+            let res = check(Some(StdoutWriter{}), &config, &storage, &CheckOptions { check_free_space: check_free_space.clone(), ..opts });
+            record_check(catalog, Phase::Restore, &config, &storage, res)
+                .map_err(|e| format!("error restoring data: {}", e))
         },
 
-        Commands::Check { config, pass, decompress_threads, buf_size } => {
+        Commands::Check {
+            config, remote, chunk_cmd, fetch, pass, decompress_threads, buf_size, rate, io_retry, label, catalog, ..
+        } => {
             let nr_threads = nr_threads_from_arg(decompress_threads)?;
+            let throttle = throttle_from_args(&None, rate)?;
+            let (config, storage) = read_storage_from_args(config, remote, chunk_cmd, fetch)?;
+            let storage = with_io_retries(storage, &retry_policy_from_args(io_retry)?).throttled(&throttle);
+            require_labels(&config, &storage, pass, label)?;
             eprintln!("verifying (using {} threads)...", nr_threads);
+            let opts = CheckOptions {
+                pass: pass.clone(),
+                nr_threads,
+                buf_size_bytes: *buf_size * 1_048_576,
+                show_info: true,
+                progress: progress.clone(),
+                ..Default::default()
+            };
+            let res = check(None::<StdoutWriter>, &config, &storage, &opts);
+            record_check(catalog, Phase::Verify, &config, &storage, res)
+        },
+
+        Commands::Repair { config, buf_size, remote } => {
+            check_remote_args(std::slice::from_ref(config), remote)?;
+            let storage = remote_storage(config, remote)?.unwrap_or(Storage::Files);
+            eprintln!("repairing...");
+            let buf_size = *buf_size * 1_048_576;
+            match repair(config, &storage, buf_size)? {
+                0 => eprintln!("all chunks are healthy, nothing to repair"),
+                n => eprintln!("{} chunk(s) rebuilt", n)
+            }
+            Ok(())
+        },
+
+        Commands::Info { config, label, pass, remote } => {
+            check_remote_args(config, remote)?;
+            let mut nr_failed = 0;
+            for config in config {
+                let storage = remote_storage(config, remote)?.unwrap_or(Storage::Files);
+                let res = has_labels(config, &storage, pass, label)
+                    .and_then(|matches| matches.then(|| info(config, &storage)).transpose());
+                match res {
+                    Ok(Some(json)) => println!("{}", json),
+                    Ok(None) => {},
+                    Err(e) => {
+                        eprintln!("could not read metadata {}: {}", config, e);
+                        nr_failed += 1;
+                    }
+                }
+            }
+            match nr_failed {
+                0 => Ok(()),
+                n => Err(format!("metadata of {} archive(s) could not be read", n))
+            }
+        },
+
+        Commands::RecoverCfg { out_template, chunk_cmd, pass, buf_size, remote } => {
+            check_remote_args(std::slice::from_ref(out_template), remote)?;
+            let storage = match remote_storage(out_template, remote)? {
+                Some(_) if chunk_cmd.is_some() => { return Err("chunk commands cannot be used with remote storage".to_owned()); },
+                Some(storage) => storage,
+                None => storage_from_arg(chunk_cmd)
+            };
+            eprintln!("recovering metadata...");
             let buf_size = *buf_size * 1_048_576;
-            check(None::<StdoutWriter>, &config, pass, nr_threads,
-                buf_size, &None, true)
+            let cfg_path = recover_cfg(out_template, &storage, pass, buf_size)?;
+            eprintln!("metadata is written to {}", cfg_path);
+            Ok(())
+        },
+
+        Commands::Catalog { command: CatalogCommands::List { catalog } } => {
+            print_archives(Catalog::new(catalog).search(&[], &None, false)?);
+            Ok(())
+        },
+
+        Commands::Catalog { command: CatalogCommands::Show { catalog, archive } } => {
+            let archives = Catalog::new(catalog).find(archive)?;
+            if archives.is_empty() {
+                return Err(format!("archive {} is not in catalog {}", archive, catalog));
+            }
+            for archive in archives {
+                for event in archive.backup.iter().chain(archive.checks.iter()) {
+                    println!("{}", event);
+                }
+            }
+            Ok(())
+        },
+
+        Commands::Catalog { command: CatalogCommands::Search { catalog, label, path, unverified } } => {
+            print_archives(Catalog::new(catalog).search(label, path, *unverified)?);
+            Ok(())
+        },
+
+        Commands::Prune { catalog, keep_last, keep_daily, keep_weekly, keep_monthly, label, dry_run } => {
+            let policy = RetentionPolicy {
+                keep_last: *keep_last,
+                keep_daily: *keep_daily,
+                keep_weekly: *keep_weekly,
+                keep_monthly: *keep_monthly
+            };
+            let res = prune(&Catalog::new(catalog), &policy, label, *dry_run)?;
+            eprintln!("{} archive(s) {}", res.nr_removed, if *dry_run { "would be deleted" } else { "deleted" });
+            if res.nr_remote > 0 {
+                eprintln!("{} copy(ies) in remote storage left to remove with tools of the storage", res.nr_remote);
+            }
+            Ok(())
+        },
+
+        Commands::Gc { repo, pass, dry_run } => {
+            eprintln!("collecting garbage...");
+            let res = Repository::open(repo, pass)?.gc(*dry_run)?;
+            eprintln!("{} manifest(s) refer to {} chunk(s); {} unreferenced chunk(s) of {} bytes {}", res.nr_manifests, res.nr_chunks,
+                res.nr_removed, res.removed_len, if *dry_run { "would be removed" } else { "removed" });
+            Ok(())
+        },
+
+        Commands::RepoCheck { repo, pass } => {
+            eprintln!("verifying repository...");
+            let (nr_manifests, nr_chunks) = Repository::open(repo, pass)?.check()?;
+            eprintln!("{} chunk(s) and {} manifest(s) are sound", nr_chunks, nr_manifests);
+            Ok(())
         },
 
         Commands::Bench { out_dir, duration, compress_levels, buf_sizes, compress_threads_nums, algs } => {
@@ -136,12 +666,18 @@ fn process_args(args: &ArgOpts) -> Result<(), String> {
                             };
 
                             let thread: thread::JoinHandle<Result<usize, String>> = thread::spawn(move|| {
-                                let bytes = backup(&mut std::io::stdin(),
-                                    &opt_enc,
-                                    usize::MAX, &out_template, 
-                                    level, threads, buf_size_bytes, Some(exit_flag_clone))?;
+                                let opts = BackupOptions {
+                                    enc: opt_enc,
+                                    compress_level: level,
+                                    nr_threads: threads,
+                                    buf_size_bytes,
+                                    exit_flag: Some(exit_flag_clone),
+                                    ..Default::default()
+                                };
+                                let bytes = backup(&mut std::io::stdin(), usize::MAX, &out_template, &Storage::Files, &opts)?;
 
-                                check(None::<StdoutWriter>, &out_cfg, &opt_pass, threads, buf_size_bytes, &None::<&str>, false)?;
+                                let check_opts = CheckOptions { pass: opt_pass, nr_threads: threads, buf_size_bytes, ..Default::default() };
+                                check(None::<StdoutWriter>, &out_cfg, &Storage::Files, &check_opts)?;
 
                                 Ok(bytes)
                             });
@@ -159,7 +695,7 @@ fn process_args(args: &ArgOpts) -> Result<(), String> {
                                 nr_threads: *nr_threads,
                                 alg: alg.clone(),
                                 time_spent_s: ts_delta,
-                                bytes: bytes,
+                                bytes,
                                 bps: if ts_delta > 0 { bytes / ts_delta as usize } else { 0 }
                             });
 
@@ -169,7 +705,7 @@ fn process_args(args: &ArgOpts) -> Result<(), String> {
                 }
             }
 
-            thrpts.sort_by(|a,b| b.bps.cmp(&a.bps));
+            thrpts.sort_by_key(|t| std::cmp::Reverse(t.bps));
             println!("statistics gathered:");
             thrpts.into_iter().for_each(|t| {
                 println!("speed = {} b/s\tbytes = {}\tthreads = {}\tseconds = {}\tlevel = {}\tbuffer = {} MB\talg = {:?}\t", 
@@ -182,9 +718,18 @@ fn process_args(args: &ArgOpts) -> Result<(), String> {
 }
 
 fn main() -> ExitCode {
+    // only possible while there are no other threads
+    set_local_offset(UtcOffset::current_local_offset().unwrap_or(UtcOffset::UTC));
     let args = ArgOpts::parse();
 
-    if let Err(e) = process_args(&args) {
+    let res = progress_from_args(&args.command).and_then(|progress| {
+        let res = process_args(&args, &progress);
+        if let Some(progress) = &progress {
+            progress.result(&res);
+        }
+        res
+    });
+    if let Err(e) = res {
         eprintln!("\nerror: {}\n", e);
         return ExitCode::from(1);
     } else {
@@ -1,6 +1,7 @@
 use std::io::Read;
 use std::sync::{Arc, atomic::{AtomicBool, Ordering}};
 use crate::finalizable::DataSink;
+use crate::progress::Progress;
 
 pub struct BufferedReader<'a, R: Read, T: DataSink> {
     read_from: &'a mut R,
@@ -8,17 +9,22 @@ pub struct BufferedReader<'a, R: Read, T: DataSink> {
     read_buf_size: usize,
     store_buf_size: usize,
     exit_flag: Option<Arc<AtomicBool>>,
+    progress: Option<Progress>
 }
 
 impl<'a, R: Read, T: DataSink> BufferedReader<'a, R, T> {
     pub fn new(read_from: &'a mut R, write_to: &'a mut T, read_buf_size: usize, store_buf_size: usize, exit_flag: Option<Arc<AtomicBool>>) -> Self {
         assert!(read_buf_size < store_buf_size);
-        Self { read_from, write_to, read_buf_size, store_buf_size, exit_flag: exit_flag }
+        Self { read_from, write_to, read_buf_size, store_buf_size, exit_flag, progress: None }
+    }
+
+    // counts input bytes as they are read
+    pub fn set_progress(&mut self, progress: &Progress) {
+        self.progress = Some(progress.clone());
     }
 
     pub fn read_and_write_all(&mut self) -> Result<(), String> {
-        let mut buf: Vec<u8> = Vec::with_capacity(self.store_buf_size);
-        buf.resize(self.store_buf_size, 0);
+        let mut buf: Vec<u8> = vec![0; self.store_buf_size];
 
         let mut eof = false;
 
@@ -39,6 +45,9 @@ impl<'a, R: Read, T: DataSink> BufferedReader<'a, R, T> {
                         //eprintln!("BufferedReader: read and buffered {} bytes from source", bytes_read);
                         offs += bytes_read;
                         left -= bytes_read;
+                        if let Some(progress) = &self.progress {
+                            progress.add_in(bytes_read);
+                        }
                     } else {
                         //eprintln!("BufferedReader: eof");
                         eof = true;
@@ -72,8 +81,7 @@ mod tests {
     }
     impl DummyReader {
         fn new(data_size: usize, read_delay_ms: u32) -> Self {
-            let mut data = Vec::with_capacity(data_size);
-            data.resize(data_size, 0);
+            let mut data = vec![0; data_size];
             thread_rng().fill_bytes(&mut data);
             Self { all_data: data, offset: 0, read_delay_ms }
         }
@@ -0,0 +1,275 @@
+// optional local catalog of archives made or checked on a host: an append-only log with one JSON object
+// per line, so that it needs no locking and a crash may cost at most its last line
+use crate::progress::Phase;
+use crate::repository::RepoBackup;
+use crate::stats::{Label, archive_id_as_string, labels_as_string, labels_as_json};
+use crate::file_set::host_name;
+use crate::{read_stats, time_str_of, timestamp, Storage};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use serde_json::{json, Value};
+
+pub struct Catalog {
+    path: String
+}
+
+// what the catalog tells about one copy of an archive
+pub struct Archive {
+    pub archive_id: Option<String>,
+    pub config: String,
+    pub backup: Option<Value>, // event of its backup, if it was made with this catalog
+    pub checks: Vec<Value>, // verify and restore events, oldest first
+    pub pruned: Option<Value> // event of its removal by prune
+}
+
+impl Archive {
+    pub fn labels(&self) -> Vec<Label> {
+        match self.backup.as_ref().and_then(|b| b.get("labels")) {
+            Some(Value::Object(fields)) => fields.iter()
+                .map(|(key, value)| Label { key: key.clone(), value: value.as_str().map(|v| v.to_owned()) })
+                .collect(),
+            _ => Vec::new()
+        }
+    }
+
+    // unix time of backup, if it was made with this catalog
+    pub fn backup_time(&self) -> Option<u64> {
+        self.backup.as_ref().and_then(|b| b.get("time")).and_then(|t| t.as_u64())
+    }
+
+    // length of compressed data; catalogs of earlier versions have it as `xz_len`
+    pub fn compressed_len(&self) -> Option<u64> {
+        self.backup.as_ref()
+            .and_then(|b| b.get("compressed_len").or_else(|| b.get("xz_len")))
+            .and_then(|l| l.as_u64())
+    }
+
+    // whether the last verification or restore succeeded
+    pub fn last_ok(&self) -> Option<bool> {
+        self.checks.last().and_then(|c| c.get("ok")).and_then(|ok| ok.as_bool())
+    }
+
+    // one line for `catalog list`
+    pub fn summary(&self) -> String {
+        let time = |event: &Value| event.get("time").and_then(|t| t.as_u64())
+            .and_then(|t| time_str_of(t).ok())
+            .unwrap_or("unknown time".to_owned());
+        let backup = match &self.backup {
+            Some(b) => format!("made at {}, {} bytes in {} chunks",
+                time(b), b.get("in_len").and_then(|l| l.as_u64()).unwrap_or(0), b.get("nr_chunks").and_then(|n| n.as_u64()).unwrap_or(0)),
+            None => "made elsewhere".to_owned()
+        };
+        let labels = match self.labels() {
+            labels if labels.is_empty() => String::new(),
+            labels => format!(", labels {}", labels_as_string(&labels))
+        };
+        let check = match self.checks.last() {
+            Some(c) => {
+                let phase = c.get("event").and_then(|e| e.as_str()).unwrap_or("check");
+                match self.last_ok() {
+                    Some(true) => format!("{} ok at {}", phase, time(c)),
+                    _ => format!("{} failed at {}: {}", phase, time(c), c.get("reason").and_then(|r| r.as_str()).unwrap_or("unknown reason"))
+                }
+            },
+            None => "not verified".to_owned()
+        };
+        format!("{} {}: {}{}; {}", self.archive_id.as_deref().unwrap_or("-"), self.config, backup, labels, check)
+    }
+}
+
+impl Catalog {
+    pub fn new(path: &str) -> Self {
+        Self { path: path.to_owned() }
+    }
+
+    // the catalog is informational, failing to update it must not fail the backup or restore
+    fn append(&self, event: Value) {
+        let res = OpenOptions::new().create(true).append(true).open(&self.path)
+            .and_then(|mut f| f.write_all(format!("{}\n", event).as_bytes()));
+        if let Err(e) = res {
+            eprintln!("could not update catalog {}: {}", self.path, e);
+        }
+    }
+
+    fn event(name: &str, archive_id: Option<String>, config: &str) -> Value {
+        json!({
+            "event": name,
+            "time": timestamp(),
+            "host": host_name().ok(),
+            "archive_id": archive_id,
+            "config": config
+        })
+    }
+
+    pub fn record_backup(&self, cfg_path: &str, template: &str, storage: &Storage) {
+        let stats = match read_stats(cfg_path, storage) {
+            Ok(stats) => stats,
+            Err(e) => {
+                eprintln!("could not record backup {} in catalog: {}", cfg_path, e);
+                return;
+            }
+        };
+        let json = stats.as_json();
+        let mut event = Self::event("backup", stats.archive_id.as_ref().map(archive_id_as_string), cfg_path);
+        event["template"] = json!(template);
+        for key in ["in_len", "compressed_len", "nr_chunks", "chunk_len", "alg", "parity_group", "parity_nr", "labels"] {
+            event[key] = json.get(key).cloned().unwrap_or(Value::Null);
+        }
+        event["duration_ms"] = json!(stats.backup_info.as_ref().map(|info| info.duration_ms));
+        self.append(event);
+    }
+
+    // a backup into a repository, told by its manifest; chunks it shares with other backups are not its own
+    pub fn record_repo_backup(&self, repo_path: &str, backup: &RepoBackup, labels: &[Label], duration_ms: u64) {
+        let mut event = Self::event("backup", Some(archive_id_as_string(&backup.archive_id)), &backup.manifest_path);
+        event["repo"] = json!(repo_path);
+        event["in_len"] = json!(backup.in_len);
+        event["nr_chunks"] = json!(backup.nr_chunks);
+        event["nr_new_chunks"] = json!(backup.nr_new_chunks);
+        event["stored_len"] = json!(backup.stored_len);
+        event["labels"] = labels_as_json(labels);
+        event["duration_ms"] = json!(duration_ms);
+        self.append(event);
+    }
+
+    pub fn record_prune(&self, archive: &Archive) {
+        self.append(Self::event("prune", archive.archive_id.clone(), &archive.config));
+    }
+
+    pub fn record_check(&self, phase: Phase, cfg_path: &str, storage: &Storage, res: &Result<(), String>) {
+        let archive_id = read_stats(cfg_path, storage).ok().and_then(|stats| stats.archive_id);
+        self.record_result(phase, archive_id, cfg_path, res);
+    }
+
+    // a check or restore of an archive whose id is known, e.g. from a manifest of a repository
+    pub fn record_result(&self, phase: Phase, archive_id: Option<[u8; 16]>, config: &str, res: &Result<(), String>) {
+        let mut event = Self::event(phase.as_str(), archive_id.as_ref().map(archive_id_as_string), config);
+        event["ok"] = json!(res.is_ok());
+        event["reason"] = json!(res.as_ref().err());
+        self.append(event);
+    }
+
+    pub fn events(&self) -> Result<Vec<Value>, String> {
+        let file = File::open(&self.path).map_err(|e| format!("could not open catalog {}: {}", self.path, e))?;
+        let mut events = Vec::new();
+        for (i, line) in BufReader::new(file).lines().enumerate() {
+            let line = line.map_err(|e| format!("could not read catalog {}: {}", self.path, e))?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            match serde_json::from_str::<Value>(&line) {
+                Ok(event) if event.get("event").is_some() => events.push(event),
+                _ => eprintln!("skipping invalid line {} of catalog {}", i + 1, self.path)
+            }
+        }
+        Ok(events)
+    }
+
+    // copies of archives in order of their first event, including pruned ones; a copy is told by its archive id and metadata path,
+    // or by the path alone for archives of older releases; an event without id, e.g. a failed check of an archive whose
+    // metadata is lost, belongs to the latest copy with the same path
+    pub fn archives(&self) -> Result<Vec<Archive>, String> {
+        let mut archives: Vec<Archive> = Vec::new();
+        let mut index = HashMap::new();
+        let mut latest: HashMap<String, usize> = HashMap::new();
+        for event in self.events()? {
+            let archive_id = event.get("archive_id").and_then(|id| id.as_str()).map(|id| id.to_owned());
+            let config = event.get("config").and_then(|c| c.as_str()).unwrap_or_default().to_owned();
+            let known = match archive_id {
+                None => latest.get(&config).copied(),
+                Some(_) => index.get(&(archive_id.clone(), config.clone())).copied()
+            };
+            let pos = known.unwrap_or_else(|| {
+                index.insert((archive_id.clone(), config.clone()), archives.len());
+                archives.push(Archive { archive_id, config: config.clone(), backup: None, checks: Vec::new(), pruned: None });
+                archives.len() - 1
+            });
+            latest.insert(config, pos);
+            match event.get("event").and_then(|e| e.as_str()) {
+                Some("backup") => archives[pos].backup = Some(event),
+                Some("prune") => archives[pos].pruned = Some(event),
+                _ => archives[pos].checks.push(event)
+            }
+        }
+        Ok(archives)
+    }
+
+    // copies of an archive told by its id or metadata path
+    pub fn find(&self, id_or_config: &str) -> Result<Vec<Archive>, String> {
+        Ok(self.archives()?.into_iter()
+            .filter(|a| a.archive_id.as_deref() == Some(id_or_config) || a.config == id_or_config)
+            .collect())
+    }
+
+    // archives not pruned yet with all of `labels`, metadata path or template containing `path`, and not verified ok if `unverified`
+    pub fn search(&self, labels: &[Label], path: &Option<String>, unverified: bool) -> Result<Vec<Archive>, String> {
+        Ok(self.archives()?.into_iter()
+            .filter(|a| a.pruned.is_none())
+            .filter(|a| {
+                let archive_labels = a.labels();
+                labels.iter().all(|l| l.matches(&archive_labels))
+            })
+            .filter(|a| match path {
+                Some(path) => a.config.contains(path.as_str())
+                    || a.backup.as_ref().and_then(|b| b.get("template")).and_then(|t| t.as_str()).is_some_and(|t| t.contains(path.as_str())),
+                None => true
+            })
+            .filter(|a| !unverified || a.last_ok() != Some(true))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Catalog;
+    use crate::stats::Label;
+
+    #[test]
+    fn archives_from_events() {
+        let path = "/tmp/catalog_unit.jsonl";
+        std::fs::write(path, concat!(
+            r#"{"event":"backup","time":1700000000,"archive_id":"a1","config":"/bk/1/0.cfg","template":"/bk/1/%%%","in_len":100,"compressed_len":80,"nr_chunks":2,"labels":{"db":"main","nightly":true}}"#, "\n",
+            r#"{"event":"verify","time":1700000100,"archive_id":"a1","config":"/bk/1/0.cfg","ok":true,"reason":null}"#, "\n",
+            r#"{"event":"backup","time":1700086400,"archive_id":"a2","config":"/bk/2/0.cfg","template":"/bk/2/%%%","in_len":200,"xz_len":150,"nr_chunks":3,"labels":{"db":"other"}}"#, "\n",
+            r#"{"event":"verify","time":1700086500,"archive_id":"a2","config":"/bk/2/0.cfg","ok":true,"reason":null}"#, "\n",
+            r#"{"event":"verify","time":1700086600,"archive_id":null,"config":"/bk/2/0.cfg","ok":false,"reason":"hash verification error"}"#, "\n",
+            "\n",
+            r#"{"event":"restore","time":1700090000,"archive_id":null,"config":"/old/0.cfg","ok":true,"reason":null}"#, "\n",
+            r#"{"event":"backup","time":1600000000,"archive_id":"a0","config":"/bk/0/0.cfg","template":"/bk/0/%%%","in_len":50,"nr_chunks":1,"labels":{"db":"main"}}"#, "\n",
+            r#"{"event":"prune","time":1700090001,"archive_id":"a0","config":"/bk/0/0.cfg"}"#, "\n",
+            r#"{"event":"verify","time":17000"#)).unwrap();
+        let catalog = Catalog::new(path);
+        assert_eq!(catalog.events().unwrap().len(), 8); // torn last line is skipped
+
+        let archives = catalog.archives().unwrap();
+        assert_eq!(archives.len(), 4);
+        assert!(archives[3].pruned.is_some());
+        assert_eq!(archives[3].backup_time(), Some(1600000000));
+        assert_eq!(archives[0].labels(), vec![Label::parse("db=main").unwrap(), Label::parse("nightly").unwrap()]);
+        assert_eq!(archives[0].last_ok(), Some(true));
+        assert_eq!(archives[0].compressed_len(), Some(80));
+        assert_eq!(archives[1].compressed_len(), Some(150));
+        assert_eq!(archives[2].compressed_len(), None);
+        // a check of an archive with lost metadata has no id, but belongs to the archive with the same path
+        assert_eq!(archives[1].checks.len(), 2);
+        assert_eq!(archives[1].last_ok(), Some(false));
+        assert!(archives[1].summary().starts_with("a2 /bk/2/0.cfg: made at "), "{}", archives[1].summary());
+        assert!(archives[1].summary().contains(", 200 bytes in 3 chunks, labels db=other; verify failed at "), "{}", archives[1].summary());
+        assert!(archives[1].summary().ends_with(": hash verification error"), "{}", archives[1].summary());
+        assert!(archives[2].summary().starts_with("- /old/0.cfg: made elsewhere; restore ok at "), "{}", archives[2].summary());
+
+        assert_eq!(catalog.find("a2").unwrap().len(), 1);
+        assert_eq!(catalog.find("/old/0.cfg").unwrap().len(), 1);
+        assert!(catalog.find("a3").unwrap().is_empty());
+
+        let ids = |archives: Vec<super::Archive>| archives.into_iter().map(|a| a.config).collect::<Vec<_>>();
+        assert_eq!(ids(catalog.search(&[Label::parse("db").unwrap()], &None, false).unwrap()), vec!["/bk/1/0.cfg", "/bk/2/0.cfg"]);
+        assert_eq!(ids(catalog.search(&[Label::parse("nightly").unwrap()], &None, false).unwrap()), vec!["/bk/1/0.cfg"]);
+        assert_eq!(ids(catalog.search(&[], &Some("/bk/".to_owned()), true).unwrap()), vec!["/bk/2/0.cfg"]);
+        assert_eq!(ids(catalog.search(&[], &Some("2/%".to_owned()), false).unwrap()), vec!["/bk/2/0.cfg"]);
+
+        std::fs::remove_file(path).unwrap();
+        assert!(catalog.events().is_err());
+    }
+}
@@ -0,0 +1,360 @@
+// every data chunk starts with a header which identifies the archive and tells how to restore it, and the last
+// one ends with a trailer with totals of the archive, so that metadata can be rebuilt from chunks alone;
+// both are authenticated with a key derived from the password, or only protected from damage without one
+use crate::stats::{Stats, labels_as_string};
+use ring::{hmac, pbkdf2};
+use rand::RngCore;
+use std::num::NonZeroU32;
+use std::ops::Range;
+
+const HEADER_MAGIC: &[u8; 4] = b"BGAH";
+const TRAILER_MAGIC: &[u8; 4] = b"BGAT";
+const MAC_LEN: usize = 32; // HMAC-SHA256
+// magic, header length, format, archive id, index, chunk length, hash seed, auth length, parity params,
+// lengths of algorithm and auth strings, mac
+const FIXED_HEADER_LEN: usize = 4 + 4 + 4 + 16 + 8 + 8 + 8 + 8 + 4 + 4 + 1 + 4 + MAC_LEN;
+pub const MAX_HEADER_LEN: usize = 65536;
+// the longest auth string which fits a header whatever the algorithm, whose name length is a single byte
+pub const MAX_AUTH_LEN: usize = MAX_HEADER_LEN - FIXED_HEADER_LEN - u8::MAX as usize;
+pub const TRAILER_LEN: usize = 4 + 8 * 4 + MAC_LEN;
+pub const ARCHIVE_ID_END: usize = 12 + 16; // how much of the beginning of a chunk `archive_id_of` needs
+
+#[derive(Clone)]
+pub struct HeaderKey(hmac::Key);
+
+impl HeaderKey {
+    // the archive id is the salt, so that keys of archives with the same password differ
+    pub fn new(pass: &Option<String>, archive_id: &[u8; 16]) -> Self {
+        let mut key = [0u8; MAC_LEN];
+        if let Some(pass) = pass {
+            let nr_iters = NonZeroU32::new(100000).unwrap(); // SAFE: not zero
+            pbkdf2::derive(pbkdf2::PBKDF2_HMAC_SHA256, nr_iters, archive_id, pass.as_bytes(), &mut key);
+        }
+        Self(hmac::Key::new(hmac::HMAC_SHA256, &key))
+    }
+
+    fn sign(&self, data: &mut Vec<u8>) {
+        let tag = hmac::sign(&self.0, data);
+        data.extend_from_slice(tag.as_ref());
+    }
+
+    fn verify(&self, data: &[u8]) -> bool {
+        let (signed, tag) = data.split_at(data.len() - MAC_LEN);
+        hmac::verify(&self.0, signed, tag).is_ok()
+    }
+}
+
+// labels are kept in metadata only; with a password, their mac tells whether they were altered
+fn labels_signed(stats: &Stats) -> Vec<u8> {
+    let mut signed = stats.archive_id.unwrap_or_default().to_vec();
+    signed.extend_from_slice(labels_as_string(&stats.labels).as_bytes());
+    signed
+}
+
+pub fn labels_mac(stats: &Stats, key: &HeaderKey) -> String {
+    let mut signed = labels_signed(stats);
+    key.sign(&mut signed);
+    signed[signed.len() - MAC_LEN..].iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn verify_labels(stats: &Stats, key: &HeaderKey) -> Result<(), String> {
+    let mac = stats.labels_mac.as_ref().ok_or("labels of the archive are not authenticated".to_owned())?;
+    let mut signed = labels_signed(stats);
+    let tag = (0..mac.len()).step_by(2)
+        .map(|i| mac.get(i..i + 2).and_then(|h| u8::from_str_radix(h, 16).ok()))
+        .collect::<Option<Vec<u8>>>()
+        .filter(|tag| tag.len() == MAC_LEN)
+        .ok_or(format!("invalid labels_mac '{}'", mac))?;
+    signed.extend_from_slice(&tag);
+    match key.verify(&signed) {
+        true => Ok(()),
+        false => Err("labels of the archive were altered or the password is wrong".to_owned())
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct ChunkHeader {
+    pub archive_id: [u8; 16],
+    pub index: usize,
+    pub format: u32,
+    pub chunk_len: usize,
+    pub hash_seed: u64,
+    pub alg: String,
+    pub auth: String,
+    pub auth_len: usize,
+    pub parity_group: usize,
+    pub parity_nr: usize
+}
+
+// what is known only when the whole input is processed
+#[derive(Clone, PartialEq, Debug)]
+pub struct ArchiveTotals {
+    pub nr_chunks: usize,
+    pub in_len: usize,
+    pub in_hash: u64,
+    pub compressed_len: usize
+}
+
+// random UUID (version 4) of a new archive
+pub fn new_archive_id() -> [u8; 16] {
+    let mut id = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut id);
+    id[6] = (id[6] & 0x0f) | 0x40;
+    id[8] = (id[8] & 0x3f) | 0x80;
+    id
+}
+
+// archive id from the beginning of a data chunk, without verifying its header: enough to tell
+// chunks of different archives apart, while damage of chunks is detected by their checksums
+pub fn archive_id_of(head: &[u8]) -> Option<[u8; 16]> {
+    if head.len() < ARCHIVE_ID_END || &head[..4] != HEADER_MAGIC {
+        return None;
+    }
+    head[12..ARCHIVE_ID_END].try_into().ok()
+}
+
+pub fn header_len(alg: &str, auth: &str) -> usize {
+    FIXED_HEADER_LEN + alg.len() + auth.len()
+}
+
+// part of a data chunk which holds archive data, without header and trailer
+pub fn payload_range(stats: &Stats, chunk_no: usize) -> Range<usize> {
+    let len = stats.chunks[chunk_no].len;
+    if stats.format_version < 3 {
+        return 0..len;
+    }
+    let end = if chunk_no + 1 == stats.chunks.len() { len.saturating_sub(TRAILER_LEN) } else { len };
+    usize::min(header_len(&stats.alg, &stats.auth_string), end)..end
+}
+
+// part of `data` read at `offset` of a chunk which falls into `range`
+pub fn clip<'a>(range: &Range<usize>, offset: usize, data: &'a [u8]) -> &'a [u8] {
+    let end = offset + data.len();
+    &data[range.start.clamp(offset, end) - offset..range.end.clamp(offset, end) - offset]
+}
+
+struct Fields<'a> {
+    data: &'a [u8],
+    pos: usize
+}
+
+impl<'a> Fields<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], String> {
+        let field = self.data.get(self.pos..self.pos + n).ok_or("header is truncated".to_owned())?;
+        self.pos += n;
+        Ok(field)
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap())) // SAFE: length is exact
+    }
+
+    fn u64(&mut self) -> Result<u64, String> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap())) // SAFE: length is exact
+    }
+
+    fn string(&mut self, len: usize) -> Result<String, String> {
+        String::from_utf8(self.take(len)?.to_vec()).map_err(|_| "header has invalid string".to_owned())
+    }
+}
+
+impl ChunkHeader {
+    pub fn from_stats(stats: &Stats) -> Self {
+        Self {
+            archive_id: stats.archive_id.unwrap_or_default(),
+            index: 0,
+            format: stats.format_version,
+            chunk_len: stats.out_chunk_size,
+            hash_seed: stats.hash_seed,
+            alg: stats.alg.clone(),
+            auth: stats.auth_string.clone(),
+            auth_len: stats.auth_chunk_size,
+            parity_group: stats.parity_group_len,
+            parity_nr: stats.parity_nr_per_group
+        }
+    }
+
+    pub fn encoded_len(&self) -> usize {
+        header_len(&self.alg, &self.auth)
+    }
+
+    pub fn encode(&self, key: &HeaderKey) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.encoded_len());
+        out.extend_from_slice(HEADER_MAGIC);
+        out.extend_from_slice(&(self.encoded_len() as u32).to_le_bytes());
+        out.extend_from_slice(&self.format.to_le_bytes());
+        out.extend_from_slice(&self.archive_id);
+        for n in [self.index as u64, self.chunk_len as u64, self.hash_seed, self.auth_len as u64] {
+            out.extend_from_slice(&n.to_le_bytes());
+        }
+        out.extend_from_slice(&(self.parity_group as u32).to_le_bytes());
+        out.extend_from_slice(&(self.parity_nr as u32).to_le_bytes());
+        out.push(self.alg.len() as u8);
+        out.extend_from_slice(self.alg.as_bytes());
+        out.extend_from_slice(&(self.auth.len() as u32).to_le_bytes());
+        out.extend_from_slice(self.auth.as_bytes());
+        key.sign(&mut out);
+        out
+    }
+
+    // `data` is the beginning of a chunk, at least as long as its header
+    pub fn decode(data: &[u8], key: &HeaderKey) -> Result<Self, String> {
+        let mut f = Fields { data, pos: 0 };
+        if f.take(4)? != HEADER_MAGIC {
+            return Err("chunk has no header".to_owned());
+        }
+        let len = f.u32()? as usize;
+        if !(FIXED_HEADER_LEN..=MAX_HEADER_LEN).contains(&len) || data.len() < len {
+            return Err(format!("invalid length of chunk header: {}", len));
+        }
+        if !key.verify(&data[..len]) {
+            return Err("chunk header is damaged or the password is wrong".to_owned());
+        }
+        let mut header = Self {
+            format: f.u32()?,
+            archive_id: f.take(16)?.try_into().unwrap(), // SAFE: length is exact
+            index: f.u64()? as usize,
+            chunk_len: f.u64()? as usize,
+            hash_seed: f.u64()?,
+            auth_len: f.u64()? as usize,
+            parity_group: f.u32()? as usize,
+            parity_nr: f.u32()? as usize,
+            alg: String::new(),
+            auth: String::new()
+        };
+        let alg_len = f.take(1)?[0] as usize;
+        header.alg = f.string(alg_len)?;
+        let auth_len = f.u32()? as usize;
+        header.auth = f.string(auth_len)?;
+        if header.encoded_len() != len {
+            return Err("chunk header is inconsistent".to_owned());
+        }
+        Ok(header)
+    }
+}
+
+impl ArchiveTotals {
+    pub fn encode(&self, archive_id: &[u8; 16], key: &HeaderKey) -> Vec<u8> {
+        let mut out = Vec::with_capacity(TRAILER_LEN);
+        out.extend_from_slice(TRAILER_MAGIC);
+        for n in [self.nr_chunks as u64, self.in_len as u64, self.in_hash, self.compressed_len as u64] {
+            out.extend_from_slice(&n.to_le_bytes());
+        }
+        // the trailer cannot be moved to another archive
+        let mut signed = archive_id.to_vec();
+        signed.extend_from_slice(&out);
+        key.sign(&mut signed);
+        out.extend_from_slice(&signed[signed.len() - MAC_LEN..]);
+        out
+    }
+
+    // `data` is the end of a chunk; None if there is no valid trailer
+    pub fn decode(data: &[u8], archive_id: &[u8; 16], key: &HeaderKey) -> Option<Self> {
+        let trailer = data.get(data.len().checked_sub(TRAILER_LEN)?..)?;
+        if &trailer[..4] != TRAILER_MAGIC {
+            return None;
+        }
+        let mut signed = archive_id.to_vec();
+        signed.extend_from_slice(trailer);
+        if !key.verify(&signed) {
+            return None;
+        }
+        let mut f = Fields { data: trailer, pos: 4 };
+        Some(Self {
+            nr_chunks: f.u64().ok()? as usize,
+            in_len: f.u64().ok()? as usize,
+            in_hash: f.u64().ok()?,
+            compressed_len: f.u64().ok()? as usize
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stats::{ChunkInfo, Label};
+
+    fn header() -> ChunkHeader {
+        ChunkHeader {
+            archive_id: [7; 16], index: 12, format: 3, chunk_len: 1_048_576, hash_seed: 0x1234,
+            alg: "aes128-gcm".to_owned(), auth: "Author Name".to_owned(), auth_len: 65536, parity_group: 10, parity_nr: 2
+        }
+    }
+
+    #[test]
+    fn longest_header() {
+        let key = HeaderKey::new(&None, &[7; 16]);
+        let longest = ChunkHeader { alg: "a".repeat(u8::MAX as usize), auth: "b".repeat(MAX_AUTH_LEN), ..header() };
+        let data = longest.encode(&key);
+        assert_eq!(data.len(), MAX_HEADER_LEN);
+        assert_eq!(ChunkHeader::decode(&data, &key).unwrap(), longest);
+
+        let too_long = ChunkHeader { auth: "b".repeat(MAX_AUTH_LEN + 1), ..longest };
+        let err = ChunkHeader::decode(&too_long.encode(&key), &key).unwrap_err();
+        assert!(err.contains("invalid length"), "{}", err);
+    }
+
+    #[test]
+    fn header_roundtrip() {
+        let key = HeaderKey::new(&Some("secret".to_owned()), &[7; 16]);
+        let mut data = header().encode(&key);
+        assert_eq!(data.len(), header().encoded_len());
+        assert_eq!(archive_id_of(&data), Some([7; 16]));
+        assert_eq!(archive_id_of(b"payload only, no header at all"), None);
+        data.extend_from_slice(b"payload");
+        assert_eq!(ChunkHeader::decode(&data, &key).unwrap(), header());
+
+        let err = ChunkHeader::decode(&data, &HeaderKey::new(&Some("wrong".to_owned()), &[7; 16])).unwrap_err();
+        assert!(err.contains("password is wrong"), "{}", err);
+        // the same password gives another key for another archive
+        assert!(ChunkHeader::decode(&data, &HeaderKey::new(&Some("secret".to_owned()), &[8; 16])).is_err());
+        data[20] ^= 1;
+        assert!(ChunkHeader::decode(&data, &key).is_err());
+        assert!(ChunkHeader::decode(b"payload only", &key).is_err());
+        assert!(ChunkHeader::decode(&data[..30], &key).is_err());
+    }
+
+    #[test]
+    fn trailer_roundtrip() {
+        let key = HeaderKey::new(&None, &[7; 16]);
+        let totals = ArchiveTotals { nr_chunks: 13, in_len: 1000, in_hash: 0xabcd, compressed_len: 500 };
+        let mut data = b"payload".to_vec();
+        data.extend_from_slice(&totals.encode(&[7; 16], &key));
+        assert_eq!(ArchiveTotals::decode(&data, &[7; 16], &key), Some(totals));
+        assert_eq!(ArchiveTotals::decode(&data, &[8; 16], &key), None);
+        assert_eq!(ArchiveTotals::decode(&data[..data.len() - 1], &[7; 16], &key), None);
+    }
+
+    #[test]
+    fn labels_authenticated() {
+        let key = HeaderKey::new(&Some("secret".to_owned()), &[7; 16]);
+        let mut stats = Stats::new();
+        stats.archive_id = Some([7; 16]);
+        stats.labels = vec![Label::parse("db=main").unwrap()];
+        assert!(verify_labels(&stats, &key).unwrap_err().contains("not authenticated"));
+        stats.labels_mac = Some(labels_mac(&stats, &key));
+        assert_eq!(stats.labels_mac.as_ref().unwrap().len(), 64);
+        verify_labels(&stats, &key).unwrap();
+        assert!(verify_labels(&stats, &HeaderKey::new(&Some("wrong".to_owned()), &[7; 16])).is_err());
+        stats.labels[0].value = Some("other".to_owned());
+        assert!(verify_labels(&stats, &key).unwrap_err().contains("altered"));
+        stats.labels_mac = Some("xyz".to_owned());
+        assert!(verify_labels(&stats, &key).unwrap_err().contains("invalid labels_mac"));
+    }
+
+    #[test]
+    fn payload_ranges() {
+        let mut stats = Stats::new();
+        stats.alg = "none".to_owned();
+        stats.chunks = vec![ChunkInfo { len: 1000, hash: 0 }, ChunkInfo { len: 500, hash: 0 }];
+        let start = header_len("none", "");
+        assert_eq!(payload_range(&stats, 0), start..1000);
+        assert_eq!(payload_range(&stats, 1), start..500 - TRAILER_LEN);
+        stats.format_version = 2;
+        assert_eq!(payload_range(&stats, 1), 0..500);
+
+        assert_eq!(clip(&(10..20), 0, &[1; 15]), &[1; 5]);
+        assert_eq!(clip(&(10..20), 15, &[1; 15]), &[1; 5]);
+        assert_eq!(clip(&(10..20), 20, &[1; 15]), &[] as &[u8]);
+    }
+}
@@ -0,0 +1,447 @@
+use crate::file_set::FileSet;
+use crate::joiner::MultiFilesReaderSource;
+use crate::multi_files_reader::MultiFilesReader;
+use crate::stats::Stats;
+use std::collections::HashMap;
+use std::io::stderr;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+const ABORT_CHECK_PERIOD: Duration = Duration::from_millis(100);
+
+// shell command to run for every chunk once it is written, e.g. to upload and delete it
+pub struct PostChunkHook {
+    pub cmd: String,
+    pub max_pending: usize, // chunks not yet processed by the hook, including the one being written
+    pub retries: usize,
+    pub first_backoff: Duration // doubled after every failed attempt
+}
+
+// data or parity chunk passed to hooks
+#[derive(Clone)]
+pub struct ChunkRef {
+    pub path: String,
+    pub index: usize,
+    pub is_parity: bool
+}
+
+struct Progress {
+    pending: usize,
+    error: Option<String>
+}
+
+// runs the hook for closed chunks one by one in a background thread
+pub struct HookRunner {
+    max_pending: usize,
+    sender: Option<mpsc::Sender<ChunkRef>>,
+    progress: Arc<(Mutex<Progress>, Condvar)>,
+    abort: Arc<AtomicBool>,
+    worker: Option<thread::JoinHandle<()>>
+}
+
+impl HookRunner {
+    pub fn start(hook: &PostChunkHook) -> Result<HookRunner, String> {
+        if hook.max_pending == 0 {
+            return Err("max number of pending chunks must be positive".to_owned());
+        }
+        let (sender, receiver) = mpsc::channel::<ChunkRef>();
+        let progress = Arc::new((Mutex::new(Progress { pending: 0, error: None }), Condvar::new()));
+        let abort = Arc::new(AtomicBool::new(false));
+
+        let (cmd, retries, first_backoff) = (hook.cmd.clone(), hook.retries, hook.first_backoff);
+        let (worker_progress, worker_abort) = (progress.clone(), abort.clone());
+        let worker = thread::spawn(move || {
+            for chunk in receiver {
+                let failed = worker_progress.0.lock().unwrap().error.is_some();
+                let res = if failed || worker_abort.load(Ordering::SeqCst) {
+                    Ok(()) // nothing is needed anymore
+                } else {
+                    run_with_retries("post-chunk hook", &cmd, &chunk, retries, first_backoff, &worker_abort)
+                };
+                let (lock, cvar) = &*worker_progress;
+                let mut p = lock.lock().unwrap();
+                p.pending -= 1;
+                if let Err(e) = res {
+                    p.error.get_or_insert(e);
+                }
+                cvar.notify_all();
+            }
+        });
+
+        Ok(HookRunner { max_pending: hook.max_pending, sender: Some(sender), progress, abort, worker: Some(worker) })
+    }
+
+    // queues the hook for a closed chunk, then blocks until there is room for the next chunk
+    pub fn chunk_closed(&mut self, chunk: ChunkRef) -> Result<(), String> {
+        let (lock, cvar) = &*self.progress;
+        let mut p = lock.lock().unwrap();
+        if let Some(e) = &p.error {
+            return Err(e.clone());
+        }
+        p.pending += 1;
+        self.sender
+            .as_ref()
+            .ok_or("post-chunk hooks are already finished".to_owned())?
+            .send(chunk)
+            .map_err(|_| "post-chunk hook runner has stopped".to_owned())?;
+        while p.pending >= self.max_pending && p.error.is_none() {
+            p = cvar.wait(p).unwrap();
+        }
+        match &p.error {
+            Some(e) => Err(e.clone()),
+            None => Ok(())
+        }
+    }
+
+    // waits until the hook is done for all chunks
+    pub fn finish(&mut self) -> Result<(), String> {
+        drop(self.sender.take());
+        if let Some(worker) = self.worker.take() {
+            worker.join().map_err(|_| "post-chunk hook runner panicked".to_owned())?;
+        }
+        match &self.progress.0.lock().unwrap().error {
+            Some(e) => Err(e.clone()),
+            None => Ok(())
+        }
+    }
+}
+
+impl Drop for HookRunner {
+    fn drop(&mut self) {
+        // backup has failed, so remaining chunks are not processed
+        self.abort.store(true, Ordering::SeqCst);
+        let _ = self.finish();
+    }
+}
+
+fn run_hook(cmd: &str, chunk: &ChunkRef) -> Result<(), String> {
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(cmd)
+        .env("BIGARCHIVER_CHUNK_PATH", &chunk.path)
+        .env("BIGARCHIVER_CHUNK_NAME", Path::new(&chunk.path).file_name().unwrap_or_default())
+        .env("BIGARCHIVER_CHUNK_INDEX", chunk.index.to_string())
+        .env("BIGARCHIVER_CHUNK_KIND", if chunk.is_parity { "parity" } else { "data" })
+        .stdin(Stdio::null())
+        .stdout(Stdio::from(stderr()))
+        .status()
+        .map_err(|e| format!("could not run command '{}': {}", cmd, e))?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(format!("command '{}' failed: {}", cmd, status))
+    }
+}
+
+fn run_with_retries(what: &str, cmd: &str, chunk: &ChunkRef, retries: usize, first_backoff: Duration, abort: &AtomicBool) -> Result<(), String> {
+    let mut backoff = first_backoff;
+    for attempt in 1.. {
+        match run_hook(cmd, chunk) {
+            Ok(()) => return Ok(()),
+            Err(e) if attempt <= retries => {
+                eprintln!("{} for {} failed (attempt {} of {}): {}, retrying in {:?}",
+                    what, chunk.path, attempt, retries + 1, e, backoff);
+                let mut slept = Duration::ZERO;
+                while slept < backoff {
+                    if abort.load(Ordering::SeqCst) {
+                        return Err(e);
+                    }
+                    let portion = Duration::min(ABORT_CHECK_PERIOD, backoff - slept);
+                    thread::sleep(portion);
+                    slept += portion;
+                }
+                backoff = Duration::min(backoff * 2, MAX_BACKOFF);
+            },
+            Err(e) => return Err(format!("{} for {} failed after {} attempt(s): {}", what, chunk.path, attempt, e))
+        }
+    }
+    unreachable!()
+}
+
+// shell commands to get every chunk into place before it is read, and to remove it when it is not needed anymore
+#[derive(Clone)]
+pub struct FetchHook {
+    pub fetch_cmd: String,
+    pub cleanup_cmd: Option<String>,
+    pub prefetch: usize, // how many next chunks to fetch in background
+    pub retries: usize,
+    pub first_backoff: Duration
+}
+
+type FetchResults = HashMap<usize, Option<Result<(), String>>>; // by position in plan, None while fetching
+
+// reads local files fetched by the hook; chunks are expected to be read in the order of `plan`,
+// group by group (every chunk of a parity group may be read several times)
+pub struct FetchingReader {
+    from: MultiFilesReader,
+    cleanup_cmd: Option<String>,
+    prefetch: usize,
+    plan: Vec<(ChunkRef, usize)>, // chunk and its group
+    positions: HashMap<String, usize>,
+    fetched: Arc<(Mutex<FetchResults>, Condvar)>,
+    sender: Option<mpsc::Sender<usize>>,
+    abort: Arc<AtomicBool>,
+    worker: Option<thread::JoinHandle<()>>,
+    current_group: usize
+}
+
+// data chunks followed by parity chunks of every group, or just data chunks one by one if there is no parity
+fn fetch_plan(file_set: &FileSet, stats: &Stats) -> Vec<(ChunkRef, usize)> {
+    let data = |index: usize| ChunkRef { path: file_set.gen_file_path(index), index, is_parity: false };
+    if stats.parity_group_len == 0 {
+        return (0..stats.chunks.len()).map(|i| (data(i), i)).collect();
+    }
+    let mut plan = Vec::new();
+    for (group, start) in (0..stats.chunks.len()).step_by(stats.parity_group_len).enumerate() {
+        for index in start..usize::min(start + stats.parity_group_len, stats.chunks.len()) {
+            plan.push((data(index), group));
+        }
+        let parity_start = group * stats.parity_nr_per_group;
+        for index in parity_start..usize::min(parity_start + stats.parity_nr_per_group, stats.parity_chunks.len()) {
+            plan.push((ChunkRef { path: file_set.gen_parity_file_path(index), index, is_parity: true }, group));
+        }
+    }
+    plan
+}
+
+impl FetchingReader {
+    pub fn new(hook: &FetchHook, file_set: &FileSet, stats: &Stats) -> Result<Self, String> {
+        if stats.chunks.is_empty() {
+            return Err("fetching chunks requires metadata with the list of chunks".to_owned());
+        }
+        let plan = fetch_plan(file_set, stats);
+        let positions = plan.iter().enumerate().map(|(pos, (chunk, _))| (chunk.path.clone(), pos)).collect();
+        let fetched = Arc::new((Mutex::new(HashMap::new()), Condvar::new()));
+        let abort = Arc::new(AtomicBool::new(false));
+        let (sender, receiver) = mpsc::channel::<usize>();
+
+        let worker_plan: Vec<ChunkRef> = plan.iter().map(|(c, _)| c.clone()).collect();
+        let (cmd, retries, first_backoff) = (hook.fetch_cmd.clone(), hook.retries, hook.first_backoff);
+        let (worker_fetched, worker_abort) = (fetched.clone(), abort.clone());
+        let worker = thread::spawn(move || {
+            for pos in receiver {
+                let res = if worker_abort.load(Ordering::SeqCst) {
+                    Err("reading is aborted".to_owned())
+                } else {
+                    run_with_retries("fetch command", &cmd, &worker_plan[pos], retries, first_backoff, &worker_abort)
+                };
+                let (lock, cvar) = &*worker_fetched;
+                lock.lock().unwrap().insert(pos, Some(res));
+                cvar.notify_all();
+            }
+        });
+
+        Ok(Self {
+            from: MultiFilesReader::new(),
+            cleanup_cmd: hook.cleanup_cmd.clone(),
+            prefetch: hook.prefetch,
+            plan,
+            positions,
+            fetched,
+            sender: Some(sender),
+            abort,
+            worker: Some(worker),
+            current_group: 0
+        })
+    }
+
+    fn request(&self, results: &mut FetchResults, pos: usize) -> Result<(), String> {
+        if pos < self.plan.len() && !results.contains_key(&pos) {
+            results.insert(pos, None);
+            self.sender
+                .as_ref()
+                .ok_or("fetching is already finished".to_owned())?
+                .send(pos)
+                .map_err(|_| "fetching thread has stopped".to_owned())?;
+        }
+        Ok(())
+    }
+
+    // removes fetched chunks of previous groups
+    fn cleanup_before(&self, results: &mut FetchResults, group: usize) {
+        let done: Vec<usize> = results.iter()
+            .filter(|(&pos, res)| self.plan[pos].1 < group && res.is_some())
+            .map(|(&pos, _)| pos)
+            .collect();
+        for pos in done {
+            if let Some(Some(Ok(()))) = results.remove(&pos) {
+                self.cleanup(&self.plan[pos].0);
+            }
+        }
+    }
+
+    fn cleanup(&self, chunk: &ChunkRef) {
+        if let Some(cmd) = &self.cleanup_cmd {
+            if let Err(e) = run_hook(cmd, chunk) {
+                eprintln!("could not clean up {}: {}", chunk.path, e);
+            }
+        }
+    }
+}
+
+impl MultiFilesReaderSource for FetchingReader {
+    fn open_next_file(&mut self, full_path: &str) -> Result<bool, String> {
+        let pos = match self.positions.get(full_path) {
+            Some(&pos) => pos,
+            None => { return self.from.open_next_file(full_path); }
+        };
+        let fetched = self.fetched.clone();
+        let (lock, cvar) = &*fetched;
+        let mut results = lock.lock().unwrap();
+        let group = self.plan[pos].1;
+        if group > self.current_group {
+            self.cleanup_before(&mut results, group);
+            self.current_group = group;
+        }
+        for p in pos..=pos + self.prefetch {
+            self.request(&mut results, p)?;
+        }
+        while let Some(None) = results.get(&pos) {
+            results = cvar.wait(results).unwrap();
+        }
+        match results.get(&pos) {
+            Some(Some(Err(e))) => {
+                let e = format!("could not fetch {}: {}", full_path, e);
+                results.remove(&pos); // will be fetched again if needed
+                Err(e)
+            },
+            _ => self.from.open_next_file(full_path)
+        }
+    }
+
+    fn read_from_current_file(&mut self, buf: &mut [u8]) -> Result<usize, String> {
+        self.from.read_from_current_file(buf)
+    }
+
+    fn close_current_file(&mut self) -> Result<(), String> {
+        self.from.close_current_file()
+    }
+}
+
+impl Drop for FetchingReader {
+    fn drop(&mut self) {
+        self.abort.store(true, Ordering::SeqCst);
+        drop(self.sender.take());
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+        let fetched = self.fetched.clone();
+        let mut results = fetched.0.lock().unwrap();
+        self.cleanup_before(&mut results, usize::MAX);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stats::ChunkInfo;
+    use std::fs;
+
+    fn chunk(index: usize) -> ChunkRef {
+        ChunkRef { path: format!("/some/chunk{}", index), index, is_parity: index % 2 == 1 }
+    }
+
+    fn hook(cmd: &str, max_pending: usize, retries: usize) -> PostChunkHook {
+        PostChunkHook { cmd: cmd.to_owned(), max_pending, retries, first_backoff: Duration::from_millis(10) }
+    }
+
+    fn log_lines(path: &str) -> Vec<String> {
+        fs::read_to_string(path).unwrap_or_default().lines().map(|l| l.to_owned()).collect()
+    }
+
+    #[test]
+    fn hooks_in_order_with_bounded_pending() {
+        let log = "/tmp/hooks_in_order.log";
+        let _ = fs::remove_file(log);
+        let cmd = format!("sleep 0.05; echo \"$BIGARCHIVER_CHUNK_INDEX $BIGARCHIVER_CHUNK_KIND $BIGARCHIVER_CHUNK_PATH\" >> {}", log);
+        let mut runner = HookRunner::start(&hook(&cmd, 2, 0)).unwrap();
+        for i in 0..5 {
+            runner.chunk_closed(chunk(i)).unwrap();
+            assert!(log_lines(log).len() >= i); // at most one chunk is still pending
+        }
+        runner.finish().unwrap();
+        assert_eq!(log_lines(log), (0..5)
+            .map(|i| format!("{} {} /some/chunk{}", i, if i % 2 == 1 { "parity" } else { "data" }, i))
+            .collect::<Vec<_>>());
+        fs::remove_file(log).unwrap();
+    }
+
+    #[test]
+    fn retries_with_backoff() {
+        let counter = "/tmp/hooks_retries.cnt";
+        let _ = fs::remove_file(counter);
+        // fails twice for every chunk
+        let cmd = format!("echo x >> {c}; [ $(wc -l < {c}) -ge 3 ] && rm {c}", c = counter);
+        let mut runner = HookRunner::start(&hook(&cmd, 1, 2)).unwrap();
+        runner.chunk_closed(chunk(0)).unwrap();
+        runner.chunk_closed(chunk(1)).unwrap();
+        runner.finish().unwrap();
+        assert!(fs::metadata(counter).is_err());
+
+        let mut runner = HookRunner::start(&hook(&cmd, 1, 1)).unwrap();
+        assert!(runner.chunk_closed(chunk(0)).unwrap_err().contains("after 2 attempt(s)"));
+        runner.chunk_closed(chunk(1)).unwrap_err();
+        runner.finish().unwrap_err();
+        let _ = fs::remove_file(counter);
+    }
+
+    #[test]
+    fn failure_reported_at_finish() {
+        let mut runner = HookRunner::start(&hook("sleep 0.1; false", 3, 0)).unwrap();
+        runner.chunk_closed(chunk(0)).unwrap();
+        runner.finish().unwrap_err();
+        assert!(HookRunner::start(&hook("true", 0, 0)).is_err());
+    }
+
+    #[test]
+    fn fetch_ahead_and_cleanup() {
+        let (local, remote) = ("/tmp/fetch_local", "/tmp/fetch_remote");
+        for dir in [local, remote] {
+            let _ = fs::remove_dir_all(dir);
+            fs::create_dir(dir).unwrap();
+        }
+        for i in 0..6 {
+            fs::write(format!("{}/{:03}", remote, i), vec![i as u8; 10]).unwrap();
+        }
+        let file_set = FileSet::from_cfg_path(&format!("{}/000.cfg", local)).unwrap();
+        let mut stats = Stats::new();
+        stats.chunks = vec![ChunkInfo { len: 10, hash: 0 }; 6];
+
+        let log = format!("{}/fetch.log", remote);
+        let hook = FetchHook {
+            fetch_cmd: format!("echo $BIGARCHIVER_CHUNK_INDEX >> {}; cp {}/$BIGARCHIVER_CHUNK_NAME \"$BIGARCHIVER_CHUNK_PATH\"", log, remote),
+            cleanup_cmd: Some("rm \"$BIGARCHIVER_CHUNK_PATH\"".to_owned()),
+            prefetch: 2,
+            retries: 0,
+            first_backoff: Duration::from_millis(10)
+        };
+        let mut reader = FetchingReader::new(&hook, &file_set, &stats).unwrap();
+        let mut buf = vec![0; 100];
+        for i in 0..6 {
+            for _ in 0..2 { // chunk is fetched once even if read several times
+                let path = file_set.gen_file_path(i);
+                assert!(reader.open_next_file(&path).unwrap());
+                assert_eq!(reader.read_from_current_file(&mut buf).unwrap(), 10);
+                assert_eq!(buf[0], i as u8);
+                reader.close_current_file().unwrap();
+            }
+            // the current chunk and at most two prefetched ones
+            assert!(fs::read_dir(local).unwrap().count() <= 3);
+        }
+        drop(reader);
+        assert_eq!(fs::read_dir(local).unwrap().count(), 0);
+        assert_eq!(log_lines(&log), (0..6).map(|i| i.to_string()).collect::<Vec<_>>());
+
+        fs::remove_file(format!("{}/003", remote)).unwrap();
+        let mut reader = FetchingReader::new(&hook, &file_set, &stats).unwrap();
+        assert!(reader.open_next_file(&file_set.gen_file_path(3)).unwrap_err().contains("could not fetch"));
+        drop(reader);
+        fs::remove_dir_all(local).unwrap();
+        fs::remove_dir_all(remote).unwrap();
+    }
+}
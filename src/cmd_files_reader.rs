@@ -0,0 +1,147 @@
+use crate::cmd_files_writer::{cmd_for_path, spawn_shell, wait_success, MISSING_CHUNK_EXIT_CODE};
+use crate::joiner::MultiFilesReaderSource;
+use std::io::Read;
+use std::process::{Child, ChildStdout, Stdio};
+
+struct RunningCmd {
+    child: Child,
+    stdout: ChildStdout,
+    cmd: String,
+    pending: Vec<u8>,
+    eof: bool
+}
+
+// reads every chunk from stdout of its own shell command instance; a command which exits with
+// MISSING_CHUNK_EXIT_CODE without printing anything means the chunk does not exist, any other failure is an error
+pub struct CmdFilesReader {
+    cmd_template: String,
+    current: Option<RunningCmd>
+}
+
+impl CmdFilesReader {
+    pub fn new(cmd_template: &str) -> Self {
+        Self { cmd_template: cmd_template.to_owned(), current: None }
+    }
+}
+
+impl MultiFilesReaderSource for CmdFilesReader {
+    fn open_next_file(&mut self, full_path: &str) -> Result<bool, String> {
+        if let Some(running) = &self.current {
+            return Err(format!("previous command '{}' was not finished before starting a new one for {}", running.cmd, full_path));
+        }
+        let cmd = cmd_for_path(&self.cmd_template, full_path);
+        let mut child = spawn_shell(&cmd, full_path, Stdio::null(), Stdio::piped())?;
+        let mut stdout = child.stdout.take().unwrap(); // SAFE: stdout is piped
+        eprintln!("reading from {} via '{}'", full_path, cmd);
+
+        // the first portion tells an existing empty chunk from a missing one
+        let mut pending = vec![0; 65536];
+        let n = match stdout.read(&mut pending) {
+            Ok(n) => n,
+            Err(e) => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(format!("could not read from command '{}': {}", cmd, e));
+            }
+        };
+        pending.truncate(n);
+        if n == 0 {
+            let status = child.wait().map_err(|e| format!("could not wait for command '{}': {}", cmd, e))?;
+            if status.code() == Some(MISSING_CHUNK_EXIT_CODE) {
+                return Ok(false);
+            }
+            if !status.success() {
+                return Err(format!("command '{}' failed: {}", cmd, status));
+            }
+        }
+        self.current = Some(RunningCmd { child, stdout, cmd, pending, eof: n == 0 });
+        Ok(true)
+    }
+
+    fn read_from_current_file(&mut self, buf: &mut [u8]) -> Result<usize, String> {
+        let running = self.current
+            .as_mut()
+            .ok_or("no current command to read from".to_owned())?;
+        if !running.pending.is_empty() {
+            let n = usize::min(buf.len(), running.pending.len());
+            buf[..n].copy_from_slice(&running.pending[..n]);
+            running.pending.drain(..n);
+            return Ok(n);
+        }
+        if running.eof {
+            return Ok(0);
+        }
+        let n = running.stdout
+            .read(buf)
+            .map_err(|e| format!("could not read max {} bytes from command '{}': {}", buf.len(), running.cmd, e))?;
+        if n == 0 {
+            running.eof = true;
+            wait_success(&mut running.child, &running.cmd)?;
+        }
+        Ok(n)
+    }
+
+    fn close_current_file(&mut self) -> Result<(), String> {
+        let mut running = self.current.take().ok_or("no current command to finish".to_owned())?;
+        if !running.eof { // the rest of output is not needed
+            let _ = running.child.kill();
+            let _ = running.child.wait();
+        }
+        Ok(())
+    }
+}
+
+impl Drop for CmdFilesReader {
+    fn drop(&mut self) {
+        if let Some(mut running) = self.current.take() {
+            let _ = running.child.kill();
+            let _ = running.child.wait();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_all(r: &mut CmdFilesReader, path: &str) -> Result<Option<Vec<u8>>, String> {
+        if !r.open_next_file(path)? {
+            return Ok(None);
+        }
+        let mut data = Vec::new();
+        let mut buf = [0; 3];
+        loop {
+            match r.read_from_current_file(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => data.extend_from_slice(&buf[..n]),
+                Err(e) => {
+                    r.close_current_file()?;
+                    return Err(e);
+                }
+            }
+        }
+        r.close_current_file()?;
+        Ok(Some(data))
+    }
+
+    #[test]
+    fn chunks_from_command() {
+        let mut r = CmdFilesReader::new("case {name} in a1) printf 12345 ;; a2) ;; a3) printf x; exit 1 ;; a4) exit 1 ;; a5) printf x; exit 4 ;; *) exit 4 ;; esac");
+        assert_eq!(read_all(&mut r, "/dir/a1").unwrap(), Some(b"12345".to_vec()));
+        assert_eq!(read_all(&mut r, "/dir/a2").unwrap(), Some(Vec::new()));
+        read_all(&mut r, "/dir/a3").unwrap_err();
+        read_all(&mut r, "/dir/a4").unwrap_err(); // only the distinct exit code means a missing chunk
+        read_all(&mut r, "/dir/a5").unwrap_err();
+        assert_eq!(read_all(&mut r, "/dir/a6").unwrap(), None);
+    }
+
+    #[test]
+    fn close_before_end() {
+        let mut r = CmdFilesReader::new("yes");
+        assert!(r.open_next_file("x").unwrap());
+        let mut buf = [0; 10];
+        assert_eq!(r.read_from_current_file(&mut buf).unwrap(), 10);
+        r.close_current_file().unwrap();
+        r.open_next_file("y").unwrap();
+    }
+}
@@ -0,0 +1,180 @@
+use crate::splitter::MultiFilesWriterTarget;
+use std::io::{stderr, Write};
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+
+// exit code of a reading command which tells that there is no such chunk, unlike any other failure
+pub const MISSING_CHUNK_EXIT_CODE: i32 = 4;
+
+fn chunk_name(full_path: &str) -> String {
+    Path::new(full_path)
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+// makes any string a single word for sh, taken literally
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+// substitutes file name and full path of a chunk into the command template, quoted for the shell
+pub fn cmd_for_path(cmd_template: &str, full_path: &str) -> String {
+    cmd_template.replace("{path}", &shell_quote(full_path)).replace("{name}", &shell_quote(&chunk_name(full_path)))
+}
+
+// runs the command for a chunk, with its path and name in the environment as well
+pub fn spawn_shell(cmd: &str, full_path: &str, stdin: Stdio, stdout: Stdio) -> Result<Child, String> {
+    Command::new("sh")
+        .arg("-c")
+        .arg(cmd)
+        .env("BIGARCHIVER_CHUNK_PATH", full_path)
+        .env("BIGARCHIVER_CHUNK_NAME", chunk_name(full_path))
+        .stdin(stdin)
+        .stdout(stdout)
+        .spawn()
+        .map_err(|e| format!("could not run command '{}': {}", cmd, e))
+}
+
+pub fn wait_success(child: &mut Child, cmd: &str) -> Result<(), String> {
+    let status = child.wait().map_err(|e| format!("could not wait for command '{}': {}", cmd, e))?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(format!("command '{}' failed: {}", cmd, status))
+    }
+}
+
+// streams every chunk into stdin of its own shell command instance
+pub struct CmdFilesWriter {
+    cmd_template: String,
+    current: Option<(Child, String)>
+}
+
+impl CmdFilesWriter {
+    pub fn new(cmd_template: &str) -> Self {
+        Self { cmd_template: cmd_template.to_owned(), current: None }
+    }
+
+    fn spawn(&self, full_path: &str) -> Result<(Child, String), String> {
+        let cmd = cmd_for_path(&self.cmd_template, full_path);
+        // output of the command must not mix with data, so it goes to stderr
+        let child = spawn_shell(&cmd, full_path, Stdio::piped(), Stdio::from(stderr()))?;
+        Ok((child, cmd))
+    }
+}
+
+fn finish_cmd(mut child: Child, cmd: &str) -> Result<(), String> {
+    drop(child.stdin.take()); // signals end of data
+    wait_success(&mut child, cmd)
+}
+
+impl MultiFilesWriterTarget for CmdFilesWriter {
+    fn open_next_file(&mut self, full_path: &str) -> Result<(), String> {
+        if let Some((_, cmd)) = &self.current {
+            return Err(format!("previous command '{}' was not finished before starting a new one for {}", cmd, full_path));
+        }
+        let (child, cmd) = self.spawn(full_path)?;
+        eprintln!("writing to {} via '{}'", full_path, cmd);
+        self.current = Some((child, cmd));
+        Ok(())
+    }
+
+    fn close_current_file(&mut self) -> Result<(), String> {
+        let (child, cmd) = self.current.take().ok_or("no current command to finish".to_owned())?;
+        finish_cmd(child, &cmd)
+    }
+
+    fn write_to_current_file(&mut self, data: &[u8]) -> Result<(), String> {
+        let (child, cmd) = self.current
+            .as_mut()
+            .ok_or("no current command to write to".to_owned())?;
+        child.stdin
+            .as_mut()
+            .ok_or(format!("stdin of command '{}' is closed", cmd))?
+            .write_all(data)
+            .map_err(|e| format!("could not write {} bytes to command '{}': {}", data.len(), cmd, e))
+    }
+
+    // as atomic as the command itself is
+    fn write_single_file(&self, path: &str, contents: &[u8]) -> Result<(), String> {
+        let (mut child, cmd) = self.spawn(path)?;
+        if let Err(e) = child.stdin.as_mut().unwrap().write_all(contents) { // SAFE: stdin is piped
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(format!("could not write {} bytes to command '{}': {}", contents.len(), cmd, e));
+        }
+        finish_cmd(child, &cmd)
+    }
+}
+
+impl Drop for CmdFilesWriter {
+    fn drop(&mut self) {
+        // unfinished chunk must not be taken for a complete one
+        if let Some((mut child, _)) = self.current.take() {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn path_substitution() {
+        assert_eq!(cmd_for_path("cp - remote:/bk/{name}", "/tmp/arch/file003"), "cp - remote:/bk/'file003'");
+        assert_eq!(cmd_for_path("put {path} {name}.x", "a/b.par"), "put 'a/b.par' 'b.par'.x");
+        assert_eq!(cmd_for_path("true", "/x"), "true");
+        assert_eq!(cmd_for_path("cat {path}", "/bk/it's $(id)"), "cat '/bk/it'\\''s $(id)'");
+    }
+
+    #[test]
+    fn odd_paths() {
+        let dir = "/tmp/cmd_writer_odd";
+        let _ = fs::remove_dir_all(dir);
+        fs::create_dir(dir).unwrap();
+
+        // names are taken literally, both substituted and from the environment
+        let w = CmdFilesWriter::new(&format!("cat > {}/{{name}}; echo \"$BIGARCHIVER_CHUNK_NAME\" > {}/env", dir, dir));
+        w.write_single_file("/bk/a b'c;$(echo x)", b"data").unwrap();
+        assert_eq!(fs::read(format!("{}/a b'c;$(echo x)", dir)).unwrap(), b"data");
+        assert_eq!(fs::read_to_string(format!("{}/env", dir)).unwrap(), "a b'c;$(echo x)\n");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn chunks_through_command() {
+        let dir = "/tmp/cmd_writer";
+        let _ = fs::remove_dir_all(dir);
+        fs::create_dir(dir).unwrap();
+
+        let mut w = CmdFilesWriter::new(&format!("cat > {}/{{name}}.up", dir));
+        w.open_next_file("/nonexistent/chunk1").unwrap();
+        w.open_next_file("/nonexistent/chunk2").unwrap_err();
+        w.write_to_current_file(b"abc").unwrap();
+        w.write_to_current_file(b"def").unwrap();
+        w.close_current_file().unwrap();
+        w.close_current_file().unwrap_err();
+        w.write_single_file("/nonexistent/meta.cfg", b"meta").unwrap();
+
+        assert_eq!(fs::read(format!("{}/chunk1.up", dir)).unwrap(), b"abcdef");
+        assert_eq!(fs::read(format!("{}/meta.cfg.up", dir)).unwrap(), b"meta");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn failed_command() {
+        let mut w = CmdFilesWriter::new("cat > /dev/null; exit 3");
+        w.open_next_file("chunk").unwrap();
+        w.write_to_current_file(b"abc").unwrap();
+        assert!(w.close_current_file().unwrap_err().contains("exit status: 3"));
+
+        let mut w = CmdFilesWriter::new("exit 0");
+        w.open_next_file("chunk").unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(200));
+        w.write_to_current_file(&[0; 1_000_000]).unwrap_err(); // nobody reads
+    }
+}
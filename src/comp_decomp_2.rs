@@ -16,7 +16,7 @@ impl<'a, T: DataSink> Conv<'a, T> {
 
 impl<'a, T: DataSink> Write for Conv<'a, T> {
     fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
-        self.t.add(data).map(|_|data.len()).map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))
+        self.t.add(data).map(|_|data.len()).map_err(std::io::Error::other)
     }
     fn flush(&mut self) -> std::io::Result<()> {
         Ok(())
@@ -62,6 +62,26 @@ impl<'a, T: DataSink> DataSink for Compressor2<'a, T> {
 }
 
 
+struct Discard;
+
+impl DataSink for Discard {
+    fn add(&mut self, _data: &[u8]) -> Result<(), String> {
+        Ok(())
+    }
+    fn finish(&mut self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+// length of data once compressed, to estimate compression ratio from a sample
+pub fn compressed_len(data: &[u8], level: u32) -> Result<usize, String> {
+    let mut discard = Discard;
+    let mut comp = Compressor2::new(&mut discard, level, 1)?;
+    comp.add(data)?;
+    comp.finish()?;
+    Ok(comp.compressed())
+}
+
 pub struct Decompressor2<'a, T: DataSink> {
     dec: XzDecoder<Conv<'a, T>>
 }
@@ -80,7 +100,7 @@ impl<'a, T: DataSink> Decompressor2<'a, T> {
     }
 
     #[allow(dead_code)]
-    pub fn get_decoder(&'a self) -> &XzDecoder<Conv<'a, T>> {
+    pub fn get_decoder(&'a self) -> &'a XzDecoder<Conv<'a, T>> {
         &self.dec
     }
 }
@@ -98,7 +118,7 @@ impl<'a, T: DataSink> DataSink for Decompressor2<'a, T> {
 #[cfg(test)]
 mod tests {
     use crate::finalizable::DataSink;
-    use super::{Compressor2, Decompressor2};
+    use super::{Compressor2, Decompressor2, compressed_len};
     use rand::{thread_rng, Rng, RngCore};
     use std::{thread, sync::{atomic::{AtomicBool, Ordering}, Arc}};
 
@@ -143,6 +163,14 @@ mod tests {
         assert_eq!(orig_data, b"HELLO");
     }
 
+    #[test]
+    fn sample_compression_ratio() {
+        let zeros = vec![0u8; 100_000];
+        assert!(compressed_len(&zeros, 6).unwrap() < 1000);
+        let random: Vec<u8> = (0..100_000).map(|_| rand::random::<u8>()).collect();
+        assert!(compressed_len(&random, 6).unwrap() > 100_000);
+    }
+
     fn add_by_random_parts<T: DataSink>(t: &mut T, data: &[u8], max_part: usize) {
         let mut left = data.len();
         let mut offs = 0;
@@ -157,8 +185,7 @@ mod tests {
 
     #[test]
     fn zip_unzip_big_2() {
-        let mut src: Vec<u8> = Vec::new();
-        src.resize(2 * 1024 * 1024, 0);
+        let mut src: Vec<u8> = vec![0; 2 * 1024 * 1024];
         thread_rng().fill_bytes(&mut src);
 
         let mut sink_for_zipped = Sink{ data: Vec::new() };
@@ -190,8 +217,7 @@ mod tests {
         let is_stop = Arc::new(AtomicBool::new(false));
         let is_stop_copy = is_stop.clone();
         let t = std::thread::spawn(move ||{            
-            let mut buf = Vec::with_capacity(SEND_SIZE);
-            buf.resize(SEND_SIZE, 0);
+            let mut buf = vec![0; SEND_SIZE];
             let mut null_sink = NullSink{};
             let mut count = 0;
             let mut comp = Compressor2::new(&mut null_sink, 6, 6).unwrap();
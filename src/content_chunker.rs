@@ -0,0 +1,126 @@
+// splits data into chunks at positions chosen by their contents with a gear rolling hash, as in FastCDC, so that
+// an insertion or deletion changes only the chunks around it while the rest of them are the same as before
+use crate::finalizable::DataSink;
+
+// never to be changed: chunks of existing repositories would not be found again
+const GEAR: [u64; 256] = gear_table();
+
+// random constants of the rolling hash, from splitmix64
+const fn gear_table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut state: u64 = 0x6269_6761_7263_6876; // "bigarchv"
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+// passes every chunk to the sink in a single `add`
+pub struct ContentChunker<'a, T: DataSink> {
+    write_to: &'a mut T,
+    min_len: usize,
+    max_len: usize,
+    mask: u64,
+    hash: u64,
+    buf: Vec<u8>
+}
+
+impl<'a, T: DataSink> ContentChunker<'a, T> {
+    // chunks are at least `min_len` and at most `max_len` long, and about `avg_len` on average
+    pub fn new(to: &'a mut T, min_len: usize, avg_len: usize, max_len: usize) -> Result<Self, String> {
+        if min_len == 0 || min_len >= avg_len || avg_len >= max_len {
+            return Err(format!("invalid chunk lengths: min {}, average {}, max {}", min_len, avg_len, max_len));
+        }
+        // a cut after min_len is found at every 2^bits bytes on average; the top bits depend on the last 64 bytes
+        let bits = (avg_len - min_len).ilog2();
+        Ok(Self { write_to: to, min_len, max_len, mask: !(u64::MAX >> bits), hash: 0, buf: Vec::with_capacity(max_len) })
+    }
+}
+
+impl<T: DataSink> DataSink for ContentChunker<'_, T> {
+    fn add(&mut self, data: &[u8]) -> Result<(), String> {
+        let mut start = 0;
+        for (i, b) in data.iter().enumerate() {
+            self.hash = (self.hash << 1).wrapping_add(GEAR[*b as usize]);
+            let len = self.buf.len() + i + 1 - start;
+            if len >= self.max_len || (len >= self.min_len && self.hash & self.mask == 0) {
+                self.buf.extend_from_slice(&data[start..=i]);
+                self.write_to.add(&self.buf)?;
+                self.buf.clear();
+                self.hash = 0;
+                start = i + 1;
+            }
+        }
+        self.buf.extend_from_slice(&data[start..]);
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), String> {
+        if !self.buf.is_empty() {
+            self.write_to.add(&self.buf)?;
+            self.buf.clear();
+        }
+        self.write_to.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ContentChunker;
+    use crate::finalizable::DataSink;
+    use rand::{RngCore, SeedableRng, rngs::StdRng};
+
+    struct Chunks(Vec<Vec<u8>>);
+
+    impl DataSink for Chunks {
+        fn add(&mut self, data: &[u8]) -> Result<(), String> {
+            self.0.push(data.to_vec());
+            Ok(())
+        }
+        fn finish(&mut self) -> Result<(), String> {
+            Ok(())
+        }
+    }
+
+    fn split(data: &[u8], portion: usize) -> Vec<Vec<u8>> {
+        let mut chunks = Chunks(Vec::new());
+        let mut chunker = ContentChunker::new(&mut chunks, 1024, 4096, 16384).unwrap();
+        for p in data.chunks(portion) {
+            chunker.add(p).unwrap();
+        }
+        chunker.finish().unwrap();
+        chunks.0
+    }
+
+    #[test]
+    fn chunks_by_content() {
+        let mut data = vec![0u8; 1_000_000];
+        StdRng::seed_from_u64(1).fill_bytes(&mut data);
+
+        let chunks = split(&data, 100_000);
+        assert_eq!(chunks.concat(), data);
+        assert!(chunks[..chunks.len() - 1].iter().all(|c| c.len() >= 1024 && c.len() <= 16384));
+        assert!(chunks.len() > 1_000_000 / 8192 && chunks.len() < 1_000_000 / 2048, "{}", chunks.len());
+        for portion in [1, 777, 1_000_000] {
+            assert_eq!(split(&data, portion), chunks);
+        }
+
+        // the same data after an insertion is split the same way except around it
+        let mut changed = data.clone();
+        changed.splice(500_000..500_000, b"inserted".iter().cloned());
+        let changed_chunks = split(&changed, 65536);
+        let nr_new = changed_chunks.iter().filter(|c| !chunks.contains(c)).count();
+        assert!(nr_new <= 2, "{}", nr_new);
+
+        // a run of the same byte is cut into equal chunks, which are stored once
+        let run = split(&[7u8; 100_000], 4096);
+        assert!(run.len() > 1 && run[..run.len() - 1].iter().all(|c| c == &run[0]));
+        assert!(ContentChunker::new(&mut Chunks(Vec::new()), 4096, 4096, 16384).is_err());
+    }
+}
@@ -4,6 +4,7 @@ use ring::aead::UnboundKey;
 use ring::aead::BoundKey;
 use ring::aead::SealingKey;
 use ring::aead::OpeningKey;
+use ring::aead::LessSafeKey;
 use ring::aead::Aad;
 use ring::aead::NonceSequence;
 use ring::aead::NONCE_LEN;
@@ -17,17 +18,29 @@ pub enum EncDecAlg {
     Chacha20Poly1305
 }
 
-fn create_unbound_key(alg: &EncDecAlg, pass_str: &str) -> (UnboundKey, usize) {
-    let a = match alg {
+fn algorithm(alg: &EncDecAlg) -> &'static ring::aead::Algorithm {
+    match alg {
         EncDecAlg::Aes128Gcm => &AES_128_GCM,
         EncDecAlg::Chacha20Poly1305 => &CHACHA20_POLY1305
-    };
-    let mut key: Vec<u8> = vec![0; a.key_len()];
+    }
+}
+
+pub fn key_len(alg: &EncDecAlg) -> usize {
+    algorithm(alg).key_len()
+}
+
+fn create_unbound_key(alg: &EncDecAlg, pass_str: &str) -> (UnboundKey, usize) {
+    let mut key: Vec<u8> = vec![0; key_len(alg)];
     let nr_iters = NonZeroU32::new(100000).unwrap();
     pbkdf2::derive(pbkdf2::PBKDF2_HMAC_SHA256, nr_iters, &[],pass_str.as_bytes(), &mut key);
+    unbound_key(alg, &key)
+}
 
-    // SAFE because algorithm is chosen explicitly, it cannot come from a user
-    (UnboundKey::new(a, &key).unwrap(), a.tag_len())
+// key must be of `key_len` bytes
+fn unbound_key(alg: &EncDecAlg, key: &[u8]) -> (UnboundKey, usize) {
+    let a = algorithm(alg);
+    // SAFE because algorithm is chosen explicitly, it cannot come from a user, and so is the length of the key
+    (UnboundKey::new(a, key).unwrap(), a.tag_len())
 }
 
 pub struct Encryptor<'a, T: DataSink> {
@@ -44,6 +57,16 @@ impl<'a, T: DataSink> Encryptor<'a, T> {
             assoc_data: Aad::from(aad_str.to_owned()),
         }
     }
+
+    // with a key derived elsewhere, e.g. one for each chunk of a repository; nonces start from 0 for every encryptor,
+    // so a key must never be used for different data
+    pub fn with_key(to: &'a mut T, alg: &'a EncDecAlg, key: &[u8], aad_str: &str) -> Encryptor<'a, T> {
+        Encryptor {
+            write_to: to,
+            sealing_key: SealingKey::new(unbound_key(alg, key).0, NonceFromCounter{ cnt: 0 }),
+            assoc_data: Aad::from(aad_str.to_owned()),
+        }
+    }
 }
 
 impl<'a, T: DataSink> DataSink for Encryptor<'a, T> {
@@ -65,17 +88,39 @@ impl<'a, T: DataSink> DataSink for Encryptor<'a, T> {
 pub struct Decryptor<'a, T: DataSink> {
     write_to: &'a mut T,
     opening_key: OpeningKey<NonceFromCounter>,
-    assoc_data: Aad<String>
+    assoc_data: Aad<String>,
+    tag_len: usize,
+    salvage: bool,
+    block_no: u64
 }
 
 impl<'a, T: DataSink> Decryptor<'a, T> {
-    pub fn new(to: &'a mut T, alg: &'a EncDecAlg, pass_str: &str, aad_str: &str) -> (Decryptor<'a, T>, usize) {
+    // in `salvage` mode, blocks failing authentication are reported as lost data instead of an error
+    pub fn new(to: &'a mut T, alg: &'a EncDecAlg, pass_str: &str, aad_str: &str, salvage: bool) -> (Decryptor<'a, T>, usize) {
         let (key, tag_len) = create_unbound_key(alg, pass_str);
         (
             Decryptor { 
                 write_to: to, 
                 opening_key: OpeningKey::new(key , NonceFromCounter{ cnt: 0 }),
                 assoc_data: Aad::from(aad_str.to_owned()),
+                tag_len,
+                salvage,
+                block_no: 0
+            },
+            tag_len
+        )
+    }
+
+    pub fn with_key(to: &'a mut T, alg: &'a EncDecAlg, key: &[u8], aad_str: &str) -> (Decryptor<'a, T>, usize) {
+        let (key, tag_len) = unbound_key(alg, key);
+        (
+            Decryptor {
+                write_to: to,
+                opening_key: OpeningKey::new(key, NonceFromCounter{ cnt: 0 }),
+                assoc_data: Aad::from(aad_str.to_owned()),
+                tag_len,
+                salvage: false,
+                block_no: 0
             },
             tag_len
         )
@@ -86,10 +131,16 @@ impl<'a, T: DataSink> DataSink for Decryptor<'a, T> {
     fn add(&mut self, data: &[u8]) -> Result<(), String> {
         //eprintln!("Decryptor: writing {} bytes", data.len());
         let mut inout_buf = data.to_vec().clone();
-        let out_ref = self.opening_key
-            .open_in_place(self.assoc_data.clone(), &mut inout_buf)
-            .map_err(|e| format!("decrypt error: {}", e))?;
-        self.write_to.add(out_ref)
+        let block_no = self.block_no;
+        self.block_no += 1; // nonce advances even if authentication fails
+        match self.opening_key.open_in_place(self.assoc_data.clone(), &mut inout_buf) {
+            Ok(out_ref) => self.write_to.add(out_ref),
+            Err(e) if self.salvage => {
+                eprintln!("authenticated block #{} is lost: decrypt error: {}", block_no, e);
+                self.write_to.add_lost(data.len().saturating_sub(self.tag_len))
+            },
+            Err(e) => Err(format!("decrypt error: {}", e))
+        }
     }
 
     fn finish(&mut self) -> Result<(), String> {
@@ -98,6 +149,35 @@ impl<'a, T: DataSink> DataSink for Decryptor<'a, T> {
     }
 }
 
+// decrypts arbitrary blocks of the encrypted stream by their sequence numbers
+pub struct BlockDecryptor {
+    key: LessSafeKey,
+    assoc_data: String,
+    tag_len: usize
+}
+
+impl BlockDecryptor {
+    pub fn new(alg: &EncDecAlg, pass_str: &str, aad_str: &str) -> BlockDecryptor {
+        let (key, tag_len) = create_unbound_key(alg, pass_str);
+        BlockDecryptor { key: LessSafeKey::new(key), assoc_data: aad_str.to_owned(), tag_len }
+    }
+
+    pub fn tag_len(&self) -> usize {
+        self.tag_len
+    }
+
+    pub fn decrypt(&self, block_no: u64, data: &[u8]) -> Result<Vec<u8>, String> {
+        let mut inout_buf = data.to_vec();
+        let nonce = NonceFromCounter{ cnt: block_no }.advance()
+            .map_err(|e| format!("nonce error: {}", e))?;
+        let plain_len = self.key
+            .open_in_place(nonce, Aad::from(self.assoc_data.clone()), &mut inout_buf)
+            .map_err(|e| format!("decrypt error of block #{}: {}", block_no, e))?
+            .len();
+        inout_buf.truncate(plain_len);
+        Ok(inout_buf)
+    }
+}
 
 struct NonceFromCounter {
     cnt: u64
@@ -164,12 +244,58 @@ mod tests {
         let mut enc = Encryptor::<CipherReceiver>::new(&mut cipher, alg, "password", "data11111111");
 
         let mut text = PlaintextReceiver(String::new());
-        let mut dec = Decryptor::<PlaintextReceiver>::new(&mut text, alg, "password", "data11111111").0;
+        let mut dec = Decryptor::<PlaintextReceiver>::new(&mut text, alg, "password", "data11111111", false).0;
 
         enc.add(b"AAAAAAAAAA").unwrap();
         //enc.write(b"BBB").unwrap();
         dec.add(&cipher.0).unwrap();
     }
 
+    struct LostReceiver(Vec<u8>, Vec<usize>);
+
+    impl DataSink for LostReceiver {
+        fn add(&mut self, data: &[u8]) -> Result<(), String> {
+            self.0.extend_from_slice(data);
+            Ok(())
+        }
+        fn finish(&mut self) -> Result<(), String> {
+            Ok(())
+        }
+        fn add_lost(&mut self, len: usize) -> Result<(), String> {
+            self.1.push(len);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn salvage_and_random_access() {
+        let alg = EncDecAlg::Chacha20Poly1305;
+        let mut cipher = CipherReceiver(Vec::new());
+        let mut enc = Encryptor::<CipherReceiver>::new(&mut cipher, &alg, "password", "aad");
+        for block in [b"0000", b"1111", b"2222"] {
+            enc.add(block).unwrap();
+        }
+        let enc_block_len = cipher.0.len() / 3;
+        cipher.0[enc_block_len + 1] ^= 1;
+
+        let mut plain = LostReceiver(Vec::new(), Vec::new());
+        let mut dec = Decryptor::new(&mut plain, &alg, "password", "aad", true).0;
+        for block in cipher.0.chunks(enc_block_len) {
+            dec.add(block).unwrap();
+        }
+        assert_eq!(plain.0, b"00002222");
+        assert_eq!(plain.1, vec![4]);
+
+        let mut strict = LostReceiver(Vec::new(), Vec::new());
+        let mut dec = Decryptor::new(&mut strict, &alg, "password", "aad", false).0;
+        dec.add(&cipher.0[..enc_block_len]).unwrap();
+        dec.add(&cipher.0[enc_block_len..2 * enc_block_len]).unwrap_err();
+
+        let bd = BlockDecryptor::new(&alg, "password", "aad");
+        assert_eq!(bd.decrypt(2, &cipher.0[2 * enc_block_len..]).unwrap(), b"2222");
+        bd.decrypt(1, &cipher.0[2 * enc_block_len..]).unwrap_err();
+        bd.decrypt(1, &cipher.0[enc_block_len..2 * enc_block_len]).unwrap_err();
+    }
+
 
 }
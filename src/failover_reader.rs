@@ -0,0 +1,104 @@
+use crate::file_set::FileSet;
+use crate::joiner::MultiFilesReaderSource;
+use crate::stats::Stats;
+use std::collections::HashMap;
+
+struct Location {
+    cfg_path: String,
+    file_set: FileSet,
+    source: Box<dyn MultiFilesReaderSource>
+}
+
+// reads every chunk from the main copy of an archive, and from the next copies only if it is
+// missing there or the reader finds it damaged
+pub struct FailoverReader {
+    primary: FileSet,
+    locations: Vec<Location>,
+    chosen: HashMap<String, usize>, // copy to read a chunk from, if not the main one
+    bad: Vec<(usize, String, String)>, // location, path and what is wrong
+    current: Option<usize>
+}
+
+impl FailoverReader {
+    // `locations` are metadata paths of the copies with their readers, the first one is the main copy
+    pub fn new(locations: Vec<(String, Box<dyn MultiFilesReaderSource>)>, stats: &Stats) -> Result<Self, String> {
+        let locations = locations.into_iter().map(|(cfg_path, source)| Ok(Location {
+            file_set: FileSet::from_cfg(&cfg_path, stats.chunk_pattern.as_deref())?,
+            cfg_path,
+            source
+        })).collect::<Result<Vec<_>, String>>()?;
+        let primary = FileSet::from_cfg(&locations.first().ok_or("no copies to read from".to_owned())?.cfg_path, stats.chunk_pattern.as_deref())?;
+        Ok(Self { primary, locations, chosen: HashMap::new(), bad: Vec::new(), current: None })
+    }
+
+    fn current_source(&mut self) -> Result<&mut Box<dyn MultiFilesReaderSource>, String> {
+        let idx = self.current.ok_or("no current file opened".to_owned())?;
+        Ok(&mut self.locations[idx].source)
+    }
+}
+
+impl MultiFilesReaderSource for FailoverReader {
+    fn open_next_file(&mut self, full_path: &str) -> Result<bool, String> {
+        let first = self.chosen.get(full_path).copied().unwrap_or(0);
+        for idx in first..self.locations.len() {
+            let path = self.primary.translate(full_path, &self.locations[idx].file_set)?;
+            if self.locations[idx].source.open_next_file(&path)? {
+                if idx > 0 {
+                    eprintln!("chunk {} is taken from copy {}", path, self.locations[idx].cfg_path);
+                }
+                self.chosen.insert(full_path.to_owned(), idx);
+                self.current = Some(idx);
+                return Ok(true);
+            }
+            eprintln!("chunk {} in copy {} is missing", path, self.locations[idx].cfg_path);
+            self.bad.push((idx, path, "missing".to_owned()));
+        }
+        // remembered as missing everywhere
+        self.chosen.insert(full_path.to_owned(), self.locations.len());
+        Ok(false)
+    }
+
+    fn read_from_current_file(&mut self, buf: &mut [u8]) -> Result<usize, String> {
+        self.current_source()?.read_from_current_file(buf)
+    }
+
+    fn close_current_file(&mut self) -> Result<(), String> {
+        let res = self.current_source()?.close_current_file();
+        self.current = None;
+        res
+    }
+
+    fn nr_copies(&self) -> usize {
+        self.locations.len()
+    }
+
+    fn fail_over(&mut self, full_path: &str, problem: &str) -> Result<bool, String> {
+        let idx = self.chosen.get(full_path).copied().unwrap_or(0);
+        if idx >= self.locations.len() {
+            return Ok(false);
+        }
+        let path = self.primary.translate(full_path, &self.locations[idx].file_set)?;
+        eprintln!("chunk {} in copy {} is bad: {}", path, self.locations[idx].cfg_path, problem);
+        self.bad.push((idx, path, problem.to_owned()));
+        self.chosen.insert(full_path.to_owned(), idx + 1);
+        Ok(idx + 1 < self.locations.len())
+    }
+}
+
+impl Drop for FailoverReader {
+    fn drop(&mut self) {
+        if self.bad.is_empty() {
+            return;
+        }
+        eprintln!("copies with missing or damaged chunks, consider repairing them:");
+        for (idx, loc) in self.locations.iter().enumerate() {
+            let bad: Vec<String> = self.bad.iter()
+                .filter(|(i, _, _)| *i == idx)
+                .map(|(_, path, e)| format!("{} ({})", path, e))
+                .collect();
+            if !bad.is_empty() {
+                eprintln!("{}: {} bad chunk(s): {}", loc.cfg_path, bad.len(), bad.join(", "));
+            }
+        }
+    }
+}
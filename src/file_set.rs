@@ -1,28 +1,51 @@
+use std::path::MAIN_SEPARATOR;
+use time::OffsetDateTime;
+use crate::stats::Label;
+use crate::local_now;
+
+// piece of output template
+#[derive(Clone, PartialEq, Debug)]
+enum Part {
+    Text(String),
+    Number(usize), // run of % or {n}: chunk number zero-padded to the width
+    Div(usize), // {n/K}: chunk number divided by K
+    Mod(usize) // {n%K}: remainder of chunk number divided by K, zero-padded to the width of K-1
+}
+
 pub struct FileSet {
     pattern_path: String,
     config_path: String,
-    offset: usize,
-    len: usize
+    parts: Vec<Part>
 }
 
 impl FileSet {
     pub fn from_pattern(pattern: &str) -> Result<Self, String> {
-        let (offset, len) = analyze_pattern(pattern)?;
         Ok(Self{
             pattern_path: String::from(pattern),
             config_path: cfg_from_pattern(pattern),
-            offset,
-            len})
+            parts: parse_pattern(pattern)?})
     }
 
     pub fn from_cfg_path(cfg_path: &str) -> Result<Self, String> {
         let pattern = pattern_from_cfg(cfg_path)?;
-        let (offset, len) = analyze_pattern(pattern.as_str())?;
         Ok(Self{
+            parts: parse_pattern(pattern.as_str())?,
+            pattern_path: pattern,
+            config_path: String::from(cfg_path)})
+    }
+
+    // `chunk_pattern` is stored in metadata when it cannot be told from the name of metadata file
+    pub fn from_cfg(cfg_path: &str, chunk_pattern: Option<&str>) -> Result<Self, String> {
+        let chunk_pattern = match chunk_pattern {
+            Some(chunk_pattern) => chunk_pattern,
+            None => { return Self::from_cfg_path(cfg_path); }
+        };
+        let dir_len = cfg_path.rfind(MAIN_SEPARATOR).map(|pos| pos + 1).unwrap_or(0);
+        let pattern = format!("{}{}", &cfg_path[..dir_len], chunk_pattern);
+        Ok(Self{
+            parts: parse_pattern(pattern.as_str())?,
             pattern_path: pattern,
-            config_path: String::from(cfg_path),
-            offset,
-            len})
+            config_path: String::from(cfg_path)})
     }
 
     pub fn pattern(&self) -> String {
@@ -33,29 +56,142 @@ impl FileSet {
         self.config_path.clone()
     }
 
+    // pattern relative to the directory of metadata file, if chunks are laid out in directories by chunk number
+    pub fn chunk_pattern(&self) -> Option<String> {
+        if !self.pattern_path.contains('{') {
+            return None;
+        }
+        Some(self.pattern_path[static_dir(&self.pattern_path).len()..].to_owned())
+    }
+
     pub fn gen_file_path(&self, n: usize) -> String {
-        let s_chunk = n.to_string();
-        let mut out = self.pattern_path.clone();
-        let mut nr_zeros: i32 = self.len as i32 - s_chunk.len() as i32;
-        if nr_zeros > 0 {
-            out.replace_range(self.offset .. self.offset + nr_zeros as usize, &"0".repeat(nr_zeros as usize));
+        render(&self.parts, n)
+    }
+
+    pub fn gen_parity_file_path(&self, n: usize) -> String {
+        format!("{}.par", self.gen_file_path(n))
+    }
+
+    // sequence number of a data or parity chunk path generated from this set, and whether it is parity
+    pub fn chunk_of(&self, path: &str) -> Option<(usize, bool)> {
+        [(path, false)].into_iter()
+            .chain(path.strip_suffix(".par").map(|path| (path, true)))
+            .find_map(|(path, is_parity)| Some((self.number_of(path)?, is_parity)))
+    }
+
+    fn number_of(&self, path: &str) -> Option<usize> {
+        let mut rest = path;
+        let mut values = Vec::new();
+        for part in &self.parts {
+            if let Part::Text(text) = part {
+                rest = rest.strip_prefix(text.as_str())?;
+                continue;
+            }
+            let len = rest.bytes().take_while(|b| b.is_ascii_digit()).count();
+            values.push((part, rest[..len].parse::<usize>().ok()?));
+            rest = &rest[len..];
+        }
+        if !rest.is_empty() {
+            return None;
+        }
+        let n = values.iter().find_map(|(part, v)| matches!(part, Part::Number(_)).then_some(*v)).or_else(|| {
+            values.iter().find_map(|(part, r)| match part {
+                Part::Mod(k) => values.iter().find_map(|(part, q)| (*part == &Part::Div(*k)).then_some(q * k + r)),
+                _ => None
+            })
+        })?;
+        // only paths exactly as generated, e.g. not with extra leading zeros
+        (self.gen_file_path(n) == path).then_some(n)
+    }
+
+    // path of the same data, parity or metadata file in another set
+    pub fn translate(&self, path: &str, to: &FileSet) -> Result<String, String> {
+        if path == self.config_path {
+            return Ok(to.cfg_path());
         }
-        if nr_zeros < 0 {
-            nr_zeros = 0;
+        match self.chunk_of(path) {
+            Some((n, false)) => Ok(to.gen_file_path(n)),
+            Some((n, true)) => Ok(to.gen_parity_file_path(n)),
+            None => Err(format!("{} is not generated from {}", path, self.pattern_path))
         }
-        out.replace_range(self.offset + nr_zeros as usize .. self.offset + self.len, &s_chunk);
-        out
     }
 }
 
+fn render(parts: &[Part], n: usize) -> String {
+    parts.iter().map(|part| match part {
+        Part::Text(text) => text.clone(),
+        Part::Number(width) => format!("{:0width$}", n, width = width),
+        Part::Div(k) => (n / k).to_string(),
+        Part::Mod(k) => format!("{:0width$}", n % k, width = (k - 1).to_string().len())
+    }).collect()
+}
+
+fn parse_placeholder(name: &str) -> Result<Part, String> {
+    let divisor = |k: &str| match k.parse::<usize>() {
+        Ok(k) if k > 1 => Ok(k),
+        _ => Err(format!("divisor in {{{}}} must be a number greater than 1", name))
+    };
+    match (name, name.strip_prefix("n/"), name.strip_prefix("n%")) {
+        ("n", _, _) => Ok(Part::Number(1)),
+        (_, Some(k), _) => Ok(Part::Div(divisor(k)?)),
+        (_, _, Some(k)) => Ok(Part::Mod(divisor(k)?)),
+        _ => Err(format!("unknown placeholder {{{}}} in pattern", name))
+    }
+}
+
+// splits pattern into text, a run of % and chunk number placeholders
+fn parse_pattern(patt: &str) -> Result<Vec<Part>, String> {
+    let mut spans = Vec::new(); // byte range in pattern and part
+    let mut pos = 0;
+    while let Some(start) = patt[pos..].find('{').map(|i| i + pos) {
+        let end = patt[start..].find('}').map(|i| i + start).ok_or(format!("unclosed {{ in pattern {}", patt))?;
+        spans.push((start, end + 1, parse_placeholder(&patt[start + 1..end])?));
+        pos = end + 1;
+    }
+    let masked: String = patt.char_indices()
+        .map(|(i, c)| if spans.iter().any(|(start, end, _)| i >= *start && i < *end) { '{' } else { c })
+        .collect();
+    if masked.contains('%') || spans.is_empty() {
+        let (offset, len) = analyze_pattern(&masked)?;
+        let start = patt.char_indices().nth(offset).map(|(i, _)| i).unwrap_or(patt.len());
+        spans.push((start, start + len, Part::Number(len)));
+    }
+    spans.sort_by_key(|(start, _, _)| *start);
+
+    let mut parts = Vec::new();
+    let mut pos = 0;
+    for (start, end, part) in spans {
+        if start > pos {
+            parts.push(Part::Text(patt[pos..start].to_owned()));
+        }
+        parts.push(part);
+        pos = end;
+    }
+    if pos < patt.len() {
+        parts.push(Part::Text(patt[pos..].to_owned()));
+    }
+    let has_number = parts.iter().any(|part| matches!(part, Part::Number(_)));
+    let has_div_and_mod = parts.iter().any(|part| matches!(part, Part::Mod(k) if parts.contains(&Part::Div(*k))));
+    if !has_number && !has_div_and_mod {
+        return Err(format!("chunk number cannot be told from pattern {}, it needs a run of %, {{n}}, or both {{n/K}} and {{n%K}}", patt));
+    }
+    Ok(parts)
+}
+
+// deepest directory of pattern which does not depend on chunk number, with trailing separator
+pub fn static_dir(patt: &str) -> &str {
+    let first_var = patt.find(['{', '%']).unwrap_or(patt.len());
+    let dir_len = patt[..first_var].rfind(MAIN_SEPARATOR).map(|pos| pos + 1).unwrap_or(0);
+    &patt[..dir_len]
+}
+
 fn analyze_pattern(patt: &str) -> Result<(usize, usize), String> { // offset inside original string and length
     let mut nr_seqs = 0;
     let mut seq_len = 0;
     let mut finished_seq_len = 0;
     let mut prev_char = None::<char>;
-    let mut pos = 0;
     let mut pat_start_pos = 0;
-    for c in patt.chars() {
+    for (pos, c) in patt.chars().enumerate() {
         if seq_len == 0 {
             if c == '%' {
                 seq_len = 1;
@@ -77,7 +213,6 @@ fn analyze_pattern(patt: &str) -> Result<(usize, usize), String> { // offset ins
             }
             prev_char = Some(c);
         }
-        pos += 1;
     }
     if seq_len != 0 {
         finished_seq_len = seq_len;
@@ -108,13 +243,99 @@ fn pattern_from_cfg(p: &str) -> Result<String, String> {
 }
 
 pub fn cfg_from_pattern(p: &str) -> String {
-    replace_only_last_path_component(format!("{}.cfg", p), '%', '0')
+    // with chunk number placeholders, metadata goes to the directory of the pattern which does not depend
+    // on chunk number, and is named after the first chunk
+    match parse_pattern(p) {
+        Ok(parts) if p.contains('{') => {
+            let first_chunk = render(&parts, 0);
+            let name = first_chunk.rsplit(MAIN_SEPARATOR).next().unwrap_or_default(); // SAFE: rsplit always returns at least one item
+            format!("{}{}.cfg", static_dir(p), name)
+        },
+        _ => replace_only_last_path_component(format!("{}.cfg", p), '%', '0')
+    }
+}
+
+// substitutes placeholders known at the start of backup, {n...} are left for chunk numbers
+pub fn resolve_template(template: &str, labels: &[Label]) -> Result<String, String> {
+    resolve_template_at(template, labels, local_now())
+}
+
+// {label} is the only plain tag, {label:key} is the value of label 'key=value'
+fn label_value(labels: &[Label], key: &str) -> Result<String, String> {
+    if !key.is_empty() {
+        return labels.iter().find(|l| l.key == key).and_then(|l| l.value.clone())
+            .ok_or(format!("template has {{label:{}}}, but no label {}=... is given", key, key));
+    }
+    match labels.iter().filter(|l| l.value.is_none()).collect::<Vec<_>>()[..] {
+        [tag] => Ok(tag.key.clone()),
+        [] => Err("template has {label}, but no label is given".to_owned()),
+        _ => Err("template has {label}, but several labels without value are given".to_owned())
+    }
+}
+
+fn resolve_template_at(template: &str, labels: &[Label], now: OffsetDateTime) -> Result<String, String> {
+    let mut out = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        out.push_str(&rest[..start]);
+        let end = rest[start..].find('}').map(|i| i + start).ok_or(format!("unclosed {{ in template {}", template))?;
+        let placeholder = &rest[start + 1..end];
+        let value = match placeholder.split_once(':').unwrap_or((placeholder, "")) {
+            ("date", "") => format_date(now, "%Y%m%d")?,
+            ("date", fmt) => format_date(now, fmt)?,
+            ("host", "") => host_name()?,
+            ("label", key) => label_value(labels, key)?,
+            _ => placeholder.to_owned() // chunk number
+        };
+        if value == placeholder {
+            out.push_str(&rest[start..=end]);
+        } else if value.contains([MAIN_SEPARATOR, '%', '{', '}']) {
+            return Err(format!("value '{}' of {{{}}} cannot be used in template", value, placeholder));
+        } else {
+            out.push_str(&value);
+        }
+        rest = &rest[end + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+fn format_date(t: OffsetDateTime, fmt: &str) -> Result<String, String> {
+    let mut out = String::new();
+    let mut chars = fmt.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('Y') => out.push_str(&format!("{:04}", t.year())),
+            Some('m') => out.push_str(&format!("{:02}", t.month() as u8)),
+            Some('d') => out.push_str(&format!("{:02}", t.day())),
+            Some('H') => out.push_str(&format!("{:02}", t.hour())),
+            Some('M') => out.push_str(&format!("{:02}", t.minute())),
+            Some('S') => out.push_str(&format!("{:02}", t.second())),
+            _ => { return Err(format!("unsupported date format '{}', use %Y, %m, %d, %H, %M and %S", fmt)); }
+        }
+    }
+    Ok(out)
+}
+
+pub fn host_name() -> Result<String, String> {
+    let mut buf = [0u8; 256];
+    let ret_code = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+    if ret_code != 0 {
+        return Err("could not get host name".to_owned());
+    }
+    let len = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+    Ok(String::from_utf8_lossy(&buf[..len]).into_owned())
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{analyze_pattern, pattern_from_cfg, cfg_from_pattern};
+    use super::{analyze_pattern, pattern_from_cfg, cfg_from_pattern, resolve_template_at};
     use super::FileSet;
+    use crate::stats::Label;
 
     #[test]
     fn empty_or_no_percent() {
@@ -195,6 +416,83 @@ mod tests {
         assert_eq!("/p0ath/p00ath/p000ath/p00a00th/12345def".to_owned(),  FileSet::from_cfg_path("/p0ath/p00ath/p000ath/p00a00th/0000def.cfg").unwrap().gen_file_path(12345));
     }
 
+    #[test]
+    fn gen_parity_path() {
+        assert_eq!("ab015def.par".to_owned(), FileSet::from_pattern("ab%%%def").unwrap().gen_parity_file_path(15));
+        assert_eq!("/p0ath/ab1234.par".to_owned(), FileSet::from_cfg_path("/p0ath/ab000.cfg").unwrap().gen_parity_file_path(1234));
+    }
+
+    #[test]
+    fn chunk_of_path() {
+        let fs = FileSet::from_pattern("/mnt/bk%%%.xz").unwrap();
+        assert_eq!(fs.chunk_of("/mnt/bk007.xz"), Some((7, false)));
+        assert_eq!(fs.chunk_of("/mnt/bk1234.xz"), Some((1234, false)));
+        assert_eq!(fs.chunk_of("/mnt/bk002.xz.par"), Some((2, true)));
+        assert_eq!(fs.chunk_of(&fs.gen_parity_file_path(12)), Some((12, true)));
+        assert_eq!(fs.chunk_of("/mnt/bk07.xz"), None);
+        assert_eq!(fs.chunk_of("/mnt/bk000.xz.cfg"), None);
+        assert_eq!(fs.chunk_of("/other/bk000.xz"), None);
+
+        let other = FileSet::from_pattern("s3://bucket/x%%%%%").unwrap();
+        assert_eq!(fs.translate("/mnt/bk012.xz", &other).unwrap(), "s3://bucket/x00012");
+        assert_eq!(fs.translate("/mnt/bk012.xz.par", &other).unwrap(), "s3://bucket/x00012.par");
+        assert_eq!(fs.translate("/mnt/bk000.xz.cfg", &other).unwrap(), "s3://bucket/x00000.cfg");
+        assert!(fs.translate("/mnt/other", &other).is_err());
+    }
+
+    #[test]
+    fn sharded_pattern() {
+        let fs = FileSet::from_pattern("/mnt/bk/{n/1000}/{n%1000}").unwrap();
+        assert_eq!(fs.gen_file_path(12345), "/mnt/bk/12/345");
+        assert_eq!(fs.gen_file_path(7), "/mnt/bk/0/007");
+        assert_eq!(fs.gen_parity_file_path(1001), "/mnt/bk/1/001.par");
+        assert_eq!(fs.cfg_path(), "/mnt/bk/000.cfg");
+        assert_eq!(fs.chunk_pattern(), Some("{n/1000}/{n%1000}".to_owned()));
+        assert_eq!(fs.chunk_of("/mnt/bk/12/345"), Some((12345, false)));
+        assert_eq!(fs.chunk_of("/mnt/bk/1/001.par"), Some((1001, true)));
+        assert_eq!(fs.chunk_of("/mnt/bk/12/0345"), None);
+        assert_eq!(fs.chunk_of("/mnt/bk/12"), None);
+
+        let fs = FileSet::from_pattern("s3://bucket/{n/100}/x%%%%.xz").unwrap();
+        assert_eq!(fs.gen_file_path(1234), "s3://bucket/12/x1234.xz");
+        assert_eq!(fs.cfg_path(), "s3://bucket/x0000.xz.cfg");
+        assert_eq!(fs.chunk_of("s3://bucket/12/x1234.xz"), Some((1234, false)));
+        assert_eq!(fs.chunk_of("s3://bucket/11/x1234.xz"), None);
+
+        // read back with the pattern stored in metadata
+        let read = FileSet::from_cfg("/other/place/000.cfg", Some("{n/1000}/{n%1000}")).unwrap();
+        assert_eq!(read.gen_file_path(12345), "/other/place/12/345");
+        assert_eq!(read.cfg_path(), "/other/place/000.cfg");
+        assert_eq!(FileSet::from_cfg("/p/x00.cfg", None).unwrap().gen_file_path(5), "/p/x05");
+        assert_eq!(FileSet::from_pattern("/p/x%%").unwrap().chunk_pattern(), None);
+
+        assert!(FileSet::from_pattern("/mnt/{n/1000}/chunk").is_err());
+        assert!(FileSet::from_pattern("/mnt/{n/100}/{n%1000}").is_err());
+        assert!(FileSet::from_pattern("/mnt/{n%1}/{n/1}").is_err());
+        assert!(FileSet::from_pattern("/mnt/{host}/%%%").is_err());
+        assert!(FileSet::from_pattern("/mnt/{n/1000/%%%").is_err());
+        assert!(FileSet::from_pattern("/mnt/%%/x%%").is_err());
+        assert_eq!(FileSet::from_pattern("/mnt/x{n}").unwrap().gen_file_path(12), "/mnt/x12");
+    }
+
+    #[test]
+    fn resolve_placeholders() {
+        let now = time::OffsetDateTime::from_unix_timestamp(1709622489).unwrap(); // 2024-03-05 07:08:09 UTC
+        let labels = |ls: &[&str]| ls.iter().map(|l| Label::parse(l).unwrap()).collect::<Vec<_>>();
+        assert_eq!(resolve_template_at("/bk/{date}/x%%%", &[], now).unwrap(), "/bk/20240305/x%%%");
+        assert_eq!(resolve_template_at("/bk/{date:%Y-%m-%d_%H%M%S}/{n/10}/{n%10}", &[], now).unwrap(), "/bk/2024-03-05_070809/{n/10}/{n%10}");
+        assert_eq!(resolve_template_at("/bk/{label}_%%%", &labels(&["db1"]), now).unwrap(), "/bk/db1_%%%");
+        assert_eq!(resolve_template_at("/bk/{label:db}/{label}_%%%", &labels(&["env=prod", "db=main", "nightly"]), now).unwrap(), "/bk/main/nightly_%%%");
+        assert!(!resolve_template_at("/bk/{host}/%%%", &[], now).unwrap().contains('{'));
+        assert!(resolve_template_at("/bk/{label}_%%%", &[], now).is_err());
+        assert!(resolve_template_at("/bk/{label}_%%%", &labels(&["db=main"]), now).is_err());
+        assert!(resolve_template_at("/bk/{label}_%%%", &labels(&["a", "b"]), now).is_err());
+        assert!(resolve_template_at("/bk/{label:env}_%%%", &labels(&["db=main", "env"]), now).is_err());
+        assert!(resolve_template_at("/bk/{label}_%%%", &labels(&["a/b"]), now).is_err());
+        assert!(resolve_template_at("/bk/{date:%y}_%%%", &[], now).is_err());
+        assert!(resolve_template_at("/bk/{date_%%%", &[], now).is_err());
+    }
+
     #[test]
     fn patt_from_cfg() {
         assert_eq!(Ok("/path/to0/di0r/out%%".to_owned()), pattern_from_cfg("/path/to0/di0r/out00.cfg"));
@@ -1,4 +1,21 @@
 pub trait DataSink {
     fn add(&mut self, data: &[u8]) -> Result<(), String>;
     fn finish(&mut self) -> Result<(), String>;
+
+    // informs that `len` bytes at the current position are lost; only salvaging sinks can handle it
+    fn add_lost(&mut self, len: usize) -> Result<(), String> {
+        Err(format!("{} bytes of data are lost", len))
+    }
+}
+
+// collects data in memory, e.g. a chunk of a repository before it is stored
+impl DataSink for Vec<u8> {
+    fn add(&mut self, data: &[u8]) -> Result<(), String> {
+        self.extend_from_slice(data);
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), String> {
+        Ok(())
+    }
 }
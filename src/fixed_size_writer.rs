@@ -9,7 +9,7 @@ pub struct FixedSizeWriter<T: DataSink> {
 
 impl<T: DataSink> FixedSizeWriter<T> {
     pub fn new(out: T, size: usize) -> FixedSizeWriter<T> {
-        FixedSizeWriter { out: out, size: size, buf: Vec::new() }
+        FixedSizeWriter { out, size, buf: Vec::new() }
     }
 }
 
@@ -91,7 +91,7 @@ mod tests {
         let out = TestOut{ actual_writes: Vec::new(), expected_writes: conv(out_writes) };
         let mut fsw = FixedSizeWriter::<TestOut>::new(out, buf_size);
         for iw in in_writes {
-            fsw.add(&iw).unwrap();
+            fsw.add(iw).unwrap();
         }
         fsw.finish().unwrap();
         assert!(fsw.internal_buf().is_empty());
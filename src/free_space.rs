@@ -1,5 +1,7 @@
 use std::ffi::CString;
 use std::mem;
+use std::thread;
+use std::time::Duration;
 
 pub fn get_free_space(mount_point: &str) -> Result<usize, String> {
     let c_mount_point = CString::new(mount_point.as_bytes())
@@ -20,10 +22,54 @@ pub fn get_free_space(mount_point: &str) -> Result<usize, String> {
                 return Err("inconsitent filesystem data".to_owned());
             }
 
-            return Ok(bfree * bsize);
+            Ok(bfree * bsize)
         }
         else {
-            return Err("bad mountpoint or filesystem to query".to_owned());
+            Err("bad mountpoint or filesystem to query".to_owned())
+        }
+    }
+}
+
+// keeps free space on the filesystem of backup destination from running out
+#[derive(Clone, PartialEq, Debug)]
+pub struct SpaceCheck {
+    pub path: String, // any path on the filesystem
+    pub margin: usize, // how many bytes to leave free besides the next chunk
+    pub poll: Option<Duration>, // how often to check again while waiting for space to appear; fail at once if None
+    pub input_len: Option<usize> // expected length of input data, to estimate up front whether the archive fits
+}
+
+impl SpaceCheck {
+    // returns once there is space for `needed` bytes plus margin
+    pub fn wait_for(&self, needed: usize) -> Result<(), String> {
+        self.wait_with(needed, || get_free_space(&self.path))
+    }
+
+    fn wait_with<F>(&self, needed: usize, free_space: F) -> Result<(), String>
+    where F: Fn() -> Result<usize, String>
+    {
+        let mut waiting = false;
+        loop {
+            let free = free_space()?;
+            if free >= needed + self.margin {
+                if waiting {
+                    eprintln!("{} bytes are free on {}, continuing", free, self.path);
+                }
+                return Ok(());
+            }
+            match self.poll {
+                None => {
+                    return Err(format!("only {} bytes are free on {} while {} are needed for the next chunk and {} are to be left free",
+                        free, self.path, needed, self.margin));
+                },
+                Some(period) => {
+                    if !waiting {
+                        eprintln!("only {} bytes are free on {} while {} are needed, waiting...", free, self.path, needed + self.margin);
+                        waiting = true;
+                    }
+                    thread::sleep(period);
+                }
+            }
         }
     }
 }
@@ -43,4 +89,18 @@ mod tests {
     fn bad() {
         assert!(get_free_space("/sdkjfsd/sdkjfdk/sdkjh").is_err());
     }
+
+    #[test]
+    fn wait_for_space() {
+        let mut check = SpaceCheck { path: "/mnt".to_owned(), margin: 100, poll: None, input_len: None };
+        check.wait_with(900, || Ok(1000)).unwrap();
+        let err = check.wait_with(901, || Ok(1000)).unwrap_err();
+        assert_eq!(err, "only 1000 bytes are free on /mnt while 901 are needed for the next chunk and 100 are to be left free");
+
+        // space appears on the third check
+        check.poll = Some(Duration::from_millis(1));
+        let calls = std::cell::Cell::new(0);
+        check.wait_with(1000, || { calls.set(calls.get() + 1); Ok(calls.get() * 500) }).unwrap();
+        assert_eq!(calls.get(), 3);
+    }
 }
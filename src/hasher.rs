@@ -1,23 +1,59 @@
 use std::hash::Hasher;
-use twox_hash::{xxh3::Hash128, Xxh3Hash128};
+use twox_hash::{xxh3::Hash128, Xxh3Hash128, XxHash64};
 use crate::finalizable::DataSink;
+use crate::stats::ChunkInfo;
+
+// streaming xxh3 of twox-hash 1.x takes the last stripe of data from a stale internal buffer when a portion
+// of data ends in a full block of it, so that the hash depends on how data is split into portions; whole blocks
+// are hashed as they come, while the last ones are held back until `finish` and then hashed in a fixed way
+const XXH3_BLOCK_LEN: usize = 256;
+const HELD_BACK_LEN: usize = 2 * XXH3_BLOCK_LEN;
 
 pub struct DataHasher<'a, T: DataSink> {
     write_to: Option<&'a mut T>,
     hasher: Hash128,
+    tail: Vec<u8>, // not hashed yet, starts at a whole block
     counter: usize,
 }
 
 // transparently copies data to `Writer`, calculaing hash in the mean time
 impl<'a, T: DataSink> DataHasher<'a, T> {
     pub fn with_writer(to: Option<&'a mut T>, seed: u64) -> DataHasher<'a, T> {
-        DataHasher { write_to: to, hasher: Xxh3Hash128::with_seed(seed), counter: 0 }
+        DataHasher { write_to: to, hasher: Xxh3Hash128::with_seed(seed), tail: Vec::with_capacity(HELD_BACK_LEN + XXH3_BLOCK_LEN), counter: 0 }
     }
 
+    // hash of all data, once `finish` is called
     pub fn result(&self) -> u64 {
+        debug_assert!(self.tail.is_empty(), "result of DataHasher before finish");
         self.hasher.finish()
     }
 
+    fn hash(&mut self, data: &[u8]) {
+        let total = self.tail.len() + data.len();
+        if total < HELD_BACK_LEN + XXH3_BLOCK_LEN {
+            self.tail.extend_from_slice(data);
+            return;
+        }
+        let to_hash = (total - HELD_BACK_LEN) / XXH3_BLOCK_LEN * XXH3_BLOCK_LEN;
+        let from_tail = usize::min(to_hash, self.tail.len());
+        self.hasher.write(&self.tail[..from_tail]);
+        self.hasher.write(&data[..to_hash - from_tail]);
+        self.tail.drain(..from_tail);
+        self.tail.extend_from_slice(&data[to_hash - from_tail..]);
+    }
+
+    // data ending in a partial block is finished in portions smaller than a block, which gives the proper xxh3;
+    // data of whole blocks is finished with the last two blocks at once, as earlier releases did when reading
+    // input in big portions, so that hashes in metadata of existing archives are still valid
+    fn hash_tail(&mut self) {
+        if self.tail.len().is_multiple_of(XXH3_BLOCK_LEN) {
+            self.hasher.write(&self.tail);
+        } else {
+            self.tail.chunks(XXH3_BLOCK_LEN - 1).for_each(|portion| self.hasher.write(portion));
+        }
+        self.tail.clear();
+    }
+
     pub fn counter(&self) -> usize {
         self.counter
     }
@@ -26,7 +62,7 @@ impl<'a, T: DataSink> DataHasher<'a, T> {
 impl<'a, T: DataSink> DataSink for DataHasher<'a, T> {
     fn add(&mut self, data: &[u8]) -> Result<(), String> {
         //eprintln!("DataHasher: writing {} bytes", data.len());
-        self.hasher.write(data);
+        self.hash(data);
         self.counter += data.len();
         if let Some(write_to) = self.write_to.as_mut() {
             write_to.add(data)
@@ -37,6 +73,7 @@ impl<'a, T: DataSink> DataSink for DataHasher<'a, T> {
 
     fn finish(&mut self) -> Result<(), String> {
         //eprintln!("DataHasher: finish");
+        self.hash_tail();
         if let Some(write_to) = &mut self.write_to {
             write_to.finish()
         } else {
@@ -44,3 +81,72 @@ impl<'a, T: DataSink> DataSink for DataHasher<'a, T> {
         }
     }
 }
+
+// hashes contents of a single chunk file, to verify each chunk independently
+pub struct ChunkHasher {
+    hasher: XxHash64,
+    len: usize
+}
+
+impl ChunkHasher {
+    pub fn new(seed: u64) -> Self {
+        Self { hasher: XxHash64::with_seed(seed), len: 0 }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.hasher.write(data);
+        self.len += data.len();
+    }
+
+    pub fn result(&self) -> ChunkInfo {
+        ChunkInfo { len: self.len, hash: self.hasher.finish() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ChunkHasher, DataHasher};
+    use crate::finalizable::DataSink;
+    use std::hash::Hasher;
+    use twox_hash::{xxh3::hash128_with_seed, Xxh3Hash128};
+
+    #[test]
+    fn data_hash_does_not_depend_on_portions() {
+        let data: Vec<u8> = (0..70000u32).map(|i| (i.wrapping_mul(2654435761) >> 13) as u8).collect();
+        for len in [0, 1, 240, 241, 256, 300, 512, 768, 1025, 1290, 4096, 65536 + 10, 65536 + 255, 70000] {
+            let data = &data[..len];
+            let hash_by = |portion: usize| {
+                let mut h = DataHasher::with_writer(None::<&mut Vec<u8>>, 5);
+                data.chunks(portion).for_each(|p| h.add(p).unwrap());
+                h.finish().unwrap();
+                assert_eq!(h.counter(), len);
+                h.result()
+            };
+            let expected = if len % 256 == 0 {
+                // as hashed by earlier releases, reading input in big portions
+                let mut h = Xxh3Hash128::with_seed(5);
+                h.write(data);
+                h.finish()
+            } else {
+                hash128_with_seed(data, 5) as u64
+            };
+            for portion in [1, 63, 255, 256, 1000, 1024, 65536, 100000] {
+                assert_eq!(hash_by(portion), expected, "length {}, portion {}", len, portion);
+            }
+        }
+    }
+
+    #[test]
+    fn chunk_hash_does_not_depend_on_portions() {
+        let data: Vec<u8> = (0..240160u32).map(|i| (i * 7919 % 251) as u8).collect();
+        let hash_by = |portion: usize| {
+            let mut h = ChunkHasher::new(5);
+            data.chunks(portion).for_each(|p| h.update(p));
+            h.result()
+        };
+        let whole = hash_by(data.len());
+        for portion in [1, 63, 1000, 65536, 100000] {
+            assert_eq!(hash_by(portion), whole);
+        }
+    }
+}
@@ -0,0 +1,357 @@
+use crate::joiner::MultiFilesReaderSource;
+use crate::splitter::MultiFilesWriterTarget;
+use crate::stats::ChunkInfo;
+use std::io;
+use std::thread;
+use std::time::Duration;
+
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+const SYNC_EVERY: usize = 16 * 1_048_576; // at most this much data is kept to be rewritten after an error
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct RetryPolicy {
+    pub retries: usize,
+    pub first_backoff: Duration // doubled after every failed attempt
+}
+
+// errors which network filesystems return when the server is briefly unavailable
+pub fn is_transient(e: &io::Error) -> bool {
+    use io::ErrorKind::*;
+    matches!(e.kind(), TimedOut | Interrupted | WouldBlock | ConnectionReset | ConnectionAborted | NotConnected | BrokenPipe
+        | StaleNetworkFileHandle | ResourceBusy | NetworkDown | NetworkUnreachable | HostUnreachable)
+        || e.raw_os_error() == Some(libc::EIO)
+}
+
+pub struct Backoff<'a> {
+    policy: &'a RetryPolicy,
+    attempt: usize,
+    delay: Duration
+}
+
+impl<'a> Backoff<'a> {
+    pub fn new(policy: &'a RetryPolicy) -> Self {
+        Self { policy, attempt: 0, delay: policy.first_backoff }
+    }
+
+    // waits before the next attempt, or returns the error if it is fatal or retries are exhausted
+    pub fn wait(&mut self, e: String, transient: bool, what: &str) -> Result<(), String> {
+        if !transient || self.policy.retries == 0 {
+            return Err(e);
+        }
+        if self.attempt == self.policy.retries {
+            return Err(format!("{} (gave up after {} retries)", e, self.attempt));
+        }
+        self.attempt += 1;
+        eprintln!("{}; retrying {} in {:?} (attempt {} of {})", e, what, self.delay, self.attempt, self.policy.retries);
+        thread::sleep(self.delay);
+        self.delay = Duration::min(self.delay * 2, MAX_BACKOFF);
+        Ok(())
+    }
+}
+
+// retries operations on chunk files which failed with transient errors; data written since
+// the last sync is kept, so that the file can be cut back to the synced length and rewritten
+pub struct RetryingWriter {
+    inner: Box<dyn MultiFilesWriterTarget>,
+    policy: RetryPolicy,
+    path: String,
+    confirmed: u64, // synced length of the current file
+    tail: Vec<u8> // written after `confirmed`
+}
+
+impl RetryingWriter {
+    pub fn new(inner: Box<dyn MultiFilesWriterTarget>, policy: &RetryPolicy) -> Self {
+        Self { inner, policy: policy.clone(), path: String::new(), confirmed: 0, tail: Vec::new() }
+    }
+
+    // reopens the file at the synced length, writes the tail and `pending` data again and syncs them
+    fn rewrite(&mut self, pending: &[u8]) -> Result<(), String> {
+        self.inner.reopen_current_file(self.confirmed)?;
+        self.inner.write_to_current_file(&self.tail)?;
+        self.inner.write_to_current_file(pending)?;
+        self.inner.sync_current_file()?;
+        self.confirmed += (self.tail.len() + pending.len()) as u64;
+        self.tail.clear();
+        Ok(())
+    }
+
+    fn recover(&mut self, e: String, pending: &[u8]) -> Result<(), String> {
+        let policy = self.policy.clone();
+        let mut backoff = Backoff::new(&policy);
+        let mut e = e;
+        loop {
+            backoff.wait(e, self.inner.last_error_is_transient(), &format!("from offset {} of {}", self.confirmed, self.path))?;
+            match self.rewrite(pending) {
+                Ok(()) => { return Ok(()); },
+                Err(next) => { e = next; }
+            }
+        }
+    }
+
+    fn sync(&mut self) -> Result<(), String> {
+        match self.inner.sync_current_file() {
+            Ok(()) => {
+                self.confirmed += self.tail.len() as u64;
+                self.tail.clear();
+                Ok(())
+            },
+            Err(e) => self.recover(e, &[])
+        }
+    }
+}
+
+impl MultiFilesWriterTarget for RetryingWriter {
+    fn open_next_file(&mut self, full_path: &str) -> Result<(), String> {
+        let mut backoff = Backoff::new(&self.policy);
+        while let Err(e) = self.inner.open_next_file(full_path) {
+            backoff.wait(e, self.inner.last_error_is_transient(), &format!("to create {}", full_path))?;
+        }
+        self.path = full_path.to_owned();
+        self.confirmed = 0;
+        self.tail.clear();
+        Ok(())
+    }
+
+    fn close_current_file(&mut self) -> Result<(), String> {
+        // nothing can be rewritten once the file is closed
+        self.sync()?;
+        let mut backoff = Backoff::new(&self.policy);
+        let mut res = self.inner.close_current_file();
+        while let Err(e) = res {
+            backoff.wait(e, self.inner.last_error_is_transient(), &format!("to close {}", self.path))?;
+            // everything is synced, so the file is only reopened to be closed again
+            res = self.inner.reopen_current_file(self.confirmed).and_then(|_| self.inner.close_current_file());
+        }
+        Ok(())
+    }
+
+    fn write_to_current_file(&mut self, data: &[u8]) -> Result<(), String> {
+        if let Err(e) = self.inner.write_to_current_file(data) {
+            return self.recover(e, data);
+        }
+        self.tail.extend_from_slice(data);
+        if self.tail.len() >= SYNC_EVERY {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    fn write_single_file(&self, path: &str, contents: &[u8]) -> Result<(), String> {
+        let mut backoff = Backoff::new(&self.policy);
+        while let Err(e) = self.inner.write_single_file(path, contents) {
+            backoff.wait(e, self.inner.last_error_is_transient(), &format!("to write {}", path))?;
+        }
+        Ok(())
+    }
+
+    fn set_current_file_info(&mut self, info: &ChunkInfo) {
+        self.inner.set_current_file_info(info)
+    }
+
+    fn remove_file(&self, path: &str) -> Result<(), String> {
+        let mut backoff = Backoff::new(&self.policy);
+        while let Err(e) = self.inner.remove_file(path) {
+            backoff.wait(e, self.inner.last_error_is_transient(), &format!("to remove {}", path))?;
+        }
+        Ok(())
+    }
+}
+
+// retries operations on chunk files which failed with transient errors, reopening the file
+// and continuing from the offset read so far
+pub struct RetryingReader {
+    inner: Box<dyn MultiFilesReaderSource>,
+    policy: RetryPolicy,
+    path: String,
+    pos: u64
+}
+
+impl RetryingReader {
+    pub fn new(inner: Box<dyn MultiFilesReaderSource>, policy: &RetryPolicy) -> Self {
+        Self { inner, policy: policy.clone(), path: String::new(), pos: 0 }
+    }
+}
+
+impl MultiFilesReaderSource for RetryingReader {
+    fn open_next_file(&mut self, full_path: &str) -> Result<bool, String> {
+        let mut backoff = Backoff::new(&self.policy);
+        loop {
+            match self.inner.open_next_file(full_path) {
+                Ok(found) => {
+                    self.path = full_path.to_owned();
+                    self.pos = 0;
+                    return Ok(found);
+                },
+                Err(e) => backoff.wait(e, self.inner.last_error_is_transient(), &format!("to open {}", full_path))?
+            }
+        }
+    }
+
+    fn read_from_current_file(&mut self, buf: &mut [u8]) -> Result<usize, String> {
+        let mut backoff = Backoff::new(&self.policy);
+        let mut res = self.inner.read_from_current_file(buf);
+        while let Err(e) = res {
+            backoff.wait(e, self.inner.last_error_is_transient(), &format!("from offset {} of {}", self.pos, self.path))?;
+            res = self.inner.reopen_current_file(self.pos).and_then(|_| self.inner.read_from_current_file(buf));
+        }
+        let nr_read = res?;
+        self.pos += nr_read as u64;
+        Ok(nr_read)
+    }
+
+    fn close_current_file(&mut self) -> Result<(), String> {
+        self.inner.close_current_file()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // keeps files in memory and fails the indicated calls with the indicated kind of error
+    struct Flaky {
+        data: Rc<RefCell<Vec<u8>>>,
+        synced: usize,
+        pos: usize,
+        calls: usize,
+        fail_on: Vec<usize>,
+        transient: bool,
+        last_transient: bool
+    }
+
+    impl Flaky {
+        fn new(fail_on: Vec<usize>, transient: bool) -> Self {
+            Self { data: Rc::new(RefCell::new(Vec::new())), synced: 0, pos: 0, calls: 0, fail_on, transient, last_transient: false }
+        }
+
+        fn call(&mut self) -> Result<(), String> {
+            self.calls += 1;
+            self.last_transient = false;
+            if self.fail_on.contains(&self.calls) {
+                self.last_transient = self.transient;
+                return Err(format!("call #{} failed", self.calls));
+            }
+            Ok(())
+        }
+    }
+
+    impl MultiFilesWriterTarget for Flaky {
+        fn open_next_file(&mut self, _full_path: &str) -> Result<(), String> {
+            self.call()?;
+            self.data.borrow_mut().clear();
+            Ok(())
+        }
+        fn close_current_file(&mut self) -> Result<(), String> {
+            self.call()
+        }
+        fn write_to_current_file(&mut self, data: &[u8]) -> Result<(), String> {
+            // a failed write leaves a part of data behind
+            if let Err(e) = self.call() {
+                self.data.borrow_mut().extend_from_slice(&data[..data.len() / 2]);
+                return Err(e);
+            }
+            self.data.borrow_mut().extend_from_slice(data);
+            Ok(())
+        }
+        fn write_single_file(&self, _path: &str, _contents: &[u8]) -> Result<(), String> {
+            Ok(())
+        }
+        fn last_error_is_transient(&self) -> bool {
+            self.last_transient
+        }
+        fn sync_current_file(&mut self) -> Result<(), String> {
+            self.call()?;
+            self.synced = self.data.borrow().len();
+            Ok(())
+        }
+        fn reopen_current_file(&mut self, offset: u64) -> Result<(), String> {
+            self.call()?;
+            assert!(offset as usize <= self.synced);
+            self.data.borrow_mut().truncate(offset as usize);
+            Ok(())
+        }
+    }
+
+    impl MultiFilesReaderSource for Flaky {
+        fn open_next_file(&mut self, _full_path: &str) -> Result<bool, String> {
+            self.call()?;
+            self.pos = 0;
+            Ok(true)
+        }
+        fn read_from_current_file(&mut self, buf: &mut [u8]) -> Result<usize, String> {
+            self.call()?;
+            let data = self.data.borrow();
+            let nr_read = usize::min(buf.len(), data.len() - self.pos);
+            buf[..nr_read].copy_from_slice(&data[self.pos..self.pos + nr_read]);
+            self.pos += nr_read;
+            Ok(nr_read)
+        }
+        fn close_current_file(&mut self) -> Result<(), String> {
+            self.call()
+        }
+        fn last_error_is_transient(&self) -> bool {
+            self.last_transient
+        }
+        fn reopen_current_file(&mut self, offset: u64) -> Result<(), String> {
+            self.call()?;
+            self.pos = offset as usize;
+            Ok(())
+        }
+    }
+
+    const POLICY: RetryPolicy = RetryPolicy { retries: 2, first_backoff: Duration::from_millis(1) };
+
+    fn write_flaky(fail_on: Vec<usize>, transient: bool) -> Result<Vec<u8>, String> {
+        let flaky = Flaky::new(fail_on, transient);
+        let data = flaky.data.clone();
+        let mut w = RetryingWriter::new(Box::new(flaky), &POLICY);
+        w.open_next_file("f")?;
+        for portion in [&b"abcd"[..], b"efgh", b"ijkl"] {
+            w.write_to_current_file(portion)?;
+        }
+        w.close_current_file()?;
+        let written = data.borrow().clone();
+        Ok(written)
+    }
+
+    #[test]
+    fn writes_are_retried() {
+        assert_eq!(write_flaky(vec![], true).unwrap(), b"abcdefghijkl");
+        // open, 1st write, 3rd write after the data is synced, sync on close
+        assert_eq!(write_flaky(vec![1, 3, 9, 14], true).unwrap(), b"abcdefghijkl");
+        // close, then reopen before closing again
+        assert_eq!(write_flaky(vec![6, 7], true).unwrap(), b"abcdefghijkl");
+        assert_eq!(write_flaky(vec![3, 4, 5], true).unwrap_err(), "call #5 failed (gave up after 2 retries)");
+        assert_eq!(write_flaky(vec![3], false).unwrap_err(), "call #3 failed");
+    }
+
+    #[test]
+    fn reads_are_retried() {
+        let flaky = Flaky::new(vec![3, 6, 7], true);
+        flaky.data.borrow_mut().extend_from_slice(b"abcdefghijkl");
+        let mut r = RetryingReader::new(Box::new(flaky), &POLICY);
+        assert!(r.open_next_file("f").unwrap());
+        let mut restored = Vec::new();
+        let mut buf = [0u8; 5];
+        loop {
+            let nr_read = r.read_from_current_file(&mut buf).unwrap();
+            if nr_read == 0 {
+                break;
+            }
+            restored.extend_from_slice(&buf[..nr_read]);
+        }
+        r.close_current_file().unwrap();
+        assert_eq!(restored, b"abcdefghijkl");
+    }
+
+    #[test]
+    fn transient_errors() {
+        assert!(is_transient(&io::Error::from_raw_os_error(libc::EIO)));
+        assert!(is_transient(&io::Error::from_raw_os_error(libc::ETIMEDOUT)));
+        assert!(is_transient(&io::Error::from(io::ErrorKind::StaleNetworkFileHandle)));
+        assert!(!is_transient(&io::Error::from_raw_os_error(libc::ENOSPC)));
+        assert!(!is_transient(&io::Error::from(io::ErrorKind::PermissionDenied)));
+    }
+}
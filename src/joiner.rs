@@ -1,49 +1,151 @@
+use std::collections::HashMap;
+use std::ops::Range;
 use crate::finalizable::DataSink;
 use crate::file_set::FileSet;
+use crate::hasher::ChunkHasher;
+use crate::parity::ShardsCombiner;
+use crate::stats::{Stats, ChunkInfo, archive_id_as_string};
+use crate::chunk_header::{payload_range, clip, archive_id_of, ARCHIVE_ID_END};
+use crate::progress::Progress;
 
 pub trait MultiFilesReaderSource {
     fn open_next_file(&mut self, full_path: &str) -> Result<bool, String>;
     fn read_from_current_file(&mut self, buf: &mut [u8]) -> Result<usize, String>;
     fn close_current_file(&mut self, ) -> Result<(), String>;
-    fn read_single_file(full_path: &str) -> Result<Vec<u8>, String>;
+    // whether the last failed operation may succeed if repeated, e.g. after a timeout of network filesystem
+    fn last_error_is_transient(&self) -> bool {
+        false
+    }
+    // opens the current file again after an error, to continue reading from `offset`
+    fn reopen_current_file(&mut self, _offset: u64) -> Result<(), String> {
+        Err("reopening a file is not supported".to_owned())
+    }
+    // how many copies of every file there are to read from
+    fn nr_copies(&self) -> usize {
+        1
+    }
+    // the file read last is damaged, so it is to be opened in the next copy; false if there is none left
+    fn fail_over(&mut self, _full_path: &str, _problem: &str) -> Result<bool, String> {
+        Ok(false)
+    }
+}
+
+impl MultiFilesReaderSource for Box<dyn MultiFilesReaderSource> {
+    fn open_next_file(&mut self, full_path: &str) -> Result<bool, String> {
+        self.as_mut().open_next_file(full_path)
+    }
+    fn read_from_current_file(&mut self, buf: &mut [u8]) -> Result<usize, String> {
+        self.as_mut().read_from_current_file(buf)
+    }
+    fn close_current_file(&mut self) -> Result<(), String> {
+        self.as_mut().close_current_file()
+    }
+    fn last_error_is_transient(&self) -> bool {
+        self.as_ref().last_error_is_transient()
+    }
+    fn reopen_current_file(&mut self, offset: u64) -> Result<(), String> {
+        self.as_mut().reopen_current_file(offset)
+    }
+    fn nr_copies(&self) -> usize {
+        self.as_ref().nr_copies()
+    }
+    fn fail_over(&mut self, full_path: &str, problem: &str) -> Result<bool, String> {
+        self.as_mut().fail_over(full_path, problem)
+    }
+}
+
+// how chunks which are missing or damaged beyond recovery are treated
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum DamagedChunks {
+    Fail,
+    // pass whatever is readable, cut or padded with zeros to the known chunk length;
+    // suitable for encrypted data where every authenticated block is verified anyway
+    FeedReadable,
+    // report the whole chunk as lost data to the target
+    ReportLost
 }
 
 pub struct Joiner<'a, T: DataSink, R: MultiFilesReaderSource> {
     from: R,
     to: &'a mut T,
     file_set: FileSet,
+    stats: Option<&'a Stats>,
+    nr_chunks: usize, // 0 if unknown
     max_read_buf_size: usize,
-    next_chunk_no: usize
+    next_chunk_no: usize,
+    on_damaged: DamagedChunks,
+    progress: Option<Progress>
 }
 
 impl <'a, T: DataSink, R: MultiFilesReaderSource> Joiner<'a, T, R> {
-    pub fn from_metadata(read_from: R, write_to: &'a mut T, metadata_path: &'a str, max_read_buf_size: usize) -> Result<Self, String> {
+    // if `stats` contain per-chunk hashes, every chunk is verified, and damaged ones are
+    // recovered from parity chunks (if any); otherwise chunks are read up to the known number of
+    // chunks, or until the first missing one if it is not known either
+    pub fn from_metadata(read_from: R, write_to: &'a mut T, metadata_path: &'a str, stats: Option<&'a Stats>, max_read_buf_size: usize) -> Result<Self, String> {
         Ok(Self { 
             from: read_from, 
             to: write_to,
-            file_set: FileSet::from_cfg_path(metadata_path)?,
+            file_set: FileSet::from_cfg(metadata_path, stats.and_then(|s| s.chunk_pattern.as_deref()))?,
+            stats: stats.filter(|s| !s.chunks.is_empty()),
+            nr_chunks: stats.map(|s| s.out_nr_chunks).unwrap_or(0),
             max_read_buf_size,
-            next_chunk_no: 0
+            next_chunk_no: 0,
+            on_damaged: DamagedChunks::Fail,
+            progress: None
         })
     }
 
+    // counts bytes of data chunks as they are read and reports the current chunk
+    pub fn set_progress(&mut self, progress: &Progress) {
+        self.progress = Some(progress.clone());
+    }
+
+    fn report(&self, chunk_no: usize, read: usize) {
+        if let Some(progress) = &self.progress {
+            progress.set_chunk(chunk_no);
+            progress.add_in(read);
+        }
+    }
+
+    // only has effect if per-chunk hashes are known
+    pub fn on_damaged_chunks(&mut self, on_damaged: DamagedChunks) {
+        self.on_damaged = on_damaged;
+    }
+
     pub fn read_and_write_all(&mut self) -> Result<(), String> {
-        let mut read_buf: Vec<u8> = Vec::with_capacity(self.max_read_buf_size);
-        read_buf.resize(self.max_read_buf_size, 0);
+        let mut read_buf: Vec<u8> = vec![0; self.max_read_buf_size];
 
-        loop {
-            if self.next_chunk_no != 0 {
-                self.from.close_current_file()?;
-            }
+        match self.stats {
+            Some(stats) => self.read_known_chunks(stats, &mut read_buf)?,
+            None => self.read_until_missing(&mut read_buf)?
+        }
+
+        self.to.finish().map_err(|e| format!("finalization error: {}", e))?;
+
+        Ok(())
+    }
 
+    fn read_until_missing(&mut self, read_buf: &mut [u8]) -> Result<(), String> {
+        while self.nr_chunks == 0 || self.next_chunk_no < self.nr_chunks {
             let path_to_open = self.file_set.gen_file_path(self.next_chunk_no);
             let path_to_open = path_to_open.as_str();
-            let opened_or_not_found = self.from.open_next_file(path_to_open)
+            let to = &mut self.to;
+            let progress = &self.progress;
+            let chunk_no = self.next_chunk_no;
+            let opened_or_not_found = read_whole_file(&mut self.from, path_to_open, read_buf, |data| {
+                    if let Some(progress) = progress {
+                        progress.set_chunk(chunk_no);
+                        progress.add_in(data.len());
+                    }
+                    to.add(data).map_err(|e| format!("target write error of {} bytes: {}", data.len(), e))
+                })
                 .map_err(|e| format!("could not read {} as chunk #{}: {}", path_to_open, self.next_chunk_no, e))?;
 
             if !opened_or_not_found {
                 if self.next_chunk_no == 0 { // first chunk must exist - otherwise it's a fatal error
                     return Err(format!("could find {} as first chunk", path_to_open));
+                } else if self.nr_chunks > 0 {
+                    return Err(format!("could not find {} as chunk #{} of {}", path_to_open, self.next_chunk_no, self.nr_chunks));
                 } else { // further chunk not found -> treat it as end of everything
                     break;
                 }
@@ -51,40 +153,441 @@ impl <'a, T: DataSink, R: MultiFilesReaderSource> Joiner<'a, T, R> {
             else {
                 self.next_chunk_no += 1;
             }
+        }
+        Ok(())
+    }
 
-            let mut eof = false;
+    fn read_known_chunks(&mut self, stats: &Stats, read_buf: &mut [u8]) -> Result<(), String> {
+        if stats.parity_group_len > 0 {
+            for group_no in 0..stats.chunks.len().div_ceil(stats.parity_group_len) {
+                self.read_group(stats, group_no, read_buf)?;
+            }
+        } else {
+            for chunk_no in 0..stats.chunks.len() {
+                self.read_known_chunk(stats, chunk_no, read_buf)?;
+            }
+        }
+        Ok(())
+    }
 
-            while !eof {
-                let mut left_for_buf = self.max_read_buf_size;
-                let mut buf_offs = 0;
+    fn read_known_chunk(&mut self, stats: &Stats, chunk_no: usize, read_buf: &mut [u8]) -> Result<(), String> {
+        let path = self.file_set.gen_file_path(chunk_no);
+        let expected = &stats.chunks[chunk_no];
+        let payload = payload_range(stats, chunk_no);
+        match self.on_damaged {
+            DamagedChunks::Fail => self.read_chunk(&path, chunk_no, expected, &payload, stats, read_buf),
+            DamagedChunks::FeedReadable => self.feed_readable(&path, chunk_no, expected, &payload, stats, read_buf),
+            DamagedChunks::ReportLost => match fetch_chunk(&mut self.from, &path, expected, stats.hash_seed, stats.archive_id, true, read_buf)? {
+                Ok(chunk) => self.pass_chunk(chunk_no, &chunk, &payload),
+                Err(e) => {
+                    eprintln!("chunk {} is lost: {}", path, e);
+                    self.report(chunk_no, expected.len);
+                    self.to.add_lost(payload.len())
+                }
+            }
+        }
+    }
 
-                while left_for_buf > 0 {
-                    let mut buf = &mut read_buf[buf_offs..];
-                    let bytes_read: usize = self.from.read_from_current_file(&mut buf)?;
-                    if bytes_read == 0 {
-                        eof = true;
-                        break; // exhausted current chunk, will move to the next (if any)
+    // every data chunk of a group is verified as it is read and passed to the target up to the first
+    // damaged one, so that healthy chunks are read only once; the rest of the group is passed
+    // after the damaged chunks are recovered from parity
+    fn read_group(&mut self, stats: &Stats, group_no: usize, read_buf: &mut [u8]) -> Result<(), String> {
+        let nr_data = stats.parity_group_len;
+        let first_chunk = group_no * nr_data;
+        let end_chunk = usize::min(first_chunk + nr_data, stats.chunks.len());
+        let mut healthy: Vec<usize> = Vec::new();
+        let mut damaged: Vec<usize> = Vec::new();
+        for chunk_no in first_chunk..end_chunk {
+            let path = self.file_set.gen_file_path(chunk_no);
+            let keep = damaged.is_empty();
+            match fetch_chunk(&mut self.from, &path, &stats.chunks[chunk_no], stats.hash_seed, stats.archive_id, keep, read_buf)? {
+                Ok(chunk) => {
+                    if damaged.is_empty() {
+                        self.pass_chunk(chunk_no, &chunk, &payload_range(stats, chunk_no))?;
                     }
-                    left_for_buf -= bytes_read;
-                    buf_offs += bytes_read;
+                    healthy.push(chunk_no - first_chunk);
+                },
+                Err(e) => {
+                    eprintln!("chunk {} is damaged: {}", path, e);
+                    damaged.push(chunk_no - first_chunk);
                 }
+            }
+        }
+        healthy.extend(end_chunk - first_chunk..nr_data); // absent tail of the last group, treated as empty chunks
+
+        let parity_present = check_parity_present(stats, group_no);
+        for shard in (nr_data..nr_data + stats.parity_nr_per_group).filter(|_| parity_present.is_ok()) {
+            let (path, chunk_no) = group_shard(&self.file_set, stats, group_no, shard);
+            match fetch_chunk(&mut self.from, &path, &stats.parity_chunks[chunk_no], stats.hash_seed, None, false, read_buf)? {
+                Ok(_) => healthy.push(shard),
+                Err(e) => eprintln!("chunk {} is damaged: {}", path, e)
+            }
+        }
+
+        let Some(&first_damaged) = damaged.first() else {
+            return Ok(());
+        };
+        let mut recovered = HashMap::new();
+        match parity_present.and_then(|()| recover_shards(&mut self.from, &self.file_set, stats, group_no, &healthy, damaged, read_buf)) {
+            Ok(chunks) => for chunk in chunks {
+                eprintln!("chunk {} is recovered from parity", chunk.path);
+                recovered.insert(chunk.chunk_no, chunk.data);
+            },
+            Err(e) if self.on_damaged != DamagedChunks::Fail => eprintln!("{}, salvaging what is left", e),
+            Err(e) => return Err(e)
+        }
+        // healthy chunks following a damaged one were not kept, so they are read once more
+        for chunk_no in first_chunk + first_damaged..end_chunk {
+            match recovered.remove(&chunk_no) {
+                Some(data) => self.pass_chunk(chunk_no, &data, &payload_range(stats, chunk_no))?,
+                None => self.read_known_chunk(stats, chunk_no, read_buf)?
+            }
+        }
+        Ok(())
+    }
 
-                if buf_offs > 0 {
-                    let _ = self.to.add(&read_buf[..buf_offs]).map_err(|e| format!("target write error of {} bytes: {}", buf_offs, e))?;
+    // `chunk` is the whole verified chunk, and `payload` is its part passed to the target
+    fn pass_chunk(&mut self, chunk_no: usize, chunk: &[u8], payload: &Range<usize>) -> Result<(), String> {
+        self.report(chunk_no, chunk.len());
+        for portion in chunk[payload.clone()].chunks(self.max_read_buf_size) {
+            self.to.add(portion).map_err(|e| format!("target write error of {} bytes: {}", portion.len(), e))?;
+        }
+        Ok(())
+    }
+
+    // `payload` is the part of chunk passed to the target
+    fn read_chunk(&mut self, path: &str, chunk_no: usize, expected: &ChunkInfo, payload: &Range<usize>, stats: &Stats, read_buf: &mut [u8]) -> Result<(), String> {
+        if self.from.nr_copies() > 1 {
+            return self.read_verified_chunk(path, chunk_no, expected, payload, stats, read_buf);
+        }
+        let mut check = ChunkCheck::new(stats.hash_seed, stats.archive_id);
+        let mut offset = 0;
+        let to = &mut self.to;
+        let progress = &self.progress;
+        let found = read_whole_file(&mut self.from, path, read_buf, |data| {
+                check.update(data);
+                if let Some(progress) = progress {
+                    progress.set_chunk(chunk_no);
+                    progress.add_in(data.len());
                 }
+                let portion = clip(payload, offset, data);
+                offset += data.len();
+                if portion.is_empty() {
+                    return Ok(());
+                }
+                to.add(portion).map_err(|e| format!("target write error of {} bytes: {}", portion.len(), e))
+            })
+            .map_err(|e| format!("could not read {} as chunk #{}: {}", path, chunk_no, e))?;
+        if !found {
+            return Err(format!("could not find {} as chunk #{}", path, chunk_no));
+        }
+        check.verify(expected).map_err(|e| format!("chunk {} is damaged: {}", path, e))
+    }
+
+    // the chunk is kept in memory until it is verified, so that a damaged one is read from another copy
+    // before anything is passed to the target
+    fn read_verified_chunk(&mut self, path: &str, chunk_no: usize, expected: &ChunkInfo, payload: &Range<usize>, stats: &Stats, read_buf: &mut [u8]) -> Result<(), String> {
+        loop {
+            let mut check = ChunkCheck::new(stats.hash_seed, stats.archive_id);
+            let mut chunk = Vec::with_capacity(expected.len);
+            let found = read_whole_file(&mut self.from, path, read_buf, |data| {
+                    check.update(data);
+                    chunk.extend_from_slice(data);
+                    Ok(())
+                })
+                .map_err(|e| format!("could not read {} as chunk #{}: {}", path, chunk_no, e))?;
+            if !found {
+                return Err(format!("could not find {} as chunk #{}", path, chunk_no));
+            }
+            match check.verify(expected) {
+                Ok(()) => return self.pass_chunk(chunk_no, &chunk, payload),
+                Err(e) if self.from.fail_over(path, &e)? => {},
+                Err(e) => { return Err(format!("chunk {} is damaged: {}", path, e)); }
             }
         }
+    }
 
-        self.to.finish().map_err(|e| format!("finalization error: {}", e))?;
+    fn feed_readable(&mut self, path: &str, chunk_no: usize, expected: &ChunkInfo, payload: &Range<usize>, stats: &Stats, read_buf: &mut [u8]) -> Result<(), String> {
+        let mut check = ChunkCheck::new(stats.hash_seed, stats.archive_id);
+        let mut offset = 0;
+        let mut fed = 0;
+        let mut target_err = None;
+        let to = &mut self.to;
+        let progress = &self.progress;
+        let res = read_whole_file(&mut self.from, path, read_buf, |data| {
+            check.update(data);
+            if let Some(progress) = progress {
+                progress.set_chunk(chunk_no);
+                progress.add_in(data.len());
+            }
+            let portion = clip(payload, offset, data);
+            offset += data.len();
+            fed += portion.len();
+            if portion.is_empty() {
+                return Ok(());
+            }
+            to.add(portion).map_err(|e| {
+                target_err = Some(format!("target write error of {} bytes: {}", portion.len(), e));
+                "target write error".to_owned()
+            })
+        });
+        if let Some(e) = target_err {
+            return Err(e);
+        }
+        match res.map(|found| found.then(|| check.verify(expected))) {
+            Ok(Some(Ok(()))) => return Ok(()),
+            Ok(Some(Err(e))) => eprintln!("chunk {} is damaged: {}", path, e),
+            Ok(None) => eprintln!("chunk {} is missing", path),
+            Err(e) => eprintln!("chunk {} is unreadable: {}", path, e)
+        }
+        let zeros = vec![0; usize::min(self.max_read_buf_size, payload.len() - fed)];
+        while fed < payload.len() {
+            let portion = &zeros[..usize::min(zeros.len(), payload.len() - fed)];
+            self.to.add(portion).map_err(|e| format!("target write error of {} bytes: {}", portion.len(), e))?;
+            fed += portion.len();
+        }
+        Ok(())
+    }
+}
+
+pub struct RecoveredChunk {
+    pub path: String,
+    pub chunk_no: usize,
+    pub is_parity: bool,
+    pub data: Vec<u8>
+}
+
+// checks all data and parity chunks of a group and recovers the damaged data ones, if possible;
+// damaged parity chunks are recovered too if `with_parity` is set
+pub fn recover_group<R: MultiFilesReaderSource>(
+    from: &mut R, file_set: &FileSet, stats: &Stats, group_no: usize, with_parity: bool, read_buf: &mut [u8]) -> Result<Vec<RecoveredChunk>, String>
+{
+    let nr_data = stats.parity_group_len;
+    let nr_parity = stats.parity_nr_per_group;
+    let nr_data_present = usize::min(nr_data, stats.chunks.len() - group_no * nr_data);
+    check_parity_present(stats, group_no)?;
+
+    let mut healthy: Vec<usize> = Vec::new();
+    let mut damaged: Vec<usize> = Vec::new();
+    for shard in 0..nr_data + nr_parity {
+        if shard >= nr_data_present && shard < nr_data {
+            healthy.push(shard); // absent tail of the last group, treated as empty chunks
+            continue;
+        }
+        let (path, chunk_no) = group_shard(file_set, stats, group_no, shard);
+        let (expected, archive_id) = if shard < nr_data {
+            (&stats.chunks[chunk_no], stats.archive_id)
+        } else {
+            (&stats.parity_chunks[chunk_no], None) // parity chunks have no header
+        };
+        match check_chunk(from, path.as_str(), expected, ChunkCheck::new(stats.hash_seed, archive_id), read_buf) {
+            Ok(()) => healthy.push(shard),
+            Err(e) => {
+                eprintln!("chunk {} is damaged: {}", path, e);
+                if shard < nr_data || with_parity {
+                    damaged.push(shard);
+                }
+            }
+        }
+    }
 
+    if damaged.is_empty() {
+        return Ok(Vec::new());
+    }
+    recover_shards(from, file_set, stats, group_no, &healthy, damaged, read_buf)
+}
+
+fn check_parity_present(stats: &Stats, group_no: usize) -> Result<(), String> {
+    if stats.parity_chunks.len() < (group_no + 1) * stats.parity_nr_per_group {
+        return Err(format!("metadata lacks parity chunks for group #{}", group_no));
+    }
+    Ok(())
+}
+
+// recovers `damaged` shards of a group from the first of its `healthy` ones, both in the order of shards
+fn recover_shards<R: MultiFilesReaderSource>(
+    from: &mut R, file_set: &FileSet, stats: &Stats, group_no: usize, healthy: &[usize], damaged: Vec<usize>, read_buf: &mut [u8]) -> Result<Vec<RecoveredChunk>, String>
+{
+    let nr_data = stats.parity_group_len;
+    let nr_parity = stats.parity_nr_per_group;
+    let nr_data_present = usize::min(nr_data, stats.chunks.len() - group_no * nr_data);
+    if healthy.len() < nr_data {
+        return Err(format!("too many damaged chunks in group #{}: only {} healthy chunks of {} needed to recover",
+            group_no, healthy.len(), nr_data));
+    }
+
+    let sources = &healthy[..nr_data];
+    let mut rec = ShardsCombiner::recoverer(nr_data, nr_parity, sources, &damaged)?;
+    for (src_idx, &shard) in sources.iter().enumerate() {
+        if shard >= nr_data_present && shard < nr_data {
+            continue; // empty chunk does not contribute anything
+        }
+        let (path, _) = group_shard(file_set, stats, group_no, shard);
+        let mut offset = 0;
+        let found = read_whole_file(from, path.as_str(), read_buf, |data| {
+            rec.add(src_idx, offset, data);
+            offset += data.len();
+            Ok(())
+        })?;
+        if !found {
+            return Err(format!("chunk {} disappeared during recovery", path));
+        }
+    }
+
+    let mut recovered = Vec::new();
+    for (shard, mut data) in damaged.into_iter().zip(rec.take()) {
+        let (path, chunk_no) = group_shard(file_set, stats, group_no, shard);
+        let is_parity = shard >= nr_data;
+        let expected = if is_parity { &stats.parity_chunks[chunk_no] } else { &stats.chunks[chunk_no] };
+        data.resize(expected.len, 0);
+        let mut hasher = ChunkHasher::new(stats.hash_seed);
+        hasher.update(&data);
+        if hasher.result() != *expected {
+            return Err(format!("could not recover chunk {}: checksum mismatch after recovery", path));
+        }
+        recovered.push(RecoveredChunk { path, chunk_no, is_parity, data });
+    }
+    Ok(recovered)
+}
+
+// path and number of data or parity chunk by its index in a group
+fn group_shard(file_set: &FileSet, stats: &Stats, group_no: usize, shard: usize) -> (String, usize) {
+    let nr_data = stats.parity_group_len;
+    if shard < nr_data {
+        let chunk_no = group_no * nr_data + shard;
+        (file_set.gen_file_path(chunk_no), chunk_no)
+    } else {
+        let chunk_no = group_no * stats.parity_nr_per_group + shard - nr_data;
+        (file_set.gen_parity_file_path(chunk_no), chunk_no)
+    }
+}
+
+// reads the whole file portion by portion into `consume`; returns false if the file does not exist
+pub fn read_whole_file<R, F>(from: &mut R, path: &str, read_buf: &mut [u8], mut consume: F) -> Result<bool, String>
+where
+    R: MultiFilesReaderSource,
+    F: FnMut(&[u8]) -> Result<(), String>
+{
+    if !from.open_next_file(path)? {
+        return Ok(false);
+    }
+
+    let mut eof = false;
+
+    while !eof {
+        let mut left_for_buf = read_buf.len();
+        let mut buf_offs = 0;
+
+        while left_for_buf > 0 {
+            let buf = &mut read_buf[buf_offs..];
+            let bytes_read: usize = match from.read_from_current_file(buf) {
+                Ok(b) => b,
+                Err(e) => {
+                    let _ = from.close_current_file();
+                    return Err(e);
+                }
+            };
+            if bytes_read == 0 {
+                eof = true;
+                break; // exhausted current chunk, will move to the next (if any)
+            }
+            left_for_buf -= bytes_read;
+            buf_offs += bytes_read;
+        }
+
+        if buf_offs > 0 {
+            if let Err(e) = consume(&read_buf[..buf_offs]) {
+                let _ = from.close_current_file();
+                return Err(e);
+            }
+        }
+    }
+
+    from.close_current_file()?;
+    Ok(true)
+}
+
+fn check_chunk<R: MultiFilesReaderSource>(from: &mut R, path: &str, expected: &ChunkInfo, mut check: ChunkCheck, read_buf: &mut [u8]) -> Result<(), String> {
+    let found = read_whole_file(from, path, read_buf, |data| {
+        check.update(data);
         Ok(())
+    })?;
+    if !found {
+        return Err("not found".to_owned());
+    }
+    check.verify(expected)
+}
+
+// reads a chunk from the next copy while it is damaged, and returns it whole if `keep` is set;
+// the inner error tells what is wrong with a chunk which is damaged in every copy
+fn fetch_chunk<R: MultiFilesReaderSource>(
+    from: &mut R, path: &str, expected: &ChunkInfo, hash_seed: u64, archive_id: Option<[u8; 16]>, keep: bool, read_buf: &mut [u8]) -> Result<Result<Vec<u8>, String>, String>
+{
+    loop {
+        let mut check = ChunkCheck::new(hash_seed, archive_id);
+        let mut chunk = Vec::new();
+        let problem = match read_whole_file(from, path, read_buf, |data| {
+                check.update(data);
+                if keep {
+                    chunk.extend_from_slice(data);
+                }
+                Ok(())
+            }) {
+            Ok(true) => match check.verify(expected) {
+                Ok(()) => return Ok(Ok(chunk)),
+                Err(e) => e
+            },
+            Ok(false) => "not found".to_owned(),
+            Err(e) => e
+        };
+        if !from.fail_over(path, &problem)? {
+            return Ok(Err(problem));
+        }
+    }
+}
+
+// verifies a chunk while it is read; if the archive id is known, a data chunk with a different one
+// in its header is told apart from a damaged one, e.g. a stale chunk left by another backup
+struct ChunkCheck {
+    hasher: ChunkHasher,
+    archive_id: Option<[u8; 16]>,
+    head: Vec<u8>
+}
+
+impl ChunkCheck {
+    fn new(hash_seed: u64, archive_id: Option<[u8; 16]>) -> Self {
+        Self { hasher: ChunkHasher::new(hash_seed), archive_id, head: Vec::new() }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        self.hasher.update(data);
+        if self.archive_id.is_some() && self.head.len() < ARCHIVE_ID_END {
+            let to_keep = usize::min(ARCHIVE_ID_END - self.head.len(), data.len());
+            self.head.extend_from_slice(&data[..to_keep]);
+        }
+    }
+
+    fn verify(self, expected: &ChunkInfo) -> Result<(), String> {
+        let actual = self.hasher.result();
+        if actual == *expected {
+            return Ok(());
+        }
+        if let Some(other) = archive_id_of(&self.head).filter(|id| Some(*id) != self.archive_id) {
+            return Err(format!("belongs to another archive {}", archive_id_as_string(&other)));
+        }
+        if actual.len != expected.len {
+            return Err(format!("length {} instead of {}", actual.len, expected.len));
+        }
+        Err("checksum mismatch".to_owned())
     }
 }
 
 #[cfg(test)]
 mod tests {
     use std::collections::{BTreeMap, HashSet};
-    use crate::{joiner::{Joiner, MultiFilesReaderSource}, finalizable::DataSink};
+    use crate::{joiner::{Joiner, DamagedChunks, MultiFilesReaderSource}, finalizable::DataSink};
+    use crate::{hasher::ChunkHasher, parity::ShardsCombiner, stats::{Stats, ChunkInfo}};
+    use crate::chunk_header::{ChunkHeader, HeaderKey, TRAILER_LEN};
     use rand::{thread_rng, Rng, RngCore};
 
     #[derive(Debug)]
@@ -108,7 +611,7 @@ mod tests {
                 Ok(true)
             }
             else {
-                return Ok(false);
+                Ok(false)
             }
         }
 
@@ -136,18 +639,34 @@ mod tests {
             *offs = None;
             Ok(())
         }
+    }
 
-        fn read_single_file(_: &str) -> Result<Vec<u8>, String> {
-            unimplemented!()
+    // counts how many times every file is opened
+    struct OpenCounter<'a> {
+        inner: TestReaderSource,
+        opened: &'a mut BTreeMap<String, usize>
+    }
+
+    impl MultiFilesReaderSource for OpenCounter<'_> {
+        fn open_next_file(&mut self, full_path: &str) -> Result<bool, String> {
+            *self.opened.entry(full_path.to_owned()).or_default() += 1;
+            self.inner.open_next_file(full_path)
+        }
+        fn read_from_current_file(&mut self, buf: &mut [u8]) -> Result<usize, String> {
+            self.inner.read_from_current_file(buf)
+        }
+        fn close_current_file(&mut self) -> Result<(), String> {
+            self.inner.close_current_file()
         }
     }
 
     struct TestReaderTarget {
-        data: Vec<u8>
+        data: Vec<u8>,
+        lost: Vec<(usize, usize)>
     }
 
     impl TestReaderTarget {
-        fn new() -> Self { Self { data: Vec::new() } }
+        fn new() -> Self { Self { data: Vec::new(), lost: Vec::new() } }
     }
 
     impl DataSink for TestReaderTarget {
@@ -158,6 +677,10 @@ mod tests {
         fn finish(&mut self) -> Result<(), String> {
             Ok(())
         }
+        fn add_lost(&mut self, len: usize) -> Result<(), String> {
+            self.lost.push((self.data.len(), len));
+            Ok(())
+        }
     }
 
     #[test]
@@ -185,8 +708,7 @@ mod tests {
             assert!(tr.open_next_file(fname).unwrap());
             for (requested_len, exp_data) in file_exp_data {
                 //eprintln!("requested len = {}, exp_data = {:?}", requested_len, exp_data);
-                let mut act_data: Vec<u8> = Vec::with_capacity(requested_len);
-                act_data.resize(requested_len, 0);
+                let mut act_data: Vec<u8> = vec![0; requested_len];
                 let bytes_act_read = tr.read_from_current_file(&mut act_data).unwrap();
                 assert_eq!(bytes_act_read, exp_data.len());
                 assert_eq!(act_data[..bytes_act_read], exp_data);
@@ -200,7 +722,7 @@ mod tests {
         { // error opening
             let src = TestReaderSource{ data: BTreeMap::new(), failed_files: HashSet::from(["failed_file".to_owned()]) };
             let mut dst = TestReaderTarget::new();
-            let mut j = Joiner::from_metadata(src, &mut dst, "file000.cfg", 3).unwrap();
+            let mut j = Joiner::from_metadata(src, &mut dst, "file000.cfg", None, 3).unwrap();
             let r = j.read_and_write_all();
             assert!(r.is_err());
             assert!(dst.data.is_empty());
@@ -208,7 +730,7 @@ mod tests {
         { // not found
             let src = TestReaderSource{ data: BTreeMap::new(), failed_files: HashSet::new() };
             let mut dst = TestReaderTarget::new();
-            let mut j = Joiner::from_metadata(src, &mut dst, "file000.cfg", 3).unwrap();
+            let mut j = Joiner::from_metadata(src, &mut dst, "file000.cfg", None, 3).unwrap();
             let r = j.read_and_write_all();
             assert!(r.is_err());
             assert!(dst.data.is_empty());
@@ -222,11 +744,56 @@ mod tests {
             ("f01".to_owned(), (vec![3], None)),
             ]), failed_files: HashSet::new() };
         let mut dst = TestReaderTarget::new();
-        let mut j = Joiner::from_metadata(src, &mut dst, "f00.cfg", 3).unwrap();
+        let mut j = Joiner::from_metadata(src, &mut dst, "f00.cfg", None, 3).unwrap();
         j.read_and_write_all().unwrap();
         assert_eq!(dst.data, vec![1,2,3]);
     }
 
+    #[test]
+    fn known_number_of_chunks() {
+        let stats = Stats { out_nr_chunks: 2, ..Default::default() };
+        let source = |names: &[&str]| TestReaderSource{
+            data: names.iter().map(|n| (n.to_string(), (vec![1], None))).collect(),
+            failed_files: HashSet::new() };
+
+        // stale chunk of a previous backup is not read
+        let mut dst = TestReaderTarget::new();
+        let mut j = Joiner::from_metadata(source(&["f00", "f01", "f02"]), &mut dst, "f00.cfg", Some(&stats), 3).unwrap();
+        j.read_and_write_all().unwrap();
+        assert_eq!(dst.data, vec![1,1]);
+
+        let mut dst = TestReaderTarget::new();
+        let mut j = Joiner::from_metadata(source(&["f00"]), &mut dst, "f00.cfg", Some(&stats), 3).unwrap();
+        let err = j.read_and_write_all().unwrap_err();
+        assert!(err.contains("chunk #1 of 2"), "{}", err);
+    }
+
+    #[test]
+    fn chunk_of_another_archive() {
+        let mut stats = Stats { alg: "none".to_owned(), archive_id: Some([1; 16]), ..Stats::new() };
+        let chunk = |n: usize, archive_id: [u8; 16]| {
+            let header = ChunkHeader { index: n, archive_id, ..ChunkHeader::from_stats(&stats) };
+            let mut chunk = header.encode(&HeaderKey::new(&None, &archive_id));
+            chunk.extend_from_slice(&[n as u8; 3]);
+            if n == 1 {
+                chunk.extend_from_slice(&[0; TRAILER_LEN]);
+            }
+            chunk
+        };
+        let chunks = [chunk(0, [1; 16]), chunk(1, [1; 16])];
+        let stale = chunk(1, [2; 16]);
+        stats.chunks = chunks.iter().map(|c| chunk_info(c)).collect();
+
+        let src = TestReaderSource{ data: BTreeMap::from([
+            ("f00".to_owned(), (chunks[0].clone(), None)),
+            ("f01".to_owned(), (stale, None)),
+            ]), failed_files: HashSet::new() };
+        let mut dst = TestReaderTarget::new();
+        let mut j = Joiner::from_metadata(src, &mut dst, "f00.cfg", Some(&stats), 3).unwrap();
+        let err = j.read_and_write_all().unwrap_err();
+        assert!(err.contains("f01 is damaged: belongs to another archive 02020202-"), "{}", err);
+    }
+
     #[test]
     fn two_big_chunks_ok() {
         let src = TestReaderSource{ data: BTreeMap::from([
@@ -234,7 +801,7 @@ mod tests {
             ("f01".to_owned(), (vec![6,7,8,9], None)),
             ]), failed_files: HashSet::new() };
         let mut dst = TestReaderTarget::new();
-        let mut j = Joiner::from_metadata(src, &mut dst, "f00.cfg", 3).unwrap();
+        let mut j = Joiner::from_metadata(src, &mut dst, "f00.cfg", None, 3).unwrap();
         j.read_and_write_all().unwrap();
         assert_eq!(dst.data, vec![1,2,3,4,5,6,7,8,9]);
     }
@@ -246,13 +813,12 @@ mod tests {
             ("f01".to_owned(), (vec![3], None)),
             ]), failed_files: HashSet::from(["f02".to_owned()]) };
         let mut dst = TestReaderTarget::new();
-        let mut j = Joiner::from_metadata(src, &mut dst, "f00.cfg", 3).unwrap();
+        let mut j = Joiner::from_metadata(src, &mut dst, "f00.cfg", None, 3).unwrap();
         j.read_and_write_all().unwrap_err();
     }
 
     fn random_chunks(src_len: usize, chunk_max_len: usize, max_read: usize) {
-        let mut src_stream: Vec<u8> = Vec::with_capacity(src_len);
-        src_stream.resize(src_len, 0);
+        let mut src_stream: Vec<u8> = vec![0; src_len];
         thread_rng().fill_bytes(&mut src_stream);
 
         let mut left_from_src = src_stream.len();
@@ -280,7 +846,7 @@ mod tests {
 
         let mut target = TestReaderTarget::new();
         let mut j = Joiner::from_metadata(
-            src, &mut target, "f000000000.cfg", max_read).unwrap();
+            src, &mut target, "f000000000.cfg", None, max_read).unwrap();
         j.read_and_write_all().unwrap();
         assert_eq!(target.data, src_stream);
     }
@@ -294,4 +860,145 @@ mod tests {
         }
     }
 
+
+    fn chunk_info(data: &[u8]) -> ChunkInfo {
+        let mut h = ChunkHasher::new(0);
+        h.update(data);
+        h.result()
+    }
+
+    // data chunks of 3 bytes, groups of 2 data + 2 parity chunks
+    fn source_with_parity(data: &[u8]) -> (TestReaderSource, Stats) {
+        let mut src = TestReaderSource{ data: BTreeMap::new(), failed_files: HashSet::new() };
+        let mut stats = Stats { parity_group_len: 2, parity_nr_per_group: 2, ..Default::default() };
+        for (group_no, group) in data.chunks(6).enumerate() {
+            let mut enc = ShardsCombiner::encoder(2, 2).unwrap();
+            for (i, chunk) in group.chunks(3).enumerate() {
+                src.data.insert(format!("f{:02}", group_no * 2 + i), (chunk.to_vec(), None));
+                stats.chunks.push(chunk_info(chunk));
+                enc.add(i, 0, chunk);
+            }
+            for (i, parity) in enc.take().into_iter().enumerate() {
+                src.data.insert(format!("f{:02}.par", group_no * 2 + i), (parity.clone(), None));
+                stats.parity_chunks.push(chunk_info(&parity));
+            }
+        }
+        (src, stats)
+    }
+
+    #[test]
+    fn recover_from_parity() {
+        let data: Vec<u8> = (1..=15).collect();
+        let (mut src, stats) = source_with_parity(&data);
+        src.data.remove("f00"); // lost
+        src.data.get_mut("f01").unwrap().0[1] = 0; // corrupted
+        src.data.get_mut("f02").unwrap().0.push(0); // wrong length
+        src.data.remove("f03.par");
+        src.failed_files.insert("f04".to_owned()); // unreadable, last group has only one data chunk
+        let mut dst = TestReaderTarget::new();
+        let mut j = Joiner::from_metadata(src, &mut dst, "f00.cfg", Some(&stats), 2).unwrap();
+        j.read_and_write_all().unwrap();
+        assert_eq!(dst.data, data);
+    }
+
+    #[test]
+    fn chunks_are_read_once() {
+        let data: Vec<u8> = (1..=15).collect();
+        let (src, stats) = source_with_parity(&data);
+        let mut opened = BTreeMap::new();
+        let mut dst = TestReaderTarget::new();
+        let mut j = Joiner::from_metadata(OpenCounter { inner: src, opened: &mut opened }, &mut dst, "f00.cfg", Some(&stats), 2).unwrap();
+        j.read_and_write_all().unwrap();
+        assert_eq!(dst.data, data);
+        assert_eq!(opened.len(), 11);
+        assert!(opened.values().all(|&n| n == 1), "{:?}", opened);
+
+        let stats = Stats { chunks: vec![chunk_info(&[1,2]), chunk_info(&[3])], ..Default::default() };
+        let src = TestReaderSource{ data: BTreeMap::from([
+            ("f00".to_owned(), (vec![1,2], None)),
+            ("f01".to_owned(), (vec![4], None)),
+            ]), failed_files: HashSet::new() };
+        let mut opened = BTreeMap::new();
+        let mut dst = TestReaderTarget::new();
+        let mut j = Joiner::from_metadata(OpenCounter { inner: src, opened: &mut opened }, &mut dst, "f00.cfg", Some(&stats), 3).unwrap();
+        j.on_damaged_chunks(DamagedChunks::ReportLost);
+        j.read_and_write_all().unwrap();
+        assert_eq!(dst.data, vec![1,2]);
+        assert_eq!(dst.lost, vec![(2, 1)]);
+        assert_eq!(opened, BTreeMap::from([("f00".to_owned(), 1), ("f01".to_owned(), 1)]));
+    }
+
+    #[test]
+    fn too_many_damaged_for_parity() {
+        let data: Vec<u8> = (1..=12).collect();
+        let (mut src, stats) = source_with_parity(&data);
+        src.data.remove("f02");
+        src.data.remove("f02.par");
+        src.data.get_mut("f03.par").unwrap().0[0] ^= 1;
+        let mut dst = TestReaderTarget::new();
+        let mut j = Joiner::from_metadata(src, &mut dst, "f00.cfg", Some(&stats), 2).unwrap();
+        j.read_and_write_all().unwrap_err();
+    }
+
+    #[test]
+    fn checksum_mismatch_without_parity() {
+        let stats = Stats { chunks: vec![chunk_info(&[1,2]), chunk_info(&[3])], ..Default::default() };
+        let src = TestReaderSource{ data: BTreeMap::from([
+            ("f00".to_owned(), (vec![1,2], None)),
+            ("f01".to_owned(), (vec![4], None)),
+            ]), failed_files: HashSet::new() };
+        let mut dst = TestReaderTarget::new();
+        let mut j = Joiner::from_metadata(src, &mut dst, "f00.cfg", Some(&stats), 3).unwrap();
+        j.read_and_write_all().unwrap_err();
+
+        // stale chunk beyond the known number of chunks is ignored
+        let src = TestReaderSource{ data: BTreeMap::from([
+            ("f00".to_owned(), (vec![1,2], None)),
+            ("f01".to_owned(), (vec![3], None)),
+            ("f02".to_owned(), (vec![5], None)),
+            ]), failed_files: HashSet::new() };
+        let mut dst = TestReaderTarget::new();
+        let mut j = Joiner::from_metadata(src, &mut dst, "f00.cfg", Some(&stats), 3).unwrap();
+        j.read_and_write_all().unwrap();
+        assert_eq!(dst.data, vec![1,2,3]);
+    }
+
+    #[test]
+    fn salvage_damaged_chunks() {
+        let stats = Stats { chunks: vec![chunk_info(&[1,2,3]), chunk_info(&[4,5,6]), chunk_info(&[7,8,9]), chunk_info(&[10])], ..Default::default() };
+        let source = || TestReaderSource{ data: BTreeMap::from([
+            ("f00".to_owned(), (vec![1,2,3], None)),
+            ("f01".to_owned(), (vec![4,0,6], None)), // corrupted
+            ("f02".to_owned(), (vec![7], None)), // truncated
+            ]), failed_files: HashSet::new() }; // last one is lost
+
+        let mut dst = TestReaderTarget::new();
+        let mut j = Joiner::from_metadata(source(), &mut dst, "f00.cfg", Some(&stats), 2).unwrap();
+        j.on_damaged_chunks(DamagedChunks::FeedReadable);
+        j.read_and_write_all().unwrap();
+        assert_eq!(dst.data, vec![1,2,3,4,0,6,7,0,0,0]);
+        assert!(dst.lost.is_empty());
+
+        let mut dst = TestReaderTarget::new();
+        let mut j = Joiner::from_metadata(source(), &mut dst, "f00.cfg", Some(&stats), 2).unwrap();
+        j.on_damaged_chunks(DamagedChunks::ReportLost);
+        j.read_and_write_all().unwrap();
+        assert_eq!(dst.data, vec![1,2,3]);
+        assert_eq!(dst.lost, vec![(3, 3), (3, 3), (3, 1)]);
+    }
+
+    #[test]
+    fn salvage_beyond_parity() {
+        let data: Vec<u8> = (1..=12).collect();
+        let (mut src, stats) = source_with_parity(&data);
+        src.data.remove("f00");
+        src.data.remove("f00.par");
+        src.data.remove("f01.par");
+        let mut dst = TestReaderTarget::new();
+        let mut j = Joiner::from_metadata(src, &mut dst, "f00.cfg", Some(&stats), 2).unwrap();
+        j.on_damaged_chunks(DamagedChunks::ReportLost);
+        j.read_and_write_all().unwrap();
+        assert_eq!(dst.data, data[3..]);
+        assert_eq!(dst.lost, vec![(0, 3)]);
+    }
 }
@@ -1,20 +1,20 @@
 mod hasher;
-use hasher::DataHasher;
+use hasher::{DataHasher, ChunkHasher};
 
 pub mod finalizable;
 use finalizable::DataSink;
 
 mod enc_dec;
-use enc_dec::{Encryptor, Decryptor, EncDecAlg};
+use enc_dec::{Encryptor, Decryptor, BlockDecryptor, EncDecAlg};
 
 mod comp_decomp_2;
-use comp_decomp_2::{Compressor2, Decompressor2};
+use comp_decomp_2::{Compressor2, Decompressor2, compressed_len};
 
 mod fixed_size_writer;
 use fixed_size_writer::FixedSizeWriter;
 
 mod joiner;
-use joiner::Joiner;
+use joiner::{Joiner, DamagedChunks, recover_group, read_whole_file};
 
 mod multi_files_reader;
 use multi_files_reader::MultiFilesReader;
@@ -22,26 +22,78 @@ use multi_files_reader::MultiFilesReader;
 mod buffered_reader;
 use buffered_reader::BufferedReader;
 
-mod stats;
-use stats::Stats;
+pub mod stats;
+use stats::{Stats, BackupInfo, Label, labels_as_string, FORMAT_VERSION};
 
 mod multi_files_writer;
 use multi_files_writer::MultiFilesWriter;
 
 mod splitter;
-use splitter::Splitter;
+use splitter::{Splitter, MultiFilesWriterTarget};
+use joiner::MultiFilesReaderSource;
 
 pub mod arg_opts;
 pub mod file_set;
+use file_set::{FileSet, cfg_from_pattern};
 
-mod free_space;
-use free_space::get_free_space;
+pub mod free_space;
+use free_space::{get_free_space, SpaceCheck};
 
-use std::time::{SystemTime, UNIX_EPOCH};
+mod parity;
+
+pub mod progress;
+
+pub mod catalog;
+
+pub mod prune;
+
+mod content_chunker;
+
+pub mod repository;
+use progress::{Progress, Phase, CountingSink};
+
+mod chunk_header;
+use chunk_header::{ChunkHeader, HeaderKey, ArchiveTotals, TRAILER_LEN, MAX_HEADER_LEN, MAX_AUTH_LEN, new_archive_id, archive_id_of, labels_mac, verify_labels};
+
+mod salvage;
+use salvage::{SalvageDecompressor, LostDataFiller, read_layout};
+
+mod cmd_files_writer;
+use cmd_files_writer::CmdFilesWriter;
+
+mod mirror_writer;
+use mirror_writer::MirrorWriter;
+
+mod failover_reader;
+use failover_reader::FailoverReader;
+
+pub mod throttle;
+use throttle::{ThrottleConfig, ThrottledWriter, ThrottledReader};
+
+pub mod io_retry;
+use io_retry::{RetryPolicy, RetryingWriter, RetryingReader};
+
+mod cmd_files_reader;
+use cmd_files_reader::CmdFilesReader;
+
+pub mod s3;
+use s3::{S3Config, S3Writer, S3Reader};
+
+pub mod sftp;
+use sftp::{SftpConfig, SftpWriter, SftpReader};
+
+pub mod webdav;
+use webdav::{WebDavConfig, WebDavWriter, WebDavReader};
+
+pub mod chunk_hooks;
+use chunk_hooks::{PostChunkHook, FetchHook, FetchingReader};
+
+use std::time::{SystemTime, UNIX_EPOCH, Instant};
 use std::io::Read;
-use time::OffsetDateTime;
-use std::sync::{Arc, atomic::AtomicBool};
+use time::{OffsetDateTime, UtcOffset};
+use std::sync::{Arc, OnceLock, atomic::AtomicBool};
 use std::fs::File;
+use std::path::Path;
 use arg_opts::Alg;
 
 pub fn timestamp() -> u64 {
@@ -51,13 +103,38 @@ pub fn timestamp() -> u64 {
         .as_secs()
 }
 
+// the local offset can be determined only while the process has a single thread, so the binary
+// sets it before starting any; otherwise it is determined on first use, falling back to UTC
+static LOCAL_OFFSET: OnceLock<UtcOffset> = OnceLock::new();
+
+pub fn set_local_offset(offset: UtcOffset) {
+    let _ = LOCAL_OFFSET.set(offset);
+}
+
+pub fn local_offset() -> UtcOffset {
+    *LOCAL_OFFSET.get_or_init(|| UtcOffset::current_local_offset().unwrap_or(UtcOffset::UTC))
+}
+
+pub fn local_now() -> OffsetDateTime {
+    OffsetDateTime::now_utc().to_offset(local_offset())
+}
+
 fn time_str() -> String {
-    let now = OffsetDateTime::now_local().unwrap_or(OffsetDateTime::now_utc());
+    time_str_at(local_now())
+}
+
+fn time_str_at(now: OffsetDateTime) -> String {
     let dt = now.date();
     let tm = now.time();
     format!("{}-{:02}-{:02} {:02}:{:02}:{:02} Z{:02}", dt.year(), dt.month() as u8, dt.day(), tm.hour(), tm.minute(), tm.second(), now.offset().whole_hours())
 }
 
+// unix time in local time zone
+fn time_str_of(unix_time: u64) -> Result<String, String> {
+    let t = OffsetDateTime::from_unix_timestamp(unix_time as i64).map_err(|e| format!("invalid time {}: {}", unix_time, e))?;
+    Ok(time_str_at(t.to_offset(local_offset())))
+}
+
 pub struct EncParams {
     pub alg: Alg,
     pub auth_msg: String,
@@ -65,19 +142,220 @@ pub struct EncParams {
     pub pass: String
 }
 
-pub fn backup<R: Read>(
-    mut read_from: R, 
-    opt_enc: &Option<EncParams>,
-    split_size_bytes: usize, out_template: &str, 
-    compress_level: u8, nr_threads: usize, buf_size_bytes: usize, exit_flag: Option<Arc<AtomicBool>>) -> Result<usize, String>
+// the auth string is stored in the header of every chunk, so it must fit one
+pub fn check_auth_len(auth: &str) -> Result<(), String> {
+    if auth.len() > MAX_AUTH_LEN {
+        return Err(format!("auth string of {} bytes is too long for chunk headers, at most {} bytes fit", auth.len(), MAX_AUTH_LEN));
+    }
+    Ok(())
+}
+
+pub struct ParityParams {
+    pub data_chunks: usize,
+    pub parity_chunks: usize
+}
+
+// where chunks are written to and read from; metadata file is kept locally unless it is in S3, on SFTP host or WebDAV server
+#[derive(Clone)]
+pub enum Storage {
+    Files,
+    Command(String), // shell command which receives a chunk in stdin on backup, or prints it on restore
+    Fetched(FetchHook), // local files fetched on demand, only for restore
+    S3(S3Config), // objects in S3-compatible storage, including metadata
+    Sftp(SftpConfig), // files on remote host accessed over SFTP, including metadata
+    WebDav(WebDavConfig), // files on WebDAV server, including metadata
+    Failover(Vec<(String, Storage)>), // metadata paths and storages of several copies of the same archive, only for reading
+    Throttled(Box<Storage>, ThrottleConfig), // any other storage with limited rate of chunk data transfer
+    Retried(Box<Storage>, RetryPolicy) // local files with operations repeated after transient errors
+}
+
+impl Storage {
+    // applies rate limits to every underlying storage, if there are any limits
+    pub fn throttled(self, cfg: &ThrottleConfig) -> Storage {
+        match self {
+            _ if cfg.write_rate.is_none() && cfg.read_rate.is_none() => self,
+            Storage::Failover(copies) => Storage::Failover(copies.into_iter().map(|(path, st)| (path, st.throttled(cfg))).collect()),
+            Storage::Throttled(inner, _) => Storage::Throttled(inner, cfg.clone()),
+            _ => Storage::Throttled(Box::new(self), cfg.clone())
+        }
+    }
+
+    // repeats failed operations on local files; remote storages retry on their own
+    pub fn with_retries(self, policy: &RetryPolicy) -> Storage {
+        match self {
+            Storage::Files | Storage::Retried(_, _) => Storage::Retried(Box::new(Storage::Files), policy.clone()),
+            Storage::Failover(copies) => Storage::Failover(copies.into_iter().map(|(path, st)| (path, st.with_retries(policy))).collect()),
+            Storage::Throttled(inner, cfg) => Storage::Throttled(Box::new(inner.with_retries(policy)), cfg),
+            _ => self
+        }
+    }
+
+    fn underlying(&self) -> &Storage {
+        match self {
+            Storage::Throttled(inner, _) | Storage::Retried(inner, _) => inner.underlying(),
+            _ => self
+        }
+    }
+}
+
+// additional destinations of a backup, each one gets all chunks and metadata
+pub struct Mirrors {
+    pub destinations: Vec<(String, Storage)>, // output template and storage
+    pub min_copies: usize // how many copies including the main one must be written for backup to succeed
+}
+
+// {name} is the file name of a chunk, which repeats in every directory when chunks are spread over directories
+pub fn check_chunk_names(storage: &Storage, sharded: bool) -> Result<(), String> {
+    match storage.underlying() {
+        Storage::Command(cmd) if sharded && cmd.contains("{name}") => {
+            Err(format!("{{name}} in chunk command '{}' is not unique when chunks are spread over directories, use {{path}} instead", cmd))
+        },
+        _ => Ok(())
+    }
+}
+
+fn chunk_writer(storage: &Storage) -> Result<Box<dyn MultiFilesWriterTarget>, String> {
+    match storage {
+        Storage::Files => Ok(Box::new(MultiFilesWriter::new())),
+        Storage::Command(cmd) => Ok(Box::new(CmdFilesWriter::new(cmd))),
+        Storage::Fetched(_) => Err("chunks can be fetched only for reading".to_owned()),
+        Storage::Failover(_) => Err("several copies can be used only for reading".to_owned()),
+        Storage::Throttled(inner, cfg) => Ok(Box::new(ThrottledWriter::new(chunk_writer(inner)?, cfg))),
+        Storage::Retried(inner, policy) => Ok(Box::new(RetryingWriter::new(chunk_writer(inner)?, policy))),
+        Storage::S3(cfg) => Ok(Box::new(S3Writer::new(cfg))),
+        Storage::Sftp(cfg) => Ok(Box::new(SftpWriter::new(cfg))),
+        Storage::WebDav(cfg) => Ok(Box::new(WebDavWriter::new(cfg)))
+    }
+}
+
+fn chunk_reader(storage: &Storage, cfg_path: &str, stats: &Stats) -> Result<Box<dyn MultiFilesReaderSource>, String> {
+    match storage {
+        Storage::Files => Ok(Box::new(MultiFilesReader::new())),
+        Storage::Command(cmd) => {
+            check_chunk_names(storage, stats.chunk_pattern.is_some())?;
+            Ok(Box::new(CmdFilesReader::new(cmd)))
+        },
+        Storage::Fetched(hook) => Ok(Box::new(FetchingReader::new(hook, &FileSet::from_cfg(cfg_path, stats.chunk_pattern.as_deref())?, stats)?)),
+        Storage::S3(cfg) => Ok(Box::new(S3Reader::new(cfg))),
+        Storage::Sftp(cfg) => Ok(Box::new(SftpReader::new(cfg))),
+        Storage::WebDav(cfg) => Ok(Box::new(WebDavReader::new(cfg))),
+        Storage::Failover(copies) => {
+            let readers = copies.iter()
+                .map(|(cfg_path, storage)| Ok((cfg_path.clone(), chunk_reader(storage, cfg_path, stats)?)))
+                .collect::<Result<Vec<_>, String>>()?;
+            Ok(Box::new(FailoverReader::new(readers, stats)?))
+        },
+        Storage::Throttled(inner, cfg) => Ok(Box::new(ThrottledReader::new(chunk_reader(inner, cfg_path, stats)?, cfg))),
+        Storage::Retried(inner, policy) => Ok(Box::new(RetryingReader::new(chunk_reader(inner, cfg_path, stats)?, policy)))
+    }
+}
+
+fn read_stats(cfg_path: &str, storage: &Storage) -> Result<Stats, String> {
+    match storage {
+        Storage::S3(cfg) => {
+            let contents = S3Reader::new(cfg).read_object(cfg_path)?
+                .ok_or(format!("could not find metadata object '{}'", cfg_path))?;
+            Stats::from_readable(&contents[..])
+        },
+        Storage::Sftp(cfg) => {
+            let contents = SftpReader::new(cfg).read_object(cfg_path)?
+                .ok_or(format!("could not find metadata file '{}'", cfg_path))?;
+            Stats::from_readable(&contents[..])
+        },
+        Storage::WebDav(cfg) => {
+            let contents = WebDavReader::new(cfg).read_object(cfg_path)?
+                .ok_or(format!("could not find metadata file '{}'", cfg_path))?;
+            Stats::from_readable(&contents[..])
+        },
+        Storage::Failover(copies) => {
+            // metadata is the same in every copy, so the first readable one is used
+            let mut errors = Vec::new();
+            for (copy_cfg_path, storage) in copies {
+                match read_stats(copy_cfg_path, storage) {
+                    Ok(stats) => { return Ok(stats); },
+                    Err(e) => {
+                        eprintln!("could not read metadata of copy {}: {}", copy_cfg_path, e);
+                        errors.push(e);
+                    }
+                }
+            }
+            Err(format!("could not read metadata of any copy: {}", errors.join("; ")))
+        },
+        Storage::Throttled(inner, _) | Storage::Retried(inner, _) => read_stats(cfg_path, inner),
+        _ => Stats::from_readable(File::open(cfg_path)
+            .map_err(|e| format!("could not open metadata file '{}': {}", cfg_path, e))?)
+    }
+}
+
+const SPACE_SAMPLE_LEN: usize = 1_048_576;
+
+// fails if the archive estimated from compression ratio of a sample does not fit, unless chunks are removed meanwhile
+fn check_archive_fits(space: &SpaceCheck, sample: &[u8], input_len: usize, compress_level: u8, opt_parity: &Option<ParityParams>, chunks_removed: bool) -> Result<(), String> {
+    if sample.is_empty() {
+        return Ok(());
+    }
+    let ratio = compressed_len(sample, compress_level as u32)? as f64 / sample.len() as f64;
+    let mut estimate = input_len as f64 * ratio;
+    if let Some(parity) = opt_parity {
+        estimate += estimate * parity.parity_chunks as f64 / parity.data_chunks as f64;
+    }
+    let estimate = estimate as usize;
+    let free = get_free_space(&space.path)?;
+    eprintln!("archive is estimated to take {} bytes (compression ratio of a sample is {:.3}), {} bytes are free on {}",
+        estimate, ratio, free, space.path);
+    if estimate + space.margin <= free {
+        Ok(())
+    } else if space.poll.is_some() || chunks_removed {
+        eprintln!("archive may not fit, relying on chunks being removed during backup");
+        Ok(())
+    } else {
+        Err(format!("archive is estimated to take {} bytes while only {} are free on {} and {} are to be left free",
+            estimate, free, space.path, space.margin))
+    }
+}
+
+// optional parts of a backup and its tuning
+pub struct BackupOptions {
+    pub enc: Option<EncParams>,
+    pub parity: Option<ParityParams>,
+    pub post_chunk_hook: Option<PostChunkHook>,
+    pub mirrors: Option<Mirrors>,
+    pub space: Option<SpaceCheck>,
+    pub compress_level: u8,
+    pub nr_threads: usize,
+    pub buf_size_bytes: usize,
+    pub exit_flag: Option<Arc<AtomicBool>>, // stops reading input when set
+    pub labels: Vec<Label>,
+    pub progress: Option<Progress>
+}
+
+impl Default for BackupOptions {
+    fn default() -> Self {
+        Self {
+            enc: None,
+            parity: None,
+            post_chunk_hook: None,
+            mirrors: None,
+            space: None,
+            compress_level: 6,
+            nr_threads: 1,
+            buf_size_bytes: 1_048_576,
+            exit_flag: None,
+            labels: Vec::new(),
+            progress: None
+        }
+    }
+}
+
+pub fn backup<R: Read>(mut read_from: R, split_size_bytes: usize, out_template: &str, storage: &Storage, opts: &BackupOptions) -> Result<usize, String>
 {
     let hash_seed = timestamp();
-    let start_time_str = time_str();
+    let start_time = Instant::now();
 
     let mut stats = Stats::new();
     let enc_alg;
 
-    ((enc_alg, stats.alg), stats.auth_string, stats.auth_chunk_size) = match opt_enc {
+    ((enc_alg, stats.alg), stats.auth_string, stats.auth_chunk_size) = match &opts.enc {
         Some(enc) => (
             match enc.alg {
                 Alg::None => {
@@ -91,22 +369,70 @@ pub fn backup<R: Read>(
         ),
         None => ((None, "none".to_owned()), String::new(), 0)
     };
+    check_auth_len(&stats.auth_string)?;
 
     stats.out_chunk_size = split_size_bytes;
     stats.hash_seed = hash_seed;
+    stats.chunk_pattern = FileSet::from_pattern(out_template)?.chunk_pattern();
+    let archive_id = new_archive_id();
+    stats.archive_id = Some(archive_id);
+    let header_key = HeaderKey::new(&opts.enc.as_ref().map(|enc| enc.pass.clone()), &archive_id);
+    stats.labels = opts.labels.clone();
+    if opts.enc.is_some() && !opts.labels.is_empty() {
+        stats.labels_mac = Some(labels_mac(&stats, &header_key));
+    }
+    if let Some(parity) = &opts.parity {
+        stats.parity_group_len = parity.data_chunks;
+        stats.parity_nr_per_group = parity.parity_chunks;
+    }
+
+    // the beginning of input is compressed up front to estimate whether the whole archive fits
+    let mut sample = Vec::new();
+    if let Some((space, input_len)) = opts.space.as_ref().and_then(|space| Some((space, space.input_len?))) {
+        read_from.by_ref().take(SPACE_SAMPLE_LEN as u64).read_to_end(&mut sample)
+            .map_err(|e| format!("could not read input data: {}", e))?;
+        check_archive_fits(space, &sample, input_len, opts.compress_level, &opts.parity, opts.post_chunk_hook.is_some())?;
+    }
+    let mut read_from = std::io::Cursor::new(sample).chain(read_from);
 
-    let mut fmgr = MultiFilesWriter::new();
-    let mut spl: Splitter<'_, MultiFilesWriter> = Splitter::from_pattern(&mut fmgr, split_size_bytes, out_template)?;
+    let mut destinations = vec![(out_template.to_owned(), storage.clone())];
+    let mut min_copies = 1;
+    if let Some(mirrors) = &opts.mirrors {
+        destinations.extend(mirrors.destinations.iter().cloned());
+        min_copies = mirrors.min_copies;
+    }
+    let writers = destinations.iter()
+        .map(|(tpl, storage)| {
+            check_chunk_names(storage, FileSet::from_pattern(tpl)?.chunk_pattern().is_some())?;
+            Ok((tpl.clone(), chunk_writer(storage)?))
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+    let mut fmgr = MirrorWriter::new(writers, min_copies)?;
+    let mut spl: Splitter<'_, MirrorWriter> = Splitter::from_pattern(&mut fmgr, split_size_bytes, out_template, hash_seed, &opts.parity)?;
+    if let Some(hook) = &opts.post_chunk_hook {
+        spl.set_post_chunk_hook(hook)?;
+    }
+    if let Some(space) = &opts.space {
+        spl.set_space_check(space);
+    }
+    spl.set_chunk_header(ChunkHeader::from_stats(&stats), header_key)?;
+    if let Some(progress) = &opts.progress {
+        spl.set_progress(progress);
+        progress.start(Phase::Backup, None);
+    }
 
-    if let Some(enc_params) = opt_enc {
-        let enc = Encryptor::new(&mut spl, &enc_alg.as_ref().unwrap(),&enc_params.pass, &enc_params.auth_msg);
+    if let Some(enc_params) = &opts.enc {
+        let enc = Encryptor::new(&mut spl, enc_alg.as_ref().unwrap(),&enc_params.pass, &enc_params.auth_msg);
         let mut fbuf = FixedSizeWriter::new(enc, enc_params.auth_every_bytes);
-        let mut comp = Compressor2::new(&mut fbuf, compress_level as u32, nr_threads as u32)?;
+        let mut comp = Compressor2::new(&mut fbuf, opts.compress_level as u32, opts.nr_threads as u32)?;
         {
             let mut hash_copier = DataHasher::with_writer(Some(&mut comp), hash_seed);
 
             let mut stdinbuf = BufferedReader::new(
-                &mut read_from, &mut hash_copier, buf_size_bytes / 8, buf_size_bytes, exit_flag);
+                &mut read_from, &mut hash_copier, opts.buf_size_bytes / 8, opts.buf_size_bytes, opts.exit_flag.clone());
+            if let Some(progress) = &opts.progress {
+                stdinbuf.set_progress(progress);
+            }
 
             stdinbuf.read_and_write_all()?;
 
@@ -116,12 +442,15 @@ pub fn backup<R: Read>(
         stats.compressed_len = comp.compressed();
     }
     else {
-        let mut comp = Compressor2::new(&mut spl, compress_level as u32, nr_threads as u32)?;
+        let mut comp = Compressor2::new(&mut spl, opts.compress_level as u32, opts.nr_threads as u32)?;
         {
             let mut hash_copier = DataHasher::with_writer(Some(&mut comp), hash_seed);
 
             let mut stdinbuf = BufferedReader::new(
-                &mut read_from, &mut hash_copier, buf_size_bytes / 8, buf_size_bytes, exit_flag);
+                &mut read_from, &mut hash_copier, opts.buf_size_bytes / 8, opts.buf_size_bytes, opts.exit_flag.clone());
+            if let Some(progress) = &opts.progress {
+                stdinbuf.set_progress(progress);
+            }
 
             stdinbuf.read_and_write_all()?;
 
@@ -131,77 +460,145 @@ pub fn backup<R: Read>(
         stats.compressed_len = comp.compressed();
     }
 
-    let end_timestamp = timestamp();
-    let end_time_str = time_str();
-    let throughput_mbps = if end_timestamp - hash_seed != 0 { stats.in_data_len as u64 / 1024 / 1024 / (end_timestamp - hash_seed) } else { 0 };
-    stats.misc_info = Some(format!("version={}, started={}, ended={}, took={}s, througput={}MB/s", 
-        option_env!("VERSION").unwrap_or("?"),
-        start_time_str, end_time_str, end_timestamp - hash_seed, throughput_mbps));
-
-    spl.write_metadata(&stats)?;
+    let duration_ms = start_time.elapsed().as_millis() as u64;
+    stats.backup_info = Some(BackupInfo {
+        tool_version: option_env!("VERSION").unwrap_or("?").to_owned(),
+        host: file_set::host_name().unwrap_or_default(),
+        started: hash_seed,
+        ended: timestamp(),
+        duration_ms,
+        throughput: (stats.in_data_len as u64 * 1000).checked_div(duration_ms).unwrap_or(0),
+        compress_level: opts.compress_level,
+        compress_threads: opts.nr_threads,
+        buf_size: opts.buf_size_bytes
+    });
+
+    spl.finish_archive(&ArchiveTotals {
+        nr_chunks: spl.nr_chunks(),
+        in_len: stats.in_data_len,
+        in_hash: stats.in_data_hash,
+        compressed_len: stats.compressed_len
+    })?;
+    stats.out_nr_chunks = spl.nr_chunks();
+    if let Some(progress) = &opts.progress {
+        progress.finish_phase();
+    }
+    (stats.chunks, stats.parity_chunks) = spl.chunks_info();
+    let mirrored = destinations.len() > 1;
+    fmgr.write_metadata(|copies| {
+        if mirrored {
+            stats.copies = copies.to_vec();
+        }
+        stats.as_string()
+    })?;
+    for ((tpl, storage), (_, written)) in destinations.iter().zip(fmgr.copies()) {
+        if written && matches!(storage.underlying(), Storage::Command(_)) {
+            MultiFilesWriter::new().write_single_file(&cfg_from_pattern(tpl), stats.as_string().as_bytes())?;
+        }
+    }
     Ok(stats.in_data_len)
 }
 
     
-pub fn check<W: DataSink>(mut write_to: Option<W>, cfg_path: &str, pass: &Option<String>, nr_threads: usize, buf_size_bytes: usize, check_free_space: &Option<&str>, show_info: bool) -> Result<(), String> {
-    let stats = Stats::from_readable(File::open(cfg_path)
-        .map_err(|e| format!("could not open metadata file '{}': {}", cfg_path, e))?)?;
-
-    let alg = match stats.alg.as_str() {
+fn alg_from_stats(stats: &Stats, pass: &Option<String>) -> Result<Option<EncDecAlg>, String> {
+    match stats.alg.as_str() {
         "none" => {
             if pass.is_some() {
                 return Err("restore of an unencrypted archive does not need a password".to_owned());
             }
-            None
+            Ok(None)
         },
         "aes128-gcm" => {            
             if pass.is_none() {
                 return Err("restore of an encrypted archive requires a password".to_owned());
             }
-            Some(EncDecAlg::Aes128Gcm)
+            Ok(Some(EncDecAlg::Aes128Gcm))
         },
         "chacha20-poly1305" => {            
             if pass.is_none() {
                 return Err("restore of an encrypted archive requires a password".to_owned());
             }
-            Some(EncDecAlg::Chacha20Poly1305)
+            Ok(Some(EncDecAlg::Chacha20Poly1305))
         },
-        x => {
-            return Err(format!("invalid encryption type in metadata: {}", x));
-        }
-    };
+        x => Err(format!("invalid encryption type in metadata: {}", x))
+    }
+}
+
+// how an archive is read by check, restore and salvage
+pub struct CheckOptions {
+    pub pass: Option<String>,
+    pub nr_threads: usize,
+    pub buf_size_bytes: usize,
+    pub check_free_space: Option<String>, // mount point or path on the filesystem data is restored to
+    pub show_info: bool, // print metadata of the archive to stderr
+    pub progress: Option<Progress>
+}
+
+impl Default for CheckOptions {
+    fn default() -> Self {
+        Self { pass: None, nr_threads: 1, buf_size_bytes: 1_048_576, check_free_space: None, show_info: false, progress: None }
+    }
+}
+
+pub fn check<W: DataSink>(mut write_to: Option<W>, cfg_path: &str, storage: &Storage, opts: &CheckOptions) -> Result<(), String>
+{
+    let stats = read_stats(cfg_path, storage)?;
+
+    let alg = alg_from_stats(&stats, &opts.pass)?;
 
-    if show_info {
+    if opts.show_info {
         eprintln!("authentication string: {}", stats.auth_string);
-        eprintln!("misc info: {}", stats.misc_info.as_ref().unwrap_or(&"none".to_owned()));
+        match &stats.backup_info {
+            Some(info) => {
+                let started = time_str_of(info.started).map_err(|e| format!("invalid start time of backup: {}", e))?;
+                eprintln!("made by bigarchiver {} on {} at {}, took {} ms", info.tool_version, info.host, started, info.duration_ms);
+            },
+            None => eprintln!("misc info: {}", stats.misc_info.as_ref().unwrap_or(&"none".to_owned()))
+        }
+        if !stats.labels.is_empty() {
+            eprintln!("labels: {}", labels_as_string(&stats.labels));
+        }
+        if stats.parity_group_len > 0 {
+            eprintln!("parity: {} chunks per each {} data chunks", stats.parity_nr_per_group, stats.parity_group_len);
+        }
     }
 
-    if let Some(mount_point) = check_free_space {
+    if let Some(mount_point) = &opts.check_free_space {
         if get_free_space(mount_point)? < stats.in_data_len {
             return Err(format!("filesystem of '{}' won't fit {} bytes of data to restore", mount_point, stats.in_data_len));
         }
     }
 
+    if let Some(progress) = &opts.progress {
+        progress.start(if write_to.is_some() { Phase::Restore } else { Phase::Verify }, chunks_len(&stats));
+    }
     let ref_write_to = write_to.as_mut();
 
     let mut hash_copier = DataHasher::with_writer(ref_write_to, stats.hash_seed);
     {
-        if alg.is_some() {
-            let mut decomp = Decompressor2::new(&mut hash_copier, nr_threads as u32)?;
-            let (dec, tag_size) = Decryptor::new(&mut decomp, &alg.as_ref().unwrap(), pass.as_ref().unwrap(), &stats.auth_string);
+        let mut counted = CountingSink::new(&mut hash_copier, opts.progress.clone());
+        if let Some(alg) = &alg {
+            let mut decomp = Decompressor2::new(&mut counted, opts.nr_threads as u32)?;
+            let (dec, tag_size) = Decryptor::new(&mut decomp, alg, opts.pass.as_ref().unwrap(), &stats.auth_string, false);
             let mut fbuf = FixedSizeWriter::new(dec, stats.auth_chunk_size + tag_size);
-            let fmgr = MultiFilesReader::new();
+            let fmgr = chunk_reader(storage, cfg_path, &stats)?;
 
             let mut joiner = Joiner::from_metadata(
-                fmgr, &mut fbuf, cfg_path, buf_size_bytes)?;
+                fmgr, &mut fbuf, cfg_path, Some(&stats), opts.buf_size_bytes)?;
+            if let Some(progress) = &opts.progress {
+                joiner.set_progress(progress);
+            }
             
             joiner.read_and_write_all()?;
         } else {
-            let mut decomp = Decompressor2::new(&mut hash_copier, nr_threads as u32)?;
-            let fmgr = MultiFilesReader::new();
+            let mut decomp = Decompressor2::new(&mut counted, opts.nr_threads as u32)?;
+            let fmgr = chunk_reader(storage, cfg_path, &stats)?;
 
             let mut joiner = Joiner::from_metadata(
-                fmgr, &mut decomp, cfg_path, buf_size_bytes)?;
+                fmgr, &mut decomp, cfg_path, Some(&stats), opts.buf_size_bytes)?;
+            if let Some(progress) = &opts.progress {
+                joiner.set_progress(progress);
+            }
             
             joiner.read_and_write_all()?;
         }
@@ -210,6 +607,267 @@ pub fn check<W: DataSink>(mut write_to: Option<W>, cfg_path: &str, pass: &Option
     if hash_copier.result() != stats.in_data_hash {
         Err("hash verification error".to_owned())
     } else {
+        if let Some(progress) = &opts.progress {
+            progress.finish_phase();
+        }
         Ok(())
     }
 }
+
+// total length of data chunks, if metadata lists them
+fn chunks_len(stats: &Stats) -> Option<u64> {
+    match stats.chunks.is_empty() {
+        true => None,
+        false => Some(stats.chunks.iter().map(|c| c.len as u64).sum())
+    }
+}
+
+// full metadata of an archive as JSON
+pub fn info(cfg_path: &str, storage: &Storage) -> Result<String, String> {
+    Ok(read_stats(cfg_path, storage)?.as_json().to_string())
+}
+
+// whether the archive has all labels of `filters`; labels of an encrypted archive are verified
+// when the password is given, so that an archive cannot be passed off as another one
+pub fn has_labels(cfg_path: &str, storage: &Storage, pass: &Option<String>, filters: &[Label]) -> Result<bool, String> {
+    let stats = read_stats(cfg_path, storage)?;
+    if pass.is_some() && stats.alg != "none" && !stats.labels.is_empty() {
+        verify_labels(&stats, &HeaderKey::new(pass, &stats.archive_id.unwrap_or_default()))?;
+    }
+    Ok(filters.iter().all(|f| f.matches(&stats.labels)))
+}
+
+// rebuilds missing or damaged data and parity chunks from the healthy ones, returns the number of rebuilt chunks
+pub fn repair(cfg_path: &str, storage: &Storage, buf_size_bytes: usize) -> Result<usize, String> {
+    let mut stats = read_stats(cfg_path, storage)?;
+
+    if stats.chunks.is_empty() {
+        return Err("metadata has no per-chunk checksums, damaged chunks cannot be detected".to_owned());
+    }
+    if stats.parity_group_len == 0 {
+        return Err("archive was created without parity chunks, nothing to rebuild damaged chunks from".to_owned());
+    }
+
+    let file_set = FileSet::from_cfg(cfg_path, stats.chunk_pattern.as_deref())?;
+    let mut fmgr = chunk_reader(storage, cfg_path, &stats)?;
+    let fwriter = chunk_writer(storage)?;
+    let mut read_buf: Vec<u8> = vec![0; buf_size_bytes];
+    let mut nr_rebuilt = 0;
+    let mut nr_failed_groups = 0;
+
+    for group_no in 0..stats.chunks.len().div_ceil(stats.parity_group_len) {
+        match recover_group(&mut fmgr, &file_set, &stats, group_no, true, &mut read_buf) {
+            Ok(recovered) => {
+                for chunk in recovered {
+                    fwriter.write_single_file(&chunk.path, &chunk.data)?;
+                    eprintln!("{} chunk {} is rebuilt", if chunk.is_parity { "parity" } else { "data" }, chunk.path);
+                    nr_rebuilt += 1;
+                }
+            },
+            Err(e) => {
+                eprintln!("could not repair group #{}: {}", group_no, e);
+                nr_failed_groups += 1;
+            }
+        }
+    }
+
+    if nr_rebuilt > 0 {
+        // earlier repairs are kept in the record
+        let record = format!("repaired={}, rebuilt={} chunk(s)", time_str(), nr_rebuilt);
+        stats.repair_info = Some(match stats.repair_info.take() {
+            Some(earlier) => format!("{}; {}", earlier, record),
+            None => record
+        });
+        fwriter.write_single_file(cfg_path, stats.as_string().as_bytes())?;
+    }
+
+    if nr_failed_groups > 0 {
+        Err(format!("{} group(s) of chunks could not be repaired, {} chunk(s) rebuilt", nr_failed_groups, nr_rebuilt))
+    } else {
+        Ok(nr_rebuilt)
+    }
+}
+
+// reads up to `len` bytes from the beginning of a file; None if there is no such file
+fn read_head<R: MultiFilesReaderSource>(from: &mut R, path: &str, len: usize) -> Result<Option<Vec<u8>>, String> {
+    if !from.open_next_file(path)? {
+        return Ok(None);
+    }
+    let mut head = vec![0; len];
+    let mut nr_read = 0;
+    while nr_read < len {
+        match from.read_from_current_file(&mut head[nr_read..]) {
+            Ok(0) => break,
+            Ok(n) => nr_read += n,
+            Err(e) => {
+                let _ = from.close_current_file();
+                return Err(e);
+            }
+        }
+    }
+    from.close_current_file()?;
+    head.truncate(nr_read);
+    Ok(Some(head))
+}
+
+// rebuilds lost metadata of an archive from headers of its chunks; returns path of the written metadata file,
+// which is kept next to the chunks in remote storages and locally otherwise
+pub fn recover_cfg(out_template: &str, storage: &Storage, pass: &Option<String>, buf_size_bytes: usize) -> Result<String, String> {
+    let file_set = FileSet::from_pattern(out_template)?;
+    let cfg_path = file_set.cfg_path();
+    let remote_cfg = matches!(storage.underlying(), Storage::S3(_) | Storage::Sftp(_) | Storage::WebDav(_));
+    let cfg_exists = match remote_cfg {
+        true => read_stats(&cfg_path, storage).is_ok(),
+        false => Path::new(&cfg_path).exists()
+    };
+    if cfg_exists {
+        return Err(format!("metadata file {} exists, move it away to recover it from chunks", cfg_path));
+    }
+
+    let mut fmgr = chunk_reader(storage, &cfg_path, &Stats { chunk_pattern: file_set.chunk_pattern(), ..Stats::new() })?;
+    let first_path = file_set.gen_file_path(0);
+    let head = read_head(&mut fmgr, &first_path, MAX_HEADER_LEN)?
+        .ok_or(format!("could not find {} as first chunk", first_path))?;
+    let archive_id = archive_id_of(&head).ok_or(format!("{} has no chunk header", first_path))?;
+    let key = HeaderKey::new(pass, &archive_id);
+    let first = ChunkHeader::decode(&head, &key).map_err(|e| format!("could not read header of {}: {}", first_path, e))?;
+    if first.format > FORMAT_VERSION {
+        return Err(format!("chunks are written in format version {} which is newer than {} supported by this release of bigarchiver, please upgrade it",
+            first.format, FORMAT_VERSION));
+    }
+
+    let mut read_buf: Vec<u8> = vec![0; buf_size_bytes];
+    let mut chunks = Vec::new();
+    let totals = loop {
+        let path = file_set.gen_file_path(chunks.len());
+        let mut hasher = ChunkHasher::new(first.hash_seed);
+        let mut head = Vec::new();
+        let mut tail = Vec::new();
+        let found = read_whole_file(&mut fmgr, &path, &mut read_buf, |data| {
+            hasher.update(data);
+            let head_left = first.encoded_len().saturating_sub(head.len());
+            head.extend_from_slice(&data[..usize::min(head_left, data.len())]);
+            tail.extend_from_slice(&data[data.len().saturating_sub(TRAILER_LEN)..]);
+            tail.drain(..tail.len().saturating_sub(TRAILER_LEN));
+            Ok(())
+        })?;
+        if !found {
+            return Err(format!("could not find {} as chunk #{}, while none of the previous chunks is the last one", path, chunks.len()));
+        }
+        let header = ChunkHeader::decode(&head, &key).map_err(|e| format!("could not read header of {}: {}", path, e))?;
+        if header.archive_id != first.archive_id {
+            return Err(format!("chunk {} belongs to another archive", path));
+        }
+        if header != (ChunkHeader { index: chunks.len(), ..first.clone() }) {
+            return Err(format!("header of chunk {} does not match the first chunk", path));
+        }
+        eprintln!("chunk {} is found", path);
+        chunks.push(hasher.result());
+        if let Some(totals) = ArchiveTotals::decode(&tail, &first.archive_id, &key).filter(|t| t.nr_chunks == chunks.len()) {
+            break totals;
+        }
+    };
+
+    let mut parity_chunks = Vec::new();
+    if first.parity_group > 0 {
+        for index in 0..chunks.len().div_ceil(first.parity_group) * first.parity_nr {
+            let path = file_set.gen_parity_file_path(index);
+            let mut hasher = ChunkHasher::new(first.hash_seed);
+            if !read_whole_file(&mut fmgr, &path, &mut read_buf, |data| { hasher.update(data); Ok(()) })? {
+                return Err(format!("could not find parity chunk {}", path));
+            }
+            parity_chunks.push(hasher.result());
+        }
+    }
+
+    let stats = Stats {
+        format_version: first.format,
+        in_data_len: totals.in_len,
+        in_data_hash: totals.in_hash,
+        hash_seed: first.hash_seed,
+        compressed_len: totals.compressed_len,
+        out_nr_chunks: totals.nr_chunks,
+        out_chunk_size: first.chunk_len,
+        alg: first.alg,
+        auth_string: first.auth,
+        auth_chunk_size: first.auth_len,
+        misc_info: Some(format!("recovered={}", time_str())),
+        chunks,
+        parity_group_len: first.parity_group,
+        parity_nr_per_group: first.parity_nr,
+        parity_chunks,
+        chunk_pattern: file_set.chunk_pattern(),
+        archive_id: Some(first.archive_id),
+        ..Stats::new()
+    };
+    match remote_cfg {
+        true => chunk_writer(storage)?.write_single_file(&cfg_path, stats.as_string().as_bytes())?,
+        false => MultiFilesWriter::new().write_single_file(&cfg_path, stats.as_string().as_bytes())?
+    }
+    Ok(cfg_path)
+}
+
+// best-effort restore of a damaged archive: data which cannot be decrypted or decompressed is written
+// as zeros (or skipped if `zero_fill` is not set); returns offsets and lengths of lost ranges of data
+pub fn salvage<W: DataSink>(mut write_to: W, cfg_path: &str, storage: &Storage, opts: &CheckOptions, zero_fill: bool) -> Result<Vec<(usize, usize)>, String>
+{
+    let stats = read_stats(cfg_path, storage)?;
+
+    let alg = alg_from_stats(&stats, &opts.pass)?;
+
+    if let Some(mount_point) = &opts.check_free_space {
+        if get_free_space(mount_point)? < stats.in_data_len {
+            return Err(format!("filesystem of '{}' won't fit {} bytes of data to restore", mount_point, stats.in_data_len));
+        }
+    }
+
+    let file_set = FileSet::from_cfg(cfg_path, stats.chunk_pattern.as_deref())?;
+    let block_dec = alg.as_ref().map(|alg| BlockDecryptor::new(alg, opts.pass.as_ref().unwrap(), &stats.auth_string));
+    let mut read_buf: Vec<u8> = vec![0; opts.buf_size_bytes];
+    let layout = match read_layout(&mut chunk_reader(storage, cfg_path, &stats)?, &file_set, &stats, block_dec.as_ref(), &mut read_buf) {
+        Ok(layout) => Some(layout),
+        Err(e) => {
+            eprintln!("could not read index of compressed data ({}), will locate blocks by their headers", e);
+            None
+        }
+    };
+
+    if let Some(progress) = &opts.progress {
+        progress.start(Phase::Restore, chunks_len(&stats));
+    }
+    let mut filler = LostDataFiller::new(&mut write_to, zero_fill);
+    {
+        let mut counted = CountingSink::new(&mut filler, opts.progress.clone());
+        let mut decomp = SalvageDecompressor::new(&mut counted, layout, stats.in_data_len);
+        if let Some(alg) = &alg {
+            let (dec, tag_size) = Decryptor::new(&mut decomp, alg, opts.pass.as_ref().unwrap(), &stats.auth_string, true);
+            let mut fbuf = FixedSizeWriter::new(dec, stats.auth_chunk_size + tag_size);
+            let fmgr = chunk_reader(storage, cfg_path, &stats)?;
+
+            let mut joiner = Joiner::from_metadata(
+                fmgr, &mut fbuf, cfg_path, Some(&stats), opts.buf_size_bytes)?;
+            joiner.on_damaged_chunks(DamagedChunks::FeedReadable);
+            if let Some(progress) = &opts.progress {
+                joiner.set_progress(progress);
+            }
+
+            joiner.read_and_write_all()?;
+        } else {
+            let fmgr = chunk_reader(storage, cfg_path, &stats)?;
+
+            let mut joiner = Joiner::from_metadata(
+                fmgr, &mut decomp, cfg_path, Some(&stats), opts.buf_size_bytes)?;
+            joiner.on_damaged_chunks(DamagedChunks::ReportLost);
+            if let Some(progress) = &opts.progress {
+                joiner.set_progress(progress);
+            }
+
+            joiner.read_and_write_all()?;
+        }
+    }
+
+    if let Some(progress) = &opts.progress {
+        progress.finish_phase();
+    }
+    Ok(filler.lost_ranges().to_vec())
+}
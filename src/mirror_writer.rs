@@ -0,0 +1,253 @@
+use crate::file_set::FileSet;
+use crate::splitter::MultiFilesWriterTarget;
+use crate::stats::ChunkInfo;
+
+struct Copy {
+    template: String,
+    file_set: FileSet,
+    writer: Box<dyn MultiFilesWriterTarget>,
+    written: Vec<String>, // paths of files created in this copy
+    error: Option<String> // once a copy fails, nothing more is written to it
+}
+
+impl Copy {
+    // an incomplete copy is of no use, so its files are removed rather than left taking space
+    fn discard(&mut self) {
+        let mut nr_removed = 0;
+        for path in self.written.drain(..) {
+            if let Err(e) = self.writer.remove_file(&path) {
+                eprintln!("incomplete copy in {} is left in place: {}", self.template, e);
+                return;
+            }
+            nr_removed += 1;
+        }
+        eprintln!("removed {} file(s) of the incomplete copy in {}", nr_removed, self.template);
+    }
+}
+
+// writes every chunk to all destinations; paths come from the first template and are
+// translated into paths of the other ones
+pub struct MirrorWriter {
+    primary: FileSet,
+    copies: Vec<Copy>,
+    min_copies: usize
+}
+
+impl MirrorWriter {
+    pub fn new(destinations: Vec<(String, Box<dyn MultiFilesWriterTarget>)>, min_copies: usize) -> Result<Self, String> {
+        if min_copies == 0 || min_copies > destinations.len() {
+            return Err(format!("required number of copies must be from 1 to {}", destinations.len()));
+        }
+        let copies = destinations.into_iter().map(|(template, writer)| Ok(Copy {
+            file_set: FileSet::from_pattern(&template)?,
+            template,
+            writer,
+            written: Vec::new(),
+            error: None
+        })).collect::<Result<Vec<_>, String>>()?;
+        // every copy gets the same metadata, so that it is readable on its own
+        if copies.iter().any(|c| c.file_set.chunk_pattern() != copies[0].file_set.chunk_pattern()) {
+            return Err("templates with chunk number placeholders must be laid out the same way in every copy".to_owned());
+        }
+        Ok(Self { primary: FileSet::from_pattern(&copies[0].template)?, copies, min_copies })
+    }
+
+    // output templates and whether all their files are written so far
+    pub fn copies(&self) -> Vec<(String, bool)> {
+        self.copies.iter().map(|c| (c.template.clone(), c.error.is_none())).collect()
+    }
+
+    // a single destination fails with its own error, mirrored ones while there are enough copies left
+    fn outcome(&self, nr_ok: usize, errors: Vec<String>) -> Result<(), String> {
+        match errors.len() {
+            0 => Ok(()),
+            _ if nr_ok >= self.min_copies => Ok(()),
+            1 if self.copies.len() == 1 => Err(errors.into_iter().next().unwrap_or_default()), // SAFE: checked above
+            _ => Err(format!("only {} of {} copies are written while {} required: {}",
+                nr_ok, self.copies.len(), self.min_copies, errors.join("; ")))
+        }
+    }
+
+    // applies the operation to every copy not failed yet; returns errors of the copies failed now
+    fn try_each_copy<F>(&mut self, mut op: F) -> Vec<String>
+    where F: FnMut(&FileSet, &mut Copy) -> Result<(), String>
+    {
+        let mirrored = self.copies.len() > 1;
+        let mut errors = Vec::new();
+        for copy in self.copies.iter_mut().filter(|c| c.error.is_none()) {
+            if let Err(e) = op(&self.primary, copy) {
+                if mirrored {
+                    eprintln!("copy to {} failed, it is not written anymore: {}", copy.template, e);
+                    copy.discard();
+                }
+                copy.error = Some(e.clone());
+                errors.push(e);
+            }
+        }
+        errors
+    }
+
+    fn for_each_copy<F>(&mut self, op: F) -> Result<(), String>
+    where F: FnMut(&FileSet, &mut Copy) -> Result<(), String>
+    {
+        let errors = self.try_each_copy(op);
+        let nr_ok = self.copies.iter().filter(|c| c.error.is_none()).count();
+        self.outcome(nr_ok, errors)
+    }
+
+    // writes metadata built by `contents` from the list of copies to every copy; a copy is listed
+    // as complete only once its metadata is written, so if that fails for some copy, the metadata
+    // of the others is written again to list it as failed
+    pub fn write_metadata<F>(&mut self, mut contents: F) -> Result<(), String>
+    where F: FnMut(&[(String, bool)]) -> String
+    {
+        let cfg_path = self.primary.cfg_path();
+        let mut errors = Vec::new();
+        loop {
+            let text = contents(&self.copies());
+            let failed = self.try_each_copy(|primary, copy| {
+                let path = primary.translate(&cfg_path, &copy.file_set)?;
+                copy.writer.write_single_file(&path, text.as_bytes())?;
+                copy.written.push(path);
+                Ok(())
+            });
+            if failed.is_empty() {
+                break;
+            }
+            errors.extend(failed);
+        }
+        let nr_ok = self.copies.iter().filter(|c| c.error.is_none()).count();
+        self.outcome(nr_ok, errors)
+    }
+}
+
+impl MultiFilesWriterTarget for MirrorWriter {
+    fn open_next_file(&mut self, full_path: &str) -> Result<(), String> {
+        self.for_each_copy(|primary, copy| {
+            let path = primary.translate(full_path, &copy.file_set)?;
+            copy.written.push(path.clone());
+            copy.writer.open_next_file(&path)
+        })
+    }
+
+    fn close_current_file(&mut self) -> Result<(), String> {
+        self.for_each_copy(|_, copy| copy.writer.close_current_file())
+    }
+
+    fn write_to_current_file(&mut self, data: &[u8]) -> Result<(), String> {
+        self.for_each_copy(|_, copy| copy.writer.write_to_current_file(data))
+    }
+
+    fn set_current_file_info(&mut self, info: &ChunkInfo) {
+        for copy in self.copies.iter_mut().filter(|c| c.error.is_none()) {
+            copy.writer.set_current_file_info(info);
+        }
+    }
+
+    fn write_single_file(&self, path: &str, contents: &[u8]) -> Result<(), String> {
+        let mut errors = Vec::new();
+        let mut nr_ok = 0;
+        for copy in self.copies.iter().filter(|c| c.error.is_none()) {
+            match self.primary.translate(path, &copy.file_set).and_then(|copy_path| copy.writer.write_single_file(&copy_path, contents)) {
+                Ok(()) => { nr_ok += 1; },
+                Err(e) => {
+                    if self.copies.len() > 1 {
+                        eprintln!("copy to {} failed: {}", copy.template, e);
+                    }
+                    errors.push(e);
+                }
+            }
+        }
+        self.outcome(nr_ok, errors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::collections::BTreeMap;
+    use std::rc::Rc;
+
+    type Files = Rc<RefCell<BTreeMap<String, Vec<u8>>>>;
+
+    // keeps files in memory and fails on writing to the indicated file
+    struct Memory {
+        files: Files,
+        current: Option<String>,
+        fail_on: &'static str
+    }
+
+    impl MultiFilesWriterTarget for Memory {
+        fn open_next_file(&mut self, full_path: &str) -> Result<(), String> {
+            self.files.borrow_mut().insert(full_path.to_owned(), Vec::new());
+            self.current = Some(full_path.to_owned());
+            Ok(())
+        }
+
+        fn close_current_file(&mut self) -> Result<(), String> {
+            self.current = None;
+            Ok(())
+        }
+
+        fn write_to_current_file(&mut self, data: &[u8]) -> Result<(), String> {
+            let path = self.current.clone().unwrap();
+            if path == self.fail_on {
+                return Err(format!("could not write to {}", path));
+            }
+            self.files.borrow_mut().get_mut(&path).unwrap().extend_from_slice(data);
+            Ok(())
+        }
+
+        fn write_single_file(&self, path: &str, contents: &[u8]) -> Result<(), String> {
+            if path == self.fail_on {
+                return Err(format!("could not write to {}", path));
+            }
+            self.files.borrow_mut().insert(path.to_owned(), contents.to_vec());
+            Ok(())
+        }
+
+        fn remove_file(&self, path: &str) -> Result<(), String> {
+            self.files.borrow_mut().remove(path);
+            Ok(())
+        }
+    }
+
+    fn mirror(fail_on: [&'static str; 2]) -> (MirrorWriter, [Files; 2]) {
+        let files: [Files; 2] = Default::default();
+        let writers = ["/a/%%", "/b/bk%%"].into_iter().zip(fail_on).zip(files.iter()).map(|((tpl, fail_on), files)| {
+            (tpl.to_owned(), Box::new(Memory { files: files.clone(), current: None, fail_on }) as Box<dyn MultiFilesWriterTarget>)
+        }).collect();
+        (MirrorWriter::new(writers, 1).unwrap(), files)
+    }
+
+    fn write_chunks(mirror: &mut MirrorWriter) {
+        for path in ["/a/01", "/a/02"] {
+            mirror.open_next_file(path).unwrap();
+            mirror.write_to_current_file(b"data").unwrap();
+            mirror.close_current_file().unwrap();
+        }
+    }
+
+    #[test]
+    fn failed_copy_is_removed() {
+        let (mut mirror, files) = mirror(["", "/b/bk02"]);
+        write_chunks(&mut mirror);
+        mirror.write_metadata(|copies| format!("{:?}", copies)).unwrap();
+
+        assert_eq!(files[0].borrow().keys().collect::<Vec<_>>(), ["/a/00.cfg", "/a/01", "/a/02"]);
+        assert_eq!(files[0].borrow()["/a/00.cfg"], br#"[("/a/%%", true), ("/b/bk%%", false)]"#);
+        assert!(files[1].borrow().is_empty());
+    }
+
+    #[test]
+    fn copy_is_complete_once_metadata_is_written() {
+        let (mut mirror, files) = mirror(["", "/b/bk00.cfg"]);
+        write_chunks(&mut mirror);
+        mirror.write_metadata(|copies| format!("{:?}", copies)).unwrap();
+
+        assert_eq!(files[0].borrow()["/a/00.cfg"], br#"[("/a/%%", true), ("/b/bk%%", false)]"#);
+        assert!(files[1].borrow().is_empty());
+        assert_eq!(mirror.copies(), [("/a/%%".to_owned(), true), ("/b/bk%%".to_owned(), false)]);
+    }
+}
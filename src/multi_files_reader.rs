@@ -1,14 +1,22 @@
+use crate::io_retry::is_transient;
 use crate::joiner::MultiFilesReaderSource;
 use std::fs::File;
-use std::io::Read;
+use std::io::{self, Read, Seek, SeekFrom};
 
 pub struct MultiFilesReader {
-    file: Option<(File, String)>
+    file: Option<(File, String)>,
+    last_transient: bool
 }
 
 impl MultiFilesReader {
     pub fn new() -> Self {
-        Self { file : None }
+        Self { file : None, last_transient: false }
+    }
+
+    // remembers whether the error is worth retrying
+    fn check<T>(&mut self, res: io::Result<T>) -> io::Result<T> {
+        self.last_transient = matches!(&res, Err(e) if is_transient(e));
+        res
     }
 }
 
@@ -18,7 +26,7 @@ impl MultiFilesReaderSource for MultiFilesReader {
             return Err(format!("previous file {} was not closed before opening a new file {}", s, full_path));
         }
         let (opt_f, ret) = 
-            match File::open(full_path) {
+            match self.check(File::open(full_path)) {
                 Ok(f) => (Some((f, full_path.to_owned())), true),
                 Err(e) => {
                     match e.kind() {
@@ -36,9 +44,9 @@ impl MultiFilesReaderSource for MultiFilesReader {
         let (file, name) = self.file
             .as_mut()
             .ok_or("no current file opened to read from".to_owned())?;
-        file
-            .read(buf)
-            .map_err(|e| format!("could not read max {} bytes from file {}: {}", buf.len(), name, e))
+        let res = file.read(buf);
+        self.last_transient = matches!(&res, Err(e) if is_transient(e));
+        res.map_err(|e| format!("could not read max {} bytes from file {}: {}", buf.len(), name, e))
     }
 
     fn close_current_file(&mut self, ) -> Result<(), String> {
@@ -49,13 +57,23 @@ impl MultiFilesReaderSource for MultiFilesReader {
         Ok(())
     }
 
-    fn read_single_file(full_path: &str) -> Result<Vec<u8>, String> {
-        let mut contents = Vec::new();
-        File::open(full_path)
-            .map_err(|e| format!("could not open single file {} for reading: {}", full_path, e))?
-            .read_to_end(&mut contents)
-            .map_err(|e| format!("could not read single file {}: {}", full_path, e))?;
-        Ok(contents)
+    fn last_error_is_transient(&self) -> bool {
+        self.last_transient
+    }
+
+    fn reopen_current_file(&mut self, offset: u64) -> Result<(), String> {
+        let name = self.file
+            .as_ref()
+            .ok_or("no current file opened to reopen".to_owned())?
+            .1
+            .clone();
+        let res = File::open(&name).and_then(|mut f| {
+            f.seek(SeekFrom::Start(offset))?;
+            Ok(f)
+        });
+        let file = self.check(res).map_err(|e| format!("could not reopen file {} at offset {}: {}", name, offset, e))?;
+        self.file = Some((file, name));
+        Ok(())
     }
 }
 
@@ -76,19 +94,17 @@ mod tests {
 
     #[test]
     fn read_from_two_files() {
-        for (fname, data) in vec![
-            ("f1", &vec![1,2,3]),
-            ("f2", &vec![4,5])
-        ]
+        for (fname, data) in [("f1", &vec![1,2,3]),
+            ("f2", &vec![4,5])]
         {
             let mut f = File::create(full_file_name(fname)).unwrap();
             f.write_all(data).unwrap();
         }
 
-        let mut mfr = MultiFilesReader{ file: None };
+        let mut mfr = MultiFilesReader::new();
         let mut all_data = Vec::new();
 
-        for fname in vec!["f1", "f2"] {
+        for fname in ["f1", "f2"] {
             mfr.open_next_file(full_file_name(fname).as_str()).unwrap();
             let mut buf = [0u8; 8];
             let b_read = mfr.read_from_current_file(buf.as_mut_slice()).unwrap();
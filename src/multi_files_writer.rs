@@ -1,14 +1,37 @@
+use crate::io_retry::is_transient;
 use crate::splitter::MultiFilesWriterTarget;
-use std::fs::File;
-use std::io::prelude::*;
+use std::cell::Cell;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, prelude::*, SeekFrom};
+use std::path::Path;
+
+// chunks of a sharded archive go to directories which may not exist yet
+fn create_with_parents(path: &str) -> io::Result<File> {
+    match File::create(path) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            if let Some(parent) = Path::new(path).parent().filter(|p| !p.as_os_str().is_empty()) {
+                fs::create_dir_all(parent)?;
+            }
+            File::create(path)
+        },
+        res => res
+    }
+}
 
 pub struct MultiFilesWriter {
-    current_file: Option<(File, String)>
+    current_file: Option<(File, String)>,
+    last_transient: Cell<bool>
 }
 
 impl MultiFilesWriter {
     pub fn new() -> Self {
-        Self { current_file: None }
+        Self { current_file: None, last_transient: Cell::new(false) }
+    }
+
+    // remembers whether the error is worth retrying
+    fn check<T>(&self, res: io::Result<T>) -> io::Result<T> {
+        self.last_transient.set(matches!(&res, Err(e) if is_transient(e)));
+        res
     }
 }
 
@@ -18,7 +41,7 @@ impl MultiFilesWriterTarget for MultiFilesWriter {
             return Err(format!("previous file {} was not closed before opening a new file {}", name, full_path));
         }
         self.current_file = Some((
-            File::create(full_path).map_err(|e| format!("could not create file {}: {}", full_path, e))?, 
+            self.check(create_with_parents(full_path)).map_err(|e| format!("could not create file {}: {}", full_path, e))?,
             full_path.to_owned()
         ));
         eprintln!("writing to {}", full_path);
@@ -34,23 +57,62 @@ impl MultiFilesWriterTarget for MultiFilesWriter {
     }
 
     fn write_to_current_file(&mut self, data: &[u8]) -> Result<(), String> {
-        let file_and_name = self.current_file
+        let (file, name) = self.current_file
             .as_mut()
             .ok_or("no current file opened to write".to_owned())?;
-        file_and_name
-            .0
-            .write_all(data)
-            .map_err(|e| format!("could not write {} bytes to file {}: {}", data.len(), file_and_name.1, e))
-            .map(|_|())
+        let res = file.write_all(data);
+        self.last_transient.set(matches!(&res, Err(e) if is_transient(e)));
+        res.map_err(|e| format!("could not write {} bytes to file {}: {}", data.len(), name, e))
+    }
+
+    fn write_single_file(&self, path: &str, contents: &[u8]) -> Result<(), String> {
+        // write to a temporary file first, so that an existing file is either fully replaced or left intact
+        let tmp_path = format!("{}.tmp", path);
+        let mut f = self.check(File::create(&tmp_path))
+            .map_err(|e| format!("could not create single file {}: {}", tmp_path, e))?;
+        self.check(f.write_all(contents))
+            .map_err(|e| format!("could not write to single file {}: {}", tmp_path, e))?;
+        self.check(f.sync_all())
+            .map_err(|e| format!("could not sync single file {}: {}", tmp_path, e))?;
+        self.check(fs::rename(&tmp_path, path))
+            .map_err(|e| format!("could not rename single file {} to {}: {}", tmp_path, path, e))
     }
 
-    fn write_single_file(&self, path: &str, contents: &str) -> Result<(), String> {
-        File::create(path)
-            .map_err(|e| format!("could not create single file {}: {}", path, e))?
-            .write_all(contents.as_bytes())
-            .map_err(|e| format!("could not write to single file {}: {}", path, e))
+    fn last_error_is_transient(&self) -> bool {
+        self.last_transient.get()
     }
 
+    fn remove_file(&self, path: &str) -> Result<(), String> {
+        match self.check(fs::remove_file(path)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(format!("could not remove file {}: {}", path, e)),
+            _ => Ok(())
+        }
+    }
+
+    fn sync_current_file(&mut self) -> Result<(), String> {
+        let (file, name) = self.current_file
+            .as_ref()
+            .ok_or("no current file opened to sync".to_owned())?;
+        let res = file.sync_data();
+        self.last_transient.set(matches!(&res, Err(e) if is_transient(e)));
+        res.map_err(|e| format!("could not sync file {}: {}", name, e))
+    }
+
+    fn reopen_current_file(&mut self, offset: u64) -> Result<(), String> {
+        let name = self.current_file
+            .as_ref()
+            .ok_or("no current file opened to reopen".to_owned())?
+            .1
+            .clone();
+        let res = OpenOptions::new().write(true).open(&name).and_then(|mut f| {
+            f.set_len(offset)?;
+            f.seek(SeekFrom::Start(offset))?;
+            Ok(f)
+        });
+        let file = self.check(res).map_err(|e| format!("could not reopen file {} at offset {}: {}", name, offset, e))?;
+        self.current_file = Some((file, name));
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -91,7 +153,7 @@ mod tests {
 
         write_to_file(&mut f, FN1, &[1,2,3]);
         write_to_file(&mut f, FN2, &[4,5,6]);
-        f.write_single_file(FN3, "single").unwrap();
+        f.write_single_file(FN3, b"single").unwrap();
 
         check_and_clear_file(FN1, &[1,2,3]);
         check_and_clear_file(FN2, &[4,5,6]);
@@ -122,4 +184,31 @@ mod tests {
         f.close_current_file().unwrap_err();
         clear_file(FN1);
     }
+
+    #[test]
+    fn reopen_at_offset() {
+        const FN1: &str = "/tmp/file11111";
+        clear_file(FN1);
+        let mut f = MultiFilesWriter::new();
+        f.open_next_file(FN1).unwrap();
+        f.write_to_current_file(&[1,2,3]).unwrap();
+        f.sync_current_file().unwrap();
+        f.write_to_current_file(&[4,5]).unwrap();
+        f.reopen_current_file(3).unwrap();
+        f.write_to_current_file(&[6]).unwrap();
+        f.close_current_file().unwrap();
+        assert!(!f.last_error_is_transient());
+        check_and_clear_file(FN1, &[1,2,3,6]);
+    }
+
+    #[test]
+    fn single_file_replaced() {
+        const FN1: &str = "/tmp/file1111";
+        clear_file(FN1);
+        let f = MultiFilesWriter::new();
+        f.write_single_file(FN1, b"first").unwrap();
+        f.write_single_file(FN1, b"second").unwrap();
+        assert!(!std::path::Path::new(&format!("{}.tmp", FN1)).exists());
+        check_and_clear_file(FN1, b"second");
+    }
 }
@@ -0,0 +1,226 @@
+// Reed-Solomon erasure coding over GF(2^8) used to produce parity chunks and to
+// recover lost data chunks from them. The generator matrix is systematic: the
+// first N rows are identity (data chunks themselves), the remaining M rows form
+// a Cauchy matrix, so any N out of N + M chunks are enough to restore the rest.
+
+pub const MAX_SHARDS: usize = 256;
+
+const fn build_exp_log() -> ([u8; 512], [u8; 256]) {
+    let mut exp = [0u8; 512];
+    let mut log = [0u8; 256];
+    let mut x: u16 = 1;
+    let mut i = 0;
+    while i < 255 {
+        exp[i] = x as u8;
+        exp[i + 255] = x as u8;
+        log[x as usize] = i as u8;
+        x <<= 1;
+        if x & 0x100 != 0 {
+            x ^= 0x11d; // primitive polynomial x^8 + x^4 + x^3 + x^2 + 1
+        }
+        i += 1;
+    }
+    (exp, log)
+}
+
+const EXP_LOG: ([u8; 512], [u8; 256]) = build_exp_log();
+
+const fn build_mul_table() -> [[u8; 256]; 256] {
+    let (exp, log) = EXP_LOG;
+    let mut t = [[0u8; 256]; 256];
+    let mut a = 1;
+    while a < 256 {
+        let mut b = 1;
+        while b < 256 {
+            t[a][b] = exp[log[a] as usize + log[b] as usize];
+            b += 1;
+        }
+        a += 1;
+    }
+    t
+}
+
+static MUL_TABLE: [[u8; 256]; 256] = build_mul_table();
+
+fn mul(a: u8, b: u8) -> u8 {
+    MUL_TABLE[a as usize][b as usize]
+}
+
+fn inv(a: u8) -> u8 {
+    assert!(a != 0);
+    let (exp, log) = EXP_LOG;
+    exp[255 - log[a as usize] as usize]
+}
+
+// row of the generator matrix for shard `shard` (data shards first, then parity ones)
+fn generator_row(nr_data: usize, shard: usize) -> Vec<u8> {
+    if shard < nr_data {
+        let mut row = vec![0; nr_data];
+        row[shard] = 1;
+        row
+    } else {
+        let x = shard as u8; // SAFE: number of shards is limited by MAX_SHARDS
+        (0..nr_data).map(|i| inv(x ^ i as u8)).collect()
+    }
+}
+
+fn invert_matrix(mut m: Vec<Vec<u8>>) -> Result<Vec<Vec<u8>>, String> {
+    let n = m.len();
+    let mut r: Vec<Vec<u8>> = (0..n).map(|i| { let mut row = vec![0; n]; row[i] = 1; row }).collect();
+    for col in 0..n {
+        let pivot = (col..n).find(|&row| m[row][col] != 0).ok_or("singular parity matrix".to_owned())?;
+        m.swap(col, pivot);
+        r.swap(col, pivot);
+        let k = inv(m[col][col]);
+        for j in 0..n {
+            m[col][j] = mul(m[col][j], k);
+            r[col][j] = mul(r[col][j], k);
+        }
+        for row in 0..n {
+            let f = m[row][col];
+            if row != col && f != 0 {
+                for j in 0..n {
+                    m[row][j] ^= mul(f, m[col][j]);
+                    r[row][j] ^= mul(f, r[col][j]);
+                }
+            }
+        }
+    }
+    Ok(r)
+}
+
+pub fn validate_params(nr_data: usize, nr_parity: usize) -> Result<(), String> {
+    if nr_data == 0 || nr_parity == 0 {
+        return Err("number of data and parity chunks in a group must be positive".to_owned());
+    }
+    if nr_data + nr_parity > MAX_SHARDS {
+        return Err(format!("data + parity chunks in a group must not exceed {}", MAX_SHARDS));
+    }
+    Ok(())
+}
+
+// Linearly combines source shards into target shards in a streaming manner:
+// each portion of every source shard is fed once, at its offset, in any order.
+pub struct ShardsCombiner {
+    coefs: Vec<Vec<u8>>, // per target, per source
+    targets: Vec<Vec<u8>>
+}
+
+impl ShardsCombiner {
+    // produces parity shards from data shards
+    pub fn encoder(nr_data: usize, nr_parity: usize) -> Result<Self, String> {
+        validate_params(nr_data, nr_parity)?;
+        Ok(Self {
+            coefs: (nr_data..nr_data + nr_parity).map(|s| generator_row(nr_data, s)).collect(),
+            targets: vec![Vec::new(); nr_parity]
+        })
+    }
+
+    // restores `targets` shards from exactly `nr_data` healthy `sources` shards
+    pub fn recoverer(nr_data: usize, nr_parity: usize, sources: &[usize], targets: &[usize]) -> Result<Self, String> {
+        validate_params(nr_data, nr_parity)?;
+        if sources.len() != nr_data {
+            return Err(format!("need exactly {} healthy chunks to recover, got {}", nr_data, sources.len()));
+        }
+        if let Some(s) = sources.iter().chain(targets.iter()).find(|&&s| s >= nr_data + nr_parity) {
+            return Err(format!("chunk index {} is out of group", s));
+        }
+        let decode = invert_matrix(sources.iter().map(|&s| generator_row(nr_data, s)).collect())?;
+        let coefs = targets.iter().map(|&t| {
+            let row = generator_row(nr_data, t);
+            (0..nr_data).map(|src| {
+                (0..nr_data).fold(0u8, |acc, i| acc ^ mul(row[i], decode[i][src]))
+            }).collect()
+        }).collect();
+        Ok(Self { coefs, targets: vec![Vec::new(); targets.len()] })
+    }
+
+    pub fn add(&mut self, source: usize, offset: usize, data: &[u8]) {
+        for (coefs, target) in self.coefs.iter().zip(self.targets.iter_mut()) {
+            if target.len() < offset + data.len() {
+                target.resize(offset + data.len(), 0);
+            }
+            let c = coefs[source];
+            if c == 0 {
+                continue;
+            }
+            let row = &MUL_TABLE[c as usize];
+            target[offset..offset + data.len()]
+                .iter_mut()
+                .zip(data)
+                .for_each(|(t, d)| *t ^= row[*d as usize]);
+        }
+    }
+
+    // returns combined shards and resets the state for the next group
+    pub fn take(&mut self) -> Vec<Vec<u8>> {
+        self.targets.iter_mut().map(std::mem::take).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{thread_rng, Rng, RngCore};
+
+    #[test]
+    fn field_arithmetics() {
+        for a in 1..=255u8 {
+            assert_eq!(mul(a, inv(a)), 1);
+            assert_eq!(mul(a, 1), a);
+            assert_eq!(mul(a, 0), 0);
+        }
+    }
+
+    fn encode(shards: &[Vec<u8>], nr_parity: usize) -> Vec<Vec<u8>> {
+        let mut enc = ShardsCombiner::encoder(shards.len(), nr_parity).unwrap();
+        for (i, s) in shards.iter().enumerate() {
+            enc.add(i, 0, &s[..s.len() / 2]);
+            enc.add(i, s.len() / 2, &s[s.len() / 2..]);
+        }
+        enc.take()
+    }
+
+    fn recover_random(nr_data: usize, nr_parity: usize, shard_len: usize) {
+        let data: Vec<Vec<u8>> = (0..nr_data).map(|_| {
+            let mut v = vec![0; shard_len];
+            thread_rng().fill_bytes(&mut v);
+            v
+        }).collect();
+        let all: Vec<Vec<u8>> = data.iter().cloned().chain(encode(&data, nr_parity)).collect();
+
+        let mut lost: Vec<usize> = Vec::new();
+        while lost.len() < nr_parity {
+            let s = thread_rng().gen::<usize>() % (nr_data + nr_parity);
+            if !lost.contains(&s) {
+                lost.push(s);
+            }
+        }
+        let sources: Vec<usize> = (0..nr_data + nr_parity).filter(|s| !lost.contains(s)).take(nr_data).collect();
+
+        let mut rec = ShardsCombiner::recoverer(nr_data, nr_parity, &sources, &lost).unwrap();
+        for (i, &s) in sources.iter().enumerate() {
+            rec.add(i, 0, &all[s]);
+        }
+        let recovered = rec.take();
+        for (i, &s) in lost.iter().enumerate() {
+            assert_eq!(recovered[i], all[s]);
+        }
+    }
+
+    #[test]
+    fn recover_various() {
+        for (n, m) in [(1, 1), (2, 1), (3, 2), (10, 4), (200, 56)] {
+            recover_random(n, m, 100);
+        }
+    }
+
+    #[test]
+    fn bad_params() {
+        assert!(ShardsCombiner::encoder(0, 1).is_err());
+        assert!(ShardsCombiner::encoder(1, 0).is_err());
+        assert!(ShardsCombiner::encoder(200, 57).is_err());
+        assert!(ShardsCombiner::recoverer(3, 1, &[0, 1], &[2]).is_err());
+        assert!(ShardsCombiner::recoverer(3, 1, &[0, 1, 4], &[2]).is_err());
+    }
+}
@@ -0,0 +1,264 @@
+// machine-readable progress of backup, verify and restore: one JSON object per line, a "progress" event
+// at the start and the end of each phase and periodically in between, and a final "result" event
+use crate::finalizable::DataSink;
+use serde_json::{json, Value};
+use std::fs::File;
+use std::io::Write;
+use std::os::fd::FromRawFd;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Phase {
+    Backup,
+    Verify,
+    Restore
+}
+
+impl Phase {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Phase::Backup => "backup",
+            Phase::Verify => "verify",
+            Phase::Restore => "restore"
+        }
+    }
+}
+
+struct State {
+    out: Box<dyn Write + Send>,
+    interval: Duration,
+    input_len: Option<u64>, // of backup, if known in advance
+    phase: Phase,
+    total_in: Option<u64>, // of the current phase
+    started: Instant,
+    last_event: Instant,
+    bytes_in: u64,
+    bytes_out: u64,
+    chunk: Option<usize>
+}
+
+// shared by the parts of a pipeline: for backup, input bytes are counted by BufferedReader and output ones
+// by Splitter; for verify and restore, input bytes are those of chunks read by Joiner
+#[derive(Clone)]
+pub struct Progress(Arc<Mutex<State>>);
+
+impl Progress {
+    // `input_len` is the size of backup input, for ETA
+    pub fn new(out: Box<dyn Write + Send>, interval: Duration, input_len: Option<u64>) -> Self {
+        let now = Instant::now();
+        Self(Arc::new(Mutex::new(State {
+            out, interval, input_len,
+            phase: Phase::Backup,
+            total_in: None,
+            started: now,
+            last_event: now,
+            bytes_in: 0,
+            bytes_out: 0,
+            chunk: None
+        })))
+    }
+
+    pub fn to_stderr(interval: Duration, input_len: Option<u64>) -> Self {
+        Self::new(Box::new(std::io::stderr()), interval, input_len)
+    }
+
+    // the descriptor is inherited from the parent process, e.g. `3>progress.log`
+    pub fn to_fd(fd: i32, interval: Duration, input_len: Option<u64>) -> Result<Self, String> {
+        if fd < 0 || unsafe { libc::fcntl(fd, libc::F_GETFD) } == -1 {
+            return Err(format!("file descriptor {} for progress is not open", fd));
+        }
+        let file = unsafe { File::from_raw_fd(fd) }; // SAFE: checked to be open, and owned by this process from now on
+        Ok(Self::new(Box::new(file), interval, input_len))
+    }
+
+    // starts counting anew; for verify and restore, `total_in` is the length of all data chunks
+    pub fn start(&self, phase: Phase, total_in: Option<u64>) {
+        let mut state = self.0.lock().unwrap();
+        let total_in = match phase {
+            Phase::Backup => state.input_len,
+            _ => total_in
+        };
+        let now = Instant::now();
+        state.phase = phase;
+        state.total_in = total_in;
+        state.started = now;
+        state.last_event = now;
+        state.bytes_in = 0;
+        state.bytes_out = 0;
+        state.chunk = None;
+        state.emit(false);
+    }
+
+    pub fn add_in(&self, len: usize) {
+        let mut state = self.0.lock().unwrap();
+        state.bytes_in += len as u64;
+        state.emit_if_due();
+    }
+
+    pub fn add_out(&self, len: usize) {
+        let mut state = self.0.lock().unwrap();
+        state.bytes_out += len as u64;
+        state.emit_if_due();
+    }
+
+    pub fn set_chunk(&self, chunk_no: usize) {
+        let mut state = self.0.lock().unwrap();
+        state.chunk = Some(chunk_no);
+        state.emit_if_due();
+    }
+
+    pub fn finish_phase(&self) {
+        self.0.lock().unwrap().emit(true);
+    }
+
+    // the last event, with the error which made the process exit, if any
+    pub fn result(&self, res: &Result<(), String>) {
+        let mut state = self.0.lock().unwrap();
+        let event = json!({
+            "event": "result",
+            "phase": state.phase.as_str(),
+            "ok": res.is_ok(),
+            "exit_code": if res.is_ok() { 0 } else { 1 },
+            "reason": match res {
+                Ok(()) => "completed",
+                Err(e) => e
+            }
+        });
+        state.write(&event);
+    }
+}
+
+impl State {
+    fn emit_if_due(&mut self) {
+        if self.last_event.elapsed() >= self.interval {
+            self.emit(false);
+        }
+    }
+
+    fn emit(&mut self, finished: bool) {
+        self.last_event = Instant::now();
+        let elapsed = self.started.elapsed();
+        let rate = (self.bytes_in as f64 / elapsed.as_secs_f64()).floor();
+        let rate = if rate.is_finite() { rate as u64 } else { 0 };
+        let eta_s = match self.total_in {
+            _ if finished => Some(0),
+            Some(total) if rate > 0 => Some(total.saturating_sub(self.bytes_in) / rate),
+            _ => None
+        };
+        let ratio = match self.phase {
+            Phase::Backup => self.bytes_out as f64 / self.bytes_in as f64,
+            _ => self.bytes_in as f64 / self.bytes_out as f64
+        };
+        let event = json!({
+            "event": "progress",
+            "phase": self.phase.as_str(),
+            "finished": finished,
+            "elapsed_ms": elapsed.as_millis() as u64,
+            "bytes_in": self.bytes_in,
+            "bytes_out": self.bytes_out,
+            "total_in": self.total_in,
+            "chunk": self.chunk,
+            "ratio": ratio, // compressed to uncompressed, null until known
+            "rate": rate, // bytes in per second
+            "eta_s": eta_s
+        });
+        self.write(&event);
+    }
+
+    fn write(&mut self, event: &Value) {
+        // progress is informational, failing to report it must not break the backup
+        let _ = writeln!(self.out, "{}", event);
+        let _ = self.out.flush();
+    }
+}
+
+// passes data through, counting it as output of the current phase
+pub struct CountingSink<'a, T: DataSink> {
+    to: &'a mut T,
+    progress: Option<Progress>
+}
+
+impl<'a, T: DataSink> CountingSink<'a, T> {
+    pub fn new(to: &'a mut T, progress: Option<Progress>) -> Self {
+        Self { to, progress }
+    }
+}
+
+impl<T: DataSink> DataSink for CountingSink<'_, T> {
+    fn add(&mut self, data: &[u8]) -> Result<(), String> {
+        self.to.add(data)?;
+        if let Some(progress) = &self.progress {
+            progress.add_out(data.len());
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), String> {
+        self.to.finish()
+    }
+
+    fn add_lost(&mut self, len: usize) -> Result<(), String> {
+        self.to.add_lost(len)?;
+        if let Some(progress) = &self.progress {
+            progress.add_out(len);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Progress, Phase};
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    #[derive(Clone)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn events() {
+        let out = Shared(Arc::new(Mutex::new(Vec::new())));
+        let progress = Progress::new(Box::new(out.clone()), Duration::from_secs(3600), Some(1000));
+        progress.start(Phase::Backup, None);
+        progress.add_in(500);
+        progress.add_out(100);
+        progress.set_chunk(0);
+        progress.finish_phase();
+        progress.start(Phase::Verify, Some(100));
+        progress.add_in(100);
+        progress.result(&Err("could not read \"x\"".to_owned()));
+
+        let text = String::from_utf8(out.0.lock().unwrap().clone()).unwrap();
+        let events = text.lines().collect::<Vec<_>>();
+        assert_eq!(events.len(), 4, "{}", text); // nothing in between within the interval
+        assert!(events[0].starts_with(r#"{"event":"progress","phase":"backup","finished":false,"elapsed_ms":"#), "{}", events[0]);
+        assert!(events[0].contains(r#""bytes_in":0,"bytes_out":0,"total_in":1000,"chunk":null,"ratio":null,"rate":0,"eta_s":null}"#), "{}", events[0]);
+        assert!(events[1].contains(r#""phase":"backup","finished":true,"#), "{}", events[1]);
+        assert!(events[1].contains(r#""bytes_in":500,"bytes_out":100,"total_in":1000,"chunk":0,"ratio":0.2,"#), "{}", events[1]);
+        assert!(events[1].ends_with(r#""eta_s":0}"#), "{}", events[1]);
+        assert!(events[2].contains(r#""phase":"verify","finished":false,"#), "{}", events[2]);
+        assert!(events[2].contains(r#""bytes_in":0,"bytes_out":0,"total_in":100,"#), "{}", events[2]);
+        assert_eq!(events[3], r#"{"event":"result","phase":"verify","ok":false,"exit_code":1,"reason":"could not read \"x\""}"#);
+
+        let progress = Progress::new(Box::new(out.clone()), Duration::ZERO, None);
+        out.0.lock().unwrap().clear();
+        progress.start(Phase::Restore, None);
+        progress.add_in(10);
+        progress.add_out(40);
+        let text = String::from_utf8(out.0.lock().unwrap().clone()).unwrap();
+        assert_eq!(text.lines().count(), 3, "{}", text);
+        assert!(text.lines().last().unwrap().contains(r#""bytes_in":10,"bytes_out":40,"total_in":null,"chunk":null,"ratio":0.25,"#), "{}", text);
+    }
+}
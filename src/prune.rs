@@ -0,0 +1,232 @@
+// removal of old archives recorded in the catalog according to a retention policy, e.g. 7 daily, 4 weekly
+// and 12 monthly ones: the newest archive of each of the most recent days, weeks and months is kept
+use crate::catalog::{Archive, Catalog};
+use crate::file_set::FileSet;
+use crate::stats::Label;
+use crate::{local_offset, time_str_of};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+use time::OffsetDateTime;
+
+#[derive(Default, Clone, Debug)]
+pub struct RetentionPolicy {
+    pub keep_last: usize,
+    pub keep_daily: usize,
+    pub keep_weekly: usize,
+    pub keep_monthly: usize
+}
+
+// what prune did or would do
+#[derive(Default, PartialEq, Debug)]
+pub struct Pruned {
+    pub nr_removed: usize, // archives with at least one copy removed
+    pub nr_remote: usize // copies left to the tools of remote storage
+}
+
+// copies of one archive, all removed together
+struct ArchiveSet {
+    copies: Vec<Archive>,
+    time: u64,
+    keep: Vec<&'static str> // reasons to keep
+}
+
+impl ArchiveSet {
+    fn name(&self) -> String {
+        let id = self.copies[0].archive_id.clone().unwrap_or_else(|| self.copies[0].config.clone());
+        format!("{} made at {}", id, time_str_of(self.time).unwrap_or("unknown time".to_owned()))
+    }
+
+    fn verified(&self) -> bool {
+        self.copies.iter().any(|c| c.last_ok() == Some(true))
+    }
+}
+
+// day, ISO week and month of unix time in local time zone
+fn periods(unix_time: u64) -> [(i32, u32); 3] {
+    let t = OffsetDateTime::from_unix_timestamp(unix_time as i64).unwrap_or(OffsetDateTime::UNIX_EPOCH)
+        .to_offset(local_offset());
+    let (iso_year, iso_week, _) = t.date().to_iso_week_date();
+    [(t.year(), t.ordinal() as u32), (iso_year, iso_week as u32), (t.year(), t.month() as u32)]
+}
+
+// marks sets to keep, `sets` are sorted newest first
+fn apply_policy(sets: &mut [ArchiveSet], policy: &RetentionPolicy) {
+    let mut left = [policy.keep_daily, policy.keep_weekly, policy.keep_monthly];
+    let mut last_kept: [Option<(i32, u32)>; 3] = [None; 3];
+    for (i, set) in sets.iter_mut().enumerate() {
+        if i < policy.keep_last {
+            set.keep.push("last");
+        }
+        for (k, period) in periods(set.time).into_iter().enumerate() {
+            if left[k] > 0 && last_kept[k] != Some(period) {
+                set.keep.push(["daily", "weekly", "monthly"][k]);
+                last_kept[k] = Some(period);
+                left[k] -= 1;
+            }
+        }
+    }
+    // never leave the host without an archive known to be restorable
+    if let Some(set) = sets.iter_mut().find(|set| set.verified()) {
+        if set.keep.is_empty() {
+            eprintln!("refusing to delete archive {}, the last one successfully verified", set.name());
+        }
+        set.keep.push("last verified");
+    }
+}
+
+// removes a file which may be already removed by an interrupted prune
+fn remove(path: &str) -> Result<bool, String> {
+    match fs::remove_file(path) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(format!("could not remove {}: {}", path, e))
+    }
+}
+
+// chunks first and metadata last, so that an interrupted prune can be repeated
+fn remove_copy(copy: &Archive) -> Result<usize, String> {
+    let backup = copy.backup.as_ref().ok_or(format!("no backup record of {}", copy.config))?;
+    // chunks of a backup into a repository may be shared with other backups, gc of the repository removes them
+    if let Some(repo) = backup.get("repo").and_then(|r| r.as_str()) {
+        let removed = remove(&copy.config)?;
+        eprintln!("chunks of {} are left to gc of repository {}", copy.config, repo);
+        return Ok(removed as usize);
+    }
+    let field = |name: &str| backup.get(name).and_then(|v| v.as_u64()).unwrap_or(0) as usize;
+    let template = backup.get("template").and_then(|t| t.as_str()).ok_or(format!("no template in backup record of {}", copy.config))?;
+    let file_set = FileSet::from_pattern(template)?;
+    let nr_chunks = field("nr_chunks");
+    let nr_parity = match field("parity_group") {
+        0 => 0,
+        group => nr_chunks.div_ceil(group) * field("parity_nr")
+    };
+    let paths = (0..nr_chunks).map(|n| file_set.gen_file_path(n))
+        .chain((0..nr_parity).map(|n| file_set.gen_parity_file_path(n)))
+        .chain([copy.config.clone()])
+        .collect::<Vec<_>>();
+    let mut nr_removed = 0;
+    for path in &paths {
+        if remove(path)? {
+            nr_removed += 1;
+        }
+    }
+    // directories left empty, e.g. of a dated archive or of chunks spread by {n/K}, up to that of metadata
+    let top = Path::new(&copy.config).parent();
+    let mut dirs = paths.iter().filter_map(|p| Path::new(p).parent()).collect::<Vec<_>>();
+    dirs.sort_by_key(|d| std::cmp::Reverse(d.components().count()));
+    dirs.dedup();
+    for dir in dirs {
+        let mut dir = Some(dir);
+        while let Some(d) = dir.filter(|d| !d.as_os_str().is_empty() && top.is_some_and(|top| d.starts_with(top))) {
+            if fs::remove_dir(d).is_err() {
+                break;
+            }
+            dir = d.parent();
+        }
+    }
+    Ok(nr_removed)
+}
+
+// removes archives of the catalog which have all of `labels` and are not kept by the policy;
+// only archives made with the catalog are considered, as only then it knows all their files
+pub fn prune(catalog: &Catalog, policy: &RetentionPolicy, labels: &[Label], dry_run: bool) -> Result<Pruned, String> {
+    if policy.keep_last + policy.keep_daily + policy.keep_weekly + policy.keep_monthly == 0 {
+        return Err("no archives are to be kept by the retention policy, refusing to delete all of them".to_owned());
+    }
+    let mut sets: Vec<ArchiveSet> = Vec::new();
+    for archive in catalog.search(labels, &None, false)? {
+        let time = match archive.backup_time() {
+            Some(time) => time,
+            None => continue
+        };
+        match sets.iter_mut().find(|set| archive.archive_id.is_some() && set.copies[0].archive_id == archive.archive_id) {
+            Some(set) => {
+                set.time = set.time.max(time);
+                set.copies.push(archive);
+            },
+            None => sets.push(ArchiveSet { copies: vec![archive], time, keep: Vec::new() })
+        }
+    }
+    sets.sort_by_key(|set| std::cmp::Reverse(set.time));
+    apply_policy(&mut sets, policy);
+
+    let mut res = Pruned::default();
+    for set in &sets {
+        if !set.keep.is_empty() {
+            eprintln!("keeping archive {} ({})", set.name(), set.keep.join(", "));
+            continue;
+        }
+        let (remote, local): (Vec<&Archive>, Vec<&Archive>) = set.copies.iter().partition(|copy| copy.config.contains("://"));
+        for copy in &remote {
+            eprintln!("copy {} of archive {} is in remote storage, remove it with tools of the storage", copy.config, set.name());
+        }
+        res.nr_remote += remote.len();
+        if local.is_empty() {
+            continue;
+        }
+        if dry_run {
+            eprintln!("would delete archive {}: {}", set.name(), local.iter().map(|c| c.config.as_str()).collect::<Vec<_>>().join(", "));
+            res.nr_removed += 1;
+            continue;
+        }
+        eprintln!("deleting archive {}...", set.name());
+        for copy in local {
+            let nr_files = remove_copy(copy)?;
+            eprintln!("removed {} file(s) of {}", nr_files, copy.config);
+            catalog.record_prune(copy);
+        }
+        res.nr_removed += 1;
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{apply_policy, ArchiveSet, RetentionPolicy};
+    use crate::catalog::Archive;
+    use serde_json::json;
+
+    fn set(time: u64, verified: bool) -> ArchiveSet {
+        let checks = match verified {
+            true => vec![json!({ "event": "verify", "ok": true })],
+            false => Vec::new()
+        };
+        ArchiveSet {
+            copies: vec![Archive { archive_id: None, config: format!("/bk/{}/0.cfg", time), backup: None, checks, pruned: None }],
+            time,
+            keep: Vec::new()
+        }
+    }
+
+    fn kept(sets: &[ArchiveSet]) -> Vec<usize> {
+        sets.iter().enumerate().filter(|(_, s)| !s.keep.is_empty()).map(|(i, _)| i).collect()
+    }
+
+    #[test]
+    fn policy() {
+        const DAY: u64 = 86400;
+        let start = 1704542400; // 2024-01-06 12:00 UTC
+        // nightly for 90 days and one more a minute after the last one, newest first
+        let times = || [start + 89 * DAY + 60].into_iter().chain((0..90).rev().map(|d| start + d * DAY));
+
+        let mut sets = times().map(|t| set(t, true)).collect::<Vec<_>>();
+        apply_policy(&mut sets, &RetentionPolicy { keep_daily: 7, ..Default::default() });
+        assert_eq!(kept(&sets), vec![0, 2, 3, 4, 5, 6, 7]); // the second one of the last day is not
+        assert_eq!(sets[0].keep, vec!["daily", "last verified"]);
+
+        let mut sets = times().map(|t| set(t, false)).collect::<Vec<_>>();
+        apply_policy(&mut sets, &RetentionPolicy { keep_last: 2, keep_daily: 3, keep_weekly: 2, keep_monthly: 3 });
+        let nr_kept_as = |reason| sets.iter().filter(|s| s.keep.contains(&reason)).count();
+        assert_eq!((nr_kept_as("last"), nr_kept_as("daily"), nr_kept_as("weekly"), nr_kept_as("monthly")), (2, 3, 2, 3));
+        assert_eq!(kept(&sets)[..3], [0, 1, 2]);
+        assert!(*kept(&sets).last().unwrap() > 31); // monthly ones go back over a month
+        assert!(sets[0].keep.contains(&"last") && sets[0].keep.contains(&"daily"));
+
+        // the last verified archive is kept even if the policy does not
+        let mut sets = times().map(|t| set(t, t < start + 10 * DAY)).collect::<Vec<_>>();
+        apply_policy(&mut sets, &RetentionPolicy { keep_daily: 3, ..Default::default() });
+        assert_eq!(kept(&sets), vec![0, 2, 3, 81]);
+        assert_eq!(sets[81].keep, vec!["last verified"]);
+    }
+}
@@ -0,0 +1,802 @@
+// repository of deduplicated archives: input is split into chunks by content, every chunk not stored yet is compressed
+// and encrypted on its own and stored under its id, a keyed hash of its contents, and a backup is a manifest listing
+// the ids of its chunks, so that each backup of slowly changing data takes space only for what has changed
+//
+//   <repo>/repo.cfg                            parameters of the repository, fixed when it is created
+//   <repo>/chunks/<2 first digits of id>/<id>  stored chunks, the encrypted ones after a random salt of their key
+//   <repo>/manifests/<archive id>.manifest     one per backup
+//   <repo>/lock                                locked by backups, restores, checks and gc
+use crate::arg_opts::Alg;
+use crate::buffered_reader::BufferedReader;
+use crate::chunk_header::new_archive_id;
+use crate::comp_decomp_2::{Compressor2, Decompressor2};
+use crate::enc_dec::{Encryptor, Decryptor, EncDecAlg, key_len};
+use crate::file_set::host_name;
+use crate::finalizable::DataSink;
+use crate::fixed_size_writer::FixedSizeWriter;
+use crate::free_space::get_free_space;
+use crate::hasher::DataHasher;
+use crate::progress::{Progress, Phase, CountingSink};
+use crate::stats::{Label, archive_id_as_string, labels_as_string};
+use crate::content_chunker::ContentChunker;
+use crate::{timestamp, BackupOptions};
+use rand::RngCore;
+use ring::{hmac, pbkdf2};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Read, Write};
+use std::num::NonZeroU32;
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::thread;
+
+pub const REPO_CFG: &str = "repo.cfg";
+pub const CHUNKS_DIR: &str = "chunks";
+pub const MANIFESTS_DIR: &str = "manifests";
+pub const MANIFEST_EXT: &str = "manifest";
+pub const LOCK_FILE: &str = "lock";
+const FORMAT_VERSION: u32 = 1;
+const AUTH_BLOCK_LEN: usize = 1_048_576; // of compressed data of a chunk, encrypted and authenticated separately
+const CHUNK_SALT_LEN: usize = 16;
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    (0..s.len()).step_by(2)
+        .map(|i| s.get(i..i + 2).and_then(|h| u8::from_str_radix(h, 16).ok()))
+        .collect()
+}
+
+// key=value lines, as in metadata of archives
+fn parse_lines<'a>(text: &'a str, what: &str) -> Result<HashMap<&'a str, &'a str>, String> {
+    let mut map = HashMap::new();
+    for line in text.lines().filter(|ln| !ln.trim().is_empty()) {
+        let (key, value) = line.split_once('=').filter(|(k, _)| !k.is_empty()).ok_or(format!("invalid line of {}: '{}'", what, line))?;
+        if map.insert(key, value).is_some() {
+            return Err(format!("duplicate key '{}' in {}", key, what));
+        }
+    }
+    Ok(map)
+}
+
+fn get<'a>(map: &HashMap<&str, &'a str>, key: &str, what: &str) -> Result<&'a str, String> {
+    map.get(key).copied().ok_or(format!("no {} in {}", key, what))
+}
+
+fn get_num<N: std::str::FromStr>(map: &HashMap<&str, &str>, key: &str, what: &str) -> Result<N, String> {
+    let value = get(map, key, what)?;
+    value.parse::<N>().map_err(|_| format!("invalid {} '{}' in {}", key, value, what))
+}
+
+// written under a temporary name and renamed, so that a chunk or manifest is either complete or missing
+fn write_atomically(path: &Path, data: &[u8]) -> Result<(), String> {
+    let mut tmp_name = path.as_os_str().to_owned();
+    tmp_name.push(format!(".tmp{}", std::process::id()));
+    let res = File::create(&tmp_name)
+        .and_then(|mut f| f.write_all(data).and_then(|_| f.sync_all()))
+        .and_then(|_| fs::rename(&tmp_name, path));
+    if let Err(e) = res {
+        let _ = fs::remove_file(&tmp_name);
+        return Err(format!("could not write {}: {}", path.display(), e));
+    }
+    Ok(())
+}
+
+// files left by interrupted writes
+fn is_temporary(path: &Path) -> bool {
+    path.file_name().is_some_and(|name| name.to_string_lossy().contains(".tmp"))
+}
+
+// entries of a directory of the repository, sorted
+fn list_dir(dir: &Path) -> Result<Vec<PathBuf>, String> {
+    let mut paths = fs::read_dir(dir)
+        .and_then(|entries| entries.map(|e| e.map(|e| e.path())).collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format!("could not list {}: {}", dir.display(), e))?;
+    paths.sort();
+    Ok(paths)
+}
+
+// backups, restores and checks hold a shared lock of the repository and gc an exclusive one, so that gc never
+// removes chunks a running backup or restore relies on; the lock goes away with its process, so a crashed one leaves nothing behind
+struct RepoLock {
+    _file: File
+}
+
+impl RepoLock {
+    fn new(repo_path: &str, exclusive: bool) -> Result<Self, String> {
+        let path = Path::new(repo_path).join(LOCK_FILE);
+        let file = OpenOptions::new().create(true).truncate(false).write(true).open(&path)
+            .map_err(|e| format!("could not open {}: {}", path.display(), e))?;
+        let op = if exclusive { libc::LOCK_EX } else { libc::LOCK_SH };
+        if unsafe { libc::flock(file.as_raw_fd(), op | libc::LOCK_NB) } != 0 {
+            let e = std::io::Error::last_os_error();
+            return Err(match e.kind() {
+                ErrorKind::WouldBlock if exclusive => format!("repository {} is in use by a backup, restore or check, try again later", repo_path),
+                ErrorKind::WouldBlock => format!("repository {} is being garbage collected, try again later", repo_path),
+                _ => format!("could not lock repository {}: {}", repo_path, e)
+            });
+        }
+        Ok(Self { _file: file })
+    }
+}
+
+// whether the path is that of a manifest in a repository, e.g. given to restore instead of metadata of an archive
+pub fn is_manifest_path(path: &str) -> bool {
+    let path = Path::new(path);
+    path.extension().is_some_and(|ext| ext == MANIFEST_EXT)
+        && path.parent().and_then(|dir| dir.file_name()).is_some_and(|dir| dir == MANIFESTS_DIR)
+}
+
+// repository the manifest belongs to
+pub fn repository_of(manifest_path: &str) -> Result<String, String> {
+    Path::new(manifest_path).parent().and_then(|dir| dir.parent())
+        .map(|repo| match repo.to_string_lossy().as_ref() {
+            "" => ".".to_owned(),
+            repo => repo.to_owned()
+        })
+        .ok_or(format!("{} is not in a repository", manifest_path))
+}
+
+// chunk ids and keys are derived from one key, which is derived from the password and salt of the repository;
+// without a password it is all zeros, so that ids are still hashes which tell damaged chunks
+struct RepoKeys {
+    master: hmac::Key,
+    id: hmac::Key
+}
+
+impl RepoKeys {
+    fn new(pass: &Option<String>, salt: &[u8]) -> Self {
+        let mut key = [0u8; 32];
+        if let Some(pass) = pass {
+            let nr_iters = NonZeroU32::new(100000).unwrap(); // SAFE: not zero
+            pbkdf2::derive(pbkdf2::PBKDF2_HMAC_SHA256, nr_iters, salt, pass.as_bytes(), &mut key);
+        }
+        let master = hmac::Key::new(hmac::HMAC_SHA256, &key);
+        let id = hmac::Key::new(hmac::HMAC_SHA256, hmac::sign(&master, b"chunk id").as_ref());
+        Self { master, id }
+    }
+
+    fn derive(&self, purpose: &str, data: &[u8]) -> hmac::Tag {
+        let mut ctx = hmac::Context::with_key(&self.master);
+        ctx.update(purpose.as_bytes());
+        ctx.update(data);
+        ctx.sign()
+    }
+
+    // tells a wrong password at once, instead of failing to decrypt chunks
+    fn check(&self) -> String {
+        hex(self.derive("password check", &[]).as_ref())
+    }
+
+    // a chunk is encrypted with a key of its own every time it is sealed, as its compressed contents differ
+    // with the compression level, so that counter nonces are never reused with a key
+    fn chunk_key(&self, id: &str, salt: &[u8], len: usize) -> Vec<u8> {
+        self.derive("chunk key", &[id.as_bytes(), salt].concat()).as_ref()[..len].to_vec()
+    }
+
+    fn manifest_mac(&self, text: &str) -> String {
+        hex(self.derive("manifest", text.as_bytes()).as_ref())
+    }
+}
+
+// one backup in a repository
+pub struct Manifest {
+    pub archive_id: [u8; 16],
+    pub created: u64,
+    pub host: String,
+    pub in_len: usize,
+    pub in_hash: u64,
+    pub hash_seed: u64,
+    pub labels: Vec<Label>,
+    pub chunks: Vec<(String, usize)> // id and length of data of each chunk, in order
+}
+
+impl Manifest {
+    fn as_string(&self, keys: &RepoKeys) -> String {
+        let text = format!("\
+            format={}\n\
+            archive_id={}\n\
+            created={}\n\
+            host={}\n\
+            in_len={}\n\
+            in_hash={:016x}\n\
+            hash_seed={:016x}\n\
+            labels={}\n\
+            nr_chunks={}\n\
+            chunks={}\n",
+            FORMAT_VERSION,
+            archive_id_as_string(&self.archive_id),
+            self.created,
+            self.host,
+            self.in_len,
+            self.in_hash,
+            self.hash_seed,
+            labels_as_string(&self.labels),
+            self.chunks.len(),
+            self.chunks.iter().map(|(id, len)| format!("{}:{}", len, id)).collect::<Vec<_>>().join(","));
+        let mac = keys.manifest_mac(&text);
+        format!("{}mac={}\n", text, mac)
+    }
+
+    // the mac tells a damaged manifest, and with a password also an altered one
+    fn from_string(text: &str, keys: &RepoKeys) -> Result<Self, String> {
+        const WHAT: &str = "manifest";
+        let (signed, mac) = text.rsplit_once("mac=").ok_or("manifest has no mac".to_owned())?;
+        if keys.manifest_mac(signed) != mac.trim_end() {
+            return Err("manifest is damaged or altered, or the password is wrong".to_owned());
+        }
+        let map = parse_lines(signed, WHAT)?;
+        let version = get_num::<u32>(&map, "format", WHAT)?;
+        if version > FORMAT_VERSION {
+            return Err(format!("manifest format version {} is newer than {} supported by this release of bigarchiver, please upgrade it", version, FORMAT_VERSION));
+        }
+        let archive_id = from_hex(&get(&map, "archive_id", WHAT)?.replace('-', ""))
+            .and_then(|id| id.try_into().ok())
+            .ok_or("invalid archive_id in manifest".to_owned())?;
+        let hex_num = |key| u64::from_str_radix(get(&map, key, WHAT)?, 16).map_err(|_| format!("invalid {} in manifest", key));
+        let chunks = match get(&map, "chunks", WHAT)? {
+            "" => Vec::new(),
+            list => list.split(',')
+                .map(|item| item.split_once(':')
+                    .and_then(|(len, id)| Some((id.to_owned(), len.parse::<usize>().ok()?)))
+                    .ok_or(format!("invalid chunk '{}' in manifest", item)))
+                .collect::<Result<Vec<_>, String>>()?
+        };
+        if get_num::<usize>(&map, "nr_chunks", WHAT)? != chunks.len() {
+            return Err(format!("manifest lists {} chunks while nr_chunks={}", chunks.len(), get(&map, "nr_chunks", WHAT)?));
+        }
+        Ok(Self {
+            archive_id,
+            created: get_num(&map, "created", WHAT)?,
+            host: get(&map, "host", WHAT)?.to_owned(),
+            in_len: get_num(&map, "in_len", WHAT)?,
+            in_hash: hex_num("in_hash")?,
+            hash_seed: hex_num("hash_seed")?,
+            labels: match get(&map, "labels", WHAT)? {
+                "" => Vec::new(),
+                list => list.split(',').map(Label::parse).collect::<Result<Vec<_>, String>>()?
+            },
+            chunks
+        })
+    }
+}
+
+// what a backup into a repository did
+pub struct RepoBackup {
+    pub archive_id: [u8; 16],
+    pub manifest_path: String,
+    pub in_len: usize,
+    pub nr_chunks: usize,
+    pub nr_new_chunks: usize,
+    pub stored_len: usize // of new chunks, compressed and encrypted
+}
+
+// what gc did or would do
+pub struct RepoGc {
+    pub nr_manifests: usize,
+    pub nr_chunks: usize, // kept
+    pub nr_removed: usize,
+    pub removed_len: u64
+}
+
+pub struct Repository {
+    path: String,
+    alg: Option<EncDecAlg>,
+    alg_name: String,
+    keys: RepoKeys,
+    min_chunk_len: usize,
+    avg_chunk_len: usize,
+    max_chunk_len: usize
+}
+
+impl Repository {
+    // new empty repository, where chunks are `avg_chunk_len` long on average, from a quarter to four times of it
+    pub fn create(path: &str, alg: &Alg, pass: &Option<String>, avg_chunk_len: usize) -> Result<Self, String> {
+        let alg_name = match (alg, pass) {
+            (Alg::None, None) => "none",
+            (Alg::None, Some(_)) => { return Err("password is given for an unencrypted repository".to_owned()); },
+            (_, None) => { return Err("encrypted repository requires a password".to_owned()); },
+            (Alg::Aes128Gcm, Some(_)) => "aes128-gcm",
+            (Alg::Chacha20Poly1305, Some(_)) => "chacha20-poly1305"
+        };
+        if avg_chunk_len < 1024 {
+            return Err(format!("average chunk length {} is too small", avg_chunk_len));
+        }
+        let cfg_path = Path::new(path).join(REPO_CFG);
+        if cfg_path.exists() {
+            return Err(format!("repository {} already exists", path));
+        }
+        for dir in [CHUNKS_DIR, MANIFESTS_DIR] {
+            fs::create_dir_all(Path::new(path).join(dir)).map_err(|e| format!("could not create repository {}: {}", path, e))?;
+        }
+        let mut salt = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut salt);
+        let keys = RepoKeys::new(pass, &salt);
+        let cfg = format!("\
+            format={}\n\
+            alg={}\n\
+            salt={}\n\
+            key_check={}\n\
+            min_chunk_len={}\n\
+            avg_chunk_len={}\n\
+            max_chunk_len={}\n",
+            FORMAT_VERSION, alg_name, hex(&salt), keys.check(), avg_chunk_len / 4, avg_chunk_len, avg_chunk_len * 4);
+        write_atomically(&cfg_path, cfg.as_bytes())?;
+        Self::open(path, pass)
+    }
+
+    pub fn open(path: &str, pass: &Option<String>) -> Result<Self, String> {
+        let cfg_path = Path::new(path).join(REPO_CFG);
+        let text = fs::read_to_string(&cfg_path).map_err(|e| format!("could not read {}: {}", cfg_path.display(), e))?;
+        let what = format!("{}", cfg_path.display());
+        let map = parse_lines(&text, &what)?;
+        let version = get_num::<u32>(&map, "format", &what)?;
+        if version > FORMAT_VERSION {
+            return Err(format!("repository format version {} is newer than {} supported by this release of bigarchiver, please upgrade it", version, FORMAT_VERSION));
+        }
+        let alg_name = get(&map, "alg", &what)?.to_owned();
+        let alg = match (alg_name.as_str(), pass) {
+            ("none", None) => None,
+            ("none", Some(_)) => { return Err("repository is not encrypted and does not need a password".to_owned()); },
+            (_, None) => { return Err("encrypted repository requires a password".to_owned()); },
+            ("aes128-gcm", Some(_)) => Some(EncDecAlg::Aes128Gcm),
+            ("chacha20-poly1305", Some(_)) => Some(EncDecAlg::Chacha20Poly1305),
+            (x, _) => { return Err(format!("invalid encryption type in {}: {}", what, x)); }
+        };
+        let salt = from_hex(get(&map, "salt", &what)?).ok_or(format!("invalid salt in {}", what))?;
+        let keys = RepoKeys::new(pass, &salt);
+        if keys.check() != get(&map, "key_check", &what)? {
+            return Err(format!("wrong password for repository {}", path));
+        }
+        Ok(Self {
+            path: path.to_owned(),
+            alg,
+            alg_name,
+            keys,
+            min_chunk_len: get_num(&map, "min_chunk_len", &what)?,
+            avg_chunk_len: get_num(&map, "avg_chunk_len", &what)?,
+            max_chunk_len: get_num(&map, "max_chunk_len", &what)?
+        })
+    }
+
+    // existing repository, which must be encrypted with `alg`, or a new one
+    pub fn open_or_create(path: &str, alg: &Alg, pass: &Option<String>, avg_chunk_len: usize) -> Result<Self, String> {
+        if !Path::new(path).join(REPO_CFG).exists() {
+            eprintln!("creating repository in {}", path);
+            return Self::create(path, alg, pass, avg_chunk_len);
+        }
+        let repo = Self::open(path, pass)?;
+        let alg_name = match alg {
+            Alg::None => "none",
+            Alg::Aes128Gcm => "aes128-gcm",
+            Alg::Chacha20Poly1305 => "chacha20-poly1305"
+        };
+        if repo.alg_name != alg_name {
+            return Err(format!("repository {} is encrypted with {}, not {}", path, repo.alg_name, alg_name));
+        }
+        Ok(repo)
+    }
+
+    fn chunk_path(&self, id: &str) -> std::path::PathBuf {
+        Path::new(&self.path).join(CHUNKS_DIR).join(&id[..2]).join(id)
+    }
+
+    fn chunk_id(&self, data: &[u8]) -> String {
+        hex(hmac::sign(&self.keys.id, data).as_ref())
+    }
+
+    // compressed and encrypted contents of a chunk, authenticated together with its id
+    fn seal(&self, id: &str, data: &[u8], compress_level: u8) -> Result<Vec<u8>, String> {
+        let mut sealed = Vec::new();
+        match &self.alg {
+            Some(alg) => {
+                let mut salt = [0u8; CHUNK_SALT_LEN];
+                rand::thread_rng().fill_bytes(&mut salt);
+                sealed.extend_from_slice(&salt);
+                let enc = Encryptor::with_key(&mut sealed, alg, &self.keys.chunk_key(id, &salt, key_len(alg)), id);
+                let mut fbuf = FixedSizeWriter::new(enc, AUTH_BLOCK_LEN);
+                let mut comp = Compressor2::new(&mut fbuf, compress_level as u32, 1)?;
+                comp.add(data)?;
+                comp.finish()?;
+            },
+            None => {
+                let mut comp = Compressor2::new(&mut sealed, compress_level as u32, 1)?;
+                comp.add(data)?;
+                comp.finish()?;
+            }
+        }
+        Ok(sealed)
+    }
+
+    // seals a chunk and writes it under its id, returns its length as stored
+    fn store_chunk(&self, id: &str, data: &[u8], compress_level: u8) -> Result<usize, String> {
+        let sealed = self.seal(id, data, compress_level)?;
+        let path = self.chunk_path(id);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(|e| format!("could not create directory {}: {}", dir.display(), e))?;
+        }
+        write_atomically(&path, &sealed)?;
+        Ok(sealed.len())
+    }
+
+    // data of a stored chunk verified by its id, and the length of the chunk as stored
+    fn open_chunk(&self, id: &str, len: Option<usize>) -> Result<(Vec<u8>, usize), String> {
+        let sealed = fs::read(self.chunk_path(id)).map_err(|e| match e.kind() {
+            ErrorKind::NotFound => format!("chunk {} is missing", id),
+            _ => format!("could not read chunk {}: {}", id, e)
+        })?;
+        let mut data = Vec::with_capacity(len.unwrap_or(self.avg_chunk_len));
+        let res = match &self.alg {
+            Some(_) if sealed.len() < CHUNK_SALT_LEN => Err("no salt of its key".to_owned()),
+            Some(alg) => {
+                let (salt, body) = sealed.split_at(CHUNK_SALT_LEN);
+                let mut decomp = Decompressor2::new(&mut data, 1)?;
+                let (dec, tag_len) = Decryptor::with_key(&mut decomp, alg, &self.keys.chunk_key(id, salt, key_len(alg)), id);
+                let mut fbuf = FixedSizeWriter::new(dec, AUTH_BLOCK_LEN + tag_len);
+                fbuf.add(body).and_then(|_| fbuf.finish())
+            },
+            None => {
+                let mut decomp = Decompressor2::new(&mut data, 1)?;
+                decomp.add(&sealed).and_then(|_| decomp.finish())
+            }
+        };
+        res.map_err(|e| format!("chunk {} is damaged: {}", id, e))?;
+        if len.is_some_and(|len| len != data.len()) || self.chunk_id(&data) != id {
+            return Err(format!("chunk {} is damaged: its contents do not match its id", id));
+        }
+        Ok((data, sealed.len()))
+    }
+
+    // paths of manifests, and temporary files left by interrupted backups
+    fn manifest_paths(&self) -> Result<(Vec<String>, Vec<PathBuf>), String> {
+        let (temporary, manifests): (Vec<_>, Vec<_>) = list_dir(&Path::new(&self.path).join(MANIFESTS_DIR))?.into_iter()
+            .filter(|path| is_temporary(path) || path.extension().is_some_and(|ext| ext == MANIFEST_EXT))
+            .partition(|path| is_temporary(path));
+        Ok((manifests.into_iter().map(|path| path.to_string_lossy().into_owned()).collect(), temporary))
+    }
+
+    // ids of stored chunks, and temporary files left by interrupted backups
+    fn stored_chunks(&self) -> Result<(Vec<String>, Vec<PathBuf>), String> {
+        let mut ids = Vec::new();
+        let mut temporary = Vec::new();
+        for dir in list_dir(&Path::new(&self.path).join(CHUNKS_DIR))? {
+            for path in list_dir(&dir)? {
+                let name = path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+                if is_temporary(&path) {
+                    temporary.push(path);
+                } else if name.len() == 64 && from_hex(&name).is_some() && path == self.chunk_path(&name) {
+                    ids.push(name);
+                } else {
+                    eprintln!("skipping unknown file {}", path.display());
+                }
+            }
+        }
+        Ok((ids, temporary))
+    }
+
+    pub fn read_manifest(&self, manifest_path: &str) -> Result<Manifest, String> {
+        let text = fs::read_to_string(manifest_path).map_err(|e| format!("could not read manifest {}: {}", manifest_path, e))?;
+        Manifest::from_string(&text, &self.keys).map_err(|e| format!("{}: {}", manifest_path, e))
+    }
+
+    // stores chunks of the input missing in the repository and writes a manifest of it; new chunks are compressed
+    // and encrypted by `opts.nr_threads` threads, one chunk each, while the input is read and split
+    pub fn backup<R: Read>(&self, mut read_from: R, opts: &BackupOptions) -> Result<RepoBackup, String> {
+        let _lock = RepoLock::new(&self.path, false)?;
+        let hash_seed = timestamp();
+        if let Some(progress) = &opts.progress {
+            progress.start(Phase::Backup, None);
+        }
+        let nr_workers = opts.nr_threads.max(1);
+        let (jobs, job_rx) = mpsc::sync_channel::<(String, Vec<u8>)>(nr_workers);
+        let (done_tx, done) = mpsc::channel();
+        let job_rx = Mutex::new(job_rx);
+        let (in_len, in_hash, store) = thread::scope(|scope| {
+            for _ in 0..nr_workers {
+                let (job_rx, done_tx) = (&job_rx, done_tx.clone());
+                scope.spawn(move || loop {
+                    // the queue is locked only until a job is taken; stops once all jobs are taken and the store is gone
+                    let job = job_rx.lock().unwrap().recv();
+                    match job {
+                        Ok((id, data)) => { let _ = done_tx.send(self.store_chunk(&id, &data, opts.compress_level)); },
+                        Err(_) => break
+                    }
+                });
+            }
+            drop(done_tx);
+            let mut store = ChunkStore {
+                repo: self, jobs: Some(jobs), done, queued: HashSet::new(), nr_pending: 0,
+                chunks: Vec::new(), nr_new: 0, stored_len: 0, progress: opts.progress.clone()
+            };
+            let mut hash_copier = DataHasher::with_writer(Some(&mut store), hash_seed);
+            {
+                let mut chunker = ContentChunker::new(&mut hash_copier, self.min_chunk_len, self.avg_chunk_len, self.max_chunk_len)?;
+                let mut stdinbuf = BufferedReader::new(
+                    &mut read_from, &mut chunker, opts.buf_size_bytes / 8, opts.buf_size_bytes, opts.exit_flag.clone());
+                if let Some(progress) = &opts.progress {
+                    stdinbuf.set_progress(progress);
+                }
+                stdinbuf.read_and_write_all()?;
+            }
+            Ok::<_, String>((hash_copier.counter(), hash_copier.result(), StoredChunks::from(store)))
+        })?;
+
+        let manifest = Manifest {
+            archive_id: new_archive_id(),
+            created: hash_seed,
+            host: host_name().unwrap_or_default(),
+            in_len,
+            in_hash,
+            hash_seed,
+            labels: opts.labels.clone(),
+            chunks: store.chunks
+        };
+        let manifest_path = Path::new(&self.path).join(MANIFESTS_DIR)
+            .join(format!("{}.{}", archive_id_as_string(&manifest.archive_id), MANIFEST_EXT));
+        write_atomically(&manifest_path, manifest.as_string(&self.keys).as_bytes())?;
+        if let Some(progress) = &opts.progress {
+            progress.finish_phase();
+        }
+        Ok(RepoBackup {
+            archive_id: manifest.archive_id,
+            manifest_path: manifest_path.to_string_lossy().into_owned(),
+            in_len,
+            nr_chunks: manifest.chunks.len(),
+            nr_new_chunks: store.nr_new,
+            stored_len: store.stored_len
+        })
+    }
+
+    // reassembles data of a manifest from chunks and verifies it, writing it to `write_to` if there is one
+    pub fn restore<W: DataSink>(&self, mut write_to: Option<W>, manifest_path: &str, check_free_space: &Option<&str>, opt_progress: &Option<Progress>) -> Result<(), String> {
+        let _lock = RepoLock::new(&self.path, false)?;
+        let manifest = self.read_manifest(manifest_path)?;
+        if let Some(mount_point) = check_free_space {
+            if get_free_space(mount_point)? < manifest.in_len {
+                return Err(format!("filesystem of '{}' won't fit {} bytes of data to restore", mount_point, manifest.in_len));
+            }
+        }
+        if let Some(progress) = opt_progress {
+            progress.start(if write_to.is_some() { Phase::Restore } else { Phase::Verify }, None);
+        }
+        let mut hash_copier = DataHasher::with_writer(write_to.as_mut(), manifest.hash_seed);
+        {
+            let mut counted = CountingSink::new(&mut hash_copier, opt_progress.clone());
+            for (chunk_no, (id, len)) in manifest.chunks.iter().enumerate() {
+                let (data, sealed_len) = self.open_chunk(id, Some(*len))?;
+                if let Some(progress) = opt_progress {
+                    progress.add_in(sealed_len);
+                    progress.set_chunk(chunk_no);
+                }
+                counted.add(&data)?;
+            }
+            counted.finish()?;
+        }
+        if hash_copier.counter() != manifest.in_len || hash_copier.result() != manifest.in_hash {
+            return Err("hash verification error".to_owned());
+        }
+        if let Some(progress) = opt_progress {
+            progress.finish_phase();
+        }
+        Ok(())
+    }
+
+    // removes chunks no manifest refers to, e.g. once manifests of old backups are deleted, and files left by interrupted
+    // backups; all manifests are read before anything is removed and are never changed, so an interrupted gc leaves
+    // only unreferenced chunks behind, which the next one removes
+    pub fn gc(&self, dry_run: bool) -> Result<RepoGc, String> {
+        let _lock = RepoLock::new(&self.path, true)?;
+        let (manifests, temporary_manifests) = self.manifest_paths()?;
+        let mut referenced = HashSet::new();
+        for path in &manifests {
+            let manifest = self.read_manifest(path).map_err(|e| format!("{}; refusing to remove chunks it may refer to", e))?;
+            referenced.extend(manifest.chunks.into_iter().map(|(id, _)| id));
+        }
+        let (ids, temporary_chunks) = self.stored_chunks()?;
+        for path in temporary_manifests.iter().chain(temporary_chunks.iter()) {
+            eprintln!("{} {}, left by an interrupted backup", if dry_run { "would remove" } else { "removing" }, path.display());
+            if !dry_run {
+                fs::remove_file(path).map_err(|e| format!("could not remove {}: {}", path.display(), e))?;
+            }
+        }
+        let mut res = RepoGc { nr_manifests: manifests.len(), nr_chunks: 0, nr_removed: 0, removed_len: 0 };
+        for id in ids {
+            if referenced.contains(&id) {
+                res.nr_chunks += 1;
+                continue;
+            }
+            let path = self.chunk_path(&id);
+            res.removed_len += fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+            res.nr_removed += 1;
+            if !dry_run {
+                fs::remove_file(&path).map_err(|e| format!("could not remove chunk {}: {}", id, e))?;
+            }
+        }
+        Ok(res)
+    }
+
+    // verifies every stored chunk, decrypting it, which checks its authentication tags, and comparing its contents with
+    // its id, and that every chunk of each manifest is stored and sound; returns the numbers of manifests and chunks
+    pub fn check(&self) -> Result<(usize, usize), String> {
+        let _lock = RepoLock::new(&self.path, false)?;
+        let (ids, _) = self.stored_chunks()?;
+        let mut damaged = HashSet::new();
+        for id in &ids {
+            if let Err(e) = self.open_chunk(id, None) {
+                eprintln!("{}", e);
+                damaged.insert(id.as_str());
+            }
+        }
+        let stored = ids.iter().map(|id| id.as_str()).collect::<HashSet<_>>();
+        let (manifests, _) = self.manifest_paths()?;
+        let mut referenced = HashSet::new();
+        let mut nr_unresolvable = 0;
+        for path in &manifests {
+            let manifest = match self.read_manifest(path) {
+                Ok(manifest) => manifest,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    nr_unresolvable += 1;
+                    continue;
+                }
+            };
+            let nr_missing = manifest.chunks.iter().filter(|(id, _)| !stored.contains(id.as_str())).count();
+            let nr_damaged = manifest.chunks.iter().filter(|(id, _)| damaged.contains(id.as_str())).count();
+            if nr_missing + nr_damaged > 0 {
+                eprintln!("manifest {} refers to {} missing and {} damaged chunk(s)", path, nr_missing, nr_damaged);
+                nr_unresolvable += 1;
+            }
+            referenced.extend(manifest.chunks.into_iter().map(|(id, _)| id));
+        }
+        let nr_unreferenced = ids.iter().filter(|id| !referenced.contains(*id)).count();
+        if nr_unreferenced > 0 {
+            eprintln!("{} chunk(s) are not referenced by any manifest, gc removes them", nr_unreferenced);
+        }
+        if !damaged.is_empty() || nr_unresolvable > 0 {
+            return Err(format!("{} of {} chunks are damaged, {} of {} manifests cannot be restored", damaged.len(), ids.len(), nr_unresolvable, manifests.len()));
+        }
+        Ok((manifests.len(), ids.len()))
+    }
+}
+
+// receives chunks from ContentChunker, passes those the repository does not have yet to the workers and lists all of them
+struct ChunkStore<'a> {
+    repo: &'a Repository,
+    jobs: Option<SyncSender<(String, Vec<u8>)>>, // id and data of chunks to store
+    done: Receiver<Result<usize, String>>, // lengths of stored chunks
+    queued: HashSet<String>, // a chunk may repeat before it is stored
+    nr_pending: usize,
+    chunks: Vec<(String, usize)>,
+    nr_new: usize,
+    stored_len: usize,
+    progress: Option<Progress>
+}
+
+// what is left of ChunkStore once all chunks are stored
+struct StoredChunks {
+    chunks: Vec<(String, usize)>,
+    nr_new: usize,
+    stored_len: usize
+}
+
+impl From<ChunkStore<'_>> for StoredChunks {
+    fn from(store: ChunkStore<'_>) -> Self {
+        Self { chunks: store.chunks, nr_new: store.nr_new, stored_len: store.stored_len }
+    }
+}
+
+impl ChunkStore<'_> {
+    // accounts for chunks stored by the workers so far, or for all of them if `wait` is set
+    fn collect(&mut self, wait: bool) -> Result<(), String> {
+        while self.nr_pending > 0 {
+            let res = match wait {
+                true => self.done.recv().map_err(|_| "threads storing chunks stopped unexpectedly".to_owned())?,
+                false => match self.done.try_recv() {
+                    Ok(res) => res,
+                    Err(_) => { return Ok(()); }
+                }
+            };
+            let len = res?;
+            self.nr_pending -= 1;
+            self.nr_new += 1;
+            self.stored_len += len;
+            if let Some(progress) = &self.progress {
+                progress.add_out(len);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl DataSink for ChunkStore<'_> {
+    fn add(&mut self, data: &[u8]) -> Result<(), String> {
+        let id = self.repo.chunk_id(data);
+        // a chunk of the same id has the same contents, it may be written by another backup at the same time
+        if !self.queued.contains(&id) && !self.repo.chunk_path(&id).exists() {
+            let jobs = self.jobs.as_ref().ok_or("chunks are added after finish".to_owned())?;
+            jobs.send((id.clone(), data.to_vec())).map_err(|_| "threads storing chunks stopped unexpectedly".to_owned())?;
+            self.queued.insert(id.clone());
+            self.nr_pending += 1;
+        }
+        self.collect(false)?;
+        self.chunks.push((id, data.len()));
+        if let Some(progress) = &self.progress {
+            progress.set_chunk(self.chunks.len() - 1);
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), String> {
+        self.jobs = None;
+        self.collect(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Manifest, RepoKeys, Repository, CHUNK_SALT_LEN, is_manifest_path, repository_of, write_atomically};
+    use crate::arg_opts::Alg;
+    use crate::stats::Label;
+    use rand::RngCore;
+
+    #[test]
+    fn manifest() {
+        let keys = RepoKeys::new(&Some("secret".to_owned()), b"salt");
+        let manifest = Manifest {
+            archive_id: [7; 16],
+            created: 1700000000,
+            host: "db1".to_owned(),
+            in_len: 300,
+            in_hash: 0xabc,
+            hash_seed: 0x123,
+            labels: vec![Label::parse("db=main").unwrap(), Label::parse("nightly").unwrap()],
+            chunks: vec![("aa01".to_owned(), 100), ("bb02".to_owned(), 200)]
+        };
+        let text = manifest.as_string(&keys);
+        let parsed = Manifest::from_string(&text, &keys).unwrap();
+        assert_eq!((parsed.archive_id, parsed.created, parsed.host.as_str()), ([7; 16], 1700000000, "db1"));
+        assert_eq!((parsed.in_len, parsed.in_hash, parsed.hash_seed), (300, 0xabc, 0x123));
+        assert_eq!(parsed.labels, manifest.labels);
+        assert_eq!(parsed.chunks, manifest.chunks);
+
+        assert!(Manifest::from_string(&text.replace("in_len=300", "in_len=301"), &keys).is_err());
+        assert!(Manifest::from_string(&text, &RepoKeys::new(&Some("other".to_owned()), b"salt")).is_err());
+        assert!(Manifest::from_string(&text, &RepoKeys::new(&None, b"salt")).is_err());
+        let keys = RepoKeys::new(&None, b"salt");
+        let empty = Manifest { chunks: Vec::new(), labels: Vec::new(), ..manifest };
+        assert!(Manifest::from_string(&empty.as_string(&keys), &keys).unwrap().chunks.is_empty());
+
+        assert!(is_manifest_path("/bk/repo/manifests/1b4e28ba-2fa1-41d2-883f-0016d3cca427.manifest"));
+        assert!(!is_manifest_path("/bk/repo/1b4e28ba-2fa1-41d2-883f-0016d3cca427.manifest"));
+        assert!(!is_manifest_path("/bk/manifests/0.cfg"));
+        assert_eq!(repository_of("/bk/repo/manifests/x.manifest").unwrap(), "/bk/repo");
+        assert_eq!(repository_of("manifests/x.manifest").unwrap(), ".");
+    }
+
+    #[test]
+    fn chunk_sealed_again() {
+        let dir = "/tmp/repo_chunk_sealed_again";
+        let _ = std::fs::remove_dir_all(dir);
+        let repo = Repository::create(dir, &Alg::Aes128Gcm, &Some("secret".to_owned()), 4096).unwrap();
+        let mut data = vec![0; 10000];
+        rand::thread_rng().fill_bytes(&mut data);
+        let id = repo.chunk_id(&data);
+
+        // e.g. by backups with different compression levels, so its contents to encrypt differ
+        let sealed = [repo.seal(&id, &data, 0).unwrap(), repo.seal(&id, &data, 9).unwrap()];
+        let key = |sealed: &[u8]| repo.keys.chunk_key(&id, &sealed[..CHUNK_SALT_LEN], 16);
+        assert_ne!(key(&sealed[0]), key(&sealed[1]));
+        assert_ne!(key(&sealed[0]), key(&repo.seal(&id, &data, 0).unwrap()));
+        std::fs::create_dir_all(repo.chunk_path(&id).parent().unwrap()).unwrap();
+        for sealed in sealed {
+            write_atomically(&repo.chunk_path(&id), &sealed).unwrap();
+            assert_eq!(repo.open_chunk(&id, Some(data.len())).unwrap(), (data.clone(), sealed.len()));
+        }
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
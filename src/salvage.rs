@@ -0,0 +1,741 @@
+// Best-effort decoding of a damaged compressed stream. Lost portions of the stream
+// (blocks failing authentication, missing chunks) are reported by the upstream sinks
+// via `add_lost`; the xz block containing them is dropped and decoding resumes at the
+// next xz block, which is independent of the previous ones. Blocks are located by the
+// xz index read from the tail of the stream or, if the index is lost, by the sizes
+// stored in block headers (these are written by the multi-threaded encoder only).
+
+use liblzma::stream::{Action, Filters, LzmaOptions, Status, Stream};
+use crate::enc_dec::BlockDecryptor;
+use crate::file_set::FileSet;
+use crate::finalizable::DataSink;
+use crate::joiner::{MultiFilesReaderSource, read_whole_file};
+use crate::stats::Stats;
+use crate::chunk_header::payload_range;
+
+const STREAM_HEADER_MAGIC: [u8; 6] = [0xfd, b'7', b'z', b'X', b'Z', 0];
+const STREAM_FOOTER_MAGIC: [u8; 2] = [b'Y', b'Z'];
+const STREAM_HEADER_LEN: usize = 12;
+const LZMA2_FILTER_ID: usize = 0x21;
+const OUT_BUF_SIZE: usize = 1024 * 1024;
+
+const fn build_crc32_table() -> [u32; 256] {
+    let mut t = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut c = i as u32;
+        let mut k = 0;
+        while k < 8 {
+            c = if c & 1 != 0 { 0xedb88320 ^ (c >> 1) } else { c >> 1 };
+            k += 1;
+        }
+        t[i] = c;
+        i += 1;
+    }
+    t
+}
+
+static CRC32_TABLE: [u32; 256] = build_crc32_table();
+
+fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |c, &b| CRC32_TABLE[((c ^ b as u32) & 0xff) as usize] ^ (c >> 8))
+}
+
+fn le_u32(b: &[u8]) -> u32 {
+    u32::from_le_bytes([b[0], b[1], b[2], b[3]])
+}
+
+// variable-length integer of xz format
+fn read_vli(buf: &[u8], pos: &mut usize) -> Result<usize, String> {
+    let mut v: u64 = 0;
+    for i in 0..9 {
+        let b = *buf.get(*pos).ok_or("truncated integer".to_owned())?;
+        *pos += 1;
+        v |= ((b & 0x7f) as u64) << (7 * i);
+        if b & 0x80 == 0 {
+            return Ok(v as usize);
+        }
+    }
+    Err("integer is too long".to_owned())
+}
+
+fn check_size(stream_flags: &[u8]) -> Result<usize, String> {
+    if stream_flags[0] != 0 || stream_flags[1] & 0xf0 != 0 {
+        return Err("unsupported stream flags".to_owned());
+    }
+    let check_type = stream_flags[1] as usize;
+    Ok(if check_type == 0 { 0 } else { 4 << ((check_type - 1) / 3) })
+}
+
+// returns compressed and uncompressed sizes of the block (if stored) and LZMA2 dictionary size
+fn parse_block_header(h: &[u8]) -> Result<(Option<usize>, Option<usize>, u32), String> {
+    let (body, crc) = h.split_at(h.len() - 4);
+    if crc32(body) != le_u32(crc) {
+        return Err("block header checksum mismatch".to_owned());
+    }
+    let flags = body[1];
+    if flags & 0x3c != 0 {
+        return Err("unsupported block flags".to_owned());
+    }
+    if flags & 0x03 != 0 {
+        return Err("only single LZMA2 filter is supported".to_owned());
+    }
+    let mut pos = 2;
+    let compressed = if flags & 0x40 != 0 { Some(read_vli(body, &mut pos)?) } else { None };
+    let uncompressed = if flags & 0x80 != 0 { Some(read_vli(body, &mut pos)?) } else { None };
+    if read_vli(body, &mut pos)? != LZMA2_FILTER_ID || read_vli(body, &mut pos)? != 1 {
+        return Err("only single LZMA2 filter is supported".to_owned());
+    }
+    let p = *body.get(pos).ok_or("truncated filter properties".to_owned())?;
+    let dict_size = match p {
+        0..=39 => (2 | (p as u32 & 1)) << (p / 2 + 11),
+        40 => u32::MAX,
+        _ => return Err("invalid LZMA2 dictionary size".to_owned())
+    };
+    Ok((compressed, uncompressed, dict_size))
+}
+
+fn lzma2_raw_decoder(dict_size: u32) -> Result<Stream, String> {
+    let mut opts = LzmaOptions::new_preset(6).map_err(|e| format!("could not create LZMA2 options: {}", e))?;
+    opts.dict_size(dict_size);
+    let mut filters = Filters::new();
+    filters.lzma2(&opts);
+    Stream::new_raw_decoder(&filters).map_err(|e| format!("could not create LZMA2 decoder: {}", e))
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct XzBlock {
+    pub start: usize,
+    pub len: usize, // including header, padding and check
+    pub uncompressed_len: usize
+}
+
+#[derive(Debug, PartialEq)]
+pub struct XzLayout {
+    pub check_size: usize,
+    pub blocks: Vec<XzBlock>
+}
+
+impl XzLayout {
+    // returns check size and length of the index preceding the footer
+    fn parse_footer(footer: &[u8]) -> Result<(usize, usize), String> {
+        if footer.len() != STREAM_HEADER_LEN || footer[10..] != STREAM_FOOTER_MAGIC {
+            return Err("no stream footer".to_owned());
+        }
+        if crc32(&footer[4..10]) != le_u32(&footer[..4]) {
+            return Err("stream footer checksum mismatch".to_owned());
+        }
+        Ok((check_size(&footer[8..10])?, (le_u32(&footer[4..8]) as usize + 1) * 4))
+    }
+
+    fn parse_index(index: &[u8], check_size: usize, index_start: usize) -> Result<XzLayout, String> {
+        if index.len() < 8 || index[0] != 0 {
+            return Err("no index".to_owned());
+        }
+        let (body, crc) = index.split_at(index.len() - 4);
+        if crc32(body) != le_u32(crc) {
+            return Err("index checksum mismatch".to_owned());
+        }
+        let mut pos = 1;
+        let nr_blocks = read_vli(body, &mut pos)?;
+        let mut blocks = Vec::new();
+        let mut start = STREAM_HEADER_LEN;
+        for _ in 0..nr_blocks {
+            let len = read_vli(body, &mut pos)?.next_multiple_of(4);
+            let uncompressed_len = read_vli(body, &mut pos)?;
+            blocks.push(XzBlock { start, len, uncompressed_len });
+            start += len;
+        }
+        if start != index_start {
+            return Err("index does not match stream length".to_owned());
+        }
+        Ok(XzLayout { check_size, blocks })
+    }
+}
+
+// reads layout of the compressed stream from the index at its tail
+pub fn read_layout<R: MultiFilesReaderSource>(
+    from: &mut R, file_set: &FileSet, stats: &Stats, dec: Option<&BlockDecryptor>, read_buf: &mut [u8]) -> Result<XzLayout, String>
+{
+    let stream_len = stats.compressed_len;
+    if stream_len < 2 * STREAM_HEADER_LEN {
+        return Err("compressed stream is too short".to_owned());
+    }
+    let footer_start = stream_len - STREAM_HEADER_LEN;
+    let footer = read_compressed(from, file_set, stats, dec, footer_start, STREAM_HEADER_LEN, read_buf)?;
+    let (check_size, index_len) = XzLayout::parse_footer(&footer)?;
+    let index_start = footer_start.checked_sub(index_len)
+        .filter(|&s| s >= STREAM_HEADER_LEN)
+        .ok_or("index is longer than the stream".to_owned())?;
+    let index = read_compressed(from, file_set, stats, dec, index_start, index_len, read_buf)?;
+    XzLayout::parse_index(&index, check_size, index_start)
+}
+
+// reads a range of the compressed stream, decrypting the authenticated blocks it spans if needed
+fn read_compressed<R: MultiFilesReaderSource>(
+    from: &mut R, file_set: &FileSet, stats: &Stats, dec: Option<&BlockDecryptor>, offset: usize, len: usize, read_buf: &mut [u8]) -> Result<Vec<u8>, String>
+{
+    let Some(dec) = dec else {
+        return read_stored(from, file_set, stats, offset, len, read_buf);
+    };
+    let plain_block = stats.auth_chunk_size;
+    let enc_block = plain_block + dec.tag_len();
+    let first_block = offset / plain_block;
+    let last_block = (offset + len - 1) / plain_block;
+    let last_block_len = usize::min(stats.compressed_len - last_block * plain_block, plain_block) + dec.tag_len();
+    let stored = read_stored(from, file_set, stats,
+        first_block * enc_block, (last_block - first_block) * enc_block + last_block_len, read_buf)?;
+
+    let mut plain = Vec::new();
+    for (i, block) in stored.chunks(enc_block).enumerate() {
+        plain.extend_from_slice(&dec.decrypt((first_block + i) as u64, block)?);
+    }
+    let skip = offset - first_block * plain_block;
+    Ok(plain[skip..skip + len].to_vec())
+}
+
+// reads a range of data as it is stored in chunks
+fn read_stored<R: MultiFilesReaderSource>(
+    from: &mut R, file_set: &FileSet, stats: &Stats, offset: usize, len: usize, read_buf: &mut [u8]) -> Result<Vec<u8>, String>
+{
+    let mut out = Vec::with_capacity(len);
+    let mut chunk_start = 0;
+    let mut chunk_no = 0;
+    while out.len() < len {
+        let payload = match stats.chunks.get(chunk_no) {
+            Some(_) => payload_range(stats, chunk_no),
+            None if stats.chunks.is_empty() => 0..stats.out_chunk_size,
+            None => return Err("range is beyond the last chunk".to_owned())
+        };
+        let chunk_len = payload.len();
+        let pos = offset + out.len();
+        if pos < chunk_start + chunk_len {
+            let path = file_set.gen_file_path(chunk_no);
+            let (range_start, range_end) = (payload.start + pos - chunk_start, payload.start + usize::min(chunk_len, pos - chunk_start + len - out.len()));
+            let mut file_offs = 0;
+            let found = read_whole_file(from, path.as_str(), read_buf, |data| {
+                let (b, e) = (usize::max(range_start, file_offs), usize::min(range_end, file_offs + data.len()));
+                if b < e {
+                    out.extend_from_slice(&data[b - file_offs..e - file_offs]);
+                }
+                file_offs += data.len();
+                Ok(())
+            })?;
+            if !found {
+                return Err(format!("chunk {} is missing", path));
+            }
+            if file_offs < range_end {
+                return Err(format!("chunk {} is truncated", path));
+            }
+        }
+        chunk_start += chunk_len;
+        chunk_no += 1;
+    }
+    Ok(out)
+}
+
+enum State {
+    StreamHeader,
+    BlockHeader,
+    BlockData(Stream),
+    Skip(usize), // rest of the current block (or the stream header) up to the indicated position
+    Index,       // index and footer, nothing more to decode
+    Desync       // next block cannot be located, the rest of data is lost
+}
+
+enum Decoded {
+    NeedMore,
+    End,
+    Corrupted(String)
+}
+
+pub struct SalvageDecompressor<'a, T: DataSink> {
+    to: &'a mut T,
+    layout: Option<XzLayout>,
+    total_len: usize,
+    check_size: Option<usize>,
+    state: State,
+    pos: usize,
+    header: Vec<u8>,
+    block_no: usize,
+    block_start: usize,
+    block_end: Option<usize>,
+    block_len: Option<usize>,
+    block_out: usize,
+    out_len: usize,
+    out_buf: Vec<u8>
+}
+
+impl<'a, T: DataSink> SalvageDecompressor<'a, T> {
+    // `total_len` is the length of decompressed data, lost tail of the stream is reported up to it
+    pub fn new(to: &'a mut T, layout: Option<XzLayout>, total_len: usize) -> SalvageDecompressor<'a, T> {
+        SalvageDecompressor {
+            to,
+            check_size: layout.as_ref().map(|l| l.check_size),
+            layout,
+            total_len,
+            state: State::StreamHeader,
+            pos: 0,
+            header: Vec::new(),
+            block_no: 0,
+            block_start: 0,
+            block_end: None,
+            block_len: None,
+            block_out: 0,
+            out_len: 0,
+            out_buf: vec![0; OUT_BUF_SIZE]
+        }
+    }
+
+    fn feed(&mut self, data: Option<&[u8]>, len: usize) -> Result<(), String> {
+        let mut offs = 0;
+        while offs < len {
+            let portion = data.map(|d| &d[offs..]);
+            let consumed = self.step(portion, len - offs)?;
+            offs += consumed;
+            self.pos += consumed;
+        }
+        Ok(())
+    }
+
+    // consumes some (possibly none) of `len` bytes, `data` is none if they are lost
+    fn step(&mut self, data: Option<&[u8]>, len: usize) -> Result<usize, String> {
+        match self.state {
+            State::StreamHeader => {
+                let Some(data) = data else {
+                    self.header.clear();
+                    return self.lose_stream_header();
+                };
+                let take = usize::min(STREAM_HEADER_LEN - self.header.len(), len);
+                self.header.extend_from_slice(&data[..take]);
+                if self.header.len() == STREAM_HEADER_LEN {
+                    let h = std::mem::take(&mut self.header);
+                    if h[..6] != STREAM_HEADER_MAGIC || crc32(&h[6..8]) != le_u32(&h[8..]) {
+                        eprintln!("stream header of compressed data is damaged");
+                        return self.lose_stream_header().map(|_| take);
+                    }
+                    self.check_size = Some(check_size(&h[6..8])?);
+                    self.begin_block(STREAM_HEADER_LEN);
+                }
+                Ok(take)
+            },
+            State::BlockHeader => {
+                let Some(data) = data else {
+                    self.lose_block(format!("header of xz block #{} is lost", self.block_no))?;
+                    return Ok(0);
+                };
+                if self.header.is_empty() && data[0] == 0 && self.layout.is_none() {
+                    self.state = State::Index; // index indicator instead of block header
+                    return Ok(0);
+                }
+                let header_len = (*self.header.first().unwrap_or(&data[0]) as usize + 1) * 4;
+                let take = usize::min(header_len - self.header.len(), len);
+                self.header.extend_from_slice(&data[..take]);
+                if self.header.len() == header_len {
+                    match parse_block_header(&self.header).and_then(|(compressed, uncompressed, dict_size)| {
+                        Ok((compressed, uncompressed, lzma2_raw_decoder(dict_size)?))
+                    }) {
+                        Ok((compressed, uncompressed, decoder)) => {
+                            if self.block_end.is_none() {
+                                self.block_end = compressed.zip(self.check_size)
+                                    .map(|(c, check)| self.block_start + header_len + c.next_multiple_of(4) + check);
+                            }
+                            self.block_len = self.block_len.or(uncompressed);
+                            self.state = State::BlockData(decoder);
+                        },
+                        Err(e) => self.lose_block(format!("header of xz block #{} is damaged: {}", self.block_no, e))?
+                    }
+                }
+                Ok(take)
+            },
+            State::BlockData(_) => {
+                let Some(data) = data else {
+                    self.lose_block(format!("xz block #{} is lost", self.block_no))?;
+                    return Ok(0);
+                };
+                let limit = self.block_end.map_or(len, |end| usize::min(len, end - self.pos));
+                let (consumed, decoded) = self.decode(&data[..limit])?;
+                match decoded {
+                    Decoded::End => {
+                        if self.block_end.is_none() {
+                            let data_end = self.pos + consumed;
+                            self.block_end = self.check_size
+                                .map(|check| self.block_start + (data_end - self.block_start).next_multiple_of(4) + check);
+                        }
+                        if self.block_len.is_some_and(|l| l != self.block_out) {
+                            self.lose_block(format!("xz block #{} has unexpected length", self.block_no))?;
+                        } else {
+                            self.end_block();
+                        }
+                    },
+                    Decoded::Corrupted(e) => self.lose_block(format!("xz block #{} is corrupted: {}", self.block_no, e))?,
+                    Decoded::NeedMore if self.block_end == Some(self.pos + consumed) => {
+                        self.lose_block(format!("xz block #{} is truncated", self.block_no))?;
+                    },
+                    Decoded::NeedMore => {}
+                }
+                Ok(consumed)
+            },
+            State::Skip(until) => {
+                if self.pos > until {
+                    eprintln!("xz block #{} overlaps the previous one, the rest of data is lost", self.block_no);
+                    self.state = State::Desync;
+                    return Ok(0);
+                }
+                if self.pos == until {
+                    self.begin_block(until);
+                    return Ok(0);
+                }
+                Ok(usize::min(len, until - self.pos))
+            },
+            State::Index | State::Desync => Ok(len)
+        }
+    }
+
+    fn decode(&mut self, input: &[u8]) -> Result<(usize, Decoded), String> {
+        let State::BlockData(stream) = &mut self.state else {
+            unreachable!();
+        };
+        let mut consumed = 0;
+        loop {
+            let (in_before, out_before) = (stream.total_in(), stream.total_out());
+            let res = stream.process(&input[consumed..], &mut self.out_buf, Action::Run);
+            let consumed_now = (stream.total_in() - in_before) as usize;
+            let produced = (stream.total_out() - out_before) as usize;
+            consumed += consumed_now;
+            if produced > 0 {
+                self.to.add(&self.out_buf[..produced])?;
+                self.block_out += produced;
+                self.out_len += produced;
+            }
+            match res {
+                Ok(Status::StreamEnd) => return Ok((consumed, Decoded::End)),
+                Ok(_) if produced == self.out_buf.len() => {},
+                Ok(_) if consumed == input.len() => return Ok((consumed, Decoded::NeedMore)),
+                Ok(_) if consumed_now == 0 && produced == 0 => return Ok((consumed, Decoded::Corrupted("no progress".to_owned()))),
+                Ok(_) => {},
+                Err(e) => return Ok((consumed, Decoded::Corrupted(e.to_string())))
+            }
+        }
+    }
+
+    fn begin_block(&mut self, start: usize) {
+        self.header.clear();
+        self.block_start = start;
+        self.block_out = 0;
+        self.block_end = None;
+        self.block_len = None;
+        self.state = State::BlockHeader;
+        if let Some(layout) = &self.layout {
+            match layout.blocks.get(self.block_no) {
+                Some(b) => {
+                    self.block_end = Some(b.start + b.len);
+                    self.block_len = Some(b.uncompressed_len);
+                },
+                None => self.state = State::Index
+            }
+        }
+    }
+
+    fn end_block(&mut self) {
+        self.block_no += 1;
+        match self.block_end {
+            Some(end) => self.state = State::Skip(end),
+            None => self.state = State::Desync
+        }
+    }
+
+    fn lose_stream_header(&mut self) -> Result<usize, String> {
+        if self.layout.is_some() {
+            self.state = State::Skip(STREAM_HEADER_LEN);
+        } else {
+            eprintln!("stream header of compressed data is lost, cannot locate blocks without index");
+            self.state = State::Desync;
+        }
+        Ok(0)
+    }
+
+    // drops the rest of the current block, reporting its undecoded part as lost
+    fn lose_block(&mut self, reason: String) -> Result<(), String> {
+        match (self.block_len, self.block_end) {
+            (Some(block_len), Some(_)) => {
+                let lost = block_len.saturating_sub(self.block_out);
+                eprintln!("{}, {} decompressed bytes are lost", reason, lost);
+                self.to.add_lost(lost)?;
+                self.out_len += lost;
+                self.block_out = block_len;
+                self.end_block();
+            },
+            _ => {
+                eprintln!("{}, next block cannot be located, the rest of data is lost", reason);
+                self.state = State::Desync;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<'a, T: DataSink> DataSink for SalvageDecompressor<'a, T> {
+    fn add(&mut self, data: &[u8]) -> Result<(), String> {
+        self.feed(Some(data), data.len())
+    }
+
+    fn add_lost(&mut self, len: usize) -> Result<(), String> {
+        self.feed(None, len)
+    }
+
+    fn finish(&mut self) -> Result<(), String> {
+        if let State::BlockHeader | State::BlockData(_) = self.state {
+            eprintln!("compressed stream ends in the middle of xz block #{}", self.block_no);
+        }
+        if self.out_len < self.total_len {
+            self.to.add_lost(self.total_len - self.out_len)?;
+            self.out_len = self.total_len;
+        }
+        self.to.finish()
+    }
+}
+
+// writes zeros in place of lost data (or just skips it) and collects lost ranges
+pub struct LostDataFiller<'a, T: DataSink> {
+    to: &'a mut T,
+    zero_fill: bool,
+    pos: usize,
+    lost: Vec<(usize, usize)>
+}
+
+impl<'a, T: DataSink> LostDataFiller<'a, T> {
+    pub fn new(to: &'a mut T, zero_fill: bool) -> LostDataFiller<'a, T> {
+        LostDataFiller { to, zero_fill, pos: 0, lost: Vec::new() }
+    }
+
+    // offsets and lengths of lost ranges of the original data
+    pub fn lost_ranges(&self) -> &[(usize, usize)] {
+        &self.lost
+    }
+}
+
+impl<'a, T: DataSink> DataSink for LostDataFiller<'a, T> {
+    fn add(&mut self, data: &[u8]) -> Result<(), String> {
+        self.pos += data.len();
+        self.to.add(data)
+    }
+
+    fn add_lost(&mut self, len: usize) -> Result<(), String> {
+        if len == 0 {
+            return Ok(());
+        }
+        match self.lost.last_mut() {
+            Some((offs, lost_len)) if *offs + *lost_len == self.pos => *lost_len += len,
+            _ => self.lost.push((self.pos, len))
+        }
+        self.pos += len;
+        if self.zero_fill {
+            let zeros = vec![0; usize::min(len, OUT_BUF_SIZE)];
+            let mut left = len;
+            while left > 0 {
+                let portion = usize::min(left, zeros.len());
+                self.to.add(&zeros[..portion])?;
+                left -= portion;
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), String> {
+        self.to.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use liblzma::stream::MtStreamBuilder;
+    use liblzma::write::XzEncoder;
+    use std::io::Write;
+    use rand::{thread_rng, RngCore};
+
+    const BLOCK_SIZE: usize = 4096;
+
+    struct Sink {
+        data: Vec<u8>
+    }
+
+    impl DataSink for Sink {
+        fn add(&mut self, data: &[u8]) -> Result<(), String> {
+            self.data.extend_from_slice(data);
+            Ok(())
+        }
+        fn finish(&mut self) -> Result<(), String> {
+            Ok(())
+        }
+    }
+
+    // half-random data of 4 full blocks and a shorter one
+    fn source() -> Vec<u8> {
+        let mut src = vec![0; 4 * BLOCK_SIZE + 1000];
+        for portion in src.chunks_mut(100).step_by(2) {
+            thread_rng().fill_bytes(portion);
+        }
+        src
+    }
+
+    fn compress(src: &[u8], multi_block: bool) -> Vec<u8> {
+        let mut enc = if multi_block {
+            let stream = MtStreamBuilder::new().preset(6).threads(2).block_size(BLOCK_SIZE as u64).encoder().unwrap();
+            XzEncoder::new_stream(Vec::new(), stream)
+        } else {
+            XzEncoder::new(Vec::new(), 6)
+        };
+        enc.write_all(src).unwrap();
+        enc.finish().unwrap()
+    }
+
+    fn layout_of(xz: &[u8]) -> XzLayout {
+        let footer_start = xz.len() - STREAM_HEADER_LEN;
+        let (check_size, index_len) = XzLayout::parse_footer(&xz[footer_start..]).unwrap();
+        XzLayout::parse_index(&xz[footer_start - index_len..footer_start], check_size, footer_start - index_len).unwrap()
+    }
+
+    // feeds the stream in small portions with bytes of `lost` range reported as lost
+    fn salvage(xz: &[u8], layout: Option<XzLayout>, lost: (usize, usize), total_len: usize) -> (Vec<u8>, Vec<(usize, usize)>) {
+        let mut sink = Sink { data: Vec::new() };
+        let mut filler = LostDataFiller::new(&mut sink, true);
+        {
+            let mut decomp = SalvageDecompressor::new(&mut filler, layout, total_len);
+            for (i, portion) in xz.chunks(333).enumerate() {
+                let (b, e) = (i * 333, i * 333 + portion.len());
+                let (lb, le) = (usize::max(b, lost.0), usize::min(e, lost.1));
+                if lb < le {
+                    decomp.add(&portion[..lb - b]).unwrap();
+                    decomp.add_lost(le - lb).unwrap();
+                    decomp.add(&portion[le - b..]).unwrap();
+                } else {
+                    decomp.add(portion).unwrap();
+                }
+            }
+            decomp.finish().unwrap();
+        }
+        let ranges = filler.lost_ranges().to_vec();
+        (sink.data, ranges)
+    }
+
+    fn assert_salvaged(src: &[u8], restored: &[u8], ranges: &[(usize, usize)]) {
+        assert_eq!(src.len(), restored.len());
+        let mut expected = src.to_vec();
+        for &(offs, len) in ranges {
+            expected[offs..offs + len].fill(0);
+        }
+        assert_eq!(expected, restored);
+    }
+
+    #[test]
+    fn crc32_known() {
+        assert_eq!(crc32(b"123456789"), 0xcbf43926);
+    }
+
+    #[test]
+    fn layout_from_index() {
+        let src = source();
+        let xz = compress(&src, true);
+        let layout = layout_of(&xz);
+        assert_eq!(layout.blocks.len(), 5);
+        assert_eq!(layout.blocks[0].start, STREAM_HEADER_LEN);
+        assert!(layout.blocks[..4].iter().all(|b| b.uncompressed_len == BLOCK_SIZE));
+        assert_eq!(layout.blocks[4].uncompressed_len, 1000);
+
+        let footer_start = xz.len() - STREAM_HEADER_LEN;
+        let (check_size, index_len) = XzLayout::parse_footer(&xz[footer_start..]).unwrap();
+        let mut index = xz[footer_start - index_len..footer_start].to_vec();
+        index[2] ^= 1;
+        assert!(XzLayout::parse_index(&index, check_size, footer_start - index_len).is_err());
+    }
+
+    #[test]
+    fn intact_stream() {
+        let src = source();
+        for multi_block in [false, true] {
+            let xz = compress(&src, multi_block);
+            for layout in [None, Some(layout_of(&xz))] {
+                let (restored, ranges) = salvage(&xz, layout, (0, 0), src.len());
+                assert!(ranges.is_empty());
+                assert_eq!(restored, src);
+            }
+        }
+    }
+
+    #[test]
+    fn lost_in_the_middle_of_block() {
+        let src = source();
+        let xz = compress(&src, true);
+        let b = layout_of(&xz).blocks[1].clone();
+        let lost = (b.start + b.len / 2, b.start + b.len / 2 + 10);
+        for layout in [None, Some(layout_of(&xz))] {
+            let (restored, ranges) = salvage(&xz, layout, lost, src.len());
+            assert_eq!(ranges.len(), 1);
+            assert!(ranges[0].0 >= BLOCK_SIZE && ranges[0].0 + ranges[0].1 == 2 * BLOCK_SIZE);
+            assert_salvaged(&src, &restored, &ranges);
+        }
+    }
+
+    #[test]
+    fn lost_across_blocks() {
+        let src = source();
+        let xz = compress(&src, true);
+        let layout = layout_of(&xz);
+        let lost = (layout.blocks[1].start + 5, layout.blocks[3].start + 5);
+
+        // headers of lost blocks are not needed with index
+        let (restored, ranges) = salvage(&xz, Some(layout), lost, src.len());
+        assert_eq!(ranges, vec![(BLOCK_SIZE, 3 * BLOCK_SIZE)]);
+        assert_salvaged(&src, &restored, &ranges);
+
+        // without index, the rest of the stream cannot be located
+        let (restored, ranges) = salvage(&xz, None, lost, src.len());
+        assert_eq!(ranges, vec![(BLOCK_SIZE, src.len() - BLOCK_SIZE)]);
+        assert_salvaged(&src, &restored, &ranges);
+    }
+
+    #[test]
+    fn lost_in_single_block_stream() {
+        let src = source();
+        let xz = compress(&src, false);
+        let lost = (xz.len() / 2, xz.len() / 2 + 1);
+        for layout in [None, Some(layout_of(&xz))] {
+            let (restored, ranges) = salvage(&xz, layout, lost, src.len());
+            assert_eq!(ranges.len(), 1);
+            assert_eq!(ranges[0].0 + ranges[0].1, src.len());
+            assert_salvaged(&src, &restored, &ranges);
+        }
+    }
+
+    #[test]
+    fn corrupted_block_data() {
+        let src = source();
+        let mut xz = compress(&src, true);
+        let layout = layout_of(&xz);
+        let b = layout.blocks[2].clone();
+        for byte in &mut xz[b.start + 20..b.start + 60] {
+            *byte ^= 0x55;
+        }
+        let (restored, ranges) = salvage(&xz, Some(layout), (0, 0), src.len());
+        assert_eq!(ranges.len(), 1);
+        assert!(ranges[0].0 >= 2 * BLOCK_SIZE && ranges[0].0 + ranges[0].1 <= 3 * BLOCK_SIZE);
+        assert_eq!(restored.len(), src.len());
+        assert_eq!(restored[..2 * BLOCK_SIZE], src[..2 * BLOCK_SIZE]);
+        assert_eq!(restored[3 * BLOCK_SIZE..], src[3 * BLOCK_SIZE..]);
+    }
+
+    #[test]
+    fn skip_lost_data() {
+        let mut sink = Sink { data: Vec::new() };
+        let mut filler = LostDataFiller::new(&mut sink, false);
+        filler.add(&[1, 2]).unwrap();
+        filler.add_lost(3).unwrap();
+        filler.add_lost(2).unwrap();
+        filler.add(&[3]).unwrap();
+        filler.add_lost(1).unwrap();
+        assert_eq!(filler.lost_ranges(), &[(2, 5), (8, 1)]);
+        assert_eq!(sink.data, vec![1, 2, 3]);
+    }
+}
@@ -0,0 +1,607 @@
+use crate::joiner::MultiFilesReaderSource;
+use crate::splitter::MultiFilesWriterTarget;
+use ssh2::{CheckResult, ErrorCode, File, FileStat, KnownHostFileKind, OpenFlags, OpenType, RenameFlags, Session, Sftp};
+use std::cell::RefCell;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::net::TcpStream;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+pub const SFTP_PREFIX: &str = "sftp://";
+const NO_SUCH_FILE: i32 = 2; // LIBSSH2_FX_NO_SUCH_FILE
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+const TIMEOUT_MS: u32 = 60_000;
+// written data kept to resume the current chunk after reconnect, as the server may
+// not have stored everything which was sent before the connection broke
+const RESUME_WINDOW: usize = 8 * 1_048_576;
+
+// key-based access to remote hosts; paths look like sftp://user@host:port/path
+#[derive(Clone)]
+pub struct SftpConfig {
+    pub key_path: Option<String>, // ssh-agent is tried first, then this key or default ones in ~/.ssh
+    pub known_hosts: Option<String>, // defaults to ~/.ssh/known_hosts
+    pub retries: usize,
+    pub first_backoff: Duration
+}
+
+impl SftpConfig {
+    pub fn new(key_path: &Option<String>) -> Self {
+        Self { key_path: key_path.clone(), known_hosts: None, retries: 5, first_backoff: Duration::from_secs(1) }
+    }
+}
+
+pub fn is_sftp_path(path: &str) -> bool {
+    path.starts_with(SFTP_PREFIX)
+}
+
+#[derive(PartialEq, Debug)]
+struct Location {
+    user: String,
+    host: String,
+    port: u16,
+    path: String
+}
+
+impl Location {
+    fn parse(url: &str) -> Result<Self, String> {
+        let err = || format!("{} is not like sftp://user@host/path", url);
+        let rest = url.strip_prefix(SFTP_PREFIX).ok_or_else(err)?;
+        let (authority, path) = rest.split_at(rest.find('/').ok_or_else(err)?);
+        let (user, host_port) = match authority.rsplit_once('@') {
+            Some((user, host_port)) => (user.to_owned(), host_port),
+            None => (std::env::var("USER").map_err(|_| format!("no user in {} and USER is not set", url))?, authority)
+        };
+        let (host, port) = match host_port.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse().map_err(|_| format!("invalid port in {}", url))?),
+            None => (host_port, 22)
+        };
+        if user.is_empty() || host.is_empty() || path.len() < 2 {
+            return Err(err());
+        }
+        Ok(Self { user, host: host.to_owned(), port, path: path.to_owned() })
+    }
+
+    fn remote(&self) -> String {
+        format!("{}@{}:{}", self.user, self.host, self.port)
+    }
+}
+
+struct Connection {
+    remote: String,
+    sftp: Sftp,
+    _session: Session
+}
+
+fn home_file(name: &str) -> Option<String> {
+    std::env::var("HOME").ok().map(|home| format!("{}/.ssh/{}", home, name))
+}
+
+fn sftp_err(what: &str, e: ssh2::Error) -> String {
+    format!("could not {}: {}", what, e)
+}
+
+fn is_not_found(e: &ssh2::Error) -> bool {
+    e.code() == ErrorCode::SFTP(NO_SUCH_FILE)
+}
+
+// chunks of a sharded archive go to directories which may not exist yet
+fn create_parents(sftp: &Sftp, path: &Path) -> Result<(), String> {
+    let parent = match path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        Some(parent) => parent,
+        None => { return Ok(()); }
+    };
+    if sftp.stat(parent).is_ok() {
+        return Ok(());
+    }
+    create_parents(sftp, parent)?;
+    sftp.mkdir(parent, 0o755).map_err(|e| sftp_err(&format!("create directory {}", parent.display()), e))
+}
+
+struct SftpClient {
+    cfg: SftpConfig,
+    conn: RefCell<Option<Connection>>
+}
+
+impl SftpClient {
+    fn new(cfg: &SftpConfig) -> Self {
+        Self { cfg: cfg.clone(), conn: RefCell::new(None) }
+    }
+
+    fn connect(&self, loc: &Location) -> Result<Connection, String> {
+        let tcp = TcpStream::connect((loc.host.as_str(), loc.port))
+            .map_err(|e| format!("could not connect to {}:{}: {}", loc.host, loc.port, e))?;
+        let mut session = Session::new().map_err(|e| sftp_err("create ssh session", e))?;
+        session.set_tcp_stream(tcp);
+        session.set_timeout(TIMEOUT_MS);
+        session.handshake().map_err(|e| sftp_err(&format!("handshake with {}", loc.host), e))?;
+
+        let known_hosts = self.cfg.known_hosts.clone().or(home_file("known_hosts")).ok_or("HOME is not set".to_owned())?;
+        let mut kh = session.known_hosts().map_err(|e| sftp_err("init known hosts", e))?;
+        kh.read_file(Path::new(&known_hosts), KnownHostFileKind::OpenSSH)
+            .map_err(|e| sftp_err(&format!("read known hosts from {}", known_hosts), e))?;
+        let (key, _) = session.host_key().ok_or(format!("no host key from {}", loc.host))?;
+        match kh.check_port(&loc.host, loc.port, key) {
+            CheckResult::Match => {},
+            CheckResult::NotFound => { return Err(format!("host key of {} is not in {}, add it with ssh-keyscan", loc.host, known_hosts)); },
+            CheckResult::Mismatch => { return Err(format!("host key of {} does not match the one in {}", loc.host, known_hosts)); },
+            CheckResult::Failure => { return Err(format!("could not check host key of {}", loc.host)); }
+        }
+
+        if session.userauth_agent(&loc.user).is_err() || !session.authenticated() {
+            let keys = match &self.cfg.key_path {
+                Some(key) => vec![key.clone()],
+                None => ["id_ed25519", "id_ecdsa", "id_rsa"].iter().filter_map(|k| home_file(k)).collect()
+            };
+            for key in keys.iter().filter(|k| Path::new(k).exists()) {
+                if session.userauth_pubkey_file(&loc.user, None, Path::new(key), None).is_ok() {
+                    break;
+                }
+            }
+        }
+        if !session.authenticated() {
+            return Err(format!("could not authenticate to {} with ssh-agent or key files", loc.remote()));
+        }
+        let sftp = session.sftp().map_err(|e| sftp_err(&format!("start sftp on {}", loc.host), e))?;
+        Ok(Connection { remote: loc.remote(), sftp, _session: session })
+    }
+
+    // runs `op` over a connection to the host of `loc`, reconnecting and retrying on failures
+    fn with_retries<T, F>(&self, loc: &Location, what: &str, mut op: F) -> Result<T, String>
+    where F: FnMut(&Sftp) -> Result<T, String>
+    {
+        let mut backoff = self.cfg.first_backoff;
+        for attempt in 1.. {
+            let mut conn = self.conn.borrow_mut();
+            if conn.as_ref().is_some_and(|c| c.remote != loc.remote()) {
+                *conn = None;
+            }
+            let res = match conn.as_ref() {
+                Some(c) => op(&c.sftp),
+                None => self.connect(loc).and_then(|c| op(&conn.insert(c).sftp))
+            };
+            let err = match res {
+                Ok(v) => { return Ok(v); },
+                Err(e) => e
+            };
+            *conn = None; // the next attempt starts with a new connection
+            if attempt > self.cfg.retries {
+                return Err(format!("{} {} failed after {} attempt(s): {}", what, loc.path, attempt, err));
+            }
+            eprintln!("{} {} failed (attempt {} of {}): {}, reconnecting in {:?}", what, loc.path, attempt, self.cfg.retries + 1, err, backoff);
+            thread::sleep(backoff);
+            backoff = Duration::min(backoff * 2, MAX_BACKOFF);
+        }
+        unreachable!()
+    }
+
+    fn drop_connection(&self) {
+        *self.conn.borrow_mut() = None;
+    }
+}
+
+// file being uploaded
+trait RemoteFile: Write {
+    fn set_len(&mut self, len: u64) -> Result<(), String>;
+    fn close(&mut self) -> Result<(), String>;
+}
+
+impl RemoteFile for File {
+    fn set_len(&mut self, len: u64) -> Result<(), String> {
+        let size = FileStat { size: Some(len), uid: None, gid: None, perm: None, atime: None, mtime: None };
+        self.setstat(size).map_err(|e| e.to_string())
+    }
+
+    fn close(&mut self) -> Result<(), String> {
+        File::close(self).map_err(|e| e.to_string())
+    }
+}
+
+// server side of uploads, apart from the writer so that tests can break connections at will
+trait UploadTarget {
+    // creates the file with any missing parent directories
+    fn create(&self, loc: &Location) -> Result<Box<dyn RemoteFile>, String>;
+    // opens the file for writing at its stored length, cut to `max_len`; no file if less than `min_len` is stored
+    fn reopen(&self, loc: &Location, min_len: u64, max_len: u64) -> Result<(Option<Box<dyn RemoteFile>>, u64), String>;
+    fn write_whole(&self, loc: &Location, contents: &[u8]) -> Result<(), String>;
+    // a missing file is not an error
+    fn remove(&self, loc: &Location) -> Result<(), String>;
+    fn drop_connection(&self);
+}
+
+impl UploadTarget for SftpClient {
+    fn create(&self, loc: &Location) -> Result<Box<dyn RemoteFile>, String> {
+        let file = self.with_retries(loc, "create", |sftp| {
+            match sftp.create(Path::new(&loc.path)) {
+                Err(e) if is_not_found(&e) => {
+                    create_parents(sftp, Path::new(&loc.path))?;
+                    sftp.create(Path::new(&loc.path)).map_err(|e| sftp_err("create", e))
+                },
+                res => res.map_err(|e| sftp_err("create", e))
+            }
+        })?;
+        Ok(Box::new(file))
+    }
+
+    fn reopen(&self, loc: &Location, min_len: u64, max_len: u64) -> Result<(Option<Box<dyn RemoteFile>>, u64), String> {
+        let path = Path::new(&loc.path);
+        let (file, stored) = self.with_retries(loc, "reopen", |sftp| {
+            let stored = u64::min(sftp.stat(path).map_err(|e| sftp_err("stat", e))?.size.unwrap_or(0), max_len);
+            if stored < min_len {
+                return Ok((None, stored));
+            }
+            let mut file = sftp.open_mode(path, OpenFlags::WRITE, 0o644, OpenType::File).map_err(|e| sftp_err("open", e))?;
+            file.seek(SeekFrom::Start(stored)).map_err(|e| format!("could not seek: {}", e))?;
+            Ok((Some(file), stored))
+        })?;
+        Ok((file.map(|f| Box::new(f) as Box<dyn RemoteFile>), stored))
+    }
+
+    fn write_whole(&self, loc: &Location, contents: &[u8]) -> Result<(), String> {
+        let tmp_path = format!("{}.tmp", loc.path);
+        self.with_retries(loc, "write", |sftp| {
+            let mut file = sftp.create(Path::new(&tmp_path)).map_err(|e| sftp_err("create", e))?;
+            file.write_all(contents).map_err(|e| format!("could not write: {}", e))?;
+            File::close(&mut file).map_err(|e| sftp_err("close", e))?;
+            // replace the file atomically if the server allows it
+            sftp.rename(Path::new(&tmp_path), Path::new(&loc.path), Some(RenameFlags::OVERWRITE | RenameFlags::ATOMIC | RenameFlags::NATIVE))
+                .map_err(|e| sftp_err("rename", e))
+        })
+    }
+
+    fn remove(&self, loc: &Location) -> Result<(), String> {
+        self.with_retries(loc, "remove", |sftp| {
+            match sftp.unlink(Path::new(&loc.path)) {
+                Err(e) if is_not_found(&e) => Ok(()),
+                res => res.map_err(|e| sftp_err("remove", e))
+            }
+        })
+    }
+
+    fn drop_connection(&self) {
+        SftpClient::drop_connection(self)
+    }
+}
+
+struct Upload {
+    loc: Location,
+    file: Option<Box<dyn RemoteFile>>,
+    pos: u64,
+    recent: Vec<u8>, // last written bytes, at least RESUME_WINDOW of them if available
+    resumed: bool
+}
+
+pub struct SftpWriter {
+    target: Box<dyn UploadTarget>,
+    retries: usize, // of a broken write, each after reconnect
+    current: Option<Upload>
+}
+
+impl SftpWriter {
+    pub fn new(cfg: &SftpConfig) -> Self {
+        Self { target: Box::new(SftpClient::new(cfg)), retries: cfg.retries, current: None }
+    }
+
+    // reopens the file at what the server has actually stored, which must be within the window
+    fn resume(target: &dyn UploadTarget, upload: &mut Upload, window_start: u64, end: u64) -> Result<(), String> {
+        let (file, stored) = target.reopen(&upload.loc, window_start, end)?;
+        upload.file = Some(file.ok_or(format!("cannot resume {}: only {} bytes are stored, data before {} is not kept",
+            upload.loc.path, stored, window_start))?);
+        upload.pos = stored;
+        upload.resumed = true;
+        Ok(())
+    }
+
+    // writes a portion which fits into RESUME_WINDOW, so that it can be resent whole
+    fn write_portion(&mut self, data: &[u8]) -> Result<(), String> {
+        let upload = self.current.as_mut().ok_or("no current file opened to write".to_owned())?;
+        upload.recent.extend_from_slice(data);
+        if upload.recent.len() > 2 * RESUME_WINDOW {
+            upload.recent.drain(..upload.recent.len() - RESUME_WINDOW);
+        }
+
+        // everything past `pos` is written from `recent`, including data resent after reconnect
+        let end = upload.pos + data.len() as u64;
+        let window_start = end - upload.recent.len() as u64;
+        let mut failures = 0;
+        while upload.pos < end {
+            let res = match upload.file.as_mut() {
+                Some(file) => file.write(&upload.recent[(upload.pos - window_start) as usize..]),
+                None => Err(std::io::Error::other("file is not open"))
+            };
+            match res {
+                Ok(n) if n > 0 => { upload.pos += n as u64; },
+                res => {
+                    let e = res.err().map(|e| e.to_string()).unwrap_or("nothing is written".to_owned());
+                    failures += 1;
+                    if failures > self.retries {
+                        return Err(format!("could not write {} bytes to {}: {}", data.len(), upload.loc.path, e));
+                    }
+                    eprintln!("writing to {} failed at offset {}: {}, resuming", upload.loc.path, upload.pos, e);
+                    upload.file = None;
+                    self.target.drop_connection();
+                    Self::resume(self.target.as_ref(), upload, window_start, end)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl MultiFilesWriterTarget for SftpWriter {
+    fn open_next_file(&mut self, full_path: &str) -> Result<(), String> {
+        if let Some(upload) = &self.current {
+            return Err(format!("previous file {} was not closed before opening a new file {}", upload.loc.path, full_path));
+        }
+        let loc = Location::parse(full_path)?;
+        let file = self.target.create(&loc)?;
+        self.current = Some(Upload { loc, file: Some(file), pos: 0, recent: Vec::new(), resumed: false });
+        eprintln!("writing to {}", full_path);
+        Ok(())
+    }
+
+    fn close_current_file(&mut self) -> Result<(), String> {
+        let mut upload = self.current.take().ok_or("no current file opened to close".to_owned())?;
+        let mut file = upload.file.take().ok_or(format!("file {} is not open", upload.loc.path))?;
+        if upload.resumed {
+            // cut whatever was written past the end before reconnecting
+            file.set_len(upload.pos).map_err(|e| format!("could not set size of {}: {}", upload.loc.path, e))?;
+        }
+        file.close().map_err(|e| format!("could not close {}: {}", upload.loc.path, e))
+    }
+
+    fn write_to_current_file(&mut self, data: &[u8]) -> Result<(), String> {
+        for portion in data.chunks(RESUME_WINDOW) {
+            self.write_portion(portion)?;
+        }
+        Ok(())
+    }
+
+    fn write_single_file(&self, path: &str, contents: &[u8]) -> Result<(), String> {
+        self.target.write_whole(&Location::parse(path)?, contents)
+    }
+
+    fn remove_file(&self, path: &str) -> Result<(), String> {
+        self.target.remove(&Location::parse(path)?)
+    }
+}
+
+struct Download {
+    loc: Location,
+    file: Option<File>,
+    pos: u64
+}
+
+pub struct SftpReader {
+    client: SftpClient,
+    current: Option<Download>
+}
+
+impl SftpReader {
+    pub fn new(cfg: &SftpConfig) -> Self {
+        Self { client: SftpClient::new(cfg), current: None }
+    }
+
+    fn open_at(client: &SftpClient, loc: &Location, pos: u64) -> Result<Option<File>, String> {
+        client.with_retries(loc, "open", |sftp| {
+            match sftp.open(Path::new(&loc.path)) {
+                Ok(mut file) => {
+                    file.seek(SeekFrom::Start(pos)).map_err(|e| format!("could not seek: {}", e))?;
+                    Ok(Some(file))
+                },
+                Err(e) if is_not_found(&e) => Ok(None),
+                Err(e) => Err(sftp_err("open", e))
+            }
+        })
+    }
+
+    // returns None if there is no such file
+    pub fn read_object(&self, path: &str) -> Result<Option<Vec<u8>>, String> {
+        let loc = Location::parse(path)?;
+        self.client.with_retries(&loc, "read", |sftp| {
+            match sftp.open(Path::new(&loc.path)) {
+                Ok(mut file) => {
+                    let mut contents = Vec::new();
+                    file.read_to_end(&mut contents).map_err(|e| format!("could not read: {}", e))?;
+                    Ok(Some(contents))
+                },
+                Err(e) if is_not_found(&e) => Ok(None),
+                Err(e) => Err(sftp_err("open", e))
+            }
+        })
+    }
+}
+
+impl MultiFilesReaderSource for SftpReader {
+    fn open_next_file(&mut self, full_path: &str) -> Result<bool, String> {
+        if let Some(download) = &self.current {
+            return Err(format!("previous file {} was not closed before opening a new file {}", download.loc.path, full_path));
+        }
+        let loc = Location::parse(full_path)?;
+        let found = match Self::open_at(&self.client, &loc, 0)? {
+            Some(file) => {
+                self.current = Some(Download { loc, file: Some(file), pos: 0 });
+                true
+            },
+            None => false
+        };
+        eprintln!("reading from {}", full_path);
+        Ok(found)
+    }
+
+    fn read_from_current_file(&mut self, buf: &mut [u8]) -> Result<usize, String> {
+        let download = self.current.as_mut().ok_or("no current file opened to read from".to_owned())?;
+        let mut failures = 0;
+        loop {
+            let res = match download.file.as_mut() {
+                Some(file) => file.read(buf),
+                None => Err(std::io::Error::other("file is not open"))
+            };
+            match res {
+                Ok(n) => {
+                    download.pos += n as u64;
+                    return Ok(n);
+                },
+                Err(e) => {
+                    failures += 1;
+                    if failures > self.client.cfg.retries {
+                        return Err(format!("could not read max {} bytes from {}: {}", buf.len(), download.loc.path, e));
+                    }
+                    eprintln!("reading {} failed at offset {}: {}, resuming", download.loc.path, download.pos, e);
+                    download.file = None;
+                    self.client.drop_connection();
+                    download.file = Some(Self::open_at(&self.client, &download.loc, download.pos)?
+                        .ok_or(format!("file {} disappeared", download.loc.path))?);
+                }
+            }
+        }
+    }
+
+    fn close_current_file(&mut self) -> Result<(), String> {
+        self.current.take().ok_or("no current file opened to close".to_owned()).map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::collections::HashMap;
+    use std::rc::Rc;
+
+    // in-memory server whose connection breaks on the indicated writes, losing the last `lost` bytes it was sent
+    #[derive(Default)]
+    struct Server {
+        files: RefCell<HashMap<String, Vec<u8>>>,
+        fail_on: Vec<usize>,
+        lost: usize,
+        nr_writes: Cell<usize>,
+        nr_reconnects: Cell<usize>
+    }
+
+    struct ServerFile {
+        server: Rc<Server>,
+        path: String,
+        pos: usize
+    }
+
+    impl Write for ServerFile {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            let nr = self.server.nr_writes.get() + 1;
+            self.server.nr_writes.set(nr);
+            let mut files = self.server.files.borrow_mut();
+            let data = files.get_mut(&self.path).unwrap();
+            if self.server.fail_on.contains(&nr) {
+                data.truncate(self.pos.saturating_sub(self.server.lost));
+                return Err(std::io::Error::from(std::io::ErrorKind::ConnectionReset));
+            }
+            let n = usize::min(buf.len(), 100_000); // as ssh2 sends at most a packet at once
+            data.truncate(self.pos);
+            data.extend_from_slice(&buf[..n]);
+            self.pos += n;
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl RemoteFile for ServerFile {
+        fn set_len(&mut self, len: u64) -> Result<(), String> {
+            self.server.files.borrow_mut().get_mut(&self.path).unwrap().truncate(len as usize);
+            Ok(())
+        }
+
+        fn close(&mut self) -> Result<(), String> {
+            Ok(())
+        }
+    }
+
+    impl UploadTarget for Rc<Server> {
+        fn create(&self, loc: &Location) -> Result<Box<dyn RemoteFile>, String> {
+            self.files.borrow_mut().insert(loc.path.clone(), Vec::new());
+            Ok(Box::new(ServerFile { server: self.clone(), path: loc.path.clone(), pos: 0 }))
+        }
+
+        fn reopen(&self, loc: &Location, min_len: u64, max_len: u64) -> Result<(Option<Box<dyn RemoteFile>>, u64), String> {
+            let stored = u64::min(self.files.borrow()[&loc.path].len() as u64, max_len);
+            if stored < min_len {
+                return Ok((None, stored));
+            }
+            Ok((Some(Box::new(ServerFile { server: self.clone(), path: loc.path.clone(), pos: stored as usize })), stored))
+        }
+
+        fn write_whole(&self, loc: &Location, contents: &[u8]) -> Result<(), String> {
+            self.files.borrow_mut().insert(loc.path.clone(), contents.to_vec());
+            Ok(())
+        }
+
+        fn remove(&self, loc: &Location) -> Result<(), String> {
+            self.files.borrow_mut().remove(&loc.path);
+            Ok(())
+        }
+
+        fn drop_connection(&self) {
+            self.nr_reconnects.set(self.nr_reconnects.get() + 1);
+        }
+    }
+
+    fn upload_with_failures(fail_on: Vec<usize>, lost: usize, portions: &[&[u8]]) -> (Result<(), String>, Rc<Server>) {
+        let server = Rc::new(Server { fail_on, lost, ..Default::default() });
+        let mut writer = SftpWriter { target: Box::new(server.clone()), retries: 2, current: None };
+        let res = writer.open_next_file("sftp://bob@host/bk/000001")
+            .and_then(|_| portions.iter().try_for_each(|p| writer.write_to_current_file(p)))
+            .and_then(|_| writer.close_current_file());
+        (res, server)
+    }
+
+    #[test]
+    fn broken_uploads_are_resumed() {
+        let data: Vec<u8> = (0..2 * RESUME_WINDOW + 12345).map(|i| (i % 251) as u8).collect();
+
+        // a single write bigger than the window breaks early, when most of it is not sent yet
+        let (res, server) = upload_with_failures(vec![3, 100], 150_000, &[&data]);
+        res.unwrap();
+        assert_eq!(server.files.borrow()["/bk/000001"], data);
+        assert_eq!(server.nr_reconnects.get(), 2);
+
+        // small writes, the server loses more than the last write
+        let portions: Vec<&[u8]> = data.chunks(70_000).collect();
+        let (res, server) = upload_with_failures(vec![2, 5, 9], 250_000, &portions);
+        res.unwrap();
+        assert_eq!(server.files.borrow()["/bk/000001"], data);
+
+        // the server has lost more than the kept window, at the last write
+        let (res, _) = upload_with_failures(vec![portions.len()], 2 * RESUME_WINDOW, &portions);
+        assert!(res.unwrap_err().contains("cannot resume"));
+
+        // retries are limited for a single write
+        let (res, _) = upload_with_failures(vec![1, 2, 3], 0, &[&data[..1000]]);
+        assert!(res.unwrap_err().contains("could not write 1000 bytes"));
+    }
+
+    #[test]
+    fn parse_urls() {
+        assert_eq!(Location::parse("sftp://bob@backup.local/srv/bk/%%%%").unwrap(),
+            Location { user: "bob".to_owned(), host: "backup.local".to_owned(), port: 22, path: "/srv/bk/%%%%".to_owned() });
+        assert_eq!(Location::parse("sftp://bob@10.0.0.1:2222/bk000.cfg").unwrap(),
+            Location { user: "bob".to_owned(), host: "10.0.0.1".to_owned(), port: 2222, path: "/bk000.cfg".to_owned() });
+        assert!(Location::parse("sftp://host/file").is_ok() || std::env::var("USER").is_err());
+        assert!(Location::parse("sftp://bob@host").is_err());
+        assert!(Location::parse("sftp://bob@host/").is_err());
+        assert!(Location::parse("sftp://bob@:22/file").is_err());
+        assert!(Location::parse("sftp://bob@host:port/file").is_err());
+        assert!(Location::parse("/local/file").is_err());
+    }
+
+    #[test]
+    fn no_server() {
+        let mut cfg = SftpConfig::new(&None);
+        cfg.retries = 1;
+        cfg.first_backoff = Duration::from_millis(10);
+        let mut writer = SftpWriter::new(&cfg);
+        assert!(writer.open_next_file("sftp://bob@127.0.0.1:1/tmp/x").unwrap_err().contains("after 2 attempt(s)"));
+        assert!(SftpReader::new(&cfg).read_object("sftp://bob@127.0.0.1:1/tmp/x").is_err());
+    }
+}
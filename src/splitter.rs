@@ -1,12 +1,68 @@
 use crate::file_set::FileSet;
 use crate::finalizable::DataSink;
-use crate::stats::Stats;
+use crate::hasher::ChunkHasher;
+use crate::parity::ShardsCombiner;
+use crate::stats::ChunkInfo;
+use crate::ParityParams;
+use crate::chunk_hooks::{PostChunkHook, HookRunner, ChunkRef};
+use crate::free_space::SpaceCheck;
+use crate::chunk_header::{ChunkHeader, HeaderKey, ArchiveTotals};
+use crate::progress::Progress;
 
 pub trait MultiFilesWriterTarget {
     fn open_next_file(&mut self, full_path: &str) -> Result<(), String>;
     fn close_current_file(&mut self) -> Result<(), String>;
     fn write_to_current_file(&mut self, data: &[u8]) -> Result<(), String>;
-    fn write_single_file(&self, path: &str, contents: &str) -> Result<(), String>;
+    fn write_single_file(&self, path: &str, contents: &[u8]) -> Result<(), String>; // creates or replaces the file; atomically, except for a chunk command which is as atomic as the command itself
+    // whether the last failed operation may succeed if repeated, e.g. after a timeout of network filesystem
+    fn last_error_is_transient(&self) -> bool {
+        false
+    }
+    // makes data written to the current file durable
+    fn sync_current_file(&mut self) -> Result<(), String> {
+        Ok(())
+    }
+    // opens the current file again after an error, dropping everything after `offset`
+    fn reopen_current_file(&mut self, _offset: u64) -> Result<(), String> {
+        Err("reopening a file is not supported".to_owned())
+    }
+    // length and hash of the current file as recorded in metadata, told before it is closed;
+    // storage which can keep them along with the file does so
+    fn set_current_file_info(&mut self, _info: &ChunkInfo) {}
+    // removes a written file; a missing file is not an error
+    fn remove_file(&self, path: &str) -> Result<(), String> {
+        Err(format!("could not remove {}: removing files is not supported by this storage", path))
+    }
+}
+
+impl MultiFilesWriterTarget for Box<dyn MultiFilesWriterTarget> {
+    fn open_next_file(&mut self, full_path: &str) -> Result<(), String> {
+        self.as_mut().open_next_file(full_path)
+    }
+    fn close_current_file(&mut self) -> Result<(), String> {
+        self.as_mut().close_current_file()
+    }
+    fn write_to_current_file(&mut self, data: &[u8]) -> Result<(), String> {
+        self.as_mut().write_to_current_file(data)
+    }
+    fn write_single_file(&self, path: &str, contents: &[u8]) -> Result<(), String> {
+        self.as_ref().write_single_file(path, contents)
+    }
+    fn last_error_is_transient(&self) -> bool {
+        self.as_ref().last_error_is_transient()
+    }
+    fn sync_current_file(&mut self) -> Result<(), String> {
+        self.as_mut().sync_current_file()
+    }
+    fn reopen_current_file(&mut self, offset: u64) -> Result<(), String> {
+        self.as_mut().reopen_current_file(offset)
+    }
+    fn set_current_file_info(&mut self, info: &ChunkInfo) {
+        self.as_mut().set_current_file_info(info)
+    }
+    fn remove_file(&self, path: &str) -> Result<(), String> {
+        self.as_ref().remove_file(path)
+    }
 }
 
 pub struct Splitter<'a, T> {
@@ -14,24 +70,209 @@ pub struct Splitter<'a, T> {
     chunk_sz: usize,
     file_set: FileSet,
     left_for_chunk: usize,
-    next_chunk_no: usize
+    chunk_offset: usize,
+    next_chunk_no: usize,
+    hash_seed: u64,
+    chunk_hasher: ChunkHasher,
+    chunks: Vec<ChunkInfo>,
+    parity_group_len: usize,
+    parity_enc: Option<ShardsCombiner>,
+    parity_chunks: Vec<ChunkInfo>,
+    hooks: Option<HookRunner>,
+    space_check: Option<SpaceCheck>,
+    header: Option<(ChunkHeader, HeaderKey)>,
+    progress: Option<Progress>
 }
 
 impl<'a, T: MultiFilesWriterTarget> Splitter<'a, T> {
-    pub fn from_pattern(tgt: &'a mut T, chunk_size: usize, pattern: &'a str) -> Result<Splitter<'a, T>, String> {
+    pub fn from_pattern(tgt: &'a mut T, chunk_size: usize, pattern: &'a str, hash_seed: u64, opt_parity: &Option<ParityParams>) -> Result<Splitter<'a, T>, String> {
+        let (parity_group_len, parity_enc) = match opt_parity {
+            Some(p) => (p.data_chunks, Some(ShardsCombiner::encoder(p.data_chunks, p.parity_chunks)?)),
+            None => (0, None)
+        };
         Ok(Self { 
             files_target: tgt, 
             chunk_sz: chunk_size, 
             file_set: FileSet::from_pattern(pattern)?,
             left_for_chunk: chunk_size, 
-            next_chunk_no: 0
+            chunk_offset: 0,
+            next_chunk_no: 0,
+            hash_seed,
+            chunk_hasher: ChunkHasher::new(hash_seed),
+            chunks: Vec::new(),
+            parity_group_len,
+            parity_enc,
+            parity_chunks: Vec::new(),
+            hooks: None,
+            space_check: None,
+            header: None,
+            progress: None
         })
     }
 
-    pub fn write_metadata(self, stats: &Stats) -> Result<(), String> {
-        self.files_target.write_single_file(
-            self.file_set.cfg_path().as_str(),
-            &stats.as_string())
+    // starts every data chunk with the header, its index set for each chunk; the last chunk is then
+    // closed by `finish_archive` instead of `finish`, to end it with totals of the archive
+    pub fn set_chunk_header(&mut self, header: ChunkHeader, key: HeaderKey) -> Result<(), String> {
+        if header.encoded_len() >= self.chunk_sz {
+            return Err(format!("chunk size of {} bytes leaves no room for data after chunk header of {} bytes",
+                self.chunk_sz, header.encoded_len()));
+        }
+        self.header = Some((header, key));
+        Ok(())
+    }
+
+    // runs the hook for every data and parity chunk once it is closed
+    pub fn set_post_chunk_hook(&mut self, hook: &PostChunkHook) -> Result<(), String> {
+        self.hooks = Some(HookRunner::start(hook)?);
+        Ok(())
+    }
+
+    // checks free space of the destination before each chunk
+    pub fn set_space_check(&mut self, check: &SpaceCheck) {
+        self.space_check = Some(check.clone());
+    }
+
+    // counts bytes written to data chunks and reports the current chunk
+    pub fn set_progress(&mut self, progress: &Progress) {
+        self.progress = Some(progress.clone());
+    }
+
+    fn ensure_space(&mut self, nr_bytes: usize) -> Result<(), String> {
+        let res = match &self.space_check {
+            Some(check) => check.wait_for(nr_bytes),
+            None => Ok(())
+        };
+        if res.is_err() {
+            self.remove_written_chunks();
+        }
+        res
+    }
+
+    // an incomplete archive is useless, so its chunks only take space
+    fn remove_written_chunks(&self) {
+        let paths = (0..self.chunks.len()).map(|n| self.file_set.gen_file_path(n))
+            .chain((0..self.parity_chunks.len()).map(|n| self.file_set.gen_parity_file_path(n)));
+        let mut nr_removed = 0;
+        for path in paths {
+            if std::fs::remove_file(&path).is_ok() {
+                nr_removed += 1;
+            }
+        }
+        if nr_removed > 0 {
+            eprintln!("removed {} chunk(s) of the incomplete archive", nr_removed);
+        }
+    }
+
+    fn chunk_closed(&mut self, path: String, index: usize, is_parity: bool) -> Result<(), String> {
+        match self.hooks.as_mut() {
+            Some(hooks) => hooks.chunk_closed(ChunkRef { path, index, is_parity }),
+            None => Ok(())
+        }
+    }
+
+    // number of data chunks, including the one being written
+    pub fn nr_chunks(&self) -> usize {
+        self.next_chunk_no
+    }
+
+    // lengths and hashes of data and parity chunks written so far
+    pub fn chunks_info(&self) -> (Vec<ChunkInfo>, Vec<ChunkInfo>) {
+        (self.chunks.clone(), self.parity_chunks.clone())
+    }
+
+    // writes to the current chunk, accounting for its hash and parity
+    fn write_to_chunk(&mut self, data: &[u8]) -> Result<(), String> {
+        self.files_target.write_to_current_file(data)?;
+        self.chunk_hasher.update(data);
+        if let Some(enc) = self.parity_enc.as_mut() {
+            enc.add((self.next_chunk_no - 1) % self.parity_group_len, self.chunk_offset, data);
+        }
+        self.chunk_offset += data.len();
+        self.left_for_chunk = self.left_for_chunk.saturating_sub(data.len());
+        if let Some(progress) = &self.progress {
+            progress.add_out(data.len());
+        }
+        Ok(())
+    }
+
+    fn open_next_chunk(&mut self) -> Result<(), String> {
+        self.ensure_space(self.chunk_sz)?;
+        self.files_target
+            .open_next_file(self.file_set.gen_file_path(self.next_chunk_no).as_str())?;
+        self.chunk_hasher = ChunkHasher::new(self.hash_seed);
+        self.next_chunk_no += 1;
+        self.left_for_chunk = self.chunk_sz;
+        self.chunk_offset = 0;
+        if let Some(progress) = &self.progress {
+            progress.set_chunk(self.next_chunk_no - 1);
+        }
+        if let Some((header, key)) = &self.header {
+            let encoded = ChunkHeader { index: self.next_chunk_no - 1, ..header.clone() }.encode(key);
+            self.write_to_chunk(&encoded)?;
+        }
+        Ok(())
+    }
+
+    fn close_current_chunk(&mut self) -> Result<(), String> {
+        let info = self.chunk_hasher.result();
+        self.files_target.set_current_file_info(&info);
+        self.files_target.close_current_file()?;
+        self.chunks.push(info);
+        let index = self.chunks.len() - 1;
+        self.chunk_closed(self.file_set.gen_file_path(index), index, false)?;
+        if self.parity_group_len > 0 && self.chunks.len().is_multiple_of(self.parity_group_len) {
+            self.write_parity_group()?;
+        }
+        Ok(())
+    }
+
+    fn write_parity_group(&mut self) -> Result<(), String> {
+        let shards = match self.parity_enc.as_mut() {
+            Some(enc) => enc.take(),
+            None => { return Ok(()); }
+        };
+        self.ensure_space(shards.iter().map(|shard| shard.len()).sum())?;
+        for shard in shards {
+            let index = self.parity_chunks.len();
+            let path = self.file_set.gen_parity_file_path(index);
+            let mut hasher = ChunkHasher::new(self.hash_seed);
+            self.files_target.open_next_file(path.as_str())?;
+            self.files_target.write_to_current_file(&shard)?;
+            hasher.update(&shard);
+            let info = hasher.result();
+            self.files_target.set_current_file_info(&info);
+            self.files_target.close_current_file()?;
+            self.parity_chunks.push(info);
+            self.chunk_closed(path, index, true)?;
+        }
+        Ok(())
+    }
+
+    // ends the last chunk with totals of the archive, if chunks have headers; without any data,
+    // a chunk of the header and the trailer alone is written, so that metadata can still be recovered
+    pub fn finish_archive(&mut self, totals: &ArchiveTotals) -> Result<(), String> {
+        if self.header.is_some() && self.next_chunk_no == 0 {
+            self.open_next_chunk()?;
+        }
+        let trailer = match &self.header {
+            Some((header, key)) => ArchiveTotals { nr_chunks: self.next_chunk_no, ..totals.clone() }.encode(&header.archive_id, key),
+            None => { return Ok(()); }
+        };
+        self.write_to_chunk(&trailer)?;
+        self.close_last_chunk()
+    }
+
+    fn close_last_chunk(&mut self) -> Result<(), String> {
+        if self.next_chunk_no > 0 {
+            self.close_current_chunk()?;
+            if self.parity_group_len > 0 && !self.chunks.len().is_multiple_of(self.parity_group_len) {
+                self.write_parity_group()?; // last incomplete group
+            }
+        }
+        match self.hooks.as_mut() {
+            Some(hooks) => hooks.finish(),
+            None => Ok(())
+        }
     }
 }
 
@@ -42,21 +283,17 @@ impl<'a, T: MultiFilesWriterTarget> DataSink for Splitter<'a, T> {
         let mut offs_for_data = 0;
         while left_for_data > 0 {
             //eprintln!("  left for chunk before write: {}", self.left_for_chunk);
-            if self.left_for_chunk == 0 || self.left_for_chunk == self.chunk_sz {
+            if self.left_for_chunk == 0 || self.next_chunk_no == 0 {
                 if self.next_chunk_no > 0 {
-                    self.files_target.close_current_file()?;
+                    self.close_current_chunk()?;
                 }
-                self.files_target
-                    .open_next_file(self.file_set.gen_file_path(self.next_chunk_no).as_str())?;
-                self.next_chunk_no += 1;
-                self.left_for_chunk = self.chunk_sz;
+                self.open_next_chunk()?;
             }
             let to_write = usize::min(left_for_data, self.left_for_chunk);
             //eprintln!("written {} bytes", to_write);
-            self.files_target.write_to_current_file(&data[offs_for_data..offs_for_data + to_write])?;
+            self.write_to_chunk(&data[offs_for_data..offs_for_data + to_write])?;
             left_for_data -= to_write;
             offs_for_data += to_write;
-            self.left_for_chunk -= to_write;
             //eprintln!("after write: left to chunk = {}, left for data = {}, offs_for_data = {}", self.left_for_chunk, left_for_data, offs_for_data);
         }
 
@@ -65,10 +302,10 @@ impl<'a, T: MultiFilesWriterTarget> DataSink for Splitter<'a, T> {
 
     fn finish(&mut self) -> Result<(), String> {
         //eprintln!("Splitter: finish");
-        if self.next_chunk_no > 0 {
-            self.files_target.close_current_file()?;
+        if self.header.is_some() {
+            return Ok(()); // totals are not known yet
         }
-        Ok(())
+        self.close_last_chunk()
     }
 }
 
@@ -112,8 +349,8 @@ mod tests {
             Ok(())
         }
 
-        fn write_single_file(&self, path: &str, contents: &str) -> Result<(), String> {
-            println!("writing single file {}:\n{}", path, contents);
+        fn write_single_file(&self, path: &str, contents: &[u8]) -> Result<(), String> {
+            println!("writing single file {}:\n{}", path, String::from_utf8_lossy(contents));
             Ok(())
         }
 
@@ -121,22 +358,15 @@ mod tests {
 
      fn assert_split(chunk_size: usize, data1: Vec<u8>, data2: Vec<u8>, expected: Vec<(&str, Vec<u8>)>) {
         let mut files = FilesEmulator{ files: Vec::new() };
-        let mut spl = Splitter::<FilesEmulator>::from_pattern(&mut files, chunk_size, "out%%%.ext").unwrap();
+        let mut spl = Splitter::<FilesEmulator>::from_pattern(&mut files, chunk_size, "out%%%.ext", 0, &None).unwrap();
         spl.add(data1.as_slice()).unwrap();
         spl.add(data2.as_slice()).unwrap();
         spl.finish().unwrap();
-        spl.write_metadata(&Stats {
-            in_data_len: 1, in_data_hash: 0x1234567812345678, 
-            compressed_len: 2, hash_seed: 0x8765432187654321,
-            out_chunk_size: 3, out_nr_chunks: 4, 
-            alg: "some_alg".to_owned(), auth_chunk_size: 5, auth_string: "auth".to_owned(),
-            misc_info: Some("XXX".to_owned())
-        }).unwrap();
         let files = &files.files;
         assert_eq!(files.len(), expected.len());
-        let mut it_exp = expected.iter();
+        let it_exp = expected.iter();
         let mut it_act = files.iter();
-        while let Some(exp) = it_exp.next() {
+        for exp in it_exp {
             let act = it_act.next().unwrap(); // SAFE because have same size
             assert_eq!(exp.0, act.0.as_str());
             assert_eq!(exp.1, act.1);
@@ -245,4 +475,51 @@ mod tests {
             ("out002.ext", vec![9,0]),
         ]);
     }
+
+    #[test]
+    fn parity_groups() {
+        let mut files = FilesEmulator{ files: Vec::new() };
+        let mut spl = Splitter::<FilesEmulator>::from_pattern(
+            &mut files, 4, "out%%%.ext", 0, &Some(ParityParams{ data_chunks: 2, parity_chunks: 2 })).unwrap();
+        spl.add(&[1,2,3,4,5,6]).unwrap();
+        spl.add(&[7,8,9]).unwrap();
+        spl.finish().unwrap();
+        let (chunks, parity_chunks) = spl.chunks_info();
+        assert_eq!(chunks.iter().map(|c| c.len).collect::<Vec<_>>(), vec![4, 4, 1]);
+        assert_eq!(parity_chunks.iter().map(|c| c.len).collect::<Vec<_>>(), vec![4, 4, 1, 1]);
+
+        let names: Vec<&str> = files.files.iter().map(|f| f.0.as_str()).collect();
+        assert_eq!(names, vec![
+            "out000.ext", "out001.ext", "out000.ext.par", "out001.ext.par",
+            "out002.ext", "out002.ext.par", "out003.ext.par"]);
+
+        // lose both data chunks of the first group and restore them from parity ones
+        let mut rec = ShardsCombiner::recoverer(2, 2, &[2, 3], &[0, 1]).unwrap();
+        rec.add(0, 0, &files.files[2].1);
+        rec.add(1, 0, &files.files[3].1);
+        assert_eq!(rec.take(), vec![vec![1,2,3,4], vec![5,6,7,8]]);
+
+        // in the last group, the missing second data chunk is treated as empty
+        let mut rec = ShardsCombiner::recoverer(2, 2, &[1, 3], &[0]).unwrap();
+        rec.add(1, 0, &files.files[6].1);
+        assert_eq!(rec.take(), vec![vec![9]]);
+    }
+
+    #[test]
+    fn empty_archive_has_header_and_trailer() {
+        let mut files = FilesEmulator{ files: Vec::new() };
+        let mut spl = Splitter::<FilesEmulator>::from_pattern(&mut files, 1000, "out%%%.ext", 0, &None).unwrap();
+        let header = ChunkHeader { archive_id: [7; 16], ..ChunkHeader::from_stats(&crate::stats::Stats::new()) };
+        let key = HeaderKey::new(&None, &[7; 16]);
+        spl.set_chunk_header(header.clone(), key.clone()).unwrap();
+        spl.finish().unwrap();
+        let totals = ArchiveTotals { nr_chunks: 0, in_len: 0, in_hash: 1, compressed_len: 0 };
+        spl.finish_archive(&totals).unwrap();
+        assert_eq!(spl.nr_chunks(), 1);
+
+        assert_eq!(files.files.len(), 1);
+        let chunk = &files.files[0].1;
+        assert_eq!(ChunkHeader::decode(chunk, &key).unwrap(), header);
+        assert_eq!(ArchiveTotals::decode(chunk, &[7; 16], &key), Some(ArchiveTotals { nr_chunks: 1, ..totals }));
+    }
 }
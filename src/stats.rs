@@ -1,24 +1,138 @@
 use std::io::Read;
 use std::collections::HashMap;
 use std::num::ParseIntError;
+use serde_json::{json, Map, Value};
+
+// version of metadata format, written as the first line; keys which older readers may safely ignore are
+// added without changing it, anything else needs a new version which they refuse to read
+//   1: unversioned metadata of early releases, possibly without list of chunks and parity keys
+//   2: 'format' key, list of chunks and parity keys are always present
+//   3: every data chunk starts with a header and the last one ends with totals of the archive, see chunk_header.rs;
+//      'xz_len' is renamed to 'compressed_len', so that releases before version 2, which know nothing of
+//      'format', refuse it instead of reading headers as data
+pub const FORMAT_VERSION: u32 = 3;
+
+const KNOWN_KEYS: [&str; 29] = [
+    "in_len", "in_hash", "hash_seed", "compressed_len", "nr_chunks", "chunk_len", "alg", "auth", "auth_len", "misc_info",
+    "chunks", "parity_group", "parity_nr", "parity_chunks", "repair_info", "copies", "chunk_pattern", "archive_id",
+    "tool_version", "host", "started", "ended", "duration_ms", "throughput", "compress_level", "compress_threads", "buf_size",
+    "labels", "labels_mac"
+];
+
+#[derive(Default, PartialEq, Eq, Debug, Clone)]
+pub struct ChunkInfo {
+    pub len: usize,
+    pub hash: u64,
+}
+
+// how, where and when the backup was made; replaces free-form misc_info of older releases
+#[derive(Default, PartialEq, Eq, Debug, Clone)]
+pub struct BackupInfo {
+    pub tool_version: String,
+    pub host: String,
+    pub started: u64, // unix time, in seconds
+    pub ended: u64,
+    pub duration_ms: u64,
+    pub throughput: u64, // bytes of input data per second
+    pub compress_level: u8,
+    pub compress_threads: usize,
+    pub buf_size: usize
+}
+
+// user label of an archive, 'key=value' or a plain tag
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct Label {
+    pub key: String,
+    pub value: Option<String>
+}
+
+impl Label {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let (key, value) = match s.split_once('=') {
+            Some((key, value)) => (key, Some(value)),
+            None => (s, None)
+        };
+        let valid = |part: &str| !part.is_empty() && part.trim() == part && !part.contains([',', '=', '\n']);
+        if !valid(key) || !value.map(valid).unwrap_or(true) {
+            return Err(format!("invalid label '{}', expected key=value or a tag without spaces around, ',' and '='", s));
+        }
+        Ok(Self { key: key.to_owned(), value: value.map(|v| v.to_owned()) })
+    }
+
+    // as a filter, a plain tag matches either the same tag or a label with that key
+    pub fn matches(&self, labels: &[Label]) -> bool {
+        labels.iter().any(|l| l.key == self.key && (self.value.is_none() || l.value == self.value))
+    }
+}
+
+impl std::fmt::Display for Label {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.value {
+            Some(value) => write!(f, "{}={}", self.key, value),
+            None => write!(f, "{}", self.key)
+        }
+    }
+}
+
+pub fn labels_as_string(labels: &[Label]) -> String {
+    labels.iter().map(|l| l.to_string()).collect::<Vec<_>>().join(",")
+}
+
+// labels as a JSON object, where a tag is true
+pub fn labels_as_json(labels: &[Label]) -> Value {
+    Value::Object(labels.iter()
+        .map(|l| (l.key.clone(), l.value.as_ref().map(|v| json!(v)).unwrap_or(json!(true))))
+        .collect())
+}
 
 #[derive(Default, PartialEq, Eq, Debug)]
 pub struct Stats {
+    pub format_version: u32, // metadata is written back in the version it was read in
     pub in_data_len: usize,
     pub in_data_hash: u64,
     pub hash_seed: u64,
     pub compressed_len: usize,
-    pub out_nr_chunks: usize,
+    pub out_nr_chunks: usize, // 0 if unknown, as early releases did not fill it in
     pub out_chunk_size: usize,
     pub alg: String,
     pub auth_string: String,
     pub auth_chunk_size: usize,
     pub misc_info: Option<String>,
+    pub backup_info: Option<BackupInfo>,
+    pub chunks: Vec<ChunkInfo>,
+    pub parity_group_len: usize, // number of data chunks covered by each group of parity chunks, 0 if no parity
+    pub parity_nr_per_group: usize,
+    pub parity_chunks: Vec<ChunkInfo>,
+    pub repair_info: Option<String>,
+    pub copies: Vec<(String, bool)>, // output templates of a mirrored backup and whether each copy was written
+    pub chunk_pattern: Option<String>, // pattern of chunk paths relative to metadata file, if not told by its name
+    pub archive_id: Option<[u8; 16]>, // random id stamped into every data chunk, see chunk_header.rs
+    pub labels: Vec<Label>,
+    pub labels_mac: Option<String>, // of labels and archive id, with a key derived from the password, see chunk_header.rs
+    pub unknown: Vec<(String, String)>, // keys added by newer releases within the same format version, kept when metadata is rewritten
+}
+
+// archive id is written as UUID, e.g. 1b4e28ba-2fa1-41d2-883f-0016d3cca427
+pub fn archive_id_as_string(id: &[u8; 16]) -> String {
+    let hex = id.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+    format!("{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..])
+}
+
+fn parse_archive_id(s: &str) -> Result<[u8; 16], String> {
+    let hex = s.replace('-', "");
+    if hex.len() != 32 || s.len() != 36 {
+        return Err(format!("invalid archive id '{}'", s));
+    }
+    let mut id = [0u8; 16];
+    for (i, b) in id.iter_mut().enumerate() {
+        *b = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).map_err(|_| format!("invalid archive id '{}'", s))?;
+    }
+    Ok(id)
 }
 
 impl Stats {
     pub fn new() -> Self {
-        Self { ..Default::default() }
+        Self { format_version: FORMAT_VERSION, ..Default::default() }
     }
 
     pub fn from_readable(mut r: impl Read) -> Result<Self, String> {
@@ -29,54 +143,204 @@ impl Stats {
             .read_to_string(&mut s)
             .map_err(|e|format!("cannot read metadata: {}", e))?;
 
-        for line in s.split("\n").map(|ln| ln.trim()).filter(|ln| ln.len() > 0) {
+        for line in s.split("\n").map(|ln| ln.trim()).filter(|ln| !ln.is_empty()) {
             let delim_pos = line.find('=').ok_or(format!("invalid metadata line: '{}'", line))?;
             if delim_pos == 0 {
                 return Err(format!("empty param name in metadata: '{}'", line));
             }
             let param = &line[.. delim_pos];
             let val = &line[delim_pos + 1 ..];            
-            if !map.insert(param, val).is_none() {
+            if map.insert(param, val).is_some() {
                 return Err(format!("duplicate key: '{}'", param));
             }
         }
 
-        Ok(Self {
+        let version = match map.remove("format") {
+            Some(v) => v.parse::<u32>().ok().filter(|v| *v > 0).ok_or(format!("invalid metadata format version '{}'", v))?,
+            None => 1
+        };
+        if version > FORMAT_VERSION {
+            return Err(format!("metadata format version {} is newer than {} supported by this release of bigarchiver, please upgrade it", version, FORMAT_VERSION));
+        }
+        Self::migrate(&mut map, version);
+
+        let mut unknown = map.iter()
+            .filter(|(k, _)| !KNOWN_KEYS.contains(k))
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect::<Vec<_>>();
+        unknown.sort();
+
+        let stats = Self {
+                format_version: version,
                 in_data_len: Self::get_and_parse::<_, _>(&map, "in_len", |v| { v.parse::<usize>() })?,
                 in_data_hash: Self::get_and_parse::<_, _>(&map, "in_hash", |v| { u64::from_str_radix(v, 16) })?,
                 hash_seed: Self::get_and_parse::<_, _>(&map, "hash_seed", |v| { u64::from_str_radix(v, 16) })?,
-                compressed_len: Self::get_and_parse::<_, _>(&map, "xz_len", |v| { v.parse::<usize>() })?,
+                compressed_len: Self::get_and_parse::<_, _>(&map, "compressed_len", |v| { v.parse::<usize>() })?,
                 out_nr_chunks: Self::get_and_parse::<_, _>(&map, "nr_chunks", |v| { v.parse::<usize>() })?,
                 out_chunk_size: Self::get_and_parse::<_, _>(&map, "chunk_len", |v| { v.parse::<usize>() })?,
                 alg: Self::get(&map, "alg")?.to_owned(),
                 auth_chunk_size: Self::get_and_parse::<_, _>(&map, "auth_len", |v| { v.parse::<usize>() })?,
                 auth_string: Self::get(&map, "auth")?.to_owned(),
-                misc_info: map.get("misc_info").map(|s| s.to_string())
-        })
+                misc_info: map.get("misc_info").map(|s| s.to_string()),
+                backup_info: Self::get_backup_info(&map)?,
+                chunks: Self::get_chunks(&map, "chunks")?,
+                parity_group_len: Self::get_and_parse::<_, _>(&map, "parity_group", |v| { v.parse::<usize>() })?,
+                parity_nr_per_group: Self::get_and_parse::<_, _>(&map, "parity_nr", |v| { v.parse::<usize>() })?,
+                parity_chunks: Self::get_chunks(&map, "parity_chunks")?,
+                repair_info: map.get("repair_info").map(|s| s.to_string()),
+                copies: Self::get_copies(&map)?,
+                chunk_pattern: map.get("chunk_pattern").map(|s| s.to_string()),
+                archive_id: map.get("archive_id").map(|v| parse_archive_id(v)).transpose()?,
+                labels: Self::get_labels(&map)?,
+                labels_mac: map.get("labels_mac").map(|s| s.to_string()),
+                unknown
+        };
+        if stats.out_nr_chunks != 0 && !stats.chunks.is_empty() && stats.out_nr_chunks != stats.chunks.len() {
+            return Err(format!("metadata lists {} chunks while nr_chunks={}", stats.chunks.len(), stats.out_nr_chunks));
+        }
+        Ok(stats)
+    }
+
+    // fills in keys which metadata of older format versions may lack
+    fn migrate(map: &mut HashMap<&str, &str>, version: u32) {
+        if version < 3 {
+            if let Some(len) = map.remove("xz_len") {
+                map.insert("compressed_len", len);
+            }
+        }
+        if version < 2 {
+            for (key, default) in [("chunks", ""), ("parity_group", "0"), ("parity_nr", "0"), ("parity_chunks", "")] {
+                map.entry(key).or_insert(default);
+            }
+        }
     }
 
     pub fn as_string(&self) -> String {
-        format!("\
+        let mut s = format!("\
+                format={}\n\
                 in_len={}\n\
                 in_hash={:016x}\n\
                 hash_seed={:016x}\n\
-                xz_len={}\n\
+                {}={}\n\
                 nr_chunks={}\n\
                 chunk_len={}\n\
                 alg={}\n\
                 auth={}\n\
                 auth_len={}\n\
-                misc_info={}\n",
+                misc_info={}\n\
+                chunks={}\n\
+                parity_group={}\n\
+                parity_nr={}\n\
+                parity_chunks={}\n",
+                self.format_version,
                 self.in_data_len,
                 self.in_data_hash,
                 self.hash_seed,
+                if self.format_version < 3 { "xz_len" } else { "compressed_len" },
                 self.compressed_len,
                 self.out_nr_chunks,
                 self.out_chunk_size,
                 self.alg,
                 self.auth_string, 
                 self.auth_chunk_size,
-                self.misc_info.as_ref().unwrap_or(&String::new()))
+                self.misc_info.as_ref().unwrap_or(&String::new()),
+                Self::chunks_as_string(&self.chunks),
+                self.parity_group_len,
+                self.parity_nr_per_group,
+                Self::chunks_as_string(&self.parity_chunks));
+        if let Some(repair_info) = &self.repair_info {
+            s.push_str(&format!("repair_info={}\n", repair_info));
+        }
+        if !self.copies.is_empty() {
+            let copies = self.copies.iter()
+                .map(|(tpl, ok)| format!("{}:{}", tpl, if *ok { "ok" } else { "failed" }))
+                .collect::<Vec<String>>()
+                .join(",");
+            s.push_str(&format!("copies={}\n", copies));
+        }
+        if let Some(chunk_pattern) = &self.chunk_pattern {
+            s.push_str(&format!("chunk_pattern={}\n", chunk_pattern));
+        }
+        if let Some(archive_id) = &self.archive_id {
+            s.push_str(&format!("archive_id={}\n", archive_id_as_string(archive_id)));
+        }
+        if !self.labels.is_empty() {
+            s.push_str(&format!("labels={}\n", labels_as_string(&self.labels)));
+        }
+        if let Some(mac) = &self.labels_mac {
+            s.push_str(&format!("labels_mac={}\n", mac));
+        }
+        if let Some(info) = &self.backup_info {
+            s.push_str(&format!("\
+                tool_version={}\n\
+                host={}\n\
+                started={}\n\
+                ended={}\n\
+                duration_ms={}\n\
+                throughput={}\n\
+                compress_level={}\n\
+                compress_threads={}\n\
+                buf_size={}\n",
+                info.tool_version, info.host, info.started, info.ended, info.duration_ms, info.throughput,
+                info.compress_level, info.compress_threads, info.buf_size));
+        }
+        for (key, val) in &self.unknown {
+            s.push_str(&format!("{}={}\n", key, val));
+        }
+        s
+    }
+
+    pub fn as_json(&self) -> Value {
+        let chunks_as_json = |chunks: &[ChunkInfo]| chunks.iter()
+            .map(|c| json!({ "len": c.len, "hash": format!("{:016x}", c.hash) }))
+            .collect::<Vec<_>>();
+        let backup_info = self.backup_info.as_ref().map(|info| json!({
+            "tool_version": info.tool_version,
+            "host": info.host,
+            "started": info.started,
+            "ended": info.ended,
+            "duration_ms": info.duration_ms,
+            "throughput": info.throughput,
+            "compress_level": info.compress_level,
+            "compress_threads": info.compress_threads,
+            "buf_size": info.buf_size
+        }));
+        let copies = self.copies.iter()
+            .map(|(tpl, ok)| json!({ "template": tpl, "ok": ok }))
+            .collect::<Vec<_>>();
+        json!({
+            "format": self.format_version,
+            "archive_id": self.archive_id.as_ref().map(archive_id_as_string),
+            "labels": labels_as_json(&self.labels),
+            "labels_authenticated": self.labels_mac.is_some(),
+            "in_len": self.in_data_len,
+            "in_hash": format!("{:016x}", self.in_data_hash),
+            "hash_seed": format!("{:016x}", self.hash_seed),
+            "compressed_len": self.compressed_len,
+            "nr_chunks": self.out_nr_chunks,
+            "chunk_len": self.out_chunk_size,
+            "alg": self.alg,
+            "auth": self.auth_string,
+            "auth_len": self.auth_chunk_size,
+            "backup": backup_info,
+            "misc_info": self.misc_info.as_ref().filter(|info| !info.is_empty()),
+            "chunks": chunks_as_json(&self.chunks),
+            "parity_group": self.parity_group_len,
+            "parity_nr": self.parity_nr_per_group,
+            "parity_chunks": chunks_as_json(&self.parity_chunks),
+            "repair_info": self.repair_info,
+            "copies": copies,
+            "chunk_pattern": self.chunk_pattern,
+            "unknown": self.unknown.iter().map(|(k, v)| (k.clone(), json!(v))).collect::<Map<_, _>>()
+        })
+    }
+
+    fn chunks_as_string(chunks: &[ChunkInfo]) -> String {
+        chunks
+            .iter()
+            .map(|c| format!("{}:{:016x}", c.len, c.hash))
+            .collect::<Vec<String>>()
+            .join(",")
     }
 
     fn get_and_parse<T, P>(map: &HashMap<&str, &str>, field_name: &str, parser: P) -> Result<T, String>
@@ -87,6 +351,65 @@ impl Stats {
                 .map_err(|e| format!("could not parse numeric field '{}': {}", field_name, e))
     }
 
+    // list of chunks in form of 'len:hash,len:hash,...'
+    fn get_chunks(map: &HashMap<&str, &str>, field_name: &str) -> Result<Vec<ChunkInfo>, String> {
+        let list = match map.get(field_name) {
+            Some(list) if !list.is_empty() => list,
+            _ => { return Ok(Vec::new()); }
+        };
+        list.split(',').map(|item| {
+            let (len, hash) = item.split_once(':')
+                .ok_or(format!("invalid chunk description '{}' in field '{}'", item, field_name))?;
+            Ok(ChunkInfo {
+                len: len.parse::<usize>()
+                    .map_err(|e| format!("could not parse chunk length '{}' in field '{}': {}", len, field_name, e))?,
+                hash: u64::from_str_radix(hash, 16)
+                    .map_err(|e| format!("could not parse chunk hash '{}' in field '{}': {}", hash, field_name, e))?
+            })
+        }).collect()
+    }
+
+    // optional list of mirrored copies in form of 'template:ok,template:failed,...'
+    fn get_copies(map: &HashMap<&str, &str>) -> Result<Vec<(String, bool)>, String> {
+        let list = match map.get("copies") {
+            Some(list) if !list.is_empty() => list,
+            _ => { return Ok(Vec::new()); }
+        };
+        list.split(',').map(|item| {
+            match item.rsplit_once(':') {
+                Some((tpl, "ok")) => Ok((tpl.to_owned(), true)),
+                Some((tpl, "failed")) => Ok((tpl.to_owned(), false)),
+                _ => Err(format!("invalid copy description '{}' in field 'copies'", item))
+            }
+        }).collect()
+    }
+
+    // optional list of labels in form of 'key=value,tag,...'
+    fn get_labels(map: &HashMap<&str, &str>) -> Result<Vec<Label>, String> {
+        match map.get("labels") {
+            Some(list) if !list.is_empty() => list.split(',').map(Label::parse).collect(),
+            _ => Ok(Vec::new())
+        }
+    }
+
+    // written all together, so all of them are required if one is present
+    fn get_backup_info(map: &HashMap<&str, &str>) -> Result<Option<BackupInfo>, String> {
+        if !map.contains_key("started") {
+            return Ok(None);
+        }
+        Ok(Some(BackupInfo {
+            tool_version: Self::get(map, "tool_version")?,
+            host: Self::get(map, "host")?,
+            started: Self::get_and_parse(map, "started", |v| v.parse::<u64>())?,
+            ended: Self::get_and_parse(map, "ended", |v| v.parse::<u64>())?,
+            duration_ms: Self::get_and_parse(map, "duration_ms", |v| v.parse::<u64>())?,
+            throughput: Self::get_and_parse(map, "throughput", |v| v.parse::<u64>())?,
+            compress_level: Self::get_and_parse(map, "compress_level", |v| v.parse::<u8>())?,
+            compress_threads: Self::get_and_parse(map, "compress_threads", |v| v.parse::<usize>())?,
+            buf_size: Self::get_and_parse(map, "buf_size", |v| v.parse::<usize>())?
+        }))
+    }
+
     fn get(map: &HashMap<&str, &str>, field_name: &str) -> Result<String, String> {
         map.get(field_name)
             .map(|s| s.to_string())
@@ -97,7 +420,8 @@ impl Stats {
 
 #[cfg(test)]
 mod tests {
-    use crate::stats::Stats;
+    use crate::stats::{Stats, ChunkInfo, BackupInfo, Label, FORMAT_VERSION};
+    use std::collections::HashMap;
 
     #[test]
     fn parse_good() {
@@ -114,6 +438,7 @@ mod tests {
                 auth_len=3\n
                 misc_info=ABC=1, XYZ=2".as_bytes().to_vec().as_slice()).unwrap(),
             Stats {
+                format_version: 1,
                 in_data_len: 12345,
                 in_data_hash: 0xabcde,
                 hash_seed: 0xedcba,
@@ -123,11 +448,135 @@ mod tests {
                 alg: "aes128-gcm".to_owned(),
                 auth_string: "Author Name".to_owned(),
                 auth_chunk_size: 3,
-                misc_info: Some("ABC=1, XYZ=2".to_owned())
+                misc_info: Some("ABC=1, XYZ=2".to_owned()),
+                backup_info: None,
+                chunks: Vec::new(),
+                parity_group_len: 0,
+                parity_nr_per_group: 0,
+                parity_chunks: Vec::new(),
+                repair_info: None,
+                copies: Vec::new(),
+                chunk_pattern: None,
+                archive_id: None,
+                labels: Vec::new(),
+                labels_mac: None,
+                unknown: Vec::new()
             }
         );
     }
 
+    #[test]
+    fn chunks_roundtrip() {
+        let stats = Stats {
+            format_version: FORMAT_VERSION,
+            in_data_len: 12345,
+            in_data_hash: 0xabcde,
+            hash_seed: 0xedcba,
+            compressed_len: 54321,
+            out_nr_chunks: 3,
+            out_chunk_size: 10,
+            alg: "none".to_owned(),
+            auth_string: String::new(),
+            auth_chunk_size: 0,
+            misc_info: None,
+            backup_info: Some(BackupInfo {
+                tool_version: "1.2.3".to_owned(), host: "backup-host".to_owned(), started: 1700000000, ended: 1700000060,
+                duration_ms: 60123, throughput: 205, compress_level: 6, compress_threads: 4, buf_size: 1048576
+            }),
+            chunks: vec![
+                ChunkInfo{ len: 10, hash: 0x1234567812345678 },
+                ChunkInfo{ len: 10, hash: 0x1 },
+                ChunkInfo{ len: 5, hash: 0xffffffffffffffff }],
+            parity_group_len: 2,
+            parity_nr_per_group: 1,
+            parity_chunks: vec![
+                ChunkInfo{ len: 10, hash: 0xabc },
+                ChunkInfo{ len: 5, hash: 0xdef }],
+            repair_info: Some("2 chunks rebuilt".to_owned()),
+            copies: vec![("/mnt/a/bk%%%%".to_owned(), true), ("s3://bucket/bk%%%%".to_owned(), false)],
+            chunk_pattern: Some("{n/1000}/{n%1000}".to_owned()),
+            archive_id: Some([0x1b, 0x4e, 0x28, 0xba, 0x2f, 0xa1, 0x41, 0xd2, 0x88, 0x3f, 0x00, 0x16, 0xd3, 0xcc, 0xa4, 0x27]),
+            labels: vec![Label::parse("db=main").unwrap(), Label::parse("nightly").unwrap()],
+            labels_mac: Some("abcdef".to_owned()),
+            unknown: vec![("added_later".to_owned(), "x=1".to_owned())]
+        };
+        assert!(stats.as_string().starts_with("format=3\n"));
+        assert!(stats.as_string().contains("\narchive_id=1b4e28ba-2fa1-41d2-883f-0016d3cca427\nlabels=db=main,nightly\nlabels_mac=abcdef\n"));
+        let mut parsed = Stats::from_readable(stats.as_string().as_bytes()).unwrap();
+        assert_eq!(parsed.misc_info, Some(String::new()));
+        parsed.misc_info = None;
+        assert_eq!(parsed, stats);
+
+        let wrong_nr = stats.as_string().replace("nr_chunks=3", "nr_chunks=4");
+        assert!(Stats::from_readable(wrong_nr.as_bytes()).is_err());
+        let bad_id = stats.as_string().replace("-0016d3cca427", "-0016d3cca42x");
+        assert!(Stats::from_readable(bad_id.as_bytes()).is_err());
+        let partial_info = stats.as_string().replace("host=backup-host\n", "");
+        assert!(Stats::from_readable(partial_info.as_bytes()).is_err());
+
+        let json = stats.as_json().to_string();
+        assert!(json.starts_with(r#"{"format":3,"archive_id":"1b4e28ba-2fa1-41d2-883f-0016d3cca427","labels":{"db":"main","nightly":true},"labels_authenticated":true,"in_len":12345,"#), "{}", json);
+        assert!(json.contains(r#""backup":{"tool_version":"1.2.3","host":"backup-host","started":1700000000,"ended":1700000060,"duration_ms":60123,"#), "{}", json);
+        assert!(json.contains(r#""misc_info":null,"chunks":[{"len":10,"hash":"1234567812345678"},"#), "{}", json);
+        assert!(json.ends_with(r#""chunk_pattern":"{n/1000}/{n%1000}","unknown":{"added_later":"x=1"}}"#), "{}", json);
+    }
+
+    #[test]
+    fn labels() {
+        let labels = ["db=main", "env=prod", "nightly"].map(|l| Label::parse(l).unwrap());
+        assert_eq!(labels[0], Label { key: "db".to_owned(), value: Some("main".to_owned()) });
+        assert_eq!(labels[2], Label { key: "nightly".to_owned(), value: None });
+        assert_eq!(labels[1].to_string(), "env=prod");
+        for bad in ["", "=x", "x=", "a=b=c", "a,b", " a", "a=b ", "a b=c\n"] {
+            assert!(Label::parse(bad).is_err(), "{}", bad);
+        }
+        assert!(Label::parse("db=main").unwrap().matches(&labels));
+        assert!(Label::parse("db").unwrap().matches(&labels));
+        assert!(Label::parse("nightly").unwrap().matches(&labels));
+        assert!(!Label::parse("db=other").unwrap().matches(&labels));
+        assert!(!Label::parse("nightly=yes").unwrap().matches(&labels));
+        assert!(!Label::parse("weekly").unwrap().matches(&labels));
+    }
+
+    #[test]
+    fn format_versions() {
+        let v3 = Stats::new().as_string();
+
+        // newer formats are refused
+        let err = Stats::from_readable(v3.replace("format=3", "format=4").as_bytes()).unwrap_err();
+        assert!(err.contains("metadata format version 4 is newer than 3"), "{}", err);
+        assert!(Stats::from_readable(v3.replace("format=3", "format=x").as_bytes()).is_err());
+        assert_eq!(Stats::from_readable(v3.replace("format=3", "format=2").as_bytes()).unwrap().format_version, 2);
+
+        // keys which may be absent in format 1 are required since format 2
+        let no_parity = v3.replace("parity_nr=0\n", "");
+        assert!(Stats::from_readable(no_parity.as_bytes()).is_err());
+        let v1 = Stats::from_readable(no_parity.replace("format=3\n", "").replace("\nchunks=\n", "\n").as_bytes()).unwrap();
+        assert_eq!(v1.format_version, 1);
+        assert_eq!(v1.parity_nr_per_group, 0);
+        assert!(v1.chunks.is_empty());
+        assert!(v1.as_string().starts_with("format=1\n"));
+        assert!(v1.as_string().contains("\nxz_len=0\n"));
+    }
+
+    // required keys of releases before version 2, which ignore 'format' as any other unknown key
+    fn parse_unversioned(s: &str) -> Result<(), String> {
+        let map: HashMap<&str, &str> = s.lines().filter_map(|ln| ln.split_once('=')).collect();
+        for key in ["in_len", "in_hash", "hash_seed", "xz_len", "nr_chunks", "chunk_len", "alg", "auth", "auth_len"] {
+            map.get(key).ok_or(format!("field '{}' not found", key))?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn unversioned_readers_refuse_format_3() {
+        let v3 = Stats::new();
+        assert_eq!(parse_unversioned(&v3.as_string()).unwrap_err(), "field 'xz_len' not found");
+        let v2 = Stats { format_version: 2, ..Stats::new() };
+        parse_unversioned(&v2.as_string()).unwrap();
+        assert_eq!(Stats::from_readable(v2.as_string().as_bytes()).unwrap().as_string(), v2.as_string());
+    }
+
     #[test]
     fn parse_bad() {
         // duplicate key
@@ -166,5 +615,21 @@ mod tests {
             auth=Author Name\n\
             auth_len=3\n
             misc_info=XXX".as_bytes().to_vec().as_slice()).is_err());
+
+        // invalid chunk list
+        for chunks in ["10", "10:", "x:abc", "10:abc,", "10:xyz"] {
+            assert!(
+                Stats::from_readable(format!("\
+                in_len=12345\n\
+                in_hash=abcde\n\
+                hash_seed=edcba\n\
+                xz_len=54321\n\
+                nr_chunks=1\n\
+                chunk_len=2\n\
+                alg=none\n\
+                auth=\n\
+                auth_len=0\n\
+                chunks={}", chunks).as_bytes()).is_err());
+        }
     }
 }
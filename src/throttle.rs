@@ -0,0 +1,236 @@
+use crate::joiner::MultiFilesReaderSource;
+use crate::splitter::MultiFilesWriterTarget;
+use crate::stats::ChunkInfo;
+use std::thread;
+use std::time::{Duration, Instant};
+use crate::local_now;
+use time::OffsetDateTime;
+
+// limits of chunk data transfer rate, in bytes per second; metadata is not limited
+#[derive(Clone, Default, PartialEq, Debug)]
+pub struct ThrottleConfig {
+    pub write_rate: Option<u64>,
+    pub read_rate: Option<u64>,
+    pub burst: Option<u64>, // how many bytes may be transferred at once after a pause; defaults to one second worth of data
+    pub schedule: Vec<(u32, u32)> // minutes of day when limits apply, e.g. (480, 1200) for 08:00-20:00; always if empty
+}
+
+// parses sizes like 1048576, 512K, 10M or 1G
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let (digits, mult) = match s.chars().last() {
+        Some('K' | 'k') => (&s[..s.len() - 1], 1024),
+        Some('M' | 'm') => (&s[..s.len() - 1], 1_048_576),
+        Some('G' | 'g') => (&s[..s.len() - 1], 1_073_741_824),
+        _ => (s, 1)
+    };
+    match digits.parse::<u64>() {
+        Ok(n) if n > 0 => Ok(n * mult),
+        _ => Err(format!("'{}' is not a positive size like 500K or 10M", s))
+    }
+}
+
+// parses time windows like 08:00-20:00 or 22:00-06:00,12:00-13:00
+pub fn parse_schedule(s: &str) -> Result<Vec<(u32, u32)>, String> {
+    let minute_of_day = |hm: &str| -> Option<u32> {
+        let (h, m) = hm.split_once(':')?;
+        let (h, m) = (h.parse::<u32>().ok()?, m.parse::<u32>().ok()?);
+        if h < 24 && m < 60 && hm.len() == 5 { Some(h * 60 + m) } else { None }
+    };
+    s.split(',').map(|window| {
+        window.split_once('-')
+            .and_then(|(from, to)| Some((minute_of_day(from)?, minute_of_day(to)?)))
+            .filter(|(from, to)| from != to)
+            .ok_or(format!("'{}' is not a time window like 08:00-20:00", window))
+    }).collect()
+}
+
+fn in_schedule(schedule: &[(u32, u32)], minute: u32) -> bool {
+    schedule.is_empty() || schedule.iter().any(|&(from, to)| {
+        if from < to { minute >= from && minute < to } else { minute >= from || minute < to } // crosses midnight
+    })
+}
+
+// token bucket which lets `rate` bytes per second through on average
+struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last: Instant,
+    schedule: Vec<(u32, u32)>,
+    clock: fn() -> OffsetDateTime // local time
+}
+
+impl RateLimiter {
+    fn new(rate: u64, cfg: &ThrottleConfig) -> Self {
+        let burst = cfg.burst.unwrap_or(rate) as f64;
+        Self { rate: rate as f64, burst, tokens: burst, last: Instant::now(), schedule: cfg.schedule.clone(), clock: local_now }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        self.tokens = f64::min(self.burst, self.tokens + now.duration_since(self.last).as_secs_f64() * self.rate);
+        self.last = now;
+    }
+
+    fn active(&self) -> bool {
+        if self.schedule.is_empty() {
+            return true;
+        }
+        let now = (self.clock)();
+        in_schedule(&self.schedule, now.hour() as u32 * 60 + now.minute() as u32)
+    }
+
+    // waits until `nr_bytes` just transferred fit into the rate
+    fn consume(&mut self, nr_bytes: usize) {
+        self.refill();
+        if !self.active() {
+            return;
+        }
+        self.tokens -= nr_bytes as f64;
+        if self.tokens < 0.0 {
+            thread::sleep(Duration::from_secs_f64(-self.tokens / self.rate));
+            self.refill();
+        }
+    }
+}
+
+pub struct ThrottledWriter {
+    inner: Box<dyn MultiFilesWriterTarget>,
+    limiter: Option<RateLimiter>
+}
+
+impl ThrottledWriter {
+    pub fn new(inner: Box<dyn MultiFilesWriterTarget>, cfg: &ThrottleConfig) -> Self {
+        Self { inner, limiter: cfg.write_rate.map(|rate| RateLimiter::new(rate, cfg)) }
+    }
+}
+
+impl MultiFilesWriterTarget for ThrottledWriter {
+    fn open_next_file(&mut self, full_path: &str) -> Result<(), String> {
+        self.inner.open_next_file(full_path)
+    }
+
+    fn close_current_file(&mut self) -> Result<(), String> {
+        self.inner.close_current_file()
+    }
+
+    fn write_to_current_file(&mut self, data: &[u8]) -> Result<(), String> {
+        // large portions are split, so that the link is not saturated by a single write
+        let portion_len = self.limiter.as_ref().map(|l| usize::max(1, (l.burst / 4.0) as usize)).unwrap_or(data.len().max(1));
+        for portion in data.chunks(portion_len) {
+            if let Some(limiter) = self.limiter.as_mut() {
+                limiter.consume(portion.len());
+            }
+            self.inner.write_to_current_file(portion)?;
+        }
+        Ok(())
+    }
+
+    fn write_single_file(&self, path: &str, contents: &[u8]) -> Result<(), String> {
+        self.inner.write_single_file(path, contents)
+    }
+
+    fn set_current_file_info(&mut self, info: &ChunkInfo) {
+        self.inner.set_current_file_info(info)
+    }
+
+    fn remove_file(&self, path: &str) -> Result<(), String> {
+        self.inner.remove_file(path)
+    }
+}
+
+pub struct ThrottledReader {
+    inner: Box<dyn MultiFilesReaderSource>,
+    limiter: Option<RateLimiter>
+}
+
+impl ThrottledReader {
+    pub fn new(inner: Box<dyn MultiFilesReaderSource>, cfg: &ThrottleConfig) -> Self {
+        Self { inner, limiter: cfg.read_rate.map(|rate| RateLimiter::new(rate, cfg)) }
+    }
+}
+
+impl MultiFilesReaderSource for ThrottledReader {
+    fn open_next_file(&mut self, full_path: &str) -> Result<bool, String> {
+        self.inner.open_next_file(full_path)
+    }
+
+    fn read_from_current_file(&mut self, buf: &mut [u8]) -> Result<usize, String> {
+        let max_len = match &self.limiter {
+            Some(limiter) => usize::min(buf.len(), usize::max(1, (limiter.burst / 4.0) as usize)),
+            None => buf.len()
+        };
+        let nr_read = self.inner.read_from_current_file(&mut buf[..max_len])?;
+        if let Some(limiter) = self.limiter.as_mut() {
+            limiter.consume(nr_read);
+        }
+        Ok(nr_read)
+    }
+
+    fn close_current_file(&mut self) -> Result<(), String> {
+        self.inner.close_current_file()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes_and_schedules() {
+        assert_eq!(parse_size("1000").unwrap(), 1000);
+        assert_eq!(parse_size("512K").unwrap(), 524288);
+        assert_eq!(parse_size("10M").unwrap(), 10 * 1_048_576);
+        assert_eq!(parse_size("1g").unwrap(), 1_073_741_824);
+        assert!(parse_size("0").is_err());
+        assert!(parse_size("M").is_err());
+        assert!(parse_size("10MB").is_err());
+
+        assert_eq!(parse_schedule("08:00-20:00").unwrap(), vec![(480, 1200)]);
+        assert_eq!(parse_schedule("22:30-06:00,12:00-13:00").unwrap(), vec![(1350, 360), (720, 780)]);
+        assert!(parse_schedule("8:00-20:00").is_err());
+        assert!(parse_schedule("08:00-24:00").is_err());
+        assert!(parse_schedule("08:00-08:00").is_err());
+        assert!(parse_schedule("08:00").is_err());
+
+        let night = parse_schedule("22:00-06:00").unwrap();
+        assert!(in_schedule(&night, 23 * 60));
+        assert!(in_schedule(&night, 0));
+        assert!(!in_schedule(&night, 6 * 60));
+        assert!(!in_schedule(&night, 12 * 60));
+        assert!(in_schedule(&[], 12 * 60));
+    }
+
+    #[test]
+    fn rate_is_limited() {
+        let cfg = ThrottleConfig { write_rate: Some(1_000_000), burst: Some(10_000), ..Default::default() };
+        let mut limiter = RateLimiter::new(1_000_000, &cfg);
+        let start = Instant::now();
+        for _ in 0..6 {
+            limiter.consume(10_000);
+        }
+        // the first 10000 bytes pass at once, the rest take 0.05 s
+        let took = start.elapsed();
+        assert!(took >= Duration::from_millis(45), "{:?}", took);
+        assert!(took < Duration::from_millis(500), "{:?}", took);
+
+        let cfg = ThrottleConfig { schedule: vec![(0, 1)], ..cfg };
+        let mut limiter = RateLimiter::new(1, &cfg);
+        limiter.clock = || OffsetDateTime::UNIX_EPOCH + Duration::from_secs(12 * 3600);
+        let start = Instant::now();
+        limiter.consume(1_000_000); // outside of the schedule
+        assert!(start.elapsed() < Duration::from_millis(100));
+    }
+
+    #[test]
+    fn schedule_is_in_local_time() {
+        let cfg = ThrottleConfig { schedule: parse_schedule("13:00-15:00").unwrap(), ..Default::default() };
+        let mut limiter = RateLimiter::new(1, &cfg);
+        // 12:00 UTC
+        limiter.clock = || OffsetDateTime::UNIX_EPOCH + Duration::from_secs(12 * 3600);
+        assert!(!limiter.active());
+        // 14:00 in a time zone 2 hours ahead of UTC
+        limiter.clock = || (OffsetDateTime::UNIX_EPOCH + Duration::from_secs(12 * 3600)).to_offset(time::UtcOffset::from_hms(2, 0, 0).unwrap());
+        assert!(limiter.active());
+    }
+}
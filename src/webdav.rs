@@ -0,0 +1,389 @@
+use crate::joiner::MultiFilesReaderSource;
+use crate::splitter::MultiFilesWriterTarget;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::thread;
+use std::time::Duration;
+
+pub const DAV_PREFIX: &str = "dav://"; // plain http
+pub const DAVS_PREFIX: &str = "davs://"; // https
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+// access to a WebDAV server; files are addressed as dav://host/path or davs://host/path
+#[derive(Clone)]
+pub struct WebDavConfig {
+    pub user: Option<String>,
+    pub pass: Option<String>,
+    pub spool_dir: String, // where a chunk is kept until its upload is verified, so that it can be sent again
+    pub retries: usize,
+    pub first_backoff: Duration
+}
+
+impl WebDavConfig {
+    // basic auth credentials are taken from BIGARCHIVER_WEBDAV_USER and BIGARCHIVER_WEBDAV_PASS
+    pub fn from_env() -> Self {
+        let var = |name: &str| std::env::var(name).ok().filter(|v| !v.is_empty());
+        Self {
+            user: var("BIGARCHIVER_WEBDAV_USER"),
+            pass: var("BIGARCHIVER_WEBDAV_PASS"),
+            spool_dir: std::env::temp_dir().to_string_lossy().into_owned(),
+            retries: 5,
+            first_backoff: Duration::from_secs(1)
+        }
+    }
+}
+
+pub fn is_dav_path(path: &str) -> bool {
+    path.starts_with(DAV_PREFIX) || path.starts_with(DAVS_PREFIX)
+}
+
+fn http_url(path: &str) -> Result<String, String> {
+    let (scheme, rest) = match (path.strip_prefix(DAV_PREFIX), path.strip_prefix(DAVS_PREFIX)) {
+        (Some(rest), _) => ("http", rest),
+        (_, Some(rest)) => ("https", rest),
+        _ => { return Err(format!("{} is not like dav://host/path or davs://host/path", path)); }
+    };
+    match rest.split_once('/') {
+        Some((host, file)) if !host.is_empty() && !file.is_empty() && !file.ends_with('/') => {
+            Ok(format!("{}://{}/{}", scheme, host, url_encode(file)))
+        },
+        _ => Err(format!("{} is not like dav://host/path or davs://host/path", path))
+    }
+}
+
+// percent-encoding of everything except unreserved characters and slashes
+fn url_encode(s: &str) -> String {
+    s.bytes().map(|b| match b {
+        b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => (b as char).to_string(),
+        _ => format!("%{:02X}", b)
+    }).collect()
+}
+
+// value of a property in PROPFIND response, with any namespace prefix
+fn dav_prop<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    let mut from = 0;
+    while let Some(pos) = xml[from..].find(name) {
+        let pos = from + pos;
+        from = pos + name.len();
+        let tag_start = xml[..pos].rfind('<')?;
+        let prefix = &xml[tag_start + 1..pos];
+        let after = &xml[from..];
+        if (prefix.is_empty() || (prefix.ends_with(':') && !prefix.starts_with('/') && !prefix.contains(' ')))
+            && after.starts_with(['>', ' ', '/'])
+        {
+            let start = from + after.find('>')? + 1;
+            if xml[..start].ends_with("/>") {
+                return Some("");
+            }
+            let len = xml[start..].find('<')?;
+            return Some(xml[start..start + len].trim());
+        }
+    }
+    None
+}
+
+struct DavClient {
+    cfg: WebDavConfig,
+    agent: ureq::Agent
+}
+
+impl DavClient {
+    fn new(cfg: &WebDavConfig) -> Self {
+        Self { cfg: cfg.clone(), agent: ureq::AgentBuilder::new().timeout_connect(Duration::from_secs(30)).build() }
+    }
+
+    fn request(&self, method: &str, url: &str) -> ureq::Request {
+        let req = self.agent.request(method, url);
+        match &self.cfg.user {
+            Some(user) => {
+                let creds = BASE64.encode(format!("{}:{}", user, self.cfg.pass.as_deref().unwrap_or("")));
+                req.set("authorization", &format!("Basic {}", creds))
+            },
+            None => req
+        }
+    }
+
+    // retries transport errors and server-side failures with backoff; returns None if there is no such file
+    fn send<F>(&self, method: &str, path: &str, mut send_once: F) -> Result<Option<ureq::Response>, String>
+    where F: FnMut(ureq::Request) -> Result<ureq::Response, Box<ureq::Error>>
+    {
+        let url = http_url(path)?;
+        let mut backoff = self.cfg.first_backoff;
+        for attempt in 1.. {
+            let err = match send_once(self.request(method, &url)).map_err(|e| *e) {
+                Ok(resp) => { return Ok(Some(resp)); },
+                Err(ureq::Error::Status(404, _)) => { return Ok(None); },
+                Err(ureq::Error::Status(code, resp)) if code < 500 && code != 429 => {
+                    return Err(format!("{} {} failed with HTTP {}: {}", method, path, code, resp.into_string().unwrap_or_default()));
+                },
+                Err(e) => e
+            };
+            if attempt > self.cfg.retries {
+                return Err(format!("{} {} failed after {} attempt(s): {}", method, path, attempt, err));
+            }
+            eprintln!("{} {} failed (attempt {} of {}): {}, retrying in {:?}", method, path, attempt, self.cfg.retries + 1, err, backoff);
+            thread::sleep(backoff);
+            backoff = Duration::min(backoff * 2, MAX_BACKOFF);
+        }
+        unreachable!()
+    }
+
+    // size and etag of a file, or None if there is no such file
+    fn stat(&self, path: &str) -> Result<Option<(u64, Option<String>)>, String> {
+        let body = "<?xml version=\"1.0\" encoding=\"utf-8\"?>\
+            <D:propfind xmlns:D=\"DAV:\"><D:prop><D:getcontentlength/><D:getetag/></D:prop></D:propfind>";
+        let resp = match self.send("PROPFIND", path, |req| {
+            req.set("depth", "0").set("content-type", "application/xml").send_string(body).map_err(Box::new)
+        })? {
+            Some(resp) => resp,
+            None => { return Ok(None); }
+        };
+        let xml = resp.into_string().map_err(|e| format!("could not read properties of {}: {}", path, e))?;
+        let size = dav_prop(&xml, "getcontentlength")
+            .and_then(|s| s.parse().ok())
+            .ok_or(format!("no size in properties of {}", path))?;
+        let etag = dav_prop(&xml, "getetag").filter(|e| !e.is_empty()).map(|e| e.replace("&quot;", "\""));
+        Ok(Some((size, etag)))
+    }
+
+    // creates the collection with any missing parents
+    fn create_collection(&self, dir: &str) -> Result<(), String> {
+        let found = self.send("PROPFIND", dir, |req| req.set("depth", "0").call().map_err(Box::new))?;
+        if found.is_some() {
+            return Ok(());
+        }
+        if let Some((parent, _)) = dir.rsplit_once('/').filter(|(parent, _)| http_url(parent).is_ok()) {
+            self.create_collection(parent)?;
+        }
+        self.send("MKCOL", dir, |req| req.call().map_err(Box::new))?;
+        Ok(())
+    }
+
+    // uploads the file and checks that the server has stored all of it
+    fn put<R: Read, F>(&self, path: &str, size: u64, mut open: F) -> Result<(), String>
+    where F: FnMut() -> Result<R, String>
+    {
+        let mut verify_attempt = 0;
+        loop {
+            let resp = self.send("PUT", path, |req| {
+                let reader = open().map_err(|e| Box::new(ureq::Error::from(std::io::Error::other(e))))?;
+                req.set("content-length", &size.to_string()).send(reader).map_err(Box::new)
+            })?.ok_or(format!("could not PUT {}: parent collection does not exist", path))?;
+            let put_etag = resp.header("etag").map(|e| e.to_owned());
+
+            let err = match self.stat(path)? {
+                None => "file is not found after upload".to_owned(),
+                Some((stored, _)) if stored != size => format!("{} bytes are stored instead of {}", stored, size),
+                Some((_, Some(etag))) if put_etag.as_ref().is_some_and(|e| !e.starts_with("W/") && e != &etag) => {
+                    format!("etag {} does not match {} returned by upload", etag, put_etag.unwrap_or_default())
+                },
+                Some(_) => { return Ok(()); }
+            };
+            verify_attempt += 1;
+            if verify_attempt > self.cfg.retries {
+                return Err(format!("upload of {} is not verified: {}", path, err));
+            }
+            eprintln!("upload of {} is not verified: {}, uploading again", path, err);
+        }
+    }
+
+    // renames the file on the server, replacing any existing one at the destination
+    fn move_file(&self, from: &str, to: &str) -> Result<(), String> {
+        let dest = http_url(to)?;
+        self.send("MOVE", from, |req| req.set("destination", &dest).set("overwrite", "T").call().map_err(Box::new))?
+            .ok_or(format!("could not MOVE {} to {}: there is no such file", from, to))?;
+        Ok(())
+    }
+}
+
+struct Upload {
+    path: String,
+    spool_path: String,
+    spool: File,
+    size: u64
+}
+
+pub struct WebDavWriter {
+    client: DavClient,
+    current: Option<Upload>,
+    dir: Option<String> // collection of the last chunk, known to exist
+}
+
+impl WebDavWriter {
+    pub fn new(cfg: &WebDavConfig) -> Self {
+        Self { client: DavClient::new(cfg), current: None, dir: None }
+    }
+}
+
+impl MultiFilesWriterTarget for WebDavWriter {
+    fn open_next_file(&mut self, full_path: &str) -> Result<(), String> {
+        if let Some(upload) = &self.current {
+            return Err(format!("previous file {} was not closed before opening a new file {}", upload.path, full_path));
+        }
+        http_url(full_path)?;
+        let (dir, name) = full_path.rsplit_once('/').unwrap_or_default(); // SAFE: checked above
+        // chunks of a sharded archive go to collections which may not exist yet
+        if self.dir.as_deref() != Some(dir) && http_url(dir).is_ok() {
+            self.client.create_collection(dir)?;
+            self.dir = Some(dir.to_owned());
+        }
+        let spool_path = format!("{}/bigarchiver-{}-{}", self.client.cfg.spool_dir, std::process::id(), name);
+        let spool = File::options().create(true).truncate(true).read(true).write(true).open(&spool_path)
+            .map_err(|e| format!("could not create spool file {}: {}", spool_path, e))?;
+        self.current = Some(Upload { path: full_path.to_owned(), spool_path, spool, size: 0 });
+        eprintln!("writing to {}", full_path);
+        Ok(())
+    }
+
+    fn close_current_file(&mut self) -> Result<(), String> {
+        let upload = self.current.take().ok_or("no current file opened to close".to_owned())?;
+        let res = self.client.put(&upload.path, upload.size, || {
+            let mut spool = upload.spool.try_clone().map_err(|e| format!("could not reopen spool file {}: {}", upload.spool_path, e))?;
+            spool.seek(SeekFrom::Start(0)).map_err(|e| format!("could not rewind spool file {}: {}", upload.spool_path, e))?;
+            Ok(spool)
+        });
+        let _ = fs::remove_file(&upload.spool_path);
+        res
+    }
+
+    fn write_to_current_file(&mut self, data: &[u8]) -> Result<(), String> {
+        let upload = self.current.as_mut().ok_or("no current file opened to write".to_owned())?;
+        upload.spool.write_all(data).map_err(|e| format!("could not write {} bytes to spool file {}: {}", data.len(), upload.spool_path, e))?;
+        upload.size += data.len() as u64;
+        Ok(())
+    }
+
+    fn write_single_file(&self, path: &str, contents: &[u8]) -> Result<(), String> {
+        let tmp_path = format!("{}.tmp", path);
+        self.client.put(&tmp_path, contents.len() as u64, || Ok(contents))?;
+        self.client.move_file(&tmp_path, path)
+    }
+
+    fn remove_file(&self, path: &str) -> Result<(), String> {
+        self.client.send("DELETE", path, |req| req.call().map_err(Box::new)).map(|_| ())
+    }
+}
+
+impl Drop for WebDavWriter {
+    fn drop(&mut self) {
+        if let Some(upload) = &self.current {
+            let _ = fs::remove_file(&upload.spool_path);
+        }
+    }
+}
+
+struct Download {
+    path: String,
+    reader: Box<dyn Read + Send + Sync>,
+    offset: usize
+}
+
+pub struct WebDavReader {
+    client: DavClient,
+    current: Option<Download>
+}
+
+impl WebDavReader {
+    pub fn new(cfg: &WebDavConfig) -> Self {
+        Self { client: DavClient::new(cfg), current: None }
+    }
+
+    fn get(client: &DavClient, path: &str, offset: usize) -> Result<Option<ureq::Response>, String> {
+        let resp = client.send("GET", path, |req| {
+            let req = if offset > 0 { req.set("range", &format!("bytes={}-", offset)) } else { req };
+            req.call().map_err(Box::new)
+        })?;
+        match resp {
+            // the server may ignore the range and send the whole file
+            Some(resp) if offset > 0 && resp.status() != 206 => Err(format!("server does not support ranged reads of {}", path)),
+            resp => Ok(resp)
+        }
+    }
+
+    // returns None if there is no such file
+    pub fn read_object(&self, path: &str) -> Result<Option<Vec<u8>>, String> {
+        match Self::get(&self.client, path, 0)? {
+            Some(resp) => {
+                let mut contents = Vec::new();
+                resp.into_reader()
+                    .read_to_end(&mut contents)
+                    .map_err(|e| format!("could not read file {}: {}", path, e))?;
+                Ok(Some(contents))
+            },
+            None => Ok(None)
+        }
+    }
+}
+
+impl MultiFilesReaderSource for WebDavReader {
+    fn open_next_file(&mut self, full_path: &str) -> Result<bool, String> {
+        if let Some(download) = &self.current {
+            return Err(format!("previous file {} was not closed before opening a new file {}", download.path, full_path));
+        }
+        let found = match Self::get(&self.client, full_path, 0)? {
+            Some(resp) => {
+                self.current = Some(Download { path: full_path.to_owned(), reader: resp.into_reader(), offset: 0 });
+                true
+            },
+            None => false
+        };
+        eprintln!("reading from {}", full_path);
+        Ok(found)
+    }
+
+    fn read_from_current_file(&mut self, buf: &mut [u8]) -> Result<usize, String> {
+        let download = self.current.as_mut().ok_or("no current file opened to read from".to_owned())?;
+        let mut attempt = 1;
+        loop {
+            match download.reader.read(buf) {
+                Ok(n) => {
+                    download.offset += n;
+                    return Ok(n);
+                },
+                Err(e) if attempt <= self.client.cfg.retries => {
+                    // continue from where the connection broke
+                    eprintln!("reading {} failed at offset {}: {}, resuming", download.path, download.offset, e);
+                    let resp = Self::get(&self.client, &download.path, download.offset)?
+                        .ok_or(format!("file {} disappeared", download.path))?;
+                    download.reader = resp.into_reader();
+                    attempt += 1;
+                },
+                Err(e) => { return Err(format!("could not read max {} bytes from file {}: {}", buf.len(), download.path, e)); }
+            }
+        }
+    }
+
+    fn close_current_file(&mut self) -> Result<(), String> {
+        self.current.take().ok_or("no current file opened to close".to_owned()).map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn urls() {
+        assert_eq!(http_url("dav://localhost:8080/bk/files000001").unwrap(), "http://localhost:8080/bk/files000001");
+        assert_eq!(http_url("davs://webdav.yandex.ru/my bk/f%1").unwrap(), "https://webdav.yandex.ru/my%20bk/f%251");
+        assert!(http_url("dav://host").is_err());
+        assert!(http_url("dav://host/").is_err());
+        assert!(http_url("dav:///file").is_err());
+        assert!(http_url("http://host/file").is_err());
+        assert!(is_dav_path("davs://host/file"));
+        assert!(!is_dav_path("/dav/file"));
+    }
+
+    #[test]
+    fn propfind_response() {
+        let xml = "<?xml version=\"1.0\"?><d:multistatus xmlns:d=\"DAV:\"><d:response><d:href>/bk/f1</d:href>\
+            <d:propstat><d:prop><lp1:getcontentlength xmlns:lp1=\"DAV:\">12345</lp1:getcontentlength>\
+            <d:getetag>&quot;abc&quot;</d:getetag></d:prop></d:propstat></d:response></d:multistatus>";
+        assert_eq!(dav_prop(xml, "getcontentlength"), Some("12345"));
+        assert_eq!(dav_prop(xml, "getetag"), Some("&quot;abc&quot;"));
+        assert_eq!(dav_prop(xml, "getlastmodified"), None);
+        assert_eq!(dav_prop("<propstat><getcontentlength>7</getcontentlength><getetag/></propstat>", "getcontentlength"), Some("7"));
+        assert_eq!(dav_prop("<getcontentlength>7</getcontentlength><getetag/>", "getetag"), Some(""));
+    }
+}
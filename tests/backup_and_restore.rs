@@ -1,8 +1,19 @@
 #[cfg(test)]
-
-use bigarchiver::{backup, check, EncParams};
+use bigarchiver::{backup, check, has_labels, info, repair, recover_cfg, salvage, BackupOptions, CheckOptions, EncParams, ParityParams, Storage, Mirrors};
 use bigarchiver::finalizable::DataSink;
 use bigarchiver::arg_opts::Alg;
+use bigarchiver::chunk_hooks::{PostChunkHook, FetchHook};
+use bigarchiver::s3::S3Config;
+use bigarchiver::sftp::SftpConfig;
+use bigarchiver::webdav::WebDavConfig;
+use bigarchiver::throttle::ThrottleConfig;
+use bigarchiver::io_retry::RetryPolicy;
+use bigarchiver::free_space::SpaceCheck;
+use bigarchiver::progress::{Progress, Phase};
+use bigarchiver::catalog::Catalog;
+use bigarchiver::prune::{prune, Pruned, RetentionPolicy};
+use bigarchiver::repository::Repository;
+use bigarchiver::stats::Label;
 
 mod common;
 
@@ -10,10 +21,26 @@ use rand::RngCore;
 use test_case::test_matrix;
 use std::io::Write;
 use std::sync::atomic::AtomicI32;
+use std::sync::{Arc, Mutex};
 use std::fs::File;
+use std::time::{Duration, Instant};
 
 static CNT: AtomicI32 = AtomicI32::new(0);
 
+// tiny buffers and no compression, so that tests are fast and cross buffer boundaries often
+fn backup_opts() -> BackupOptions {
+    BackupOptions { compress_level: 0, buf_size_bytes: 100, ..Default::default() }
+}
+
+// backup into a repository by `nr_threads` threads
+fn repo_opts(nr_threads: usize) -> BackupOptions {
+    BackupOptions { compress_level: 1, nr_threads, buf_size_bytes: 65536, ..Default::default() }
+}
+
+fn check_opts() -> CheckOptions {
+    CheckOptions { buf_size_bytes: 100, show_info: true, ..Default::default() }
+}
+
 struct SinkToVector<'a> {
     incoming: Vec<u8>,
     etalon: &'a [u8]
@@ -31,10 +58,32 @@ impl DataSink for SinkToVector<'_> {
     }
 }
 
+// collects progress events
+#[derive(Clone)]
+struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+impl Write for SharedBuf {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl SharedBuf {
+    fn take_lines(&self) -> Vec<String> {
+        let text = String::from_utf8(std::mem::take(&mut *self.0.lock().unwrap())).unwrap();
+        text.lines().map(|l| l.to_owned()).collect()
+    }
+}
+
 #[test_matrix(
-    [10, 100, 1000], // input_size
+    [10, 100, 1290], // input_size, the last one ends 10 bytes after a block of xxh3
     [10, 100, 1000], // auth_size
-    [10, 100, 1000], // split_size
+    [150, 1000, 10000], // split_size, just above chunk header at the low end
     [10, 100, 1000],  // buf_size
     [1, 4] // nr_threads
 )]
@@ -46,32 +95,28 @@ fn backup_restore_all_ok(input_size: usize, auth_size: usize, split_size: usize,
     let out_tpl = format!("{}/%%%%%%", &parent_dir);
     let out_cfg = format!("{}/000000.cfg", &parent_dir);
 
-    let mut src: Vec<u8> = Vec::with_capacity(input_size);
-    src.resize(input_size, 0);
+    let mut src: Vec<u8> = vec![0; input_size];
     rand::thread_rng().fill_bytes(&mut src);
 
     backup(
         &src[..],
-        &Some(EncParams{
+        split_size,
+        &out_tpl,
+        &Storage::Files,
+        &BackupOptions { enc: Some(EncParams{
             alg: Alg::Aes128Gcm,
             auth_msg: "The Author".to_owned(),
             auth_every_bytes: auth_size,
             pass: "secret".to_owned()
-        }),
-        split_size,
-        &out_tpl,
-        9,
-        nr_threads,
-        buf_size, None).unwrap();
+        }), compress_level: 9, nr_threads, buf_size_bytes: buf_size, ..backup_opts() }).unwrap();
 
     let src_unpacked = SinkToVector{ incoming: Vec::new(), etalon: &src };
 
     check(
         Some(src_unpacked),
         &out_cfg,
-        &Some("secret".to_owned()),
-        nr_threads,
-        buf_size, &None::<&str>, true).unwrap();
+        &Storage::Files,
+        &CheckOptions { pass: Some("secret".to_owned()), nr_threads, buf_size_bytes: buf_size, ..check_opts() }).unwrap();
 
 }
 
@@ -88,6 +133,1321 @@ fn restore_no_free_space() {
         auth=Author Name\n\
         auth_len=3", usize::MAX);
     File::create(cfg_path).unwrap().write_all(cfg_contents.as_bytes()).unwrap();
-    let err = check(Some(SinkToVector{ incoming: Vec::new(), etalon: b"" }), cfg_path, &Some("".to_owned()), 1, 100, &Some("/tmp"), true).unwrap_err();
+    let err = check(Some(SinkToVector{ incoming: Vec::new(), etalon: b"" }), cfg_path, &Storage::Files, &CheckOptions { pass: Some("".to_owned()), check_free_space: Some("/tmp".to_owned()), ..check_opts() }).unwrap_err();
     println!("err = {}", err);
 }
+
+#[test]
+fn restore_with_metadata_of_other_formats() {
+    let dir = "/tmp/metadata_formats";
+    let _ = std::fs::remove_dir_all(dir);
+    std::fs::create_dir(dir).unwrap();
+    let cfg_path = format!("{}/000.cfg", dir);
+    let mut src: Vec<u8> = vec![0; 10000];
+    rand::thread_rng().fill_bytes(&mut src);
+    backup(&src[..], 3000, &format!("{}/%%%", dir), &Storage::Files, &backup_opts()).unwrap();
+    let cfg = std::fs::read_to_string(&cfg_path).unwrap();
+    assert!(cfg.starts_with("format=3\n"));
+
+    // unknown keys are ignored, newer formats are refused
+    std::fs::write(&cfg_path, format!("{}added_later=1\n", cfg)).unwrap();
+    check(Some(SinkToVector{ incoming: Vec::new(), etalon: &src }), &cfg_path, &Storage::Files, &check_opts()).unwrap();
+    std::fs::write(&cfg_path, cfg.replace("format=3", "format=99")).unwrap();
+    let err = check(None::<SinkToVector>, &cfg_path, &Storage::Files, &check_opts()).unwrap_err();
+    assert!(err.contains("please upgrade"), "{}", err);
+
+    // chunks of early releases have neither header nor trailer
+    let mut header_len = 0;
+    for n in 0..4 {
+        let path = format!("{}/{:03}", dir, n);
+        let data = std::fs::read(&path).unwrap();
+        header_len = u32::from_le_bytes(data[4..8].try_into().unwrap()) as usize;
+        let end = if n == 3 { data.len() - 68 } else { data.len() };
+        std::fs::write(&path, &data[header_len..end]).unwrap();
+    }
+
+    // unversioned metadata of early releases, without chunk list and parity keys
+    let legacy = cfg.lines()
+        .filter(|ln| !["format=", "chunks=", "parity_"].iter().any(|key| ln.starts_with(key)))
+        .map(|ln| if ln == "chunk_len=3000" { format!("chunk_len={}", 3000 - header_len) } else { ln.replace("compressed_len=", "xz_len=") })
+        .collect::<Vec<_>>()
+        .join("\n");
+    std::fs::write(&cfg_path, legacy).unwrap();
+    check(Some(SinkToVector{ incoming: Vec::new(), etalon: &src }), &cfg_path, &Storage::Files, &check_opts()).unwrap();
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn info_as_json() {
+    let dir = "/tmp/info_json";
+    let _ = std::fs::remove_dir_all(dir);
+    std::fs::create_dir(dir).unwrap();
+    let cfg_path = format!("{}/000.cfg", dir);
+    let mut src: Vec<u8> = vec![0; 10000];
+    rand::thread_rng().fill_bytes(&mut src);
+    backup(
+        &src[..],
+        3000,
+        &format!("{}/%%%", dir),
+        &Storage::Files,
+        &BackupOptions { parity: Some(ParityParams{ data_chunks: 2, parity_chunks: 1 }), compress_level: 3, nr_threads: 2, ..backup_opts() }).unwrap();
+
+    let json = info(&cfg_path, &Storage::Files).unwrap();
+    assert!(json.starts_with(r#"{"format":3,"archive_id":""#), "{}", json);
+    assert!(json.contains(r#""in_len":10000,"#), "{}", json);
+    assert!(json.contains(r#""compressed_len":"#) && !json.contains("xz_len"), "{}", json);
+    assert!(json.contains(r#""nr_chunks":4,"chunk_len":3000,"alg":"none","#), "{}", json);
+    assert!(json.contains(r#","compress_level":3,"compress_threads":2,"buf_size":100},"misc_info":null,"#), "{}", json);
+    assert!(json.contains(r#""parity_group":2,"parity_nr":1,"parity_chunks":[{"len":"#), "{}", json);
+    assert!(info(&format!("{}/nothing.cfg", dir), &Storage::Files).is_err());
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn archive_labels() {
+    let dir = "/tmp/archive_labels";
+    let _ = std::fs::remove_dir_all(dir);
+    std::fs::create_dir(dir).unwrap();
+    let labels = |ls: &[&str]| ls.iter().map(|l| Label::parse(l).unwrap()).collect::<Vec<_>>();
+    let mut src: Vec<u8> = vec![0; 10000];
+    rand::thread_rng().fill_bytes(&mut src);
+    let pass = Some("secret".to_owned());
+
+    for (name, opt_enc) in [("plain", None), ("encrypted", Some(EncParams{
+            alg: Alg::Chacha20Poly1305, auth_msg: "The Author".to_owned(), auth_every_bytes: 1000, pass: "secret".to_owned() }))] {
+        let cfg_path = format!("{}/{}000.cfg", dir, name);
+        let opts = BackupOptions { enc: opt_enc, labels: labels(&["db=main", "nightly"]), ..backup_opts() };
+        backup(&src[..], 3000, &format!("{}/{}%%%", dir, name), &Storage::Files, &opts).unwrap();
+
+        let json = info(&cfg_path, &Storage::Files).unwrap();
+        assert!(json.contains(&format!(r#","labels":{{"db":"main","nightly":true}},"labels_authenticated":{},"#, opts.enc.is_some())), "{}", json);
+        for (filters, expected) in [(vec![], true), (vec!["db=main"], true), (vec!["db", "nightly"], true), (vec!["db=main", "weekly"], false), (vec!["db=other"], false)] {
+            assert_eq!(has_labels(&cfg_path, &Storage::Files, &pass, &labels(&filters)).unwrap(), expected, "{:?}", filters);
+        }
+
+        let altered = std::fs::read_to_string(&cfg_path).unwrap().replace("labels=db=main", "labels=db=other");
+        std::fs::write(&cfg_path, altered).unwrap();
+        assert!(has_labels(&cfg_path, &Storage::Files, &None, &labels(&["db=other"])).unwrap());
+        match opts.enc {
+            Some(_) => {
+                let err = has_labels(&cfg_path, &Storage::Files, &pass, &labels(&["db=other"])).unwrap_err();
+                assert!(err.contains("labels of the archive were altered"), "{}", err);
+                let stripped = std::fs::read_to_string(&cfg_path).unwrap().lines()
+                    .filter(|l| !l.starts_with("labels_mac=")).map(|l| format!("{}\n", l)).collect::<String>();
+                std::fs::write(&cfg_path, stripped).unwrap();
+                assert!(has_labels(&cfg_path, &Storage::Files, &pass, &[]).unwrap_err().contains("not authenticated"));
+            },
+            None => assert!(has_labels(&cfg_path, &Storage::Files, &pass, &labels(&["db=other"])).unwrap())
+        }
+    }
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn catalog_of_archives() {
+    let dir = "/tmp/catalog_of_archives";
+    let _ = std::fs::remove_dir_all(dir);
+    std::fs::create_dir(dir).unwrap();
+    let catalog = Catalog::new(&format!("{}/catalog.jsonl", dir));
+    let mut src: Vec<u8> = vec![0; 10000];
+    rand::thread_rng().fill_bytes(&mut src);
+
+    for (name, label) in [("a", "db=main"), ("b", "db=other")] {
+        let template = format!("{}/{}%%%", dir, name);
+        let cfg_path = format!("{}/{}000.cfg", dir, name);
+        backup(
+            &src[..],
+            3000,
+            &template,
+            &Storage::Files,
+            &BackupOptions { labels: vec![Label::parse(label).unwrap()], ..backup_opts() }).unwrap();
+        catalog.record_backup(&cfg_path, &template, &Storage::Files);
+        let res = check(None::<SinkToVector>, &cfg_path, &Storage::Files, &CheckOptions { show_info: false, ..check_opts() });
+        catalog.record_check(Phase::Verify, &cfg_path, &Storage::Files, &res);
+    }
+    std::fs::remove_file(format!("{}/b001", dir)).unwrap();
+    let cfg_path = format!("{}/b000.cfg", dir);
+    let res = check(None::<SinkToVector>, &cfg_path, &Storage::Files, &CheckOptions { show_info: false, ..check_opts() });
+    catalog.record_check(Phase::Verify, &cfg_path, &Storage::Files, &res);
+    catalog.record_check(Phase::Restore, &format!("{}/missing.cfg", dir), &Storage::Files, &Err("no such archive".to_owned()));
+
+    let archives = catalog.archives().unwrap();
+    assert_eq!(archives.len(), 3);
+    let id = archives[0].archive_id.clone().unwrap();
+    assert_eq!(id.len(), 36);
+    let json = info(&format!("{}/a000.cfg", dir), &Storage::Files).unwrap();
+    assert!(json.contains(&id));
+    // the catalog names fields as metadata does
+    assert!(json.contains(&format!(r#""compressed_len":{},"#, archives[0].compressed_len().unwrap())), "{}", json);
+    let summary = archives[0].summary();
+    assert!(summary.starts_with(&format!("{} {}/a000.cfg: made at ", id, dir)), "{}", summary);
+    assert!(summary.contains(", 10000 bytes in 4 chunks, labels db=main; verify ok at "), "{}", summary);
+    assert_eq!(archives[1].checks.len(), 2);
+    assert!(archives[1].summary().contains("; verify failed at "), "{}", archives[1].summary());
+    assert!(archives[2].summary().starts_with(&format!("- {}/missing.cfg: made elsewhere; restore failed at ", dir)), "{}", archives[2].summary());
+
+    let configs = |archives: Vec<bigarchiver::catalog::Archive>| archives.into_iter().map(|a| a.config).collect::<Vec<_>>();
+    assert_eq!(configs(catalog.find(&id).unwrap()), vec![format!("{}/a000.cfg", dir)]);
+    assert_eq!(configs(catalog.search(&[Label::parse("db=other").unwrap()], &None, false).unwrap()), vec![format!("{}/b000.cfg", dir)]);
+    assert_eq!(configs(catalog.search(&[], &Some("/b%".to_owned()), false).unwrap()), vec![format!("{}/b000.cfg", dir)]);
+    assert_eq!(configs(catalog.search(&[], &None, true).unwrap()), vec![format!("{}/b000.cfg", dir), format!("{}/missing.cfg", dir)]);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn prune_old_archives() {
+    let dir = "/tmp/prune_old_archives";
+    let _ = std::fs::remove_dir_all(dir);
+    std::fs::create_dir(dir).unwrap();
+    let catalog_path = format!("{}/catalog.jsonl", dir);
+    let catalog = Catalog::new(&catalog_path);
+    let mut src: Vec<u8> = vec![0; 10000];
+    rand::thread_rng().fill_bytes(&mut src);
+
+    // four nightly archives, with parity and chunks spread over directories, only the oldest one verified
+    for day in 1..=4 {
+        let template = format!("{}/day{}/{{n/2}}/{{n%2}}", dir, day);
+        backup(
+            &src[..],
+            3000,
+            &template,
+            &Storage::Files,
+            &BackupOptions { parity: Some(ParityParams{ data_chunks: 2, parity_chunks: 1 }), labels: vec![Label::parse(&format!("day={}", day)).unwrap()], ..backup_opts() }).unwrap();
+        catalog.record_backup(&format!("{}/day{}/0.cfg", dir, day), &template, &Storage::Files);
+    }
+    catalog.record_check(Phase::Verify, &format!("{}/day1/0.cfg", dir), &Storage::Files, &Ok(()));
+    catalog.record_check(Phase::Verify, &format!("{}/day4/0.cfg", dir), &Storage::Files, &Err("hash verification error".to_owned()));
+    // as if they were made on consecutive days
+    let events = std::fs::read_to_string(&catalog_path).unwrap().lines().enumerate().map(|(i, line)| {
+        let (head, tail) = line.split_once(r#""time":"#).unwrap();
+        let tail = tail.trim_start_matches(|c: char| c.is_ascii_digit());
+        format!(r#"{}"time":{}{}"#, head, 1704542400 + 86400 * i.min(3) as u64, tail) + "\n"
+    }).collect::<String>();
+    // and an older one with the only copy in remote storage, which is not deleted
+    let remote = r#"{"event":"backup","time":1704456000,"archive_id":"remote","config":"s3://bucket/bk/0.cfg","template":"s3://bucket/bk/%%%"}"#;
+    std::fs::write(&catalog_path, events + remote + "\n").unwrap();
+
+    let policy = RetentionPolicy { keep_daily: 1, ..Default::default() };
+    assert!(prune(&catalog, &RetentionPolicy::default(), &[], true).is_err());
+    assert_eq!(prune(&catalog, &policy, &[], true).unwrap(), Pruned { nr_removed: 2, nr_remote: 1 });
+    assert!(std::path::Path::new(&format!("{}/day2/0.cfg", dir)).exists());
+
+    // the only one is the newest
+    assert_eq!(prune(&catalog, &policy, &[Label::parse("day=3").unwrap()], false).unwrap(), Pruned::default());
+    assert_eq!(prune(&catalog, &policy, &[], false).unwrap(), Pruned { nr_removed: 2, nr_remote: 1 });
+    for day in [2, 3] {
+        assert!(!std::path::Path::new(&format!("{}/day{}", dir, day)).exists(), "{}", day);
+    }
+    for day in [1, 4] {
+        check(None::<SinkToVector>, &format!("{}/day{}/0.cfg", dir, day), &Storage::Files, &CheckOptions { show_info: false, ..check_opts() }).unwrap();
+    }
+    let left = catalog.search(&[], &None, false).unwrap().into_iter().map(|a| a.config).collect::<Vec<_>>();
+    assert_eq!(left, vec![format!("{}/day1/0.cfg", dir), format!("{}/day4/0.cfg", dir), "s3://bucket/bk/0.cfg".to_owned()]);
+    assert_eq!(catalog.archives().unwrap().len(), 5);
+    assert_eq!(prune(&catalog, &policy, &[], false).unwrap(), Pruned { nr_removed: 0, nr_remote: 1 });
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn prune_after_newest_archive_lost_metadata() {
+    let dir = "/tmp/prune_lost_metadata";
+    let _ = std::fs::remove_dir_all(dir);
+    std::fs::create_dir(dir).unwrap();
+    let catalog_path = format!("{}/catalog.jsonl", dir);
+    let catalog = Catalog::new(&catalog_path);
+    let cfg = |day: usize| format!("{}/day{}/000.cfg", dir, day);
+    let mut src: Vec<u8> = vec![0; 10000];
+    rand::thread_rng().fill_bytes(&mut src);
+
+    // three nightly archives, the oldest and the newest ones verified
+    for day in 1..=3 {
+        let template = format!("{}/day{}/%%%", dir, day);
+        backup(&src[..], 3000, &template, &Storage::Files, &backup_opts()).unwrap();
+        catalog.record_backup(&cfg(day), &template, &Storage::Files);
+    }
+    for day in [1, 3] {
+        catalog.record_check(Phase::Verify, &cfg(day), &Storage::Files, &Ok(()));
+    }
+    // then metadata of the newest one is lost, and so is its check
+    std::fs::remove_file(cfg(3)).unwrap();
+    let res = check(None::<SinkToVector>, &cfg(3), &Storage::Files, &CheckOptions { show_info: false, ..check_opts() });
+    assert!(res.is_err());
+    catalog.record_check(Phase::Verify, &cfg(3), &Storage::Files, &res);
+    assert_eq!(catalog.archives().unwrap().len(), 3);
+    assert_eq!(catalog.find(&cfg(3)).unwrap()[0].last_ok(), Some(false));
+    // as if they were made on consecutive days
+    let events = std::fs::read_to_string(&catalog_path).unwrap().lines().enumerate().map(|(i, line)| {
+        let (head, tail) = line.split_once(r#""time":"#).unwrap();
+        let tail = tail.trim_start_matches(|c: char| c.is_ascii_digit());
+        format!(r#"{}"time":{}{}"#, head, 1704542400 + 86400 * i.min(2) as u64, tail) + "\n"
+    }).collect::<String>();
+    std::fs::write(&catalog_path, events).unwrap();
+
+    // the newest one is kept by the policy, and the oldest one is the last one known to be restorable
+    let policy = RetentionPolicy { keep_daily: 1, ..Default::default() };
+    assert_eq!(prune(&catalog, &policy, &[], false).unwrap().nr_removed, 1);
+    assert!(!std::path::Path::new(&format!("{}/day2", dir)).exists());
+    check(None::<SinkToVector>, &cfg(1), &Storage::Files, &CheckOptions { show_info: false, ..check_opts() }).unwrap();
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test_matrix(
+    [Alg::None, Alg::Chacha20Poly1305]
+)]
+fn repository_dedup(alg: Alg) {
+    let dir = format!("/tmp/repository_dedup_{:?}", alg);
+    let _ = std::fs::remove_dir_all(&dir);
+    let pass = if alg == Alg::None { None } else { Some("secret".to_owned()) };
+    let repo = Repository::create(&dir, &alg, &pass, 16384).unwrap();
+    assert!(Repository::create(&dir, &alg, &pass, 16384).is_err());
+
+    let mut src: Vec<u8> = vec![0; 1_000_000];
+    rand::thread_rng().fill_bytes(&mut src[..600_000]); // the rest compresses well
+    let first = repo.backup(&src[..], &BackupOptions { labels: vec![Label::parse("day=1").unwrap()], ..repo_opts(4) }).unwrap();
+    assert_eq!(first.in_len, src.len());
+    assert!(first.nr_chunks > 20 && first.stored_len < 700_000, "{} chunks, {} bytes", first.nr_chunks, first.stored_len);
+    assert!(first.nr_new_chunks < first.nr_chunks); // zeros are cut into the same chunks
+
+    // a few bytes inserted and changed take a few new chunks, the same data takes none
+    let mut changed = src.clone();
+    changed.splice(300_000..300_000, b"inserted".iter().cloned());
+    changed[800_000] = 1;
+    let second = repo.backup(&changed[..], &repo_opts(4)).unwrap();
+    assert!(second.nr_new_chunks <= 4 && second.nr_new_chunks < second.nr_chunks, "{} of {}", second.nr_new_chunks, second.nr_chunks);
+    let third = repo.backup(&src[..], &BackupOptions { buf_size_bytes: 100, ..repo_opts(1) }).unwrap();
+    assert_eq!((third.nr_new_chunks, third.stored_len), (0, 0));
+    assert_eq!(std::fs::read_dir(format!("{}/manifests", dir)).unwrap().count(), 3);
+
+    let repo = Repository::open(&dir, &pass).unwrap();
+    repo.restore(Some(SinkToVector{ incoming: Vec::new(), etalon: &src }), &first.manifest_path, &None, &None).unwrap();
+    repo.restore(Some(SinkToVector{ incoming: Vec::new(), etalon: &changed }), &second.manifest_path, &None, &None).unwrap();
+    repo.restore(None::<SinkToVector>, &third.manifest_path, &None, &None).unwrap();
+    assert_eq!(repo.read_manifest(&first.manifest_path).unwrap().labels, vec![Label::parse("day=1").unwrap()]);
+    if pass.is_some() {
+        assert_eq!(Repository::open(&dir, &Some("wrong".to_owned())).err().unwrap(), format!("wrong password for repository {}", dir));
+        assert!(Repository::open(&dir, &None).is_err());
+    }
+
+    // a damaged chunk which only the first backup has, and a missing one which only the second backup has
+    let manifest = repo.read_manifest(&first.manifest_path).unwrap();
+    let second_ids = repo.read_manifest(&second.manifest_path).unwrap().chunks.into_iter().map(|(id, _)| id).collect::<Vec<_>>();
+    let (id, _) = manifest.chunks.iter().find(|(id, _)| !second_ids.contains(id)).unwrap();
+    let chunk_path = format!("{}/chunks/{}/{}", dir, &id[..2], id);
+    let mut chunk = std::fs::read(&chunk_path).unwrap();
+    chunk[10] ^= 1;
+    std::fs::write(&chunk_path, chunk).unwrap();
+    let err = repo.restore(None::<SinkToVector>, &first.manifest_path, &None, &None).unwrap_err();
+    assert!(err.starts_with(&format!("chunk {} is damaged: ", id)), "{}", err);
+    let first_ids = manifest.chunks.iter().map(|(id, _)| id).collect::<Vec<_>>();
+    let (new_id, _) = repo.read_manifest(&second.manifest_path).unwrap().chunks.into_iter().find(|(id, _)| !first_ids.contains(&id)).unwrap();
+    std::fs::remove_file(format!("{}/chunks/{}/{}", dir, &new_id[..2], new_id)).unwrap();
+    assert_eq!(repo.restore(None::<SinkToVector>, &second.manifest_path, &None, &None).unwrap_err(), format!("chunk {} is missing", new_id));
+
+    let manifest_text = std::fs::read_to_string(&third.manifest_path).unwrap();
+    std::fs::write(&third.manifest_path, manifest_text.replace("in_len=1000000", "in_len=999999")).unwrap();
+    assert!(repo.read_manifest(&third.manifest_path).err().unwrap().contains("manifest is damaged or altered"));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn repository_in_catalog() {
+    let dir = "/tmp/repository_in_catalog";
+    let _ = std::fs::remove_dir_all(dir);
+    let repo_dir = format!("{}/repo", dir);
+    let repo = Repository::create(&repo_dir, &Alg::None, &None, 16384).unwrap();
+    let catalog_path = format!("{}/catalog.jsonl", dir);
+    let catalog = Catalog::new(&catalog_path);
+    let mut old: Vec<u8> = vec![0; 300_000];
+    rand::thread_rng().fill_bytes(&mut old);
+    let mut new = old.clone();
+    rand::thread_rng().fill_bytes(&mut new[200_000..]);
+
+    let backups = [&old, &new].map(|data| {
+        let backup = repo.backup(&data[..], &BackupOptions { labels: vec![Label::parse("db=main").unwrap()], ..repo_opts(2) }).unwrap();
+        catalog.record_repo_backup(&repo_dir, &backup, &[Label::parse("db=main").unwrap()], 0);
+        backup
+    });
+    let res = repo.restore(None::<SinkToVector>, &backups[1].manifest_path, &None, &None);
+    catalog.record_result(Phase::Verify, Some(backups[1].archive_id), &backups[1].manifest_path, &res);
+    // as if they were made on consecutive days
+    let events = std::fs::read_to_string(&catalog_path).unwrap().lines().enumerate().map(|(i, line)| {
+        let (head, tail) = line.split_once(r#""time":"#).unwrap();
+        let tail = tail.trim_start_matches(|c: char| c.is_ascii_digit());
+        format!(r#"{}"time":{}{}"#, head, 1704542400 + 86400 * i.min(1) as u64, tail) + "\n"
+    }).collect::<String>();
+    std::fs::write(&catalog_path, events).unwrap();
+
+    let archives = catalog.search(&[Label::parse("db=main").unwrap()], &None, false).unwrap();
+    assert_eq!(archives.len(), 2);
+    assert_eq!(archives[1].last_ok(), Some(true));
+    assert!(archives[0].summary().contains(", 300000 bytes in "), "{}", archives[0].summary());
+
+    // only the manifest of the old backup is deleted, its chunks are left to gc
+    assert_eq!(prune(&catalog, &RetentionPolicy { keep_last: 1, ..Default::default() }, &[], false).unwrap().nr_removed, 1);
+    assert!(!std::path::Path::new(&backups[0].manifest_path).exists());
+    assert_eq!(repo.check().unwrap(), (1, backups[0].nr_new_chunks + backups[1].nr_new_chunks));
+    assert!(repo.gc(false).unwrap().nr_removed > 0);
+    repo.restore(Some(SinkToVector{ incoming: Vec::new(), etalon: &new }), &backups[1].manifest_path, &None, &None).unwrap();
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+// input which tries to collect garbage of the repository it is backed up into
+struct GcDuringBackup<'a> {
+    data: &'a [u8],
+    repo: &'a str,
+    gc_error: Option<String>
+}
+
+impl std::io::Read for GcDuringBackup<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.gc_error.is_none() {
+            self.gc_error = Repository::open(self.repo, &None).unwrap().gc(false).err();
+        }
+        self.data.read(buf)
+    }
+}
+
+// target of restore which tries to collect garbage of the repository it is restored from
+struct GcDuringRestore<'a> {
+    repo: &'a str,
+    gc_error: &'a mut Option<String>
+}
+
+impl DataSink for GcDuringRestore<'_> {
+    fn add(&mut self, _data: &[u8]) -> Result<(), String> {
+        if self.gc_error.is_none() {
+            *self.gc_error = Repository::open(self.repo, &None).unwrap().gc(false).err();
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+#[test]
+fn repository_gc_and_check() {
+    let dir = "/tmp/repository_gc_and_check";
+    let _ = std::fs::remove_dir_all(dir);
+    let pass = Some("secret".to_owned());
+    let repo = Repository::create(dir, &Alg::Aes128Gcm, &pass, 16384).unwrap();
+    let mut old: Vec<u8> = vec![0; 300_000];
+    rand::thread_rng().fill_bytes(&mut old);
+    let mut new = old.clone();
+    rand::thread_rng().fill_bytes(&mut new[200_000..]);
+    let old_backup = repo.backup(&old[..], &repo_opts(1)).unwrap();
+    let new_backup = repo.backup(&new[..], &repo_opts(2)).unwrap();
+    let nr_stored = old_backup.nr_new_chunks + new_backup.nr_new_chunks;
+    assert_eq!(repo.check().unwrap(), (2, nr_stored));
+
+    // nothing to collect while every chunk is referenced, then chunks only the old backup had
+    assert_eq!(repo.gc(false).unwrap().nr_removed, 0);
+    std::fs::remove_file(&old_backup.manifest_path).unwrap();
+    let leftover = format!("{}/manifests/x.manifest.tmp1", dir);
+    std::fs::write(&leftover, b"interrupted").unwrap();
+    let dry = repo.gc(true).unwrap();
+    assert_eq!((dry.nr_manifests, dry.nr_chunks, dry.nr_removed), (1, new_backup.nr_chunks, nr_stored - new_backup.nr_chunks));
+    assert!(dry.nr_removed > 0 && dry.removed_len > 0);
+    assert!(std::path::Path::new(&leftover).exists());
+    assert_eq!(repo.check().unwrap(), (1, nr_stored));
+    let res = repo.gc(false).unwrap();
+    assert_eq!((res.nr_removed, res.removed_len), (dry.nr_removed, dry.removed_len));
+    assert!(!std::path::Path::new(&leftover).exists());
+    assert_eq!(repo.check().unwrap(), (1, new_backup.nr_chunks));
+    repo.restore(Some(SinkToVector{ incoming: Vec::new(), etalon: &new }), &new_backup.manifest_path, &None, &None).unwrap();
+
+    // gc refuses to run during a backup, and to remove anything when a manifest cannot be read
+    let plain_dir = format!("{}/plain", dir);
+    let plain = Repository::create(&plain_dir, &Alg::None, &None, 16384).unwrap();
+    let mut input = GcDuringBackup { data: &old, repo: &plain_dir, gc_error: None };
+    plain.backup(&mut input, &repo_opts(1)).unwrap();
+    assert_eq!(input.gc_error.unwrap(), format!("repository {} is in use by a backup, restore or check, try again later", plain_dir));
+    let manifest_path = plain.backup(&old[..], &repo_opts(1)).unwrap().manifest_path;
+    let mut gc_error = None;
+    plain.restore(Some(GcDuringRestore { repo: &plain_dir, gc_error: &mut gc_error }), &manifest_path, &None, &None).unwrap();
+    assert!(gc_error.unwrap().contains("in use by a backup, restore or check"));
+    std::fs::write(format!("{}/manifests/bad.manifest", plain_dir), b"format=1\n").unwrap();
+    assert!(plain.gc(false).err().unwrap().ends_with("refusing to remove chunks it may refer to"));
+    assert!(plain.check().is_err());
+
+    // a damaged chunk and a missing one make manifests unresolvable
+    let manifest = repo.read_manifest(&new_backup.manifest_path).unwrap();
+    let (damaged, _) = &manifest.chunks[0];
+    let damaged_path = format!("{}/chunks/{}/{}", dir, &damaged[..2], damaged);
+    let mut chunk = std::fs::read(&damaged_path).unwrap();
+    let last = chunk.len() - 1;
+    chunk[last] ^= 1; // authentication tag
+    std::fs::write(&damaged_path, chunk).unwrap();
+    assert_eq!(repo.check().unwrap_err(), format!("1 of {} chunks are damaged, 1 of 1 manifests cannot be restored", new_backup.nr_chunks));
+    std::fs::remove_file(&damaged_path).unwrap();
+    assert_eq!(repo.check().unwrap_err(), format!("0 of {} chunks are damaged, 1 of 1 manifests cannot be restored", new_backup.nr_chunks - 1));
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn progress_events() {
+    let dir = "/tmp/progress_events";
+    let _ = std::fs::remove_dir_all(dir);
+    std::fs::create_dir(dir).unwrap();
+    let cfg_path = format!("{}/000.cfg", dir);
+    let mut src: Vec<u8> = vec![0; 10000];
+    rand::thread_rng().fill_bytes(&mut src);
+    let out = SharedBuf(Arc::new(Mutex::new(Vec::new())));
+    let progress = Some(Progress::new(Box::new(out.clone()), Duration::ZERO, Some(10000)));
+
+    backup(
+        &src[..],
+        3000,
+        &format!("{}/%%%", dir),
+        &Storage::Files,
+        &BackupOptions { buf_size_bytes: 1000, progress: progress.clone(), ..backup_opts() }).unwrap();
+    let events = out.take_lines();
+    assert!(events.len() > 10, "{:?}", events); // every read and every chunk write with zero interval
+    assert!(events[0].contains(r#""phase":"backup","finished":false,"#), "{}", events[0]);
+    assert!(events[0].contains(r#""bytes_in":0,"bytes_out":0,"total_in":10000,"chunk":null,"#), "{}", events[0]);
+    let last = events.last().unwrap();
+    assert!(last.contains(r#""phase":"backup","finished":true,"#), "{}", last);
+    assert!(last.contains(r#""bytes_in":10000,"#), "{}", last);
+    assert!(last.contains(r#","total_in":10000,"chunk":3,"#), "{}", last);
+    assert!(last.ends_with(r#""eta_s":0}"#), "{}", last);
+
+    check(None::<SinkToVector>, &cfg_path, &Storage::Files, &CheckOptions { buf_size_bytes: 1000, show_info: false, progress: progress.clone(), ..check_opts() }).unwrap();
+    let events = out.take_lines();
+    assert!(events[0].contains(r#""phase":"verify","finished":false,"#), "{}", events[0]);
+    let last = events.last().unwrap();
+    assert!(last.contains(r#""phase":"verify","finished":true,"#), "{}", last);
+    assert!(last.contains(r#","bytes_out":10000,"#), "{}", last);
+    assert!(last.contains(r#","chunk":3,"#), "{}", last);
+
+    let src_unpacked = SinkToVector{ incoming: Vec::new(), etalon: &src };
+    check(Some(src_unpacked), &cfg_path, &Storage::Files, &CheckOptions { buf_size_bytes: 1000, show_info: false, progress: progress.clone(), ..check_opts() }).unwrap();
+    let events = out.take_lines();
+    assert!(events.last().unwrap().contains(r#""phase":"restore","finished":true,"#), "{:?}", events);
+
+    progress.as_ref().unwrap().result(&Ok(()));
+    assert_eq!(out.take_lines(), vec![r#"{"event":"result","phase":"restore","ok":true,"exit_code":0,"reason":"completed"}"#]);
+
+    std::fs::remove_file(format!("{}/001", dir)).unwrap();
+    let err = check(None::<SinkToVector>, &cfg_path, &Storage::Files, &CheckOptions { buf_size_bytes: 1000, show_info: false, progress: progress.clone(), ..check_opts() }).unwrap_err();
+    let events = out.take_lines();
+    assert!(events.iter().all(|e| !e.contains(r#""finished":true"#)), "{:?}", events);
+    progress.as_ref().unwrap().result(&Err(err));
+    let events = out.take_lines();
+    assert!(events[0].starts_with(r#"{"event":"result","phase":"verify","ok":false,"exit_code":1,"reason":"#), "{:?}", events);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn restore_from_parity() {
+    let parent_dir = "/tmp/parity_restore";
+    let _ = std::fs::remove_dir_all(parent_dir);
+    std::fs::create_dir(parent_dir).unwrap();
+    let out_tpl = format!("{}/%%%%%%", parent_dir);
+    let out_cfg = format!("{}/000000.cfg", parent_dir);
+
+    let mut src: Vec<u8> = vec![0; 10000];
+    rand::thread_rng().fill_bytes(&mut src);
+
+    backup(
+        &src[..],
+        1000,
+        &out_tpl,
+        &Storage::Files,
+        &BackupOptions { parity: Some(ParityParams{ data_chunks: 3, parity_chunks: 2 }), ..backup_opts() }).unwrap();
+
+    // one chunk lost in the first group, one corrupted and one parity chunk lost in the second group
+    std::fs::remove_file(format!("{}/000001", parent_dir)).unwrap();
+    File::options().write(true).open(format!("{}/000004", parent_dir)).unwrap().write_all(b"garbage").unwrap();
+    std::fs::remove_file(format!("{}/000003.par", parent_dir)).unwrap();
+
+    check(
+        Some(SinkToVector{ incoming: Vec::new(), etalon: &src }),
+        &out_cfg,
+        &Storage::Files,
+        &check_opts()).unwrap();
+
+    // too many chunks lost in the second group
+    std::fs::remove_file(format!("{}/000005", parent_dir)).unwrap();
+    check(
+        None::<SinkToVector>,
+        &out_cfg,
+        &Storage::Files,
+        &check_opts()).unwrap_err();
+
+    std::fs::remove_dir_all(parent_dir).unwrap();
+}
+
+#[test]
+fn repair_damaged_chunks() {
+    let parent_dir = "/tmp/parity_repair";
+    let _ = std::fs::remove_dir_all(parent_dir);
+    std::fs::create_dir(parent_dir).unwrap();
+    let out_tpl = format!("{}/%%%%%%", parent_dir);
+    let out_cfg = format!("{}/000000.cfg", parent_dir);
+
+    let mut src: Vec<u8> = vec![0; 10000];
+    rand::thread_rng().fill_bytes(&mut src);
+
+    backup(
+        &src[..],
+        1000,
+        &out_tpl,
+        &Storage::Files,
+        &BackupOptions { enc: Some(EncParams{
+            alg: Alg::Chacha20Poly1305,
+            auth_msg: "The Author".to_owned(),
+            auth_every_bytes: 100,
+            pass: "secret".to_owned()
+        }), parity: Some(ParityParams{ data_chunks: 4, parity_chunks: 2 }), ..backup_opts() }).unwrap();
+
+    assert_eq!(repair(&out_cfg, &Storage::Files, 100).unwrap(), 0);
+
+    let damaged = ["000000", "000002.par", "000006"];
+    let originals: Vec<Vec<u8>> = damaged.iter().map(|f| std::fs::read(format!("{}/{}", parent_dir, f)).unwrap()).collect();
+    std::fs::remove_file(format!("{}/{}", parent_dir, damaged[0])).unwrap();
+    std::fs::remove_file(format!("{}/{}", parent_dir, damaged[1])).unwrap();
+    File::options().write(true).open(format!("{}/{}", parent_dir, damaged[2])).unwrap().write_all(b"garbage").unwrap();
+
+    assert_eq!(repair(&out_cfg, &Storage::Files, 100).unwrap(), 3);
+    for (f, orig) in damaged.iter().zip(originals) {
+        assert_eq!(std::fs::read(format!("{}/{}", parent_dir, f)).unwrap(), orig);
+    }
+    assert!(std::fs::read_to_string(&out_cfg).unwrap().contains("repair_info="));
+    assert_eq!(repair(&out_cfg, &Storage::Files, 100).unwrap(), 0);
+
+    // a later repair is recorded next to the earlier one
+    std::fs::remove_file(format!("{}/{}", parent_dir, damaged[0])).unwrap();
+    assert_eq!(repair(&out_cfg, &Storage::Files, 100).unwrap(), 1);
+    let repair_info = std::fs::read_to_string(&out_cfg).unwrap().lines()
+        .find_map(|l| l.strip_prefix("repair_info=").map(|v| v.to_owned())).unwrap();
+    assert_eq!(repair_info.split("; ").count(), 2, "{}", repair_info);
+
+    check(
+        Some(SinkToVector{ incoming: Vec::new(), etalon: &src }),
+        &out_cfg,
+        &Storage::Files,
+        &CheckOptions { pass: Some("secret".to_owned()), ..check_opts() }).unwrap();
+
+    std::fs::remove_dir_all(parent_dir).unwrap();
+}
+
+#[test]
+fn recover_lost_metadata() {
+    let parent_dir = "/tmp/recover_cfg";
+    let _ = std::fs::remove_dir_all(parent_dir);
+    std::fs::create_dir(parent_dir).unwrap();
+    let out_tpl = format!("{}/%%%%%%", parent_dir);
+    let out_cfg = format!("{}/000000.cfg", parent_dir);
+
+    let mut src: Vec<u8> = vec![0; 10000];
+    rand::thread_rng().fill_bytes(&mut src);
+
+    backup(
+        &src[..],
+        1000,
+        &out_tpl,
+        &Storage::Files,
+        &BackupOptions { enc: Some(EncParams{
+            alg: Alg::Chacha20Poly1305,
+            auth_msg: "The Author".to_owned(),
+            auth_every_bytes: 100,
+            pass: "secret".to_owned()
+        }), parity: Some(ParityParams{ data_chunks: 4, parity_chunks: 2 }), ..backup_opts() }).unwrap();
+
+    let err = recover_cfg(&out_tpl, &Storage::Files, &Some("secret".to_owned()), 100).unwrap_err();
+    assert!(err.contains("exists"), "{}", err);
+
+    let original = std::fs::read_to_string(&out_cfg).unwrap();
+    std::fs::remove_file(&out_cfg).unwrap();
+    let err = recover_cfg(&out_tpl, &Storage::Files, &Some("wrong".to_owned()), 100).unwrap_err();
+    assert!(err.contains("password is wrong"), "{}", err);
+
+    assert_eq!(recover_cfg(&out_tpl, &Storage::Files, &Some("secret".to_owned()), 100).unwrap(), out_cfg);
+    let recovered = std::fs::read_to_string(&out_cfg).unwrap();
+    // how the backup was made is not known from chunks
+    let backup_keys = ["misc_info=", "tool_version=", "host=", "started=", "ended=", "duration_ms=", "throughput=", "compress_level=", "compress_threads=", "buf_size="];
+    let comparable = |cfg: &str| cfg.lines()
+        .filter(|ln| !backup_keys.iter().any(|key| ln.starts_with(key)))
+        .collect::<Vec<_>>()
+        .join("\n");
+    assert_eq!(comparable(&recovered), comparable(&original));
+    assert!(recovered.contains("misc_info=recovered="));
+
+    check(
+        Some(SinkToVector{ incoming: Vec::new(), etalon: &src }),
+        &out_cfg,
+        &Storage::Files,
+        &CheckOptions { pass: Some("secret".to_owned()), ..check_opts() }).unwrap();
+
+    // a missing data chunk can be rebuilt by repair once metadata is back, but not recovered from
+    std::fs::remove_file(&out_cfg).unwrap();
+    std::fs::remove_file(format!("{}/000003", parent_dir)).unwrap();
+    let err = recover_cfg(&out_tpl, &Storage::Files, &Some("secret".to_owned()), 100).unwrap_err();
+    assert!(err.contains("000003"), "{}", err);
+
+    std::fs::remove_dir_all(parent_dir).unwrap();
+}
+
+#[test]
+fn too_long_auth_string() {
+    let parent_dir = "/tmp/too_long_auth";
+    let _ = std::fs::remove_dir_all(parent_dir);
+    std::fs::create_dir(parent_dir).unwrap();
+    let out_tpl = format!("{}/%%%%%%", parent_dir);
+    let opts = |auth_len: usize| BackupOptions { enc: Some(EncParams{
+        alg: Alg::Aes128Gcm,
+        auth_msg: "a".repeat(auth_len),
+        auth_every_bytes: 100,
+        pass: "secret".to_owned()
+    }), ..backup_opts() };
+
+    // a header of every chunk keeps the auth string, it must be read back from one
+    let err = backup(&[1; 100][..], 1000, &out_tpl, &Storage::Files, &opts(65536)).unwrap_err();
+    assert!(err.contains("too long"), "{}", err);
+    assert_eq!(std::fs::read_dir(parent_dir).unwrap().count(), 0);
+
+    backup(&[1; 100][..], 100_000, &out_tpl, &Storage::Files, &opts(65000)).unwrap();
+    std::fs::remove_file(format!("{}/000000.cfg", parent_dir)).unwrap();
+    let out_cfg = recover_cfg(&out_tpl, &Storage::Files, &Some("secret".to_owned()), 100).unwrap();
+    check(
+        Some(SinkToVector{ incoming: Vec::new(), etalon: &[1; 100] }),
+        &out_cfg,
+        &Storage::Files,
+        &CheckOptions { pass: Some("secret".to_owned()), ..check_opts() }).unwrap();
+
+    std::fs::remove_dir_all(parent_dir).unwrap();
+}
+
+#[test]
+fn recover_metadata_in_remote_storage() {
+    let dav = common::HttpStandIn::webdav(0, 0);
+    let storage = Storage::WebDav(WebDavConfig {
+        user: Some("user".to_owned()),
+        pass: Some("pass".to_owned()),
+        spool_dir: std::env::temp_dir().to_string_lossy().into_owned(),
+        retries: 0,
+        first_backoff: Duration::from_millis(10)
+    });
+    let out_tpl = format!("{}/bk/%%%%%%", dav.url);
+    let out_cfg = format!("{}/bk/000000.cfg", dav.url);
+
+    // an empty input still has a chunk with the header and the trailer
+    backup(&[][..], 1000, &out_tpl, &storage, &backup_opts()).unwrap();
+    assert!(dav.file("/bk/000000").is_some());
+    let err = recover_cfg(&out_tpl, &storage, &None, 100).unwrap_err();
+    assert!(err.contains("exists"), "{}", err);
+
+    dav.remove_file("/bk/000000.cfg");
+    assert_eq!(recover_cfg(&out_tpl, &storage, &None, 100).unwrap(), out_cfg);
+    assert!(String::from_utf8(dav.file("/bk/000000.cfg").unwrap()).unwrap().contains("misc_info=recovered="));
+    check(Some(SinkToVector{ incoming: Vec::new(), etalon: &[] }), &out_cfg, &storage, &check_opts()).unwrap();
+}
+
+#[test]
+fn stale_chunks_of_previous_backup() {
+    let parent_dir = "/tmp/stale_chunks";
+    let _ = std::fs::remove_dir_all(parent_dir);
+    std::fs::create_dir(parent_dir).unwrap();
+    let out_tpl = format!("{}/%%%%%%", parent_dir);
+    let out_cfg = format!("{}/000000.cfg", parent_dir);
+    let other_tpl = format!("{}/other%%%%%%", parent_dir);
+
+    let mut src: Vec<u8> = vec![0; 10000];
+    rand::thread_rng().fill_bytes(&mut src);
+    let backup_to = |data: &[u8], tpl: &str| {
+        backup(data, 1000, tpl, &Storage::Files, &backup_opts()).unwrap();
+    };
+    let archive_id = |cfg: &str| std::fs::read_to_string(cfg).unwrap().lines()
+        .find_map(|ln| ln.strip_prefix("archive_id=").map(|id| id.to_owned())).unwrap();
+
+    // rerun with a smaller input leaves higher-numbered chunks of the first run behind
+    backup_to(&src, &out_tpl);
+    let first_id = archive_id(&out_cfg);
+    backup_to(&src[..3000], &out_tpl);
+    assert_ne!(archive_id(&out_cfg), first_id);
+    let cfg = std::fs::read_to_string(&out_cfg).unwrap();
+    assert!(cfg.contains("\nnr_chunks=4\n"), "{}", cfg);
+    assert!(std::path::Path::new(&format!("{}/000010", parent_dir)).exists());
+    check(Some(SinkToVector{ incoming: Vec::new(), etalon: &src[..3000] }), &out_cfg, &Storage::Files, &check_opts()).unwrap();
+
+    // a chunk of another archive in place of the right one
+    backup_to(&src, &other_tpl);
+    std::fs::copy(format!("{}/other000001", parent_dir), format!("{}/000001", parent_dir)).unwrap();
+    let err = check(None::<SinkToVector>, &out_cfg, &Storage::Files, &check_opts()).unwrap_err();
+    assert!(err.contains("belongs to another archive"), "{}", err);
+
+    std::fs::remove_dir_all(parent_dir).unwrap();
+}
+
+struct CollectingSink(Vec<u8>);
+
+impl DataSink for &mut CollectingSink {
+    fn add(&mut self, data: &[u8]) -> Result<(), String> {
+        self.0.extend_from_slice(data);
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+fn assert_salvaged(src: &[u8], restored: &[u8], lost: &[(usize, usize)]) {
+    let mut expected = src.to_vec();
+    for &(offset, len) in lost {
+        expected[offset..offset + len].fill(0);
+    }
+    assert_eq!(restored, expected);
+}
+
+#[test]
+fn salvage_damaged_archive() {
+    for encrypted in [true, false] {
+        let parent_dir = "/tmp/salvage";
+        let _ = std::fs::remove_dir_all(parent_dir);
+        std::fs::create_dir(parent_dir).unwrap();
+        let out_tpl = format!("{}/%%%%%%", parent_dir);
+        let out_cfg = format!("{}/000000.cfg", parent_dir);
+
+        // partially compressible data, multi-threaded compression produces independent blocks of 1 MB
+        let mut src: Vec<u8> = vec![0; 8_000_000];
+        for portion in src.chunks_mut(1000).step_by(2) {
+            rand::thread_rng().fill_bytes(portion);
+        }
+        let (opt_enc, pass) = if encrypted {
+            (Some(EncParams{
+                alg: Alg::Aes128Gcm,
+                auth_msg: "The Author".to_owned(),
+                auth_every_bytes: 65536,
+                pass: "secret".to_owned()
+            }), Some("secret".to_owned()))
+        } else {
+            (None, None)
+        };
+        backup(&src[..], 300_000, &out_tpl, &Storage::Files, &BackupOptions { enc: opt_enc, nr_threads: 2, buf_size_bytes: 1_000_000, ..backup_opts() }).unwrap();
+
+        let mut sink = CollectingSink(Vec::new());
+        assert!(salvage(&mut sink, &out_cfg, &Storage::Files, &CheckOptions { pass: pass.clone(), buf_size_bytes: 100_000, ..check_opts() }, true).unwrap().is_empty());
+        assert_eq!(sink.0, src);
+
+        let mut chunk = std::fs::read(format!("{}/000002", parent_dir)).unwrap();
+        chunk[1000] ^= 1;
+        std::fs::write(format!("{}/000002", parent_dir), chunk).unwrap();
+        std::fs::remove_file(format!("{}/000009", parent_dir)).unwrap();
+        check(None::<SinkToVector>, &out_cfg, &Storage::Files, &CheckOptions { pass: pass.clone(), buf_size_bytes: 100_000, show_info: false, ..check_opts() }).unwrap_err();
+
+        let mut sink = CollectingSink(Vec::new());
+        let lost = salvage(&mut sink, &out_cfg, &Storage::Files, &CheckOptions { pass: pass.clone(), buf_size_bytes: 100_000, ..check_opts() }, true).unwrap();
+        let total_lost: usize = lost.iter().map(|(_, len)| len).sum();
+        assert!(!lost.is_empty() && total_lost < src.len() / 2);
+        assert_salvaged(&src, &sink.0, &lost);
+
+        let mut sink = CollectingSink(Vec::new());
+        assert_eq!(salvage(&mut sink, &out_cfg, &Storage::Files, &CheckOptions { pass: pass.clone(), buf_size_bytes: 100_000, ..check_opts() }, false).unwrap(), lost);
+        assert_eq!(sink.0.len(), src.len() - total_lost);
+
+        std::fs::remove_dir_all(parent_dir).unwrap();
+    }
+}
+
+#[test]
+fn backup_restore_via_commands() {
+    let local_dir = "/tmp/cmd_local";
+    let remote_dir = "/tmp/cmd_remote";
+    for dir in [local_dir, remote_dir] {
+        let _ = std::fs::remove_dir_all(dir);
+        std::fs::create_dir(dir).unwrap();
+    }
+    let out_tpl = format!("{}/%%%%%%", local_dir);
+    let out_cfg = format!("{}/000000.cfg", local_dir);
+
+    let mut src: Vec<u8> = vec![0; 10000];
+    rand::thread_rng().fill_bytes(&mut src);
+
+    backup(
+        &src[..],
+        1000,
+        &out_tpl,
+        &Storage::Command(format!("cat > {}/{{name}}", remote_dir)),
+        &BackupOptions { parity: Some(ParityParams{ data_chunks: 2, parity_chunks: 1 }), ..backup_opts() }).unwrap();
+
+    // only metadata is kept locally, its copy is uploaded along with chunks
+    assert_eq!(std::fs::read_dir(local_dir).unwrap().count(), 1);
+    assert_eq!(std::fs::read(&out_cfg).unwrap(), std::fs::read(format!("{}/000000.cfg", remote_dir)).unwrap());
+    std::fs::remove_file(format!("{}/000002", remote_dir)).unwrap();
+
+    check(
+        Some(SinkToVector{ incoming: Vec::new(), etalon: &src }),
+        &out_cfg,
+        &Storage::Command(format!("cat {}/{{name}}", remote_dir)),
+        &check_opts()).unwrap();
+
+    backup(&src[..], 1000, &out_tpl, &Storage::Command("cat > /dev/null; false".to_owned()), &backup_opts()).unwrap_err();
+
+    std::fs::remove_dir_all(local_dir).unwrap();
+    std::fs::remove_dir_all(remote_dir).unwrap();
+}
+
+#[test]
+fn backup_to_mirrors() {
+    let dirs = ["/tmp/mirror_a", "/tmp/mirror_b"];
+    for dir in dirs {
+        let _ = std::fs::remove_dir_all(dir);
+        std::fs::create_dir(dir).unwrap();
+    }
+    let s3 = common::HttpStandIn::s3(0);
+    let s3_cfg = S3Config {
+        endpoint: s3.url.clone(),
+        region: "us-east-1".to_owned(),
+        access_key: "access".to_owned(),
+        secret_key: "secret".to_owned(),
+        part_size: 1_000_000,
+        retry: RetryPolicy { retries: 0, first_backoff: Duration::from_millis(10) }
+    };
+
+    let mut src: Vec<u8> = vec![0; 10000];
+    rand::thread_rng().fill_bytes(&mut src);
+
+    // the last destination fails, but 3 of 4 copies are enough
+    let mirrors = Mirrors {
+        destinations: vec![
+            (format!("{}/bk%%%%", dirs[1]), Storage::Files),
+            ("s3://bucket/bk%%%%".to_owned(), Storage::S3(s3_cfg)),
+            ("/tmp/mirror_c/bk%%%%".to_owned(), Storage::Command("cat > /dev/null; false".to_owned()))
+        ],
+        min_copies: 3
+    };
+    backup(
+        &src[..],
+        1000,
+        &format!("{}/%%%%%%", dirs[0]),
+        &Storage::Files,
+        &BackupOptions { parity: Some(ParityParams{ data_chunks: 3, parity_chunks: 1 }), mirrors: Some(mirrors), ..backup_opts() }).unwrap();
+
+    let cfg = std::fs::read_to_string(format!("{}/000000.cfg", dirs[0])).unwrap();
+    assert!(cfg.contains("copies=/tmp/mirror_a/%%%%%%:ok,/tmp/mirror_b/bk%%%%:ok,s3://bucket/bk%%%%:ok,/tmp/mirror_c/bk%%%%:failed\n"));
+    assert_eq!(std::fs::read_to_string(format!("{}/bk0000.cfg", dirs[1])).unwrap(), cfg);
+    assert_eq!(s3.file("/bucket/bk0003.par").unwrap(), std::fs::read(format!("{}/000003.par", dirs[0])).unwrap());
+    assert_eq!(std::fs::read(format!("{}/bk0009", dirs[1])).unwrap(), std::fs::read(format!("{}/000009", dirs[0])).unwrap());
+
+    for cfg_path in [format!("{}/000000.cfg", dirs[0]), format!("{}/bk0000.cfg", dirs[1])] {
+        check(Some(SinkToVector{ incoming: Vec::new(), etalon: &src }), &cfg_path, &Storage::Files, &check_opts()).unwrap();
+    }
+
+    // not enough copies
+    let mirrors = Mirrors {
+        destinations: vec![("/tmp/mirror_c/bk%%%%".to_owned(), Storage::Command("cat > /dev/null; false".to_owned()))],
+        min_copies: 2
+    };
+    let err = backup(
+        &src[..],
+        1000,
+        &format!("{}/%%%%%%", dirs[0]),
+        &Storage::Files,
+        &BackupOptions { mirrors: Some(mirrors), ..backup_opts() }).unwrap_err();
+    assert!(err.contains("only 1 of 2 copies are written while 2 required"));
+
+    for dir in dirs {
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
+
+#[test]
+fn restore_with_failover() {
+    let dirs = ["/tmp/failover_a", "/tmp/failover_b"];
+    for dir in dirs {
+        let _ = std::fs::remove_dir_all(dir);
+        std::fs::create_dir(dir).unwrap();
+    }
+    let mut src: Vec<u8> = vec![0; 10000];
+    rand::thread_rng().fill_bytes(&mut src);
+
+    let mirrors = Mirrors { destinations: vec![(format!("{}/bk%%%%", dirs[1]), Storage::Files)], min_copies: 2 };
+    backup(&src[..], 1000, &format!("{}/%%%%%%", dirs[0]), &Storage::Files, &BackupOptions { mirrors: Some(mirrors), ..backup_opts() }).unwrap();
+    let copies = Storage::Failover(vec![
+        (format!("{}/000000.cfg", dirs[0]), Storage::Files),
+        (format!("{}/bk0000.cfg", dirs[1]), Storage::Files)
+    ]);
+    let cfg_path = format!("{}/000000.cfg", dirs[0]);
+
+    // every chunk is intact in at least one copy
+    let mut damaged = std::fs::read(format!("{}/000001", dirs[0])).unwrap();
+    damaged[10] ^= 1;
+    std::fs::write(format!("{}/000001", dirs[0]), damaged).unwrap();
+    std::fs::remove_file(format!("{}/000002", dirs[0])).unwrap();
+    std::fs::remove_file(format!("{}/bk0003", dirs[1])).unwrap();
+    check(Some(SinkToVector{ incoming: Vec::new(), etalon: &src }), &cfg_path, &copies, &check_opts()).unwrap();
+    check(None::<SinkToVector>, &cfg_path, &Storage::Files, &check_opts()).unwrap_err();
+
+    // every chunk is read from the main copy once, even a damaged one
+    let log = format!("{}/reads", dirs[0]);
+    let logged = Storage::Failover(vec![
+        (cfg_path.clone(), Storage::Command(format!("echo {{path}} >> {}; cat {{path}} 2>/dev/null || exit 4", log))),
+        (format!("{}/bk0000.cfg", dirs[1]), Storage::Files)
+    ]);
+    check(Some(SinkToVector{ incoming: Vec::new(), etalon: &src }), &cfg_path, &logged, &check_opts()).unwrap();
+    let reads = std::fs::read_to_string(&log).unwrap();
+    let mut read_once: Vec<&str> = reads.lines().collect();
+    read_once.dedup();
+    assert_eq!(read_once.len(), reads.lines().count());
+    assert!(read_once.contains(&"/tmp/failover_a/000001") && read_once.contains(&"/tmp/failover_a/000002"));
+
+    // metadata of the main copy is lost too
+    std::fs::remove_file(&cfg_path).unwrap();
+    check(Some(SinkToVector{ incoming: Vec::new(), etalon: &src }), &cfg_path, &copies, &check_opts()).unwrap();
+
+    // chunk is lost in both copies
+    std::fs::remove_file(format!("{}/bk0002", dirs[1])).unwrap();
+    let err = check(None::<SinkToVector>, &cfg_path, &copies, &check_opts()).unwrap_err();
+    assert!(err.contains("could not find /tmp/failover_a/000002 as chunk #2"));
+
+    for dir in dirs {
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
+
+#[test]
+fn backup_restore_throttled() {
+    let dir = "/tmp/throttled";
+    let _ = std::fs::remove_dir_all(dir);
+    std::fs::create_dir(dir).unwrap();
+    let mut src: Vec<u8> = vec![0; 100_000];
+    rand::thread_rng().fill_bytes(&mut src);
+    let cfg_path = format!("{}/000.cfg", dir);
+
+    // chunks are written and read at 500 KB/s, after the first 10 KB which pass at once
+    let throttle = ThrottleConfig { write_rate: Some(500_000), read_rate: Some(500_000), burst: Some(10_000), ..Default::default() };
+    let storage = Storage::Files.throttled(&throttle);
+    let start = Instant::now();
+    backup(&src[..], 30_000, &format!("{}/%%%", dir), &storage, &backup_opts()).unwrap();
+    assert!(start.elapsed() >= Duration::from_millis(150), "{:?}", start.elapsed());
+
+    let start = Instant::now();
+    check(Some(SinkToVector{ incoming: Vec::new(), etalon: &src }), &cfg_path, &storage, &check_opts()).unwrap();
+    assert!(start.elapsed() >= Duration::from_millis(150), "{:?}", start.elapsed());
+
+    // limits outside of the schedule do not apply
+    let storage = Storage::Files.throttled(&ThrottleConfig { read_rate: Some(1000), schedule: vec![(0, 1)], ..Default::default() });
+    if !matches!(time::OffsetDateTime::now_local().unwrap_or(time::OffsetDateTime::now_utc()).time().hour(), 0) {
+        check(Some(SinkToVector{ incoming: Vec::new(), etalon: &src }), &cfg_path, &storage, &check_opts()).unwrap();
+    }
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn backup_restore_with_io_retries() {
+    let dir = "/tmp/io_retries";
+    let _ = std::fs::remove_dir_all(dir);
+    std::fs::create_dir(dir).unwrap();
+    let mut src: Vec<u8> = vec![0; 100_000];
+    rand::thread_rng().fill_bytes(&mut src);
+
+    let storage = Storage::Files.with_retries(&RetryPolicy { retries: 3, first_backoff: Duration::from_millis(10) });
+    backup(&src[..], 30_000, &format!("{}/%%%", dir), &storage, &BackupOptions { parity: Some(ParityParams{ data_chunks: 2, parity_chunks: 1 }), ..backup_opts() }).unwrap();
+    check(Some(SinkToVector{ incoming: Vec::new(), etalon: &src }), &format!("{}/000.cfg", dir), &storage, &check_opts()).unwrap();
+
+    // fatal errors are not retried
+    std::fs::remove_dir_all(dir).unwrap();
+    std::fs::write(dir, b"not a directory").unwrap();
+    let err = backup(&src[..], 30_000, &format!("{}/%%%", dir), &storage, &backup_opts()).unwrap_err();
+    assert!(!err.contains("gave up"), "{}", err);
+    std::fs::remove_file(dir).unwrap();
+}
+
+#[test]
+fn backup_with_space_check() {
+    let dir = "/tmp/space_check";
+    let _ = std::fs::remove_dir_all(dir);
+    std::fs::create_dir(dir).unwrap();
+    let mut src: Vec<u8> = vec![0; 100_000];
+    rand::thread_rng().fill_bytes(&mut src[..50_000]);
+    let out_tpl = format!("{}/%%%", dir);
+
+    let space = SpaceCheck { path: dir.to_owned(), margin: 0, poll: None, input_len: Some(src.len()) };
+    backup(&src[..], 20_000, &out_tpl, &Storage::Files, &BackupOptions { parity: Some(ParityParams{ data_chunks: 2, parity_chunks: 1 }), space: Some(space.clone()), ..backup_opts() }).unwrap();
+    check(Some(SinkToVector{ incoming: Vec::new(), etalon: &src }), &format!("{}/000.cfg", dir), &Storage::Files, &check_opts()).unwrap();
+    std::fs::remove_dir_all(dir).unwrap();
+    std::fs::create_dir(dir).unwrap();
+
+    // the estimate does not fit
+    let huge = SpaceCheck { input_len: Some(usize::MAX / 4), ..space.clone() };
+    let err = backup(&src[..], 20_000, &out_tpl, &Storage::Files, &BackupOptions { space: Some(huge), ..backup_opts() }).unwrap_err();
+    assert!(err.contains("archive is estimated to take"), "{}", err);
+    assert_eq!(std::fs::read_dir(dir).unwrap().count(), 0);
+
+    // no space for the first chunk
+    let no_space = SpaceCheck { margin: usize::MAX / 4, input_len: None, ..space };
+    let err = backup(&src[..], 20_000, &out_tpl, &Storage::Files, &BackupOptions { space: Some(no_space), ..backup_opts() }).unwrap_err();
+    assert!(err.contains("are needed for the next chunk"), "{}", err);
+    assert_eq!(std::fs::read_dir(dir).unwrap().count(), 0);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn backup_restore_sharded() {
+    let dir = "/tmp/sharded";
+    let _ = std::fs::remove_dir_all(dir);
+    let dav = common::HttpStandIn::webdav(0, 0);
+    let dav_cfg = WebDavConfig {
+        user: Some("user".to_owned()),
+        pass: Some("pass".to_owned()),
+        spool_dir: std::env::temp_dir().to_string_lossy().into_owned(),
+        retries: 0,
+        first_backoff: Duration::from_millis(10)
+    };
+    let mut src: Vec<u8> = vec![0; 25_000];
+    rand::thread_rng().fill_bytes(&mut src);
+
+    // chunk directories are created as needed, metadata stays on top
+    let mirrors = Mirrors { destinations: vec![(format!("{}/bk/{{n/10}}/{{n%10}}", dav.url), Storage::WebDav(dav_cfg.clone()))], min_copies: 2 };
+    backup(
+        &src[..],
+        1000,
+        &format!("{}/{{n/10}}/{{n%10}}", dir),
+        &Storage::Files,
+        &BackupOptions { parity: Some(ParityParams{ data_chunks: 5, parity_chunks: 1 }), mirrors: Some(mirrors), ..backup_opts() }).unwrap();
+    let cfg = std::fs::read_to_string(format!("{}/0.cfg", dir)).unwrap();
+    assert!(cfg.contains("chunk_pattern={n/10}/{n%10}\n"), "{}", cfg);
+    assert_eq!(std::fs::read(format!("{}/2/4", dir)).unwrap().len(), 1000);
+    assert!(std::fs::metadata(format!("{}/0/4.par", dir)).is_ok());
+    assert_eq!(dav.file("/bk/2/4").unwrap(), std::fs::read(format!("{}/2/4", dir)).unwrap());
+    assert!(dav.dirs().contains(&"/bk/2".to_owned()));
+
+    // a chunk lost in one copy is taken from another one
+    std::fs::remove_file(format!("{}/1/3", dir)).unwrap();
+    let copies = Storage::Failover(vec![
+        (format!("{}/0.cfg", dir), Storage::Files),
+        (format!("{}/bk/0.cfg", dav.url), Storage::WebDav(dav_cfg))
+    ]);
+    check(Some(SinkToVector{ incoming: Vec::new(), etalon: &src }), &format!("{}/0.cfg", dir), &copies, &check_opts()).unwrap();
+
+    // chunk names repeat in every directory
+    let by_name = Storage::Command("cat > /tmp/sharded/{name}".to_owned());
+    let err = backup(&src[..], 1000, "/tmp/sharded/{n/10}/{n%10}", &by_name, &backup_opts()).unwrap_err();
+    assert!(err.contains("{name} in chunk command 'cat > /tmp/sharded/{name}' is not unique"), "{}", err);
+    let by_name = Storage::Command("cat /tmp/sharded/{name}".to_owned());
+    let err = check(None::<SinkToVector>, &format!("{}/0.cfg", dir), &by_name, &check_opts()).unwrap_err();
+    assert!(err.contains("is not unique when chunks are spread over directories"), "{}", err);
+    check(None::<SinkToVector>, &format!("{}/0.cfg", dir), &Storage::Command("cat {path}".to_owned()), &check_opts()).unwrap();
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn backup_with_post_chunk_hook() {
+    let local_dir = "/tmp/hook_local";
+    let remote_dir = "/tmp/hook_remote";
+    for dir in [local_dir, remote_dir] {
+        let _ = std::fs::remove_dir_all(dir);
+        std::fs::create_dir(dir).unwrap();
+    }
+    let out_tpl = format!("{}/%%%%%%", local_dir);
+    let out_cfg = format!("{}/000000.cfg", local_dir);
+
+    let mut src: Vec<u8> = vec![0; 10000];
+    rand::thread_rng().fill_bytes(&mut src);
+
+    // every hook also records how many chunks are on local disk at the moment
+    let hook = |cmd: String| Some(PostChunkHook { cmd, max_pending: 2, retries: 1, first_backoff: Duration::from_millis(10) });
+    let upload = format!(
+        "ls {l} | grep -v cfg | wc -l >> {r}/spool.log; mv \"$BIGARCHIVER_CHUNK_PATH\" {r}/",
+        l = local_dir, r = remote_dir);
+    backup(
+        &src[..],
+        1000,
+        &out_tpl,
+        &Storage::Files,
+        &BackupOptions { parity: Some(ParityParams{ data_chunks: 3, parity_chunks: 1 }), post_chunk_hook: hook(upload), ..backup_opts() }).unwrap();
+
+    assert_eq!(std::fs::read_dir(local_dir).unwrap().count(), 1);
+    let spool = std::fs::read_to_string(format!("{}/spool.log", remote_dir)).unwrap();
+    assert!(spool.lines().all(|l| l.trim().parse::<usize>().unwrap() <= 2));
+    check(
+        Some(SinkToVector{ incoming: Vec::new(), etalon: &src }),
+        &out_cfg,
+        &Storage::Command(format!("cat {}/{{name}}", remote_dir)),
+        &check_opts()).unwrap();
+
+    let err = backup(
+        &src[..],
+        1000,
+        &out_tpl,
+        &Storage::Files,
+        &BackupOptions { post_chunk_hook: hook("false".to_owned()), ..backup_opts() }).unwrap_err();
+    assert!(err.contains("failed after 2 attempt(s)"));
+
+    std::fs::remove_dir_all(local_dir).unwrap();
+    std::fs::remove_dir_all(remote_dir).unwrap();
+}
+
+#[test]
+fn restore_with_fetch_hook() {
+    let local_dir = "/tmp/fetch_hook_local";
+    let remote_dir = "/tmp/fetch_hook_remote";
+    for dir in [local_dir, remote_dir] {
+        let _ = std::fs::remove_dir_all(dir);
+        std::fs::create_dir(dir).unwrap();
+    }
+    let out_tpl = format!("{}/%%%%%%", local_dir);
+    let out_cfg = format!("{}/000000.cfg", local_dir);
+
+    let mut src: Vec<u8> = vec![0; 10000];
+    rand::thread_rng().fill_bytes(&mut src);
+
+    backup(
+        &src[..],
+        1000,
+        &out_tpl,
+        &Storage::Command(format!("cat > {}/{{name}}", remote_dir)),
+        &BackupOptions { parity: Some(ParityParams{ data_chunks: 3, parity_chunks: 1 }), ..backup_opts() }).unwrap();
+    std::fs::remove_file(format!("{}/000004", remote_dir)).unwrap();
+
+    // chunks present locally at the moment of every fetch
+    let storage = Storage::Fetched(FetchHook {
+        fetch_cmd: format!(
+            "ls {l} | grep -v cfg | wc -l >> {r}/scratch.log; cp {r}/$BIGARCHIVER_CHUNK_NAME \"$BIGARCHIVER_CHUNK_PATH\"",
+            l = local_dir, r = remote_dir),
+        cleanup_cmd: Some("rm \"$BIGARCHIVER_CHUNK_PATH\"".to_owned()),
+        prefetch: 2,
+        retries: 1,
+        first_backoff: Duration::from_millis(10)
+    });
+    check(
+        Some(SinkToVector{ incoming: Vec::new(), etalon: &src }),
+        &out_cfg,
+        &storage,
+        &check_opts()).unwrap();
+
+    assert_eq!(std::fs::read_dir(local_dir).unwrap().count(), 1);
+    let scratch = std::fs::read_to_string(format!("{}/scratch.log", remote_dir)).unwrap();
+    assert!(scratch.lines().all(|l| l.trim().parse::<usize>().unwrap() <= 6)); // parity group and prefetched chunks
+
+    std::fs::remove_dir_all(local_dir).unwrap();
+    std::fs::remove_dir_all(remote_dir).unwrap();
+}
+
+#[derive(Debug, PartialEq)]
+enum Http {
+    S3,
+    WebDav
+}
+
+#[test_matrix(
+    [Http::S3, Http::WebDav]
+)]
+fn backup_restore_via_http(kind: Http) {
+    // some requests fail and some WebDAV uploads are truncated, both are retried
+    let (server, storage, wrong_storage, denied, prefix, root) = match kind {
+        Http::S3 => {
+            let server = common::HttpStandIn::s3(7);
+            let cfg = S3Config {
+                endpoint: server.url.clone(),
+                region: "us-east-1".to_owned(),
+                access_key: "access".to_owned(),
+                secret_key: "secret".to_owned(),
+                part_size: 1000,
+                retry: RetryPolicy { retries: 3, first_backoff: Duration::from_millis(10) }
+            };
+            (server, Storage::S3(cfg.clone()), Storage::S3(S3Config { access_key: "wrong".to_owned(), ..cfg }), "HTTP 403", "s3://bucket".to_owned(), "/bucket")
+        },
+        Http::WebDav => {
+            let server = common::HttpStandIn::webdav(5, 4);
+            let cfg = WebDavConfig {
+                user: Some("user".to_owned()),
+                pass: Some("pass".to_owned()),
+                spool_dir: std::env::temp_dir().to_string_lossy().into_owned(),
+                retries: 3,
+                first_backoff: Duration::from_millis(10)
+            };
+            let prefix = server.url.clone();
+            (server, Storage::WebDav(cfg.clone()), Storage::WebDav(WebDavConfig { pass: Some("wrong".to_owned()), ..cfg }), "HTTP 401", prefix, "")
+        }
+    };
+
+    let mut src: Vec<u8> = vec![0; 20000];
+    rand::thread_rng().fill_bytes(&mut src);
+
+    backup(
+        &src[..],
+        2500,
+        &format!("{}/bk/%%%%%%", prefix),
+        &storage,
+        &BackupOptions { parity: Some(ParityParams{ data_chunks: 2, parity_chunks: 1 }), ..backup_opts() }).unwrap();
+
+    // data, parity and metadata files
+    assert!(server.nr_files() > 3);
+    assert_eq!(server.file(&format!("{}/bk/000001", root)).unwrap().len(), 2500);
+    let cfg_text = String::from_utf8(server.file(&format!("{}/bk/000000.cfg", root)).unwrap()).unwrap();
+
+    if kind == Http::S3 {
+        // chunks bigger than a part are uploaded in parts
+        assert!(server.nr_multipart_uploads() > 0);
+        assert_eq!(server.nr_unfinished_uploads(), 0);
+
+        // data and parity chunks are tagged with their length and hash as in metadata
+        for (object, key) in [("/bucket/bk/000001", "chunks="), ("/bucket/bk/000000.par", "parity_chunks=")] {
+            let tag = server.tag(object, "bigarchiver-chunk").unwrap();
+            assert!(tag.starts_with("2500:"), "{}", tag);
+            assert!(cfg_text.lines().any(|l| l.starts_with(key) && l.contains(&tag)), "{} is not in {}", tag, cfg_text);
+        }
+        assert!(server.tag("/bucket/bk/000000.cfg", "bigarchiver-chunk").is_none());
+    }
+    server.remove_file(&format!("{}/bk/000002", root));
+
+    check(
+        Some(SinkToVector{ incoming: Vec::new(), etalon: &src }),
+        &format!("{}/bk/000000.cfg", prefix),
+        &storage,
+        &check_opts()).unwrap();
+
+    // the lost chunk is rebuilt on the server, metadata is replaced without leaving a temporary file
+    assert_eq!(repair(&format!("{}/bk/000000.cfg", prefix), &storage, 100).unwrap(), 1);
+    assert_eq!(server.file(&format!("{}/bk/000002", root)).unwrap().len(), 2500);
+    assert!(String::from_utf8(server.file(&format!("{}/bk/000000.cfg", root)).unwrap()).unwrap().contains("repair_info="));
+    assert!(server.file(&format!("{}/bk/000000.cfg.tmp", root)).is_none());
+
+    check(None::<SinkToVector>, &format!("{}/other/000000.cfg", prefix), &storage, &check_opts()).unwrap_err();
+
+    let err = check(None::<SinkToVector>, &format!("{}/bk/000000.cfg", prefix), &wrong_storage, &check_opts()).unwrap_err();
+    assert!(err.contains(denied), "{}", err);
+}
+
+// needs sshd with a writable directory, e.g. BIGARCHIVER_TEST_SFTP=sftp://user@localhost/tmp/bk,
+// and key-based access to it from ssh-agent or ~/.ssh
+#[test]
+#[ignore]
+fn backup_restore_via_sftp() {
+    let dir = std::env::var("BIGARCHIVER_TEST_SFTP").expect("BIGARCHIVER_TEST_SFTP is not set");
+    let storage = Storage::Sftp(SftpConfig::new(&None));
+
+    let mut src: Vec<u8> = vec![0; 20000];
+    rand::thread_rng().fill_bytes(&mut src);
+
+    backup(
+        &src[..],
+        2500,
+        &format!("{}/bk%%%%%%", dir),
+        &storage,
+        &BackupOptions { parity: Some(ParityParams{ data_chunks: 2, parity_chunks: 1 }), ..backup_opts() }).unwrap();
+
+    check(
+        Some(SinkToVector{ incoming: Vec::new(), etalon: &src }),
+        &format!("{}/bk000000.cfg", dir),
+        &storage,
+        &check_opts()).unwrap();
+
+    check(None::<SinkToVector>, &format!("{}/missing000000.cfg", dir), &storage, &check_opts()).unwrap_err();
+}
@@ -0,0 +1,280 @@
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use tiny_http::{Header, Method, Request, Response, Server};
+
+#[derive(Default)]
+struct Store {
+    files: HashMap<String, Vec<u8>>,
+    nr_requests: usize,
+    // S3
+    tags: HashMap<String, String>,
+    uploads: HashMap<String, BTreeMap<usize, Vec<u8>>>,
+    nr_multipart: usize,
+    // WebDAV
+    dirs: Vec<String>,
+    nr_puts: usize,
+    truncate_every: usize
+}
+
+struct Reply {
+    code: u16,
+    body: Vec<u8>,
+    etag: Option<String>
+}
+
+impl Reply {
+    fn new(code: u16, body: &[u8]) -> Self {
+        Self { code, body: body.to_vec(), etag: None }
+    }
+}
+
+// serves an authorized request: path, query parameters and body
+type Serve = fn(&mut Store, &Request, &str, &HashMap<&str, &str>, Vec<u8>) -> Reply;
+
+// what differs between the protocols: how failures and denials look, who is authorized and how requests are served
+struct Protocol {
+    failure: (u16, &'static [u8]),
+    denial: (u16, &'static [u8]),
+    authorized: fn(&Request) -> bool,
+    serve: Serve
+}
+
+// minimal in-process HTTP server keeping files in memory, which imitates S3 or WebDAV;
+// fails every `fail_every`-th request with a server error to exercise retries
+pub struct HttpStandIn {
+    pub url: String, // S3 endpoint, or dav://host:port
+    state: Arc<Mutex<Store>>
+}
+
+fn header(req: &Request, name: &str) -> Option<String> {
+    req.headers().iter().find(|h| h.field.to_string().eq_ignore_ascii_case(name)).map(|h| h.value.to_string())
+}
+
+fn sha256_base64(data: &[u8]) -> String {
+    BASE64.encode(ring::digest::digest(&ring::digest::SHA256, data).as_ref())
+}
+
+// offset of a ranged read like bytes=100-
+fn range_offset(req: &Request) -> Option<usize> {
+    header(req, "range").and_then(|r| r.strip_prefix("bytes=").and_then(|r| r.strip_suffix('-')).map(|o| o.parse::<usize>().unwrap()))
+}
+
+fn handle(state: &Mutex<Store>, proto: &Protocol, fail_every: usize, mut req: Request) {
+    let mut body = Vec::new();
+    req.as_reader().read_to_end(&mut body).unwrap();
+    let (path, query) = req.url().split_once('?').map(|(p, q)| (p.to_owned(), q.to_owned())).unwrap_or((req.url().to_owned(), String::new()));
+    let params: HashMap<&str, &str> = query.split('&').filter(|p| !p.is_empty()).map(|p| p.split_once('=').unwrap_or((p, ""))).collect();
+
+    let mut st = state.lock().unwrap();
+    st.nr_requests += 1;
+    let reply = if fail_every > 0 && st.nr_requests.is_multiple_of(fail_every) {
+        Reply::new(proto.failure.0, proto.failure.1)
+    } else if !(proto.authorized)(&req) {
+        Reply::new(proto.denial.0, proto.denial.1)
+    } else {
+        (proto.serve)(&mut st, &req, &path, &params, body)
+    };
+    let mut resp = Response::from_data(reply.body).with_status_code(reply.code);
+    if let Some(etag) = reply.etag {
+        resp = resp.with_header(Header::from_bytes("ETag", etag).unwrap());
+    }
+    let _ = req.respond(resp);
+}
+
+// verifies signed payload hashes and checksums, supports multipart uploads, tagging and ranged reads
+fn serve_s3(st: &mut Store, req: &Request, path: &str, params: &HashMap<&str, &str>, body: Vec<u8>) -> Reply {
+    let payload_hash: String = ring::digest::digest(&ring::digest::SHA256, &body).as_ref().iter().map(|b| format!("{:02x}", b)).collect();
+    if header(req, "x-amz-content-sha256") != Some(payload_hash) {
+        return Reply::new(400, b"<Error><Code>XAmzContentSHA256Mismatch</Code></Error>");
+    }
+    if header(req, "x-amz-checksum-sha256").is_some_and(|checksum| checksum != sha256_base64(&body)) {
+        return Reply::new(400, b"<Error><Code>BadDigest</Code></Error>");
+    }
+
+    match (req.method(), params.get("uploadId"), params.get("partNumber")) {
+        (Method::Post, None, _) if params.contains_key("uploads") => {
+            let id = format!("upload{}", st.nr_requests);
+            st.uploads.insert(id.clone(), BTreeMap::new());
+            st.nr_multipart += 1;
+            Reply::new(200, format!("<InitiateMultipartUploadResult><UploadId>{}</UploadId></InitiateMultipartUploadResult>", id).as_bytes())
+        },
+        (Method::Put, Some(id), Some(part)) => match st.uploads.get_mut(*id) {
+            Some(parts) => {
+                parts.insert(part.parse().unwrap(), body);
+                Reply { etag: Some(format!("\"{}-{}\"", id, part)), ..Reply::new(200, b"") }
+            },
+            None => Reply::new(404, b"")
+        },
+        (Method::Post, Some(id), None) => {
+            let completion = String::from_utf8(body).unwrap();
+            match st.uploads.remove(*id) {
+                Some(parts) if parts.keys().all(|n| completion.contains(&format!("<ETag>\"{}-{}\"</ETag>", id, n))) => {
+                    st.tags.remove(path);
+                    st.files.insert(path.to_owned(), parts.into_values().flatten().collect());
+                    Reply::new(200, b"<CompleteMultipartUploadResult/>")
+                },
+                _ => Reply::new(400, b"<Error><Code>InvalidPart</Code></Error>")
+            }
+        },
+        (Method::Delete, Some(id), None) => {
+            st.uploads.remove(*id);
+            Reply::new(204, b"")
+        },
+        (Method::Delete, None, None) => {
+            st.tags.remove(path);
+            st.files.remove(path);
+            Reply::new(204, b"")
+        },
+        (Method::Put, None, None) if params.contains_key("tagging") => {
+            match (st.files.contains_key(path), header(req, "x-amz-checksum-sha256")) {
+                (true, Some(_)) => {
+                    st.tags.insert(path.to_owned(), String::from_utf8(body).unwrap());
+                    Reply::new(200, b"")
+                },
+                (true, None) => Reply::new(400, b"<Error><Code>InvalidRequest</Code></Error>"),
+                (false, _) => Reply::new(404, b"<Error><Code>NoSuchKey</Code></Error>")
+            }
+        },
+        (Method::Put, None, None) => {
+            st.tags.remove(path);
+            st.files.insert(path.to_owned(), body);
+            Reply::new(200, b"")
+        },
+        (Method::Get, None, None) => {
+            let offset = range_offset(req).unwrap_or(0);
+            match st.files.get(path) {
+                Some(data) => Reply::new(if offset > 0 { 206 } else { 200 }, &data[offset..]),
+                None => Reply::new(404, b"<Error><Code>NoSuchKey</Code></Error>")
+            }
+        },
+        _ => Reply::new(400, b"")
+    }
+}
+
+fn etag(data: &[u8]) -> String {
+    format!("\"{}\"", &sha256_base64(data)[..16])
+}
+
+// PUT, GET with ranges, DELETE, MKCOL, MOVE and PROPFIND of size and etag; stores only a half of every `truncate_every`-th upload
+fn serve_dav(st: &mut Store, req: &Request, path: &str, _: &HashMap<&str, &str>, mut body: Vec<u8>) -> Reply {
+    match req.method().as_str() {
+        "PUT" => {
+            st.nr_puts += 1;
+            let tag = etag(&body);
+            if st.truncate_every > 0 && st.nr_puts.is_multiple_of(st.truncate_every) {
+                body.truncate(body.len() / 2);
+            }
+            st.files.insert(path.to_owned(), body);
+            Reply { etag: Some(tag), ..Reply::new(201, b"") }
+        },
+        "GET" => {
+            let offset = range_offset(req);
+            match st.files.get(path) {
+                Some(data) => Reply::new(if offset.is_some() { 206 } else { 200 }, &data[offset.unwrap_or(0)..]),
+                None => Reply::new(404, b"")
+            }
+        },
+        "PROPFIND" => match st.files.get(path) {
+            Some(data) => {
+                let xml = format!("<?xml version=\"1.0\"?><d:multistatus xmlns:d=\"DAV:\"><d:response><d:href>{}</d:href><d:propstat><d:prop>\
+                    <d:getcontentlength>{}</d:getcontentlength><d:getetag>{}</d:getetag></d:prop></d:propstat></d:response></d:multistatus>",
+                    path, data.len(), etag(data).replace('"', "&quot;"));
+                Reply::new(207, xml.as_bytes())
+            },
+            None if st.dirs.iter().any(|d| d == path) => Reply::new(207, b""),
+            None => Reply::new(404, b"")
+        },
+        "DELETE" => match st.files.remove(path) {
+            Some(_) => Reply::new(204, b""),
+            None => Reply::new(404, b"")
+        },
+        "MKCOL" => {
+            st.dirs.push(path.to_owned());
+            Reply::new(201, b"")
+        },
+        "MOVE" => {
+            // destination is a full URL: http://host:port/path
+            let dest = header(req, "destination")
+                .and_then(|d| d.split_once("://").and_then(|(_, rest)| rest.find('/').map(|i| rest[i..].to_owned())));
+            match (dest, st.files.remove(path)) {
+                (Some(dest), Some(data)) => {
+                    st.files.insert(dest, data);
+                    Reply::new(201, b"")
+                },
+                (None, _) => Reply::new(400, b""),
+                (_, None) => Reply::new(404, b"")
+            }
+        },
+        _ => Reply::new(405, b"")
+    }
+}
+
+impl HttpStandIn {
+    fn start(scheme: &str, fail_every: usize, truncate_every: usize, proto: Protocol) -> Self {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let url = format!("{}://{}", scheme, server.server_addr().to_ip().unwrap());
+        let state = Arc::new(Mutex::new(Store { truncate_every, ..Default::default() }));
+        let server_state = state.clone();
+        thread::spawn(move || {
+            for req in server.incoming_requests() {
+                handle(&server_state, &proto, fail_every, req);
+            }
+        });
+        Self { url, state }
+    }
+
+    // S3 for the access key "access"; fails with 500
+    pub fn s3(fail_every: usize) -> Self {
+        Self::start("http", fail_every, 0, Protocol {
+            failure: (500, b"<Error><Code>InternalError</Code></Error>"),
+            denial: (403, b"<Error><Code>AccessDenied</Code></Error>"),
+            authorized: |req| header(req, "authorization").is_some_and(|a| a.starts_with("AWS4-HMAC-SHA256 Credential=access/")),
+            serve: serve_s3
+        })
+    }
+
+    // WebDAV for user:pass; fails with 503, and stores only a half of every `truncate_every`-th upload
+    pub fn webdav(fail_every: usize, truncate_every: usize) -> Self {
+        Self::start("dav", fail_every, truncate_every, Protocol {
+            failure: (503, b""),
+            denial: (401, b""),
+            authorized: |req| header(req, "authorization") == Some(format!("Basic {}", BASE64.encode("user:pass"))),
+            serve: serve_dav
+        })
+    }
+
+    // file by its path on the server, e.g. /bk/000000.cfg, or /bucket/bk/000000.cfg for S3
+    pub fn file(&self, path: &str) -> Option<Vec<u8>> {
+        self.state.lock().unwrap().files.get(path).cloned()
+    }
+
+    pub fn remove_file(&self, path: &str) {
+        self.state.lock().unwrap().files.remove(path);
+    }
+
+    pub fn nr_files(&self) -> usize {
+        self.state.lock().unwrap().files.len()
+    }
+
+    // value of a tag of the S3 object
+    pub fn tag(&self, path: &str, key: &str) -> Option<String> {
+        let tagging = self.state.lock().unwrap().tags.get(path).cloned()?;
+        let start = tagging.find(&format!("<Key>{}</Key><Value>", key))? + key.len() + 18;
+        tagging[start..].split_once("</Value>").map(|(value, _)| value.to_owned())
+    }
+
+    pub fn nr_multipart_uploads(&self) -> usize {
+        self.state.lock().unwrap().nr_multipart
+    }
+
+    pub fn nr_unfinished_uploads(&self) -> usize {
+        self.state.lock().unwrap().uploads.len()
+    }
+
+    // collections created by WebDAV clients, e.g. /bk
+    pub fn dirs(&self) -> Vec<String> {
+        self.state.lock().unwrap().dirs.clone()
+    }
+}